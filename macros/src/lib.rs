@@ -0,0 +1,180 @@
+//! Attribute macros for [actix-extensible-rate-limit](https://docs.rs/actix-extensible-rate-limit),
+//! see `actix_extensible_rate_limit::rate_limit`.
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{quote, quote_spanned};
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::{
+    parse_macro_input, Error, Expr, ExprLit, FnArg, ItemFn, Lit, LitStr, MetaNameValue, ReturnType,
+    Token,
+};
+
+struct Args {
+    per: LitStr,
+    max: u64,
+    key: proc_macro2::TokenStream,
+    name: Option<String>,
+}
+
+/// Rate limit a single actix-web handler, see the documentation of
+/// `actix_extensible_rate_limit::rate_limit`.
+#[proc_macro_attribute]
+pub fn rate_limit(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut function = parse_macro_input!(item as ItemFn);
+    let args = match Punctuated::<MetaNameValue, Token![,]>::parse_terminated.parse(attr) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    if function.sig.asyncness.is_none() {
+        return Error::new_spanned(
+            function.sig.fn_token,
+            "rate_limit requires an async handler",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    // Qualified by the module path, so that handlers with the same name in different modules
+    // don't share a limit unless `name` says so
+    let route = match args.name {
+        Some(name) => quote!(#name),
+        None => {
+            let ident = function.sig.ident.to_string();
+            quote!(::std::concat!(::std::module_path!(), "::", #ident))
+        }
+    };
+    let per = args.per;
+    // Parsed by the main crate, so that `per` has the same grammar as rate strings; evaluated as a
+    // constant, so that an invalid duration is still a compile error
+    let message = LitStr::new(
+        &format!(
+            "invalid duration `{}` for `per`, expected e.g. \"500ms\", \"10s\", \"5m\", \"1h\" or \"1d\"",
+            per.value()
+        ),
+        per.span(),
+    );
+    let per = quote_spanned! {per.span()=>
+        const __RATE_LIMIT_PER: ::std::time::Duration =
+            match ::actix_extensible_rate_limit::__private::parse_duration(#per) {
+                ::std::option::Option::Some(per) if !per.is_zero() => per,
+                _ => ::std::panic!(#message),
+            };
+    };
+    let max = args.max;
+    let key = args.key;
+    let output = match &function.sig.output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(_, ty) => quote!(#ty),
+    };
+    // The original handler becomes an inner function, called with the same arguments
+    let mut inner = function.clone();
+    inner.sig.ident = syn::Ident::new("__rate_limited", Span::call_site());
+    inner.attrs.clear();
+    inner.vis = syn::Visibility::Inherited;
+    let mut args = Vec::new();
+    for (i, input) in function.sig.inputs.iter_mut().enumerate() {
+        match input {
+            FnArg::Typed(typed) => {
+                let ident = syn::Ident::new(&format!("__arg{i}"), Span::call_site());
+                typed.pat = syn::parse_quote!(#ident);
+                args.push(ident);
+            }
+            FnArg::Receiver(receiver) => {
+                return Error::new_spanned(receiver, "rate_limit cannot be used on methods")
+                    .to_compile_error()
+                    .into()
+            }
+        }
+    }
+    function.sig.inputs.insert(
+        0,
+        syn::parse_quote!(__rate_limit_req: ::actix_web::HttpRequest),
+    );
+    function.sig.output = syn::parse_quote!(
+        -> ::actix_web::Either<::actix_web::HttpResponse, #output>
+    );
+    function.block = syn::parse_quote!({
+        #inner
+        #per
+        if let Some(response) = ::actix_extensible_rate_limit::__private::check_route(
+            &__rate_limit_req,
+            #route,
+            __RATE_LIMIT_PER,
+            #max,
+            #key,
+        )
+        .await
+        {
+            return ::actix_web::Either::Left(response);
+        }
+        ::actix_web::Either::Right(__rate_limited(#(#args),*).await)
+    });
+    quote!(#function).into()
+}
+
+fn parse_args(args: Punctuated<MetaNameValue, Token![,]>) -> Result<Args, Error> {
+    let mut per = None;
+    let mut max = None;
+    let mut key = None;
+    let mut name = None;
+    for arg in args {
+        let ident = arg
+            .path
+            .get_ident()
+            .ok_or_else(|| Error::new_spanned(&arg.path, "expected `per`, `max`, `key` or `name`"))?
+            .to_string();
+        match ident.as_str() {
+            "per" => per = Some(lit_str(&arg.value)?),
+            "max" => match &arg.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Int(int), ..
+                }) => max = Some(int.base10_parse::<u64>()?),
+                value => return Err(Error::new_spanned(value, "expected an integer")),
+            },
+            "key" => {
+                let value = lit_str(&arg.value)?;
+                let variant = match value.value().as_str() {
+                    "global" => quote!(Global),
+                    "peer_ip" => quote!(PeerIp),
+                    "real_ip" => quote!(RealIp),
+                    "path" => quote!(Path),
+                    _ => {
+                        return Err(Error::new_spanned(
+                            value,
+                            "expected `global`, `peer_ip`, `real_ip` or `path`",
+                        ))
+                    }
+                };
+                key = Some(quote!(::actix_extensible_rate_limit::__private::RouteKey::#variant));
+            }
+            "name" => name = Some(lit_str(&arg.value)?.value()),
+            _ => {
+                return Err(Error::new_spanned(
+                    &arg.path,
+                    "expected `per`, `max`, `key` or `name`",
+                ))
+            }
+        }
+    }
+    Ok(Args {
+        per: per.ok_or_else(|| Error::new(Span::call_site(), "missing `per`"))?,
+        max: max.ok_or_else(|| Error::new(Span::call_site(), "missing `max`"))?,
+        key: key
+            .unwrap_or_else(|| quote!(::actix_extensible_rate_limit::__private::RouteKey::PeerIp)),
+        name,
+    })
+}
+
+fn lit_str(expr: &Expr) -> Result<LitStr, Error> {
+    match expr {
+        Expr::Lit(ExprLit {
+            lit: Lit::Str(s), ..
+        }) => Ok(s.clone()),
+        _ => Err(Error::new_spanned(expr, "expected a string")),
+    }
+}
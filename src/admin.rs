@@ -0,0 +1,339 @@
+//! Administrative HTTP endpoints for inspecting and resetting rate limits.
+use crate::backend::overrides::{LimitOverride, OverrideStore};
+use crate::backend::{InspectableBackend, KeyStatus};
+use actix_web::guard::Guard;
+use actix_web::{web, HttpResponse, Scope};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_TOP_KEYS: usize = 10;
+
+/// Create an actix [Scope] with endpoints for support teams to inspect and reset rate limits:
+///
+/// - `GET {path}/keys?prefix=&limit=`: The keys with the highest counts, optionally filtered by
+///   prefix (`limit` defaults to 10).
+/// - `GET {path}/keys/{key}`: The status of a key, or 404 if it has no requests counted.
+/// - `DELETE {path}/keys/{key}`: Reset a key.
+///
+/// Statuses are returned as JSON objects, e.g. `{"key": "...", "count": 5, "ttl_secs": 30}`.
+///
+/// These endpoints must not be publicly accessible, so every request has to pass the given
+/// `guard`, and should be placed outside of any rate limiting middleware.
+///
+/// # Example
+/// ```no_run
+/// # use actix_extensible_rate_limit::admin::admin_scope;
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_web::{guard, App};
+/// let backend = InMemoryBackend::builder().build();
+/// let app = App::new().service(admin_scope(
+///     "/admin/rate-limits",
+///     backend.clone(),
+///     guard::Header("x-admin-token", "secret"),
+/// ));
+/// ```
+pub fn admin_scope<B, G>(path: &str, backend: B, guard: G) -> Scope
+where
+    B: InspectableBackend + 'static,
+    B::Error: Display,
+    G: Guard + 'static,
+{
+    web::scope(path)
+        .guard(guard)
+        .app_data(web::Data::new(backend))
+        .route("/keys", web::get().to(top_keys::<B>))
+        .route("/keys/{key:.*}", web::get().to(get_key::<B>))
+        .route("/keys/{key:.*}", web::delete().to(delete_key::<B>))
+}
+
+/// As [admin_scope], with additional endpoints to manage the [LimitOverride]s in an
+/// [OverrideStore]:
+///
+/// - `GET {path}/overrides/{key}`: The override for a key, or 404 if it has none.
+/// - `PUT {path}/overrides/{key}`: Set the override for a key, e.g.
+///   `{"interval_secs": 3600, "max_requests": 10000, "expires_at": 1767225600}`, where
+///   `expires_at` is in seconds since the Unix epoch.
+/// - `DELETE {path}/overrides/{key}`: Remove the override for a key.
+pub fn admin_scope_with_overrides<B, S, G>(path: &str, backend: B, overrides: S, guard: G) -> Scope
+where
+    B: InspectableBackend + 'static,
+    B::Error: Display,
+    S: OverrideStore + 'static,
+    G: Guard + 'static,
+{
+    admin_scope(path, backend, guard)
+        .app_data(web::Data::new(overrides))
+        .route("/overrides/{key:.*}", web::get().to(get_override::<S>))
+        .route("/overrides/{key:.*}", web::put().to(put_override::<S>))
+        .route(
+            "/overrides/{key:.*}",
+            web::delete().to(delete_override::<S>),
+        )
+}
+
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    key: String,
+    count: u64,
+    ttl_secs: u64,
+}
+
+impl StatusResponse {
+    fn new(key: String, status: KeyStatus) -> Self {
+        Self {
+            key,
+            count: status.count,
+            ttl_secs: status.ttl.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OverrideBody {
+    interval_secs: u64,
+    max_requests: u64,
+    expires_at: u64,
+}
+
+impl From<&LimitOverride> for OverrideBody {
+    fn from(limit: &LimitOverride) -> Self {
+        Self {
+            interval_secs: limit.interval.as_secs(),
+            max_requests: limit.max_requests,
+            expires_at: limit
+                .expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TopKeysQuery {
+    #[serde(default)]
+    prefix: String,
+    limit: Option<usize>,
+}
+
+fn backend_error(e: impl Display) -> HttpResponse {
+    log::error!("Rate limit admin request failed: {e}");
+    HttpResponse::InternalServerError().finish()
+}
+
+async fn top_keys<B>(backend: web::Data<B>, query: web::Query<TopKeysQuery>) -> HttpResponse
+where
+    B: InspectableBackend,
+    B::Error: Display,
+{
+    let keys = match backend.list_keys(&query.prefix).await {
+        Ok(keys) => keys,
+        Err(e) => return backend_error(e),
+    };
+    let mut statuses = Vec::with_capacity(keys.len());
+    for key in keys {
+        match backend.key_status(&key).await {
+            // The key may have expired since it was listed
+            Ok(Some(status)) => statuses.push(StatusResponse::new(key, status)),
+            Ok(None) => {}
+            Err(e) => return backend_error(e),
+        }
+    }
+    statuses.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+    statuses.truncate(query.limit.unwrap_or(DEFAULT_TOP_KEYS));
+    HttpResponse::Ok().json(statuses)
+}
+
+async fn get_key<B>(backend: web::Data<B>, key: web::Path<String>) -> HttpResponse
+where
+    B: InspectableBackend,
+    B::Error: Display,
+{
+    match backend.key_status(&key).await {
+        Ok(Some(status)) => HttpResponse::Ok().json(StatusResponse::new(key.into_inner(), status)),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => backend_error(e),
+    }
+}
+
+async fn delete_key<B>(backend: web::Data<B>, key: web::Path<String>) -> HttpResponse
+where
+    B: InspectableBackend,
+    B::Error: Display,
+{
+    match backend.remove_key(&key).await {
+        Ok(()) => {
+            log::info!("Rate limit key {:?} was reset", key.as_str());
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => backend_error(e),
+    }
+}
+
+async fn get_override<S: OverrideStore>(
+    store: web::Data<S>,
+    key: web::Path<String>,
+) -> HttpResponse {
+    match store.get(&key).await {
+        Ok(Some(limit)) => HttpResponse::Ok().json(OverrideBody::from(&limit)),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => backend_error(e),
+    }
+}
+
+async fn put_override<S: OverrideStore>(
+    store: web::Data<S>,
+    key: web::Path<String>,
+    body: web::Json<OverrideBody>,
+) -> HttpResponse {
+    if body.interval_secs == 0 {
+        return HttpResponse::BadRequest().body("interval_secs must be non-zero");
+    }
+    let limit = LimitOverride::new(
+        Duration::from_secs(body.interval_secs),
+        body.max_requests,
+        UNIX_EPOCH + Duration::from_secs(body.expires_at),
+    );
+    if limit.expires <= SystemTime::now() {
+        return HttpResponse::BadRequest().body("expires_at must be in the future");
+    }
+    match store.set(&key, limit).await {
+        Ok(()) => {
+            log::info!(
+                "Rate limit override for key {:?} was set to {} requests per {}s",
+                key.as_str(),
+                body.max_requests,
+                body.interval_secs
+            );
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => backend_error(e),
+    }
+}
+
+async fn delete_override<S: OverrideStore>(
+    store: web::Data<S>,
+    key: web::Path<String>,
+) -> HttpResponse {
+    match store.remove(&key).await {
+        Ok(()) => {
+            log::info!("Rate limit override for key {:?} was removed", key.as_str());
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => backend_error(e),
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use crate::backend::{Backend, SimpleInput};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{guard, App};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_admin_scope() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        for (key, cost) in [("user/1", 1), ("user/2", 3), ("other", 2)] {
+            let input = SimpleInput {
+                interval: Duration::from_secs(60),
+                max_requests: 5,
+                key: key.to_string(),
+                cost,
+            };
+            backend.request(input).await.unwrap();
+        }
+        let app = test::init_service(App::new().service(admin_scope(
+            "/admin",
+            backend.clone(),
+            guard::Header("x-admin", "1"),
+        )))
+        .await;
+        let admin = |req: TestRequest| req.insert_header(("x-admin", "1")).to_request();
+
+        let req = TestRequest::get().uri("/admin/keys/user/1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let req = admin(TestRequest::get().uri("/admin/keys/user/1"));
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({"key": "user/1", "count": 1, "ttl_secs": 60}));
+
+        let req = admin(TestRequest::get().uri("/admin/keys?limit=2"));
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body[0]["key"], "user/2");
+        assert_eq!(body[1]["key"], "other");
+        assert_eq!(body.as_array().unwrap().len(), 2);
+
+        let req = admin(TestRequest::get().uri("/admin/keys?prefix=user/"));
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.as_array().unwrap().len(), 2);
+
+        let req = admin(TestRequest::delete().uri("/admin/keys/user/2"));
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let req = admin(TestRequest::get().uri("/admin/keys/user/2"));
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_overrides() {
+        use crate::backend::overrides::MemoryOverrideStore;
+
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let store = MemoryOverrideStore::new();
+        let app = test::init_service(App::new().service(admin_scope_with_overrides(
+            "/admin",
+            backend,
+            store.clone(),
+            guard::Header("x-admin", "1"),
+        )))
+        .await;
+        let admin = |req: TestRequest| req.insert_header(("x-admin", "1")).to_request();
+
+        let expires_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        let body = json!({"interval_secs": 3600, "max_requests": 10000, "expires_at": expires_at});
+        let req = admin(
+            TestRequest::put()
+                .uri("/admin/overrides/customer")
+                .set_json(&body),
+        );
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            store.get("customer").await.unwrap().unwrap().max_requests,
+            10000
+        );
+
+        let req = admin(TestRequest::get().uri("/admin/overrides/customer"));
+        let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(response, body);
+
+        let expired = json!({"interval_secs": 3600, "max_requests": 10000, "expires_at": 1});
+        let req = admin(
+            TestRequest::put()
+                .uri("/admin/overrides/customer")
+                .set_json(&expired),
+        );
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let req = admin(TestRequest::delete().uri("/admin/overrides/customer"));
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let req = admin(TestRequest::get().uri("/admin/overrides/customer"));
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
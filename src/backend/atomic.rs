@@ -0,0 +1,379 @@
+use crate::backend::clock::{Clock, TokioClock};
+use crate::backend::memory::{nanos, nanos_since, wait_unless_shutdown, GcTask};
+use crate::backend::{Backend, BackendError, SimpleInput, SimpleOutput, SimpleRollbackToken};
+use crate::supervisor::{ShutdownSignal, Supervisor};
+use actix_web::rt::time::Instant;
+use dashmap::DashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_GC_INTERVAL_SECONDS: u64 = 60 * 10;
+
+/// A Fixed Window rate limiter [Backend] that stores each bucket as a pair of atomic integers,
+/// so that concurrent requests for the same key never wait for one another.
+///
+/// Compared to the [InMemoryBackend](crate::backend::memory::InMemoryBackend), which takes an
+/// exclusive lock on a [DashMap] shard for every request, this only takes a shared lock to find
+/// an existing bucket, and then updates it with atomic operations. This is faster at high
+/// concurrency on a small number of keys, at the cost of some accuracy: requests that arrive at
+/// the exact moment a window resets may be counted against the previous window.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::atomic::AtomicInMemoryBackend;
+/// # async {
+/// let backend = AtomicInMemoryBackend::builder().build();
+/// # };
+/// ```
+#[derive(Clone)]
+pub struct AtomicInMemoryBackend<K = String, S = RandomState> {
+    map: Arc<DashMap<K, Bucket, S>>,
+    // Expiry times are stored as nanoseconds since this instant
+    epoch: Instant,
+    clock: Arc<dyn Clock>,
+    gc_handle: Option<Arc<GcTask>>,
+    // Held so that a supervised garbage collector isn't stopped by the caller dropping their handle
+    _supervisor: Option<Supervisor>,
+}
+
+struct Bucket {
+    count: AtomicU64,
+    expiry: AtomicU64,
+}
+
+impl AtomicInMemoryBackend {
+    pub fn builder() -> Builder {
+        Builder {
+            gc_interval: Some(Duration::from_secs(DEFAULT_GC_INTERVAL_SECONDS)),
+            hasher: RandomState::new(),
+            shard_amount: None,
+            clock: Arc::new(TokioClock),
+            supervisor: None,
+        }
+    }
+}
+
+impl<K: Eq + Hash + 'static, S: BuildHasher + Clone + 'static> AtomicInMemoryBackend<K, S> {
+    /// Stop the garbage collector, waiting for it to finish, see
+    /// [InMemoryBackend::close](crate::backend::memory::InMemoryBackend::close).
+    pub async fn close(&self) {
+        if let Some(gc) = &self.gc_handle {
+            gc.close().await;
+        }
+    }
+
+    async fn garbage_collector(
+        map: Arc<DashMap<K, Bucket, S>>,
+        epoch: Instant,
+        clock: Arc<dyn Clock>,
+        interval: Duration,
+        mut shutdown: Option<ShutdownSignal>,
+    ) {
+        loop {
+            let now = nanos_since(epoch, clock.now());
+            map.retain(|_k, v| v.expiry.load(Ordering::Acquire) > now);
+            let wait = actix_web::rt::time::sleep(interval);
+            if !wait_unless_shutdown(wait, &mut shutdown).await {
+                break;
+            }
+        }
+    }
+}
+
+pub struct Builder<S = RandomState> {
+    gc_interval: Option<Duration>,
+    hasher: S,
+    shard_amount: Option<usize>,
+    clock: Arc<dyn Clock>,
+    supervisor: Option<Supervisor>,
+}
+
+impl<S: BuildHasher + Clone + 'static> Builder<S> {
+    /// Override the default garbage collector interval.
+    ///
+    /// Set to None to disable garbage collection.
+    ///
+    /// The garbage collector periodically scans the internal map, removing expired buckets.
+    pub fn with_gc_interval(mut self, interval: Option<Duration>) -> Self {
+        self.gc_interval = interval;
+        self
+    }
+
+    /// Hash keys with `hasher`, see
+    /// [memory::Builder::with_hasher](crate::backend::memory::Builder::with_hasher).
+    pub fn with_hasher<H: BuildHasher + Clone + 'static>(self, hasher: H) -> Builder<H> {
+        Builder {
+            gc_interval: self.gc_interval,
+            hasher,
+            shard_amount: self.shard_amount,
+            clock: self.clock,
+            supervisor: self.supervisor,
+        }
+    }
+
+    /// Override the number of shards the map is split into, see
+    /// [memory::Builder::with_shard_amount](crate::backend::memory::Builder::with_shard_amount).
+    ///
+    /// # Panics
+    ///
+    /// If `shard_amount` is not a power of two greater than 1.
+    pub fn with_shard_amount(mut self, shard_amount: usize) -> Self {
+        assert!(
+            shard_amount > 1 && shard_amount.is_power_of_two(),
+            "Shard amount must be a power of two greater than 1"
+        );
+        self.shard_amount = Some(shard_amount);
+        self
+    }
+
+    /// Read the time from `clock` rather than tokio, see
+    /// [memory::Builder::with_clock](crate::backend::memory::Builder::with_clock).
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Run the garbage collector under a [Supervisor], see
+    /// [memory::Builder::with_supervisor](crate::backend::memory::Builder::with_supervisor).
+    pub fn with_supervisor(mut self, supervisor: Supervisor) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    pub fn build(self) -> AtomicInMemoryBackend<String, S>
+    where
+        S: Send + Sync,
+    {
+        self.build_keyed()
+    }
+
+    /// Build a backend that uses keys of type `K` rather than [String], for [SimpleInput]s with
+    /// the same key type.
+    pub fn build_keyed<K>(self) -> AtomicInMemoryBackend<K, S>
+    where
+        K: Eq + Hash + Clone + Send + Sync + 'static,
+        S: Send + Sync,
+    {
+        let map = Arc::new(match self.shard_amount {
+            Some(shard_amount) => DashMap::with_hasher_and_shard_amount(self.hasher, shard_amount),
+            None => DashMap::with_hasher(self.hasher),
+        });
+        let epoch = self.clock.now();
+        let mut gc_handle = None;
+        if let Some(gc_interval) = self.gc_interval {
+            assert!(
+                gc_interval.as_secs_f64() > 0f64,
+                "GC interval must be non-zero"
+            );
+            let gc_map = map.clone();
+            let clock = self.clock.clone();
+            gc_handle = Some(Arc::new(GcTask::spawn(
+                self.supervisor.clone(),
+                move |shutdown| {
+                    AtomicInMemoryBackend::garbage_collector(
+                        gc_map,
+                        epoch,
+                        clock,
+                        gc_interval,
+                        shutdown,
+                    )
+                },
+            )));
+        }
+        AtomicInMemoryBackend {
+            map,
+            epoch,
+            clock: self.clock,
+            gc_handle,
+            _supervisor: self.supervisor,
+        }
+    }
+}
+
+impl Bucket {
+    // Add cost to the bucket, starting a new window if the current one has expired, returning
+    // the new count and the window's expiry
+    fn increment(&self, cost: u64, now: u64, interval: u64) -> (u64, u64) {
+        loop {
+            let expiry = self.expiry.load(Ordering::Acquire);
+            if expiry > now {
+                let count = self.count.fetch_add(cost, Ordering::AcqRel);
+                return (count.saturating_add(cost), expiry);
+            }
+            let new_expiry = now.saturating_add(interval);
+            if self
+                .expiry
+                .compare_exchange(expiry, new_expiry, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                self.count.store(cost, Ordering::Release);
+                return (cost, new_expiry);
+            }
+            // Another request started the new window first, count against it instead
+        }
+    }
+}
+
+impl<K, S> Backend<SimpleInput<K>> for AtomicInMemoryBackend<K, S>
+where
+    K: Eq + Hash + Clone + 'static,
+    S: BuildHasher + Clone + 'static,
+{
+    type Output = SimpleOutput;
+    type RollbackToken = SimpleRollbackToken<K>;
+    type Error = BackendError;
+
+    async fn request(
+        &self,
+        input: SimpleInput<K>,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        if let Some(gc) = &self.gc_handle {
+            gc.start();
+        }
+        let now = nanos_since(self.epoch, self.clock.now());
+        let interval = nanos(input.interval, "Interval")?;
+        let increment = |bucket: &Bucket| bucket.increment(input.cost, now, interval);
+        // Only take the shard's write lock the first time a key is seen
+        let (count, expiry) = match self.map.get(&input.key) {
+            Some(bucket) => increment(&bucket),
+            None => increment(
+                &self.map.entry(input.key.clone()).or_insert_with(|| Bucket {
+                    count: AtomicU64::new(0),
+                    expiry: AtomicU64::new(0),
+                }),
+            ),
+        };
+        let allow = count <= input.max_requests;
+        let output = SimpleOutput {
+            limit: input.max_requests,
+            remaining: input.max_requests.saturating_sub(count),
+            reset: self.epoch + Duration::from_nanos(expiry),
+        };
+        let token = SimpleRollbackToken {
+            key: input.key,
+            cost: input.cost,
+        };
+        Ok((allow, output, token))
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        if let Some(bucket) = self.map.get(&token.key) {
+            let _ = bucket
+                .count
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                    Some(count.saturating_sub(token.cost))
+                });
+        }
+        Ok(())
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        Some(token.cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn input(key: &str) -> SimpleInput {
+        SimpleInput {
+            interval: MINUTE,
+            max_requests: 5,
+            key: key.to_string(),
+            cost: 1,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_allow_deny() {
+        tokio::time::pause();
+        let backend = AtomicInMemoryBackend::builder().build();
+        for _ in 0..5 {
+            let (allow, _, _) = backend.request(input("KEY1")).await.unwrap();
+            assert!(allow);
+        }
+        let (allow, output, _) = backend.request(input("KEY1")).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.remaining, 0);
+        assert_eq!(output.reset, backend.epoch + MINUTE);
+        // A new window starts once the previous one expires
+        tokio::time::advance(MINUTE).await;
+        let (allow, output, _) = backend.request(input("KEY1")).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 4);
+    }
+
+    #[actix_web::test]
+    async fn test_rollback() {
+        let backend = AtomicInMemoryBackend::builder().build();
+        let (_, output, token) = backend.request(input("KEY1")).await.unwrap();
+        assert_eq!(output.remaining, 4);
+        backend.rollback(token).await.unwrap();
+        let (_, output, _) = backend.request(input("KEY1")).await.unwrap();
+        assert_eq!(output.remaining, 4);
+    }
+
+    #[actix_web::test]
+    async fn test_hasher() {
+        let backend = AtomicInMemoryBackend::builder()
+            .with_hasher(BuildHasherDefault::<DefaultHasher>::default())
+            .with_shard_amount(4)
+            .build();
+        backend.request(input("KEY1")).await.unwrap();
+        assert_eq!(backend.map.shards().len(), 4);
+        assert!(backend.map.contains_key("KEY1"));
+    }
+
+    #[actix_web::test]
+    async fn test_interval_overflow() {
+        let backend = AtomicInMemoryBackend::builder().build();
+        let oversized = SimpleInput {
+            interval: Duration::MAX,
+            ..input("KEY1")
+        };
+        let err = backend.request(oversized).await.unwrap_err();
+        assert_eq!(err.kind(), "overflow");
+        assert!(backend.request(input("KEY1")).await.unwrap().0);
+    }
+
+    #[actix_web::test]
+    async fn test_garbage_collection() {
+        tokio::time::pause();
+        let backend = AtomicInMemoryBackend::builder()
+            .with_gc_interval(Some(MINUTE))
+            .build();
+        backend.request(input("KEY1")).await.unwrap();
+        assert!(backend.map.contains_key("KEY1"));
+        tokio::time::advance(MINUTE).await;
+        tokio::task::yield_now().await;
+        assert!(!backend.map.contains_key("KEY1"));
+    }
+
+    #[actix_web::test]
+    async fn test_supervised_garbage_collection() {
+        tokio::time::pause();
+        let supervisor = Supervisor::new();
+        let backend = AtomicInMemoryBackend::builder()
+            .with_gc_interval(Some(MINUTE))
+            .with_supervisor(supervisor.clone())
+            .build();
+        backend.request(input("KEY1")).await.unwrap();
+        tokio::time::advance(MINUTE).await;
+        tokio::task::yield_now().await;
+        assert!(!backend.map.contains_key("KEY1"));
+        // Once shut down the garbage collector should no longer run
+        supervisor.shutdown().await;
+        backend.request(input("KEY2")).await.unwrap();
+        tokio::time::advance(MINUTE * 2).await;
+        tokio::task::yield_now().await;
+        assert!(backend.map.contains_key("KEY2"));
+    }
+}
@@ -0,0 +1,219 @@
+use crate::backend::{Backend, Health, SimpleInput, SimpleOutput, SimpleRollbackToken};
+use futures::future::LocalBoxFuture;
+use std::sync::Arc;
+
+type BoxResult<'a, T> = LocalBoxFuture<'a, Result<T, actix_web::Error>>;
+
+// An object safe version of Backend, with the error type erased.
+trait DynBackend<I, O, T> {
+    fn dyn_request(&self, input: I) -> BoxResult<'_, (bool, O, T)>;
+
+    fn dyn_request_many(&self, inputs: Vec<I>) -> BoxResult<'_, Vec<(bool, O, T)>>;
+
+    fn dyn_rollback(&self, token: T) -> BoxResult<'_, ()>;
+
+    fn dyn_rollback_cost(&self, token: &T) -> Option<u64>;
+
+    fn dyn_health(&self) -> BoxResult<'_, Health>;
+
+    fn clone_box(&self) -> Box<dyn DynBackend<I, O, T> + Send + Sync>;
+}
+
+impl<B, I, O, T> DynBackend<I, O, T> for B
+where
+    B: Backend<I, Output = O, RollbackToken = T> + Send + Sync + 'static,
+    B::Error: Into<actix_web::Error>,
+    I: 'static,
+    T: 'static,
+{
+    fn dyn_request(&self, input: I) -> BoxResult<'_, (bool, O, T)> {
+        Box::pin(async move { Backend::request(self, input).await.map_err(Into::into) })
+    }
+
+    fn dyn_request_many(&self, inputs: Vec<I>) -> BoxResult<'_, Vec<(bool, O, T)>> {
+        Box::pin(async move {
+            Backend::request_many(self, inputs)
+                .await
+                .map_err(Into::into)
+        })
+    }
+
+    fn dyn_rollback(&self, token: T) -> BoxResult<'_, ()> {
+        Box::pin(async move { Backend::rollback(self, token).await.map_err(Into::into) })
+    }
+
+    fn dyn_rollback_cost(&self, token: &T) -> Option<u64> {
+        Backend::rollback_cost(self, token)
+    }
+
+    fn dyn_health(&self) -> BoxResult<'_, Health> {
+        Box::pin(async move { Backend::health(self).await.map_err(Into::into) })
+    }
+
+    fn clone_box(&self) -> Box<dyn DynBackend<I, O, T> + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+/// A type-erased [Backend] for [SimpleInput], so that the backend can be chosen at runtime (e.g.
+/// from configuration) without the rest of the application being generic over it.
+///
+/// Errors are converted into [actix_web::Error]s. Cloning clones the underlying backend, see
+/// [ArcBackend] for a wrapper that is cheaper to clone.
+///
+/// The backend must be [Send] and [Sync] (as the provided backends are), so that it can be
+/// shared with every worker of the `HttpServer`.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::BoxBackend;
+/// fn make_backend() -> BoxBackend {
+///     // e.g. a RedisBackend in production
+///     BoxBackend::new(InMemoryBackend::builder().build())
+/// }
+/// ```
+pub struct BoxBackend<O = SimpleOutput, T = SimpleRollbackToken>(
+    Box<dyn DynBackend<SimpleInput, O, T> + Send + Sync>,
+);
+
+impl<O, T: 'static> BoxBackend<O, T> {
+    pub fn new<B>(backend: B) -> Self
+    where
+        B: Backend<SimpleInput, Output = O, RollbackToken = T> + Send + Sync + 'static,
+        B::Error: Into<actix_web::Error>,
+    {
+        Self(Box::new(backend))
+    }
+}
+
+impl<O, T> Clone for BoxBackend<O, T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone_box())
+    }
+}
+
+impl<O, T> Backend<SimpleInput> for BoxBackend<O, T> {
+    type Output = O;
+    type RollbackToken = T;
+    type Error = actix_web::Error;
+
+    async fn request(
+        &self,
+        input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        self.0.dyn_request(input).await
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        self.0.dyn_rollback(token).await
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        self.0.dyn_rollback_cost(token)
+    }
+
+    async fn request_many(
+        &self,
+        inputs: Vec<SimpleInput>,
+    ) -> Result<Vec<(bool, Self::Output, Self::RollbackToken)>, Self::Error> {
+        self.0.dyn_request_many(inputs).await
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.0.dyn_health().await
+    }
+}
+
+/// A type-erased [Backend] behind an [Arc], otherwise the same as [BoxBackend].
+///
+/// Cloning shares the underlying backend rather than cloning it.
+pub struct ArcBackend<O = SimpleOutput, T = SimpleRollbackToken>(
+    Arc<dyn DynBackend<SimpleInput, O, T> + Send + Sync>,
+);
+
+impl<O, T: 'static> ArcBackend<O, T> {
+    pub fn new<B>(backend: B) -> Self
+    where
+        B: Backend<SimpleInput, Output = O, RollbackToken = T> + Send + Sync + 'static,
+        B::Error: Into<actix_web::Error>,
+    {
+        Self(Arc::new(backend))
+    }
+}
+
+impl<O, T> Clone for ArcBackend<O, T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<O, T> Backend<SimpleInput> for ArcBackend<O, T> {
+    type Output = O;
+    type RollbackToken = T;
+    type Error = actix_web::Error;
+
+    async fn request(
+        &self,
+        input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        self.0.dyn_request(input).await
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        self.0.dyn_rollback(token).await
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        self.0.dyn_rollback_cost(token)
+    }
+
+    async fn request_many(
+        &self,
+        inputs: Vec<SimpleInput>,
+    ) -> Result<Vec<(bool, Self::Output, Self::RollbackToken)>, Self::Error> {
+        self.0.dyn_request_many(inputs).await
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.0.dyn_health().await
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use std::time::Duration;
+
+    fn input() -> SimpleInput {
+        SimpleInput {
+            interval: Duration::from_secs(60),
+            max_requests: 1,
+            key: "KEY1".to_string(),
+            cost: 1,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_box_backend() {
+        let backend = BoxBackend::new(InMemoryBackend::builder().with_gc_interval(None).build());
+        let (allow, _, token) = backend.request(input()).await.unwrap();
+        assert!(allow);
+        // Clones the underlying backend, which shares its map
+        let (allow, _, _) = backend.clone().request(input()).await.unwrap();
+        assert!(!allow);
+        backend.rollback(token).await.unwrap();
+        assert_eq!(backend.health().await.unwrap().latency, Duration::ZERO);
+    }
+
+    #[actix_web::test]
+    async fn test_arc_backend() {
+        let backend: ArcBackend = ArcBackend::new(InMemoryBackend::builder().build());
+        let results = backend.request_many(vec![input(), input()]).await.unwrap();
+        assert!(results[0].0);
+        assert!(!results[1].0);
+        let (allow, _, _) = backend.clone().request(input()).await.unwrap();
+        assert!(!allow);
+    }
+}
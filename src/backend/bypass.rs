@@ -0,0 +1,147 @@
+//! Signed tokens granting temporary bypasses of the rate limit, e.g. for internal batch jobs or
+//! trusted partners.
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderName;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The default header carrying a bypass token.
+pub const BYPASS_HEADER: &str = "x-ratelimit-bypass";
+
+/// Issues and verifies bypass tokens, see
+/// [SimpleInputFunctionBuilder::bypass_tokens](crate::backend::SimpleInputFunctionBuilder::bypass_tokens).
+///
+/// A token is an HMAC-SHA256 over a rate limiting key and an expiry time, so it only applies to
+/// the key it was issued for, until it expires. Tokens have the form `<expiry>.<signature>`,
+/// where the expiry is in seconds since the Unix epoch, and the signature is lowercase hex.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::bypass::BypassTokens;
+/// # use std::time::Duration;
+/// let tokens = BypassTokens::new(b"a long random secret").max_requests(10_000);
+/// // Given to the batch job, which sends it in the x-ratelimit-bypass header
+/// let token = tokens.issue("batch-job", Duration::from_secs(24 * 60 * 60));
+/// assert!(tokens.verify(&token, "batch-job"));
+/// assert!(!tokens.verify(&token, "someone-else"));
+/// ```
+#[derive(Clone)]
+pub struct BypassTokens {
+    mac: HmacSha256,
+    header: HeaderName,
+    max_requests: Option<u64>,
+}
+
+impl BypassTokens {
+    /// Sign tokens with `secret`, which should be at least 32 random bytes.
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            mac: HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length"),
+            header: HeaderName::from_static(BYPASS_HEADER),
+            max_requests: None,
+        }
+    }
+
+    /// Override the header carrying the token, the default is [BYPASS_HEADER].
+    ///
+    /// # Panics
+    ///
+    /// If `name` is not a valid header name.
+    pub fn header(mut self, name: &str) -> Self {
+        self.header = HeaderName::try_from(name).expect("Invalid header name");
+        self
+    }
+
+    /// Relax the limit of requests with a valid token to `max_requests`, instead of exempting
+    /// them from the rate limit entirely.
+    pub fn max_requests(mut self, max_requests: u64) -> Self {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Issue a token for `key`, valid for `ttl`.
+    pub fn issue(&self, key: &str, ttl: Duration) -> String {
+        let expiry = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let signature = self.sign(key, expiry).finalize().into_bytes();
+        let mut token = format!("{expiry}.");
+        for byte in signature {
+            let _ = write!(token, "{byte:02x}");
+        }
+        token
+    }
+
+    /// Whether `token` was issued for `key`, and hasn't expired.
+    pub fn verify(&self, token: &str, key: &str) -> bool {
+        let Some((expiry, signature)) = token.split_once('.') else {
+            return false;
+        };
+        let (Ok(expiry), Some(signature)) = (expiry.parse::<u64>(), decode_hex(signature)) else {
+            return false;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        expiry > now && self.sign(key, expiry).verify_slice(&signature).is_ok()
+    }
+
+    /// The token sent with a request, if any.
+    pub(crate) fn token(&self, req: &ServiceRequest) -> Option<String> {
+        let value = req.headers().get(&self.header)?.to_str().ok()?;
+        Some(value.trim().to_owned())
+    }
+
+    /// The relaxed limit, or [None] to exempt requests with a valid token.
+    pub(crate) fn relaxed_max_requests(&self) -> Option<u64> {
+        self.max_requests
+    }
+
+    fn sign(&self, key: &str, expiry: u64) -> HmacSha256 {
+        let mut mac = self.mac.clone();
+        // The expiry is only digits, so the last newline always separates it from the key
+        mac.update(key.as_bytes());
+        mac.update(b"\n");
+        mac.update(expiry.to_string().as_bytes());
+        mac
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let tokens = BypassTokens::new(b"secret");
+        let token = tokens.issue("key", Duration::from_secs(60));
+        assert!(tokens.verify(&token, "key"));
+        assert!(!tokens.verify(&token, "other"));
+        assert!(!BypassTokens::new(b"other secret").verify(&token, "key"));
+
+        // Tampering with the expiry invalidates the signature
+        let (_, signature) = token.split_once('.').unwrap();
+        assert!(!tokens.verify(&format!("99999999999.{signature}"), "key"));
+
+        let expired = tokens.issue("key", Duration::ZERO);
+        assert!(!tokens.verify(&expired, "key"));
+        assert!(!tokens.verify("garbage", "key"));
+        assert!(!tokens.verify("1.zz", "key"));
+    }
+}
@@ -0,0 +1,138 @@
+//! The source of time for the backends, and conversion between the monotonic [Instant]s they use
+//! and wall-clock time.
+use actix_web::rt::time::Instant;
+use once_cell::sync::Lazy;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// An instant and the wall-clock time it corresponds to, captured once so that every conversion
+// agrees, rather than drifting as the system clock is adjusted
+static ANCHOR: Lazy<(Instant, SystemTime)> = Lazy::new(|| (Instant::now(), SystemTime::now()));
+
+/// Convert an [Instant] (e.g. [SimpleOutput::reset](crate::backend::SimpleOutput::reset)) into
+/// wall-clock time, e.g. for a Unix timestamp in a response header, or to persist it.
+///
+/// Every conversion uses the same reference point, so the same instant always converts to the same
+/// time, regardless of when it is converted.
+pub fn system_time(instant: Instant) -> SystemTime {
+    let (anchor, anchor_time) = *ANCHOR;
+    if instant >= anchor {
+        anchor_time + (instant - anchor)
+    } else {
+        anchor_time - (anchor - instant)
+    }
+}
+
+/// A source of the current time, that can be injected into a backend, e.g.
+/// [memory::Builder::with_clock](crate::backend::memory::Builder::with_clock).
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    /// The current wall-clock time.
+    ///
+    /// Defaults to converting [Clock::now] with [system_time], so that it moves with the clock.
+    fn system_time(&self) -> SystemTime {
+        system_time(self.now())
+    }
+}
+
+/// The default [Clock], using tokio's time (which can be paused and advanced with
+/// `tokio::time::pause` in tests).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A [Clock] that only moves when told to, e.g. to step a backend across a window boundary in a
+/// test.
+///
+/// Clones share the same time.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::clock::ManualClock;
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use std::time::Duration;
+/// # async {
+/// let clock = ManualClock::new();
+/// let backend = InMemoryBackend::builder()
+///     .with_clock(clock.clone())
+///     .build();
+/// // ... exhaust the limit
+/// clock.advance(Duration::from_secs(60));
+/// // ... the limit has reset
+/// # };
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// A clock starting at the current time.
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Move the clock forwards.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Move the clock to the given time.
+    pub fn set(&self, now: Instant) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_system_time() {
+        tokio::time::pause();
+        let now = Instant::now();
+        let converted = system_time(now);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(system_time(now), converted);
+        assert_eq!(
+            system_time(now + Duration::from_secs(60)),
+            converted + Duration::from_secs(60)
+        );
+        assert_eq!(
+            system_time(now - Duration::from_secs(1)),
+            converted - Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let system_start = clock.system_time();
+        clock.clone().advance(Duration::from_secs(60));
+        assert_eq!(clock.now(), start + Duration::from_secs(60));
+        assert_eq!(clock.system_time(), system_start + Duration::from_secs(60));
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
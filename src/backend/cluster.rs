@@ -0,0 +1,552 @@
+//! An experimental in-memory backend that shares its counters with peer instances by gossip.
+use crate::backend::memory::wait_unless_shutdown;
+use crate::backend::{Backend, SimpleInput, SimpleOutput, SimpleRollbackToken};
+use crate::supervisor::{ShutdownSignal, Supervisor};
+use actix_web::rt::task::JoinHandle;
+use actix_web::rt::time::Instant;
+use dashmap::DashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+pub const DEFAULT_GOSSIP_INTERVAL_MILLIS: u64 = 500;
+
+// Identifies a gossip datagram, and the version of its encoding
+const MAGIC: &[u8; 4] = b"ERL1";
+
+// Kept below the typical path MTU, so that datagrams aren't fragmented
+const MAX_DATAGRAM: usize = 1200;
+
+// The magic, and the sending node's id
+const HEADER_LEN: usize = 4 + 8;
+
+// The window, interval, count, and key length of each entry
+const ENTRY_HEADER_LEN: usize = 8 + 8 + 8 + 2;
+
+/// **Experimental**: a Fixed Window rate limiter [Backend] that stores keys in memory, and
+/// periodically exchanges its per-key counters with peer instances over UDP, so that each
+/// instance converges on an approximate global count without a shared datastore.
+///
+/// Each instance counts its own requests, and adds the latest counts gossiped by its peers for
+/// the same window. Windows are aligned to the system clock (rather than starting at a key's
+/// first request), so the instances' clocks should be synchronized, e.g. by NTP. Until the next
+/// gossip arrives, each instance may allow up to the full limit, so the global limit can be
+/// exceeded by the requests made in one gossip interval on each instance.
+///
+/// Gossip is neither authenticated nor encrypted, and should only be exchanged on a private
+/// network. The format of the datagrams may change in any release.
+///
+/// # Example
+/// ```no_run
+/// # use actix_extensible_rate_limit::backend::cluster::ClusterMemoryBackend;
+/// # async {
+/// let backend = ClusterMemoryBackend::builder("0.0.0.0:7946".parse().unwrap())
+///     .peers(["10.0.0.2:7946".parse().unwrap(), "10.0.0.3:7946".parse().unwrap()])
+///     .build()
+///     .await
+///     .unwrap();
+/// # };
+/// ```
+#[derive(Clone)]
+pub struct ClusterMemoryBackend {
+    state: Arc<State>,
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    // Held so that supervised tasks aren't stopped by the caller dropping their handle
+    _supervisor: Option<Supervisor>,
+}
+
+struct State {
+    node_id: u64,
+    socket: Arc<UdpSocket>,
+    peers: Mutex<Vec<SocketAddr>>,
+    map: DashMap<String, Counter>,
+}
+
+struct Counter {
+    // The index of the window since the Unix epoch
+    window: u64,
+    interval_ms: u64,
+    local: u64,
+    // Whether the local count has been gossiped, so that it is still sent if rolled back to zero
+    gossiped: bool,
+    // The latest count gossiped by each peer for this window
+    remote: HashMap<u64, u64>,
+}
+
+impl Counter {
+    fn new(window: u64, interval_ms: u64) -> Self {
+        Self {
+            window,
+            interval_ms,
+            local: 0,
+            gossiped: false,
+            remote: HashMap::new(),
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.remote
+            .values()
+            .fold(self.local, |total, count| total.saturating_add(*count))
+    }
+}
+
+struct Entry<'a> {
+    key: &'a str,
+    window: u64,
+    interval_ms: u64,
+    count: u64,
+}
+
+impl ClusterMemoryBackend {
+    /// Gossip on the UDP socket bound to `bind`.
+    pub fn builder(bind: SocketAddr) -> Builder {
+        Builder {
+            bind,
+            peers: Vec::new(),
+            gossip_interval: Duration::from_millis(DEFAULT_GOSSIP_INTERVAL_MILLIS),
+            supervisor: None,
+        }
+    }
+
+    /// The address of the gossip socket, e.g. if it was bound to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.state.socket.local_addr()
+    }
+
+    /// Start gossiping with `peer`, e.g. when a new instance is discovered.
+    pub fn add_peer(&self, peer: SocketAddr) {
+        let mut peers = self.state.peers.lock().unwrap();
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+    }
+
+    /// Stop gossiping with `peer`.
+    ///
+    /// The counts it has already gossiped are used until their window ends.
+    pub fn remove_peer(&self, peer: SocketAddr) {
+        self.state.peers.lock().unwrap().retain(|p| *p != peer);
+    }
+
+    /// Stop gossiping, waiting for the background tasks to finish.
+    ///
+    /// Tasks run under a [Supervisor] are unaffected, see [Supervisor::shutdown()] instead.
+    pub async fn close(&self) {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for task in tasks {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+}
+
+impl State {
+    // Send the counts of this node's keys in their current window to every peer
+    async fn gossip(&self) {
+        let now_ms = unix_millis();
+        let mut datagrams = Vec::new();
+        let mut datagram = self.datagram();
+        self.map.retain(|key, counter| {
+            if now_ms / counter.interval_ms > counter.window {
+                // The window has ended
+                return false;
+            }
+            if counter.local == 0 && !counter.gossiped {
+                return true;
+            }
+            if HEADER_LEN + ENTRY_HEADER_LEN + key.len() > MAX_DATAGRAM {
+                log::debug!("Rate limit key too long to gossip: {}", key);
+                return true;
+            }
+            if datagram.len() + ENTRY_HEADER_LEN + key.len() > MAX_DATAGRAM {
+                datagrams.push(std::mem::replace(&mut datagram, self.datagram()));
+            }
+            datagram.extend_from_slice(&counter.window.to_be_bytes());
+            datagram.extend_from_slice(&counter.interval_ms.to_be_bytes());
+            datagram.extend_from_slice(&counter.local.to_be_bytes());
+            datagram.extend_from_slice(&(key.len() as u16).to_be_bytes());
+            datagram.extend_from_slice(key.as_bytes());
+            counter.gossiped = true;
+            true
+        });
+        if datagram.len() > HEADER_LEN {
+            datagrams.push(datagram);
+        }
+
+        let peers = self.peers.lock().unwrap().clone();
+        for peer in peers {
+            for datagram in &datagrams {
+                if let Err(e) = self.socket.send_to(datagram, peer).await {
+                    log::warn!("Failed to gossip rate limits to {}: {}", peer, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn datagram(&self) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(MAX_DATAGRAM);
+        datagram.extend_from_slice(MAGIC);
+        datagram.extend_from_slice(&self.node_id.to_be_bytes());
+        datagram
+    }
+
+    // Merge the counts gossiped by a peer
+    fn receive(&self, datagram: &[u8]) {
+        let Some((node_id, entries)) = decode(datagram) else {
+            log::debug!("Ignoring invalid rate limit gossip");
+            return;
+        };
+        if node_id == self.node_id {
+            return;
+        }
+        let now_ms = unix_millis();
+        for entry in entries {
+            if entry.window < now_ms / entry.interval_ms {
+                continue;
+            }
+            let mut counter = self
+                .map
+                .entry(entry.key.to_owned())
+                .or_insert_with(|| Counter::new(entry.window, entry.interval_ms));
+            if counter.interval_ms != entry.interval_ms || entry.window < counter.window {
+                continue;
+            }
+            if entry.window > counter.window {
+                // The peer's clock is ahead
+                *counter = Counter::new(entry.window, entry.interval_ms);
+            }
+            counter.remote.insert(node_id, entry.count);
+        }
+    }
+}
+
+fn decode(datagram: &[u8]) -> Option<(u64, Vec<Entry<'_>>)> {
+    let (magic, rest) = datagram.split_first_chunk::<4>()?;
+    if magic != MAGIC {
+        return None;
+    }
+    let (node_id, mut rest) = rest.split_first_chunk::<8>()?;
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let (window, r) = rest.split_first_chunk::<8>()?;
+        let (interval_ms, r) = r.split_first_chunk::<8>()?;
+        let (count, r) = r.split_first_chunk::<8>()?;
+        let (key_len, r) = r.split_first_chunk::<2>()?;
+        let key_len = usize::from(u16::from_be_bytes(*key_len));
+        if r.len() < key_len {
+            return None;
+        }
+        let (key, r) = r.split_at(key_len);
+        let interval_ms = u64::from_be_bytes(*interval_ms);
+        if interval_ms == 0 {
+            return None;
+        }
+        entries.push(Entry {
+            key: std::str::from_utf8(key).ok()?,
+            window: u64::from_be_bytes(*window),
+            interval_ms,
+            count: u64::from_be_bytes(*count),
+        });
+        rest = r;
+    }
+    Some((u64::from_be_bytes(*node_id), entries))
+}
+
+fn unix_millis() -> u64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+}
+
+async fn gossip_loop(state: Weak<State>, interval: Duration, mut shutdown: Option<ShutdownSignal>) {
+    let mut ticker = actix_web::rt::time::interval(interval);
+    loop {
+        let tick = async {
+            ticker.tick().await;
+        };
+        if !wait_unless_shutdown(tick, &mut shutdown).await {
+            return;
+        }
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        state.gossip().await;
+    }
+}
+
+async fn receive_loop(
+    socket: Arc<UdpSocket>,
+    state: Weak<State>,
+    mut shutdown: Option<ShutdownSignal>,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        let received = match &mut shutdown {
+            None => socket.recv_from(&mut buf).await,
+            Some(shutdown) => {
+                tokio::select! {
+                    received = socket.recv_from(&mut buf) => received,
+                    _ = shutdown.recv() => return,
+                }
+            }
+        };
+        let len = match received {
+            Ok((len, _)) => len,
+            Err(e) => {
+                log::debug!("Failed to receive rate limit gossip: {}", e);
+                continue;
+            }
+        };
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        state.receive(&buf[..len]);
+    }
+}
+
+pub struct Builder {
+    bind: SocketAddr,
+    peers: Vec<SocketAddr>,
+    gossip_interval: Duration,
+    supervisor: Option<Supervisor>,
+}
+
+impl Builder {
+    /// The addresses of the other instances' gossip sockets.
+    pub fn peers<I: IntoIterator<Item = SocketAddr>>(mut self, peers: I) -> Self {
+        self.peers.extend(peers);
+        self
+    }
+
+    /// Override how often the counters are sent to each peer.
+    ///
+    /// A shorter interval lets the instances converge sooner, at the cost of more traffic.
+    pub fn with_gossip_interval(mut self, interval: Duration) -> Self {
+        self.gossip_interval = interval;
+        self
+    }
+
+    /// Run the background tasks that send and receive gossip under a [Supervisor].
+    ///
+    /// They will then be stopped by [Supervisor::shutdown()], rather than by
+    /// [ClusterMemoryBackend::close].
+    pub fn with_supervisor(mut self, supervisor: Supervisor) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    /// Bind the gossip socket, and start the background tasks that send and receive gossip.
+    ///
+    /// # Panics
+    ///
+    /// If the gossip interval is zero.
+    pub async fn build(self) -> io::Result<ClusterMemoryBackend> {
+        assert!(
+            !self.gossip_interval.is_zero(),
+            "Gossip interval must be non-zero"
+        );
+        let socket = Arc::new(UdpSocket::bind(self.bind).await?);
+        let state = Arc::new(State {
+            node_id: RandomState::new().build_hasher().finish(),
+            socket: socket.clone(),
+            peers: Mutex::new(self.peers),
+            map: DashMap::new(),
+        });
+        let (gossip_state, receive_state) = (Arc::downgrade(&state), Arc::downgrade(&state));
+        let interval = self.gossip_interval;
+        let mut tasks = Vec::new();
+        match &self.supervisor {
+            Some(supervisor) => {
+                supervisor.spawn_send(move |shutdown| {
+                    gossip_loop(gossip_state, interval, Some(shutdown))
+                });
+                supervisor.spawn_send(move |shutdown| {
+                    receive_loop(socket, receive_state, Some(shutdown))
+                });
+            }
+            None => {
+                tasks.push(tokio::spawn(gossip_loop(gossip_state, interval, None)));
+                tasks.push(tokio::spawn(receive_loop(socket, receive_state, None)));
+            }
+        }
+        Ok(ClusterMemoryBackend {
+            state,
+            tasks: Arc::new(Mutex::new(tasks)),
+            _supervisor: self.supervisor,
+        })
+    }
+}
+
+impl Backend<SimpleInput> for ClusterMemoryBackend {
+    type Output = SimpleOutput;
+    type RollbackToken = SimpleRollbackToken;
+    type Error = Infallible;
+
+    async fn request(
+        &self,
+        input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        let now_ms = unix_millis();
+        let interval_ms = u64::try_from(input.interval.as_millis())
+            .unwrap_or(u64::MAX)
+            .max(1);
+        let window = now_ms / interval_ms;
+        let mut counter = self
+            .state
+            .map
+            .entry(input.key.clone())
+            .or_insert_with(|| Counter::new(window, interval_ms));
+        if counter.interval_ms != interval_ms || counter.window < window {
+            *counter = Counter::new(window, interval_ms);
+        }
+        let total = counter.total();
+        let allow = total.saturating_add(input.cost) <= input.max_requests;
+        let used = if allow {
+            counter.local += input.cost;
+            total + input.cost
+        } else {
+            total
+        };
+        let ends_ms = counter.window.saturating_add(1).saturating_mul(interval_ms);
+        drop(counter);
+
+        let output = SimpleOutput {
+            limit: input.max_requests,
+            remaining: input.max_requests.saturating_sub(used),
+            reset: Instant::now() + Duration::from_millis(ends_ms.saturating_sub(now_ms)),
+        };
+        let token = SimpleRollbackToken {
+            key: input.key,
+            cost: if allow { input.cost } else { 0 },
+        };
+        Ok((allow, output, token))
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        if let Some(mut counter) = self.state.map.get_mut(&token.key) {
+            counter.local = counter.local.saturating_sub(token.cost);
+        }
+        Ok(())
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        Some(token.cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input() -> SimpleInput {
+        SimpleInput {
+            interval: Duration::from_secs(60 * 60),
+            max_requests: 5,
+            key: "KEY1".to_string(),
+            cost: 1,
+        }
+    }
+
+    async fn backend() -> ClusterMemoryBackend {
+        // Gossip is sent manually by the tests
+        ClusterMemoryBackend::builder("127.0.0.1:0".parse().unwrap())
+            .with_gossip_interval(Duration::from_secs(60 * 60))
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_decode() {
+        let backend = backend().await;
+        let mut datagram = backend.state.datagram();
+        datagram.extend_from_slice(&1u64.to_be_bytes());
+        datagram.extend_from_slice(&1000u64.to_be_bytes());
+        datagram.extend_from_slice(&3u64.to_be_bytes());
+        datagram.extend_from_slice(&4u16.to_be_bytes());
+        datagram.extend_from_slice(b"KEY1");
+        let (node_id, entries) = decode(&datagram).unwrap();
+        assert_eq!(node_id, backend.state.node_id);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, "KEY1");
+        assert_eq!(entries[0].count, 3);
+        // Truncated
+        assert!(decode(&datagram[..datagram.len() - 1]).is_none());
+        assert!(decode(b"nonsense").is_none());
+    }
+
+    // Wait for the gossip to be received
+    async fn received(backend: &ClusterMemoryBackend, total: u64) {
+        for _ in 0..100 {
+            let counter = backend.state.map.get("KEY1");
+            if counter.is_some_and(|counter| counter.total() == total) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Gossip was not received");
+    }
+
+    #[actix_web::test]
+    async fn test_gossip() {
+        let a = backend().await;
+        let b = backend().await;
+        a.add_peer(b.local_addr().unwrap());
+        b.add_peer(a.local_addr().unwrap());
+
+        for _ in 0..3 {
+            assert!(a.request(input()).await.unwrap().0);
+        }
+        let (_, _, token) = b.request(input()).await.unwrap();
+        a.state.gossip().await;
+        b.state.gossip().await;
+        received(&a, 4).await;
+        received(&b, 4).await;
+
+        let (allow, output, _) = a.request(input()).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 0);
+        // Until the next gossip, b only knows of 4 requests
+        a.state.gossip().await;
+        received(&b, 5).await;
+        assert!(!b.request(input()).await.unwrap().0);
+
+        // A rollback is gossiped as a lower count
+        b.rollback(token).await.unwrap();
+        b.state.gossip().await;
+        received(&a, 4).await;
+        assert!(a.request(input()).await.unwrap().0);
+        a.close().await;
+        b.close().await;
+    }
+
+    #[actix_web::test]
+    async fn test_supervised() {
+        let supervisor = Supervisor::new();
+        let a = ClusterMemoryBackend::builder("127.0.0.1:0".parse().unwrap())
+            .with_gossip_interval(Duration::from_millis(10))
+            .with_supervisor(supervisor.clone())
+            .build()
+            .await
+            .unwrap();
+        let b = backend().await;
+        a.add_peer(b.local_addr().unwrap());
+        assert!(a.request(input()).await.unwrap().0);
+        received(&b, 1).await;
+        // Once shut down, a no longer gossips
+        supervisor.shutdown().await;
+        assert!(a.request(input()).await.unwrap().0);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(b.state.map.get("KEY1").unwrap().total(), 1);
+        b.close().await;
+    }
+}
@@ -0,0 +1,306 @@
+use crate::backend::{
+    Backend, Health, PartialRollbackToken, SimpleBackend, SimpleInput, SimpleOutput,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+type Batches<T> = Arc<Mutex<HashMap<BatchKey, Batch<T>>>>;
+
+/// Wraps a [Backend], merging concurrent requests for the same key into a single call, with the
+/// total cost; e.g. so that a single client flooding one key costs one backend call per window,
+/// rather than one per request.
+///
+/// The first request for a key waits for the [window](CoalescingBackend::window) (1ms by
+/// default), collecting any other requests for the same key, interval and limit that arrive in
+/// the meantime, then makes the call. The results are shared out in the order the requests
+/// arrived, so that the last to arrive sees the least remaining.
+///
+/// If the combined request is denied, every request in it is denied, even though the first few
+/// might have fit within the remaining limit. If it fails, the other requests fall back to
+/// calling the backend individually.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::CoalescingBackend;
+/// # use std::time::Duration;
+/// # async {
+/// let backend = CoalescingBackend::new(InMemoryBackend::builder().build())
+///     .window(Duration::from_millis(2));
+/// # };
+/// ```
+pub struct CoalescingBackend<B: Backend> {
+    inner: B,
+    window: Duration,
+    batches: Batches<B::RollbackToken>,
+}
+
+// Derived Clone would also require the rollback token to be Clone
+impl<B: Backend> Clone for CoalescingBackend<B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            window: self.window,
+            batches: self.batches.clone(),
+        }
+    }
+}
+
+// Requests are only merged if they would be charged against the same limit
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct BatchKey {
+    key: String,
+    interval: Duration,
+    max_requests: u64,
+}
+
+struct Batch<T> {
+    // The cost of each request that joined the batch, after the first
+    costs: Vec<u64>,
+    senders: Vec<oneshot::Sender<(bool, SimpleOutput, T)>>,
+}
+
+impl<B: Backend> CoalescingBackend<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            window: Duration::from_millis(1),
+            batches: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Override how long the first request for a key waits for others to join it.
+    ///
+    /// This is added to the latency of every request, so it should be kept small.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+}
+
+// Removes the batch if the first request is dropped while collecting it, so that the requests
+// that joined it fall back to calling the backend themselves
+struct BatchGuard<'a, T> {
+    batches: &'a Mutex<HashMap<BatchKey, Batch<T>>>,
+    key: Option<BatchKey>,
+}
+
+impl<T> BatchGuard<'_, T> {
+    fn take(&mut self) -> Batch<T> {
+        let key = self.key.take().expect("Batch already taken");
+        self.batches
+            .lock()
+            .unwrap()
+            .remove(&key)
+            .expect("Batch removed by another request")
+    }
+}
+
+impl<T> Drop for BatchGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(key) = &self.key {
+            self.batches.lock().unwrap().remove(key);
+        }
+    }
+}
+
+impl<B> Backend<SimpleInput> for CoalescingBackend<B>
+where
+    B: Backend<SimpleInput, Output = SimpleOutput>,
+    B::RollbackToken: PartialRollbackToken,
+{
+    type Output = SimpleOutput;
+    type RollbackToken = B::RollbackToken;
+    type Error = B::Error;
+
+    async fn request(
+        &self,
+        input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        let key = BatchKey {
+            key: input.key.clone(),
+            interval: input.interval,
+            max_requests: input.max_requests,
+        };
+        let receiver = {
+            let mut batches = self.batches.lock().unwrap();
+            match batches.get_mut(&key) {
+                Some(batch) => {
+                    let (sender, receiver) = oneshot::channel();
+                    batch.costs.push(input.cost);
+                    batch.senders.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    let batch = Batch {
+                        costs: Vec::new(),
+                        senders: Vec::new(),
+                    };
+                    batches.insert(key.clone(), batch);
+                    None
+                }
+            }
+        };
+        if let Some(receiver) = receiver {
+            return match receiver.await {
+                Ok(result) => Ok(result),
+                Err(_) => self.inner.request(input).await,
+            };
+        }
+
+        let mut guard = BatchGuard {
+            batches: &self.batches,
+            key: Some(key),
+        };
+        actix_web::rt::time::sleep(self.window).await;
+        let batch = guard.take();
+        if batch.senders.is_empty() {
+            return self.inner.request(input).await;
+        }
+
+        let own_cost = input.cost;
+        let total = batch
+            .costs
+            .iter()
+            .fold(own_cost, |a, b| a.saturating_add(*b));
+        let combined = SimpleInput {
+            cost: total,
+            ..input
+        };
+        // Dropping the senders on error lets the other requests retry by themselves
+        let (allow, output, token) = self.inner.request(combined).await?;
+        // Before the i-th request, the requests after it still had `total - charged` to charge
+        let share = |charged: u64| {
+            let mut output = output.clone();
+            if allow {
+                output.remaining = output
+                    .remaining
+                    .saturating_add(total - charged)
+                    .min(output.limit);
+            }
+            output
+        };
+        let mut charged = own_cost;
+        let result = (allow, share(charged), token.with_cost(own_cost));
+        for (cost, sender) in batch.costs.into_iter().zip(batch.senders) {
+            charged = charged.saturating_add(cost);
+            let _ = sender.send((allow, share(charged), token.with_cost(cost)));
+        }
+        Ok(result)
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        self.inner.rollback(token).await
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        self.inner.rollback_cost(token)
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.inner.health().await
+    }
+}
+
+impl<B> SimpleBackend for CoalescingBackend<B>
+where
+    B: SimpleBackend,
+    B::RollbackToken: PartialRollbackToken,
+{
+    async fn remove_key(&self, key: &str) -> Result<(), Self::Error> {
+        self.inner.remove_key(key).await
+    }
+
+    async fn peek(&self, input: &SimpleInput) -> Result<(bool, SimpleOutput), Self::Error> {
+        self.inner.peek(input).await
+    }
+
+    async fn set_key(&self, key: &str, count: u64, ttl: Duration) -> Result<(), Self::Error> {
+        self.inner.set_key(key, count, ttl).await
+    }
+
+    async fn remove_keys(&self, prefix: &str) -> Result<u64, Self::Error> {
+        self.inner.remove_keys(prefix).await
+    }
+
+    async fn clear(&self) -> Result<u64, Self::Error> {
+        self.inner.clear().await
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use crate::backend::InstrumentedBackend;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn input(max_requests: u64) -> SimpleInput {
+        SimpleInput {
+            interval: MINUTE,
+            max_requests,
+            key: "KEY1".to_string(),
+            cost: 1,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_coalescing() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let memory = InMemoryBackend::builder().with_gc_interval(None).build();
+        let backend = CoalescingBackend::new(
+            InstrumentedBackend::new(memory)
+                .on_call(move |call, _| recorded.lock().unwrap().push(call)),
+        );
+        let results = futures::future::join_all((0..3).map(|_| backend.request(input(5)))).await;
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        let remaining = results
+            .iter()
+            .map(|r| r.as_ref().unwrap().1.remaining)
+            .collect::<Vec<_>>();
+        assert_eq!(remaining, [4, 3, 2]);
+
+        // Each request can be rolled back individually
+        let (_, _, token) = results.into_iter().next().unwrap().unwrap();
+        assert_eq!(token.cost, 1);
+        backend.rollback(token).await.unwrap();
+        let (allow, output, _) = backend.request(input(5)).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_coalescing_denied() {
+        let backend = CoalescingBackend::new(InMemoryBackend::builder().build());
+        let results = futures::future::join_all((0..3).map(|_| backend.request(input(2)))).await;
+        for result in results {
+            let (allow, output, _) = result.unwrap();
+            assert!(!allow);
+            assert_eq!(output.remaining, 0);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_leader_dropped() {
+        let backend = CoalescingBackend::new(InMemoryBackend::builder().build());
+        let mut leader = Box::pin(backend.request(input(5)));
+        let mut follower = Box::pin(backend.request(input(5)));
+        // Start the batch, join it, then abandon it
+        assert!(futures::poll!(&mut leader).is_pending());
+        assert!(futures::poll!(&mut follower).is_pending());
+        drop(leader);
+        let (allow, output, _) = follower.await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 4);
+        assert!(backend.batches.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,348 @@
+//! Loading rate limiting policies from configuration files.
+use crate::backend::input_builder::Subnet;
+use crate::backend::rate::{parse_duration, parse_rate};
+use crate::backend::{KeyStrategy, MatchMode};
+use crate::backend::{Policy, PolicyMap, SimpleInputFunctionBuilder};
+use actix_web::http::Method;
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+/// A table of rate limiting policies, as read from a configuration file.
+///
+/// Intervals may be given either as an integer number of seconds, or as a string with a unit
+/// suffix of `ms`, `s`, `m`, `h` or `d`, e.g. `"500ms"` or `"5m"`. Alternatively, a policy's
+/// `interval` and `max_requests` may be given together as a `rate`, see
+/// [parse_rate](crate::backend::parse_rate).
+///
+/// # Example
+/// ```toml
+/// match_mode = "longest_prefix"
+/// exempt_ips = ["10.0.0.0/8"]
+///
+/// [default]
+/// rate = "100/1m"
+/// key = ["peer_ip"]
+///
+/// [[rules]]
+/// name = "login"
+/// method = "POST"
+/// path = "/login"
+/// interval = 60
+/// max_requests = 5
+/// key = ["peer_ip", { header = "x-user" }]
+///
+/// [[rules]]
+/// name = "health"
+/// path = "/healthz"
+/// exempt = true
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// The policy for requests that don't match any rule.
+    pub default: Policy,
+    #[serde(default)]
+    pub match_mode: MatchMode,
+    /// IP addresses or CIDR subnets that are exempt from rate limiting.
+    #[serde(default)]
+    pub exempt_ips: Vec<String>,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+/// A single rule within a [PolicyConfig].
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleConfig {
+    pub name: String,
+    /// Only match requests with this method, matches any method if not set.
+    #[serde(default)]
+    pub method: Option<String>,
+    /// The path pattern, see [PolicyMap].
+    pub path: String,
+    #[serde(flatten)]
+    pub policy: Policy,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Unable to read policy config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid TOML policy config: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[cfg(feature = "config-yaml")]
+    #[error("Invalid YAML policy config: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("Unsupported policy config file extension: {0:?}")]
+    UnsupportedFormat(Option<String>),
+    #[error("Invalid method in rule {rule:?}: {method}")]
+    InvalidMethod { rule: String, method: String },
+    #[error("Invalid exempt IP or subnet: {0}")]
+    InvalidSubnet(String),
+}
+
+impl PolicyConfig {
+    pub fn from_toml(s: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(s)?)
+    }
+
+    #[cfg(feature = "config-yaml")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "config-yaml")))]
+    pub fn from_yaml(s: &str) -> Result<Self, ConfigError> {
+        Ok(serde_yaml::from_str(s)?)
+    }
+
+    /// Read a config file, the format is chosen by its extension (`.toml`, or `.yaml` / `.yml`
+    /// with the `config-yaml` feature).
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::from_toml(&contents),
+            #[cfg(feature = "config-yaml")]
+            Some("yaml" | "yml") => Self::from_yaml(&contents),
+            other => Err(ConfigError::UnsupportedFormat(other.map(str::to_owned))),
+        }
+    }
+
+    /// Build the [PolicyMap] described by this config.
+    pub fn into_policy_map(self) -> Result<PolicyMap, ConfigError> {
+        for ip in &self.exempt_ips {
+            if ip.parse::<Subnet>().is_err() {
+                return Err(ConfigError::InvalidSubnet(ip.clone()));
+            }
+        }
+        let mut map = PolicyMap::new(self.default)
+            .match_mode(self.match_mode)
+            .exempt_ips(&self.exempt_ips);
+        for rule in self.rules {
+            let method = match rule.method {
+                Some(method) => match Method::from_str(&method.to_ascii_uppercase()) {
+                    Ok(method) => Some(method),
+                    Err(_) => {
+                        return Err(ConfigError::InvalidMethod {
+                            rule: rule.name,
+                            method,
+                        })
+                    }
+                },
+                None => None,
+            };
+            map = map.rule(&rule.name, method, &rule.path, rule.policy);
+        }
+        Ok(map)
+    }
+
+    /// Create a [SimpleInputFunctionBuilder] that applies the policies described by this config.
+    pub fn into_builder(self) -> Result<SimpleInputFunctionBuilder, ConfigError> {
+        let interval = self.default.interval;
+        let max_requests = self.default.max_requests;
+        let map = self.into_policy_map()?;
+        Ok(SimpleInputFunctionBuilder::new(interval, max_requests).policy_map(map))
+    }
+}
+
+// The fields of a Policy, as read from a configuration file
+#[derive(Deserialize)]
+pub(crate) struct PolicyFields {
+    #[serde(default)]
+    rate: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    interval: Duration,
+    #[serde(default)]
+    max_requests: u64,
+    #[serde(default)]
+    key: Vec<KeyStrategy>,
+    #[serde(default)]
+    exempt: bool,
+}
+
+impl TryFrom<PolicyFields> for Policy {
+    type Error = String;
+
+    fn try_from(fields: PolicyFields) -> Result<Self, Self::Error> {
+        let (interval, max_requests) = match fields.rate {
+            Some(_) if !fields.interval.is_zero() || fields.max_requests != 0 => {
+                return Err("rate cannot be combined with interval or max_requests".to_owned())
+            }
+            Some(rate) => parse_rate(&rate).map_err(|e| e.to_string())?,
+            None => (fields.interval, fields.max_requests),
+        };
+        Ok(Policy {
+            interval,
+            max_requests,
+            key: fields.key,
+            exempt: fields.exempt,
+        })
+    }
+}
+
+pub(crate) fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct DurationVisitor;
+
+    impl Visitor<'_> for DurationVisitor {
+        type Value = Duration;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a number of seconds, or a duration such as \"500ms\" or \"5m\"")
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Duration, E> {
+            Ok(Duration::from_secs(v))
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Duration, E> {
+            u64::try_from(v)
+                .map(Duration::from_secs)
+                .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Duration, E> {
+            parse_duration(v).ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &self))
+        }
+    }
+
+    deserializer.deserialize_any(DurationVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{KeyStrategy, PolicyHandle};
+    use crate::Exempt;
+    use actix_web::test::TestRequest;
+
+    const CONFIG: &str = r#"
+        match_mode = "longest_prefix"
+        exempt_ips = ["10.0.0.0/8"]
+
+        [default]
+        rate = "100/1m"
+        key = ["peer_ip"]
+
+        [[rules]]
+        name = "login"
+        method = "post"
+        path = "/login"
+        interval = 30
+        max_requests = 5
+        key = [{ header = "x-user" }]
+
+        [[rules]]
+        name = "health"
+        path = "/healthz"
+        exempt = true
+    "#;
+
+    #[test]
+    fn test_from_toml() {
+        let config = PolicyConfig::from_toml(CONFIG).unwrap();
+        assert_eq!(config.default.interval, Duration::from_secs(60));
+        assert_eq!(config.default.key, vec![KeyStrategy::PeerIp]);
+        assert_eq!(config.match_mode, MatchMode::LongestPrefix);
+        assert_eq!(
+            config.rules[0].policy.key,
+            vec![KeyStrategy::Header("x-user".to_owned())]
+        );
+        let map = config.into_policy_map().unwrap();
+        let (name, policy) = map.find(&Method::POST, "/login");
+        assert_eq!(name, "login");
+        assert_eq!(policy.interval, Duration::from_secs(30));
+        assert_eq!(map.find(&Method::GET, "/login").0, "default");
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = CONFIG.replace("\"post\"", "\"NOT A METHOD\"");
+        let err = PolicyConfig::from_toml(&config)
+            .unwrap()
+            .into_policy_map()
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidMethod { .. }));
+        let config = CONFIG.replace("10.0.0.0/8", "10.0.0.0/33");
+        let err = PolicyConfig::from_toml(&config)
+            .unwrap()
+            .into_policy_map()
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidSubnet(_)));
+        let config = CONFIG.replace("interval = 30", "interval = \"1 fortnight\"");
+        assert!(matches!(
+            PolicyConfig::from_toml(&config),
+            Err(ConfigError::Toml(_))
+        ));
+        let config = CONFIG.replace("\"100/1m\"", "\"100 per minute\"");
+        assert!(matches!(
+            PolicyConfig::from_toml(&config),
+            Err(ConfigError::Toml(_))
+        ));
+        let config = CONFIG.replace("rate = \"100/1m\"", "rate = \"100/1m\"\nmax_requests = 5");
+        assert!(matches!(
+            PolicyConfig::from_toml(&config),
+            Err(ConfigError::Toml(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_into_builder() {
+        let input_fn = PolicyConfig::from_toml(CONFIG)
+            .unwrap()
+            .into_builder()
+            .unwrap()
+            .build();
+        let req = TestRequest::with_uri("/healthz").to_srv_request();
+        let err = input_fn(&req).await.unwrap_err();
+        assert!(err.as_error::<Exempt>().is_some());
+        let req = TestRequest::post()
+            .uri("/login")
+            .insert_header(("x-user", "alice"))
+            .to_srv_request();
+        let input = input_fn(&req).await.unwrap();
+        assert_eq!(input.key, "login:alice");
+        assert_eq!(input.max_requests, 5);
+    }
+
+    #[test]
+    fn test_reload_from() {
+        let path = std::env::temp_dir().join(format!("policies-{}.toml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let handle = PolicyHandle::new(PolicyMap::new(Policy::new(Duration::from_secs(1), 1)));
+        handle.reload_from(&path).unwrap();
+        assert_eq!(handle.load().find(&Method::POST, "/login").0, "login");
+
+        std::fs::write(&path, "not valid").unwrap();
+        assert!(handle.reload_from(&path).is_err());
+        assert_eq!(handle.load().find(&Method::POST, "/login").0, "login");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "config-yaml")]
+    #[test]
+    fn test_from_yaml() {
+        let config = PolicyConfig::from_yaml(
+            r#"
+            default:
+              interval: 1h
+              max_requests: 1000
+            rules:
+              - name: api
+                path: /api/*
+                interval: 10s
+                max_requests: 10
+                key: [real_ip, { query: token }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.default.interval, Duration::from_secs(3600));
+        assert_eq!(
+            config.rules[0].policy.key,
+            vec![KeyStrategy::RealIp, KeyStrategy::Query("token".to_owned())]
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use crate::backend::clock::{Clock, TokioClock};
+use crate::backend::{Backend, Health, KeyedInput, SimpleBackend, SimpleInput, SimpleOutput};
+use actix_web::rt::time::Instant;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// The minimum time between sweeps of a full cache for expired entries
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wraps a [Backend], remembering which keys have been denied until their limit resets, so that
+/// further requests for those keys that cost more than the remaining quota are denied locally
+/// without calling the backend; e.g. to save a Redis round trip each time a client retries in a
+/// tight loop. Cheaper requests that would still fit are sent to the backend.
+///
+/// The cache is local to this instance (and its clones), so it can go stale if a key is reset
+/// elsewhere, or if a later request for the same key would have a higher limit. Keys reset
+/// through this wrapper's [SimpleBackend] functions are removed from the cache.
+///
+/// Requests denied from the cache return [None] as their rollback token, and rolling them back
+/// has no effect.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::DenyCacheBackend;
+/// # async {
+/// let backend = DenyCacheBackend::new(InMemoryBackend::builder().build());
+/// # };
+/// ```
+#[derive(Clone)]
+pub struct DenyCacheBackend<B> {
+    inner: B,
+    capacity: usize,
+    cache: Arc<Mutex<Denials>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct Denials {
+    keys: HashMap<String, SimpleOutput>,
+    // A full cache is only swept again once an entry may have expired, and SWEEP_INTERVAL has
+    // passed since the last sweep
+    next_sweep: Option<Instant>,
+}
+
+impl<B> DenyCacheBackend<B> {
+    /// Cache denials made by `inner`, keeping at most 10,000 entries.
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            capacity: 10_000,
+            cache: Arc::new(Mutex::new(Denials {
+                keys: HashMap::new(),
+                next_sweep: None,
+            })),
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    /// Override the maximum number of cached entries.
+    ///
+    /// Once full, expired entries are removed (at most once a second), and if there is still no
+    /// space, new denials are not cached until existing entries expire.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Read the time from `clock` rather than tokio, e.g. a
+    /// [ManualClock](crate::backend::clock::ManualClock) shared with the wrapped backend in tests.
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Remove a key from the cache, so that its next request is sent to the backend.
+    pub fn invalidate(&self, key: &str) {
+        self.cache.lock().unwrap().keys.remove(key);
+    }
+
+    /// Remove every key starting with `prefix` from the cache.
+    pub fn invalidate_prefix(&self, prefix: &str) {
+        self.cache
+            .lock()
+            .unwrap()
+            .keys
+            .retain(|key, _| !key.starts_with(prefix));
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    // The cached denial for a key, if a request of the given cost would still be denied
+    fn cached(&self, key: &str, cost: u64) -> Option<SimpleOutput> {
+        let mut cache = self.cache.lock().unwrap();
+        let output = cache.keys.get(key)?;
+        if output.reset <= self.clock.now() {
+            cache.keys.remove(key);
+            return None;
+        }
+        (cost > output.remaining).then(|| output.clone())
+    }
+
+    fn insert(&self, key: String, output: &SimpleOutput) {
+        let now = self.clock.now();
+        if output.reset <= now {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        let full = cache.keys.len() >= self.capacity && !cache.keys.contains_key(&key);
+        if full && cache.next_sweep.is_none_or(|next| next <= now) {
+            cache.keys.retain(|_, output| output.reset > now);
+            let next_expiry = cache.keys.values().map(|output| output.reset).min();
+            cache.next_sweep = Some(next_expiry.map_or(now, |next| next.max(now + SWEEP_INTERVAL)));
+        }
+        if cache.keys.len() < self.capacity || cache.keys.contains_key(&key) {
+            cache.keys.insert(key, output.clone());
+        }
+    }
+}
+
+impl<B, I> Backend<I> for DenyCacheBackend<B>
+where
+    B: Backend<I, Output = SimpleOutput>,
+    I: KeyedInput + 'static,
+{
+    type Output = SimpleOutput;
+    type RollbackToken = Option<B::RollbackToken>;
+    type Error = B::Error;
+
+    async fn request(
+        &self,
+        input: I,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        if let Some(output) = self.cached(input.key(), input.cost()) {
+            return Ok((false, output, None));
+        }
+        let key = input.key().to_owned();
+        let (allow, output, token) = self.inner.request(input).await?;
+        if allow {
+            // The cached remaining quota is now out of date
+            self.invalidate(&key);
+        } else {
+            self.insert(key, &output);
+        }
+        Ok((allow, output, Some(token)))
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        match token {
+            Some(token) => self.inner.rollback(token).await,
+            None => Ok(()),
+        }
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        match token {
+            Some(token) => self.inner.rollback_cost(token),
+            None => Some(0),
+        }
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.inner.health().await
+    }
+}
+
+impl<B: SimpleBackend> SimpleBackend for DenyCacheBackend<B> {
+    async fn remove_key(&self, key: &str) -> Result<(), Self::Error> {
+        self.invalidate(key);
+        self.inner.remove_key(key).await
+    }
+
+    async fn peek(&self, input: &SimpleInput) -> Result<(bool, SimpleOutput), Self::Error> {
+        match self.cached(&input.key, input.cost) {
+            Some(output) => Ok((false, output)),
+            None => self.inner.peek(input).await,
+        }
+    }
+
+    async fn set_key(&self, key: &str, count: u64, ttl: Duration) -> Result<(), Self::Error> {
+        self.invalidate(key);
+        self.inner.set_key(key, count, ttl).await
+    }
+
+    async fn remove_keys(&self, prefix: &str) -> Result<u64, Self::Error> {
+        self.invalidate_prefix(prefix);
+        self.inner.remove_keys(prefix).await
+    }
+
+    async fn clear(&self) -> Result<u64, Self::Error> {
+        self.invalidate_prefix("");
+        self.inner.clear().await
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::clock::ManualClock;
+    use crate::backend::memory::InMemoryBackend;
+    use crate::backend::token_bucket::{Rate, TokenBucketBackend, TokenBucketInput};
+    use crate::backend::InstrumentedBackend;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[actix_web::test]
+    async fn test_deny_cache() {
+        tokio::time::pause();
+        let calls = Arc::new(Mutex::new(0));
+        let counted = calls.clone();
+        let memory = InMemoryBackend::builder().with_gc_interval(None).build();
+        let backend = DenyCacheBackend::new(
+            InstrumentedBackend::new(memory).on_call(move |_, _| *counted.lock().unwrap() += 1),
+        );
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 1,
+            key: "KEY1".to_string(),
+            cost: 1,
+        };
+        let (allow, _, token) = backend.request(input.clone()).await.unwrap();
+        assert!(allow && token.is_some());
+        let (allow, _, _) = backend.request(input.clone()).await.unwrap();
+        assert!(!allow);
+        // Denied from the cache
+        let (allow, output, token) = backend.request(input.clone()).await.unwrap();
+        assert!(!allow && token.is_none());
+        assert_eq!(output.remaining, 0);
+        backend.rollback(token).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), 2);
+
+        // Until the limit resets
+        tokio::time::advance(MINUTE).await;
+        let (allow, _, _) = backend.request(input.clone()).await.unwrap();
+        assert!(allow);
+        assert_eq!(*calls.lock().unwrap(), 3);
+
+        backend.request(input.clone()).await.unwrap();
+        backend.remove_key("KEY1").await.unwrap();
+        let (allow, _, _) = backend.request(input).await.unwrap();
+        assert!(allow);
+    }
+
+    #[actix_web::test]
+    async fn test_mixed_costs() {
+        tokio::time::pause();
+        let calls = Arc::new(Mutex::new(0));
+        let counted = calls.clone();
+        // Denied requests take no tokens, so cheaper requests may still be allowed
+        let buckets = TokenBucketBackend::builder().with_gc_interval(None).build();
+        let backend = DenyCacheBackend::new(
+            InstrumentedBackend::new(buckets).on_call(move |_, _| *counted.lock().unwrap() += 1),
+        );
+        let input = |cost| TokenBucketInput {
+            sustained: Rate::new(0.001, 10),
+            peak: None,
+            key: "KEY1".to_string(),
+            cost,
+        };
+        let (allow, _, _) = backend.request(input(5)).await.unwrap();
+        assert!(allow);
+        let (allow, output, _) = backend.request(input(10)).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.remaining, 5);
+        // Expensive requests are denied from the cache
+        let (allow, _, token) = backend.request(input(6)).await.unwrap();
+        assert!(!allow && token.is_none());
+        assert_eq!(*calls.lock().unwrap(), 2);
+        // But cheaper ones that fit are sent to the backend
+        let (allow, output, token) = backend.request(input(3)).await.unwrap();
+        assert!(allow && token.is_some());
+        assert_eq!(output.remaining, 2);
+        assert_eq!(*calls.lock().unwrap(), 3);
+        let (allow, _, _) = backend.request(input(2)).await.unwrap();
+        assert!(allow);
+        let (allow, output, _) = backend.request(input(1)).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.remaining, 0);
+        let (allow, _, token) = backend.request(input(1)).await.unwrap();
+        assert!(!allow && token.is_none());
+        assert_eq!(*calls.lock().unwrap(), 5);
+    }
+
+    #[actix_web::test]
+    async fn test_capacity() {
+        tokio::time::pause();
+        let memory = InMemoryBackend::builder().with_gc_interval(None).build();
+        let backend = DenyCacheBackend::new(memory).capacity(1);
+        for key in ["KEY1", "KEY2"] {
+            let input = SimpleInput {
+                interval: MINUTE,
+                max_requests: 0,
+                key: key.to_string(),
+                cost: 1,
+            };
+            backend.request(input).await.unwrap();
+        }
+        assert!(backend.cache.lock().unwrap().keys.contains_key("KEY1"));
+        assert_eq!(backend.cache.lock().unwrap().keys.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_sweep() {
+        let clock = ManualClock::new();
+        let memory = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .with_clock(clock.clone())
+            .build();
+        let backend = DenyCacheBackend::new(memory)
+            .capacity(2)
+            .with_clock(clock.clone());
+        let deny = |key: &str, interval| {
+            backend.request(SimpleInput {
+                interval,
+                max_requests: 0,
+                key: key.to_string(),
+                cost: 1,
+            })
+        };
+        let cache = |key| backend.cache.lock().unwrap().keys.contains_key(key);
+        let next_sweep = || backend.cache.lock().unwrap().next_sweep;
+        let start = clock.now();
+        deny("KEY1", Duration::from_millis(100)).await.unwrap();
+        deny("KEY2", MINUTE).await.unwrap();
+        // Full, so KEY3 isn't cached, and the cache isn't swept again for SWEEP_INTERVAL, even
+        // though KEY1 expires sooner
+        deny("KEY3", MINUTE).await.unwrap();
+        assert!(!cache("KEY3"));
+        assert_eq!(next_sweep(), Some(start + SWEEP_INTERVAL));
+        clock.advance(SWEEP_INTERVAL / 2);
+        deny("KEY3", MINUTE).await.unwrap();
+        assert!(cache("KEY1") && !cache("KEY3"));
+        clock.advance(SWEEP_INTERVAL / 2);
+        deny("KEY3", MINUTE).await.unwrap();
+        assert!(!cache("KEY1") && cache("KEY3"));
+        // Nothing else can expire before KEY2
+        deny("KEY4", MINUTE).await.unwrap();
+        assert!(!cache("KEY4"));
+        assert_eq!(next_sweep(), Some(start + MINUTE));
+    }
+}
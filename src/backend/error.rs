@@ -0,0 +1,133 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use thiserror::Error;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The error returned by the provided backends that can fail, categorised so that middleware
+/// policies and metrics can tell the failure modes apart, see [BackendError::kind].
+///
+/// Responds with a `503 Service Unavailable` if the store timed out or could not be reached, or
+/// a `500 Internal Server Error` otherwise, without exposing the cause in the body. The status
+/// can be replaced with
+/// [RateLimiterBuilder::backend_error_status](crate::RateLimiterBuilder::backend_error_status).
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum BackendError {
+    /// The store did not respond in time.
+    #[error("Rate limit backend timed out")]
+    Timeout,
+    /// The store could not be reached, or the connection was lost.
+    #[error("Rate limit backend connection failed: {0}")]
+    Connection(#[source] BoxError),
+    /// The store's response could not be decoded.
+    #[error("Rate limit backend sent an invalid response: {0}")]
+    Serialization(#[source] BoxError),
+    /// A script run by the store failed.
+    #[error("Rate limit backend script failed: {0}")]
+    Script(#[source] BoxError),
+    /// A count or point in time was too large to be represented.
+    #[error("Rate limit backend overflowed: {0}")]
+    Overflow(String),
+}
+
+impl BackendError {
+    /// A short name for the kind of failure, suitable as a metrics label: one of `timeout`,
+    /// `connection`, `serialization`, `script` or `overflow`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BackendError::Timeout => "timeout",
+            BackendError::Connection(_) => "connection",
+            BackendError::Serialization(_) => "serialization",
+            BackendError::Script(_) => "script",
+            BackendError::Overflow(_) => "overflow",
+        }
+    }
+}
+
+impl ResponseError for BackendError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            BackendError::Timeout | BackendError::Connection(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::new(self.status_code())
+    }
+}
+
+#[cfg(feature = "redis")]
+impl From<redis::RedisError> for BackendError {
+    fn from(e: redis::RedisError) -> Self {
+        use redis::ErrorKind;
+        if e.is_timeout() {
+            return BackendError::Timeout;
+        }
+        match e.kind() {
+            ErrorKind::TypeError | ErrorKind::ResponseError => {
+                BackendError::Serialization(Box::new(e))
+            }
+            ErrorKind::NoScriptError | ErrorKind::ExecAbortError | ErrorKind::ExtensionError => {
+                BackendError::Script(Box::new(e))
+            }
+            _ => BackendError::Connection(Box::new(e)),
+        }
+    }
+}
+
+#[cfg(feature = "redis-deadpool")]
+impl From<deadpool_redis::PoolError> for BackendError {
+    fn from(e: deadpool_redis::PoolError) -> Self {
+        match e {
+            deadpool_redis::PoolError::Timeout(_) => BackendError::Timeout,
+            deadpool_redis::PoolError::Backend(e) => e.into(),
+            e => BackendError::Connection(Box::new(e)),
+        }
+    }
+}
+
+#[cfg(feature = "redis-bb8")]
+impl From<bb8::RunError<redis::RedisError>> for BackendError {
+    fn from(e: bb8::RunError<redis::RedisError>) -> Self {
+        match e {
+            bb8::RunError::User(e) => e.into(),
+            bb8::RunError::TimedOut => BackendError::Timeout,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let error = BackendError::Connection("refused".into());
+        assert_eq!(error.kind(), "connection");
+        assert_eq!(error.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            error.to_string(),
+            "Rate limit backend connection failed: refused"
+        );
+        let error = BackendError::Overflow("interval too large".to_owned());
+        assert_eq!(error.kind(), "overflow");
+        assert_eq!(
+            error.error_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn test_from_redis() {
+        let error = redis::RedisError::from((redis::ErrorKind::TypeError, "not an integer"));
+        assert_eq!(BackendError::from(error).kind(), "serialization");
+        let error = redis::RedisError::from(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        assert_eq!(BackendError::from(error).kind(), "timeout");
+        let error =
+            redis::RedisError::from(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert_eq!(BackendError::from(error).kind(), "connection");
+    }
+}
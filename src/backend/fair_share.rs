@@ -0,0 +1,209 @@
+use crate::backend::{Backend, Health, SimpleInput, SimpleOutput};
+use actix_web::rt::time::Instant;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type WeightFn = dyn Fn(&str) -> u64 + Send + Sync;
+
+/// Wraps a [Backend], dividing a global capacity between the keys (tenants) that are currently
+/// active, in proportion to their weights; so that a few busy tenants can't use up a shared
+/// resource, even while each stays below its own limit.
+///
+/// Each key's `max_requests` is lowered to its share of the `capacity`: its weight divided by the
+/// total weight of the active keys, rounded down, but at least 1. A key is active if it made a
+/// request in the previous period; the shares are recomputed at the start of each period, and a
+/// key that wasn't active is given the share it would have had if it were. The capacity is shared
+/// over the interval of each request, so should be given for the same interval as the
+/// `max_requests`.
+///
+/// The active keys are tracked by this instance (and its clones), so with several instances
+/// sharing a backend, each should be given its part of the capacity.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::FairShareBackend;
+/// # use std::time::Duration;
+/// // Tenants on the enterprise plan get 4 times the share of other tenants
+/// let backend = FairShareBackend::new(
+///     InMemoryBackend::builder().build(),
+///     10_000,
+///     Duration::from_secs(10),
+/// )
+/// .weights(|key| if key.starts_with("enterprise:") { 4 } else { 1 });
+/// ```
+#[derive(Clone)]
+pub struct FairShareBackend<B> {
+    inner: B,
+    capacity: u64,
+    period: Duration,
+    weights: Arc<WeightFn>,
+    shares: Arc<Mutex<Shares>>,
+}
+
+struct Shares {
+    // The keys seen in the current period, with their weights
+    active: HashMap<String, u64>,
+    // The keys seen in the previous period, with their weights
+    previous: HashMap<String, u64>,
+    // The total weight of the previous period's keys
+    total_weight: u64,
+    recompute_at: Instant,
+}
+
+impl<B> FairShareBackend<B> {
+    /// Share `capacity` between the keys active in the last `period`, giving each key a weight
+    /// of 1.
+    pub fn new(inner: B, capacity: u64, period: Duration) -> Self {
+        Self {
+            inner,
+            capacity,
+            period,
+            weights: Arc::new(|_| 1),
+            shares: Arc::new(Mutex::new(Shares {
+                active: HashMap::new(),
+                previous: HashMap::new(),
+                total_weight: 0,
+                recompute_at: Instant::now() + period,
+            })),
+        }
+    }
+
+    /// Choose the weight of each key, a weight of zero is treated as 1.
+    pub fn weights<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> u64 + Send + Sync + 'static,
+    {
+        self.weights = Arc::new(f);
+        self
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// The current share of the capacity for `key`.
+    pub fn share(&self, key: &str) -> u64 {
+        let weight = (self.weights)(key).max(1);
+        let shares = self.shares.lock().unwrap();
+        self.share_of(&shares, key, weight)
+    }
+
+    fn share_of(&self, shares: &Shares, key: &str, weight: u64) -> u64 {
+        let total = if shares.previous.contains_key(key) {
+            shares.total_weight
+        } else {
+            shares.total_weight.saturating_add(weight)
+        };
+        let share = u128::from(self.capacity) * u128::from(weight) / u128::from(total.max(1));
+        u64::try_from(share).unwrap_or(u64::MAX).max(1)
+    }
+
+    // Records that the key is active, returning its share
+    fn activate(&self, key: &str) -> u64 {
+        let weight = (self.weights)(key).max(1);
+        let now = Instant::now();
+        let mut shares = self.shares.lock().unwrap();
+        if now >= shares.recompute_at {
+            // If a whole period has passed without requests, no keys are active
+            let previous = if now >= shares.recompute_at + self.period {
+                HashMap::new()
+            } else {
+                std::mem::take(&mut shares.active)
+            };
+            shares.active.clear();
+            shares.total_weight = previous
+                .values()
+                .fold(0u64, |total, weight| total.saturating_add(*weight));
+            shares.previous = previous;
+            shares.recompute_at = now + self.period;
+        }
+        shares.active.insert(key.to_owned(), weight);
+        self.share_of(&shares, key, weight)
+    }
+}
+
+impl<B> Backend<SimpleInput> for FairShareBackend<B>
+where
+    B: Backend<SimpleInput, Output = SimpleOutput>,
+{
+    type Output = SimpleOutput;
+    type RollbackToken = B::RollbackToken;
+    type Error = B::Error;
+
+    async fn request(
+        &self,
+        mut input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        let share = self.activate(&input.key);
+        input.max_requests = input.max_requests.min(share);
+        self.inner.request(input).await
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        self.inner.rollback(token).await
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        self.inner.rollback_cost(token)
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.inner.health().await
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn input(key: &str) -> SimpleInput {
+        SimpleInput {
+            interval: MINUTE,
+            max_requests: 100,
+            key: key.to_string(),
+            cost: 1,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_fair_share() {
+        tokio::time::pause();
+        let memory = InMemoryBackend::builder().with_gc_interval(None).build();
+        let backend = FairShareBackend::new(memory, 60, Duration::from_secs(10)).weights(|key| {
+            if key == "big" {
+                2
+            } else {
+                1
+            }
+        });
+
+        // Alone, a key is given the whole capacity
+        let (_, output, _) = backend.request(input("small")).await.unwrap();
+        assert_eq!(output.limit, 60);
+        // A key that wasn't active is given the share it would have had
+        let (_, output, _) = backend.request(input("big")).await.unwrap();
+        assert_eq!(output.limit, 60);
+
+        // Recomputed with both keys active
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let (_, output, _) = backend.request(input("small")).await.unwrap();
+        assert_eq!(output.limit, 20);
+        assert_eq!(backend.share("big"), 40);
+        assert_eq!(backend.share("other"), 15);
+
+        // Only the small key was active in the last period
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let (_, output, _) = backend.request(input("small")).await.unwrap();
+        assert_eq!(output.limit, 60);
+
+        // A whole period without requests
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert_eq!(backend.activate("big"), 60);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::backend::{Backend, Health, SimpleInput};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+/// Wraps a [Backend] that accepts `u64` keys, hashing each request's key before it is stored;
+/// e.g. an [InMemoryBackend](crate::backend::memory::InMemoryBackend) built with
+/// [build_keyed::\<u64\>](crate::backend::memory::Builder::build_keyed).
+///
+/// This shrinks the memory of each bucket to a fixed size regardless of the key length, which
+/// adds up for high-cardinality keys such as client IPs, and means keys are no longer retained.
+///
+/// # Collisions
+///
+/// Requests whose keys hash to the same value share a limit. With a 64-bit hash the chance of
+/// any collision among a million distinct keys is around 1 in 37 million, but it is not zero; so
+/// this trade-off may not be acceptable where a single collision would be costly.
+///
+/// The default hasher, [RandomState], is seeded randomly for each backend (and shared by its
+/// clones), so clients can't craft keys that collide with one another. A faster hasher can be
+/// given with [HashedKeyBackend::with_hasher] where keys are not chosen by clients; hashes are
+/// never shared between processes, so it needn't be stable.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::{HashedKeyBackend, SimpleInputFunctionBuilder};
+/// # use actix_extensible_rate_limit::RateLimiter;
+/// let memory = InMemoryBackend::builder().build_keyed::<u64>();
+/// let backend = HashedKeyBackend::new(memory);
+/// let input = SimpleInputFunctionBuilder::per_minute(60).real_ip_key().build();
+/// let middleware = RateLimiter::builder(backend, input).add_headers().build();
+/// ```
+#[derive(Clone)]
+pub struct HashedKeyBackend<B, S = RandomState> {
+    inner: B,
+    hasher: S,
+}
+
+impl<B> HashedKeyBackend<B> {
+    pub fn new(inner: B) -> Self {
+        Self::with_hasher(inner, RandomState::new())
+    }
+}
+
+impl<B, S: BuildHasher> HashedKeyBackend<B, S> {
+    /// Hash keys with the given hasher, see [HashedKeyBackend#collisions].
+    pub fn with_hasher(inner: B, hasher: S) -> Self {
+        Self { inner, hasher }
+    }
+
+    /// The hash that `key` is stored under, e.g. to look up a key in the wrapped backend.
+    pub fn hash_key(&self, key: &str) -> u64 {
+        self.hasher.hash_one(key)
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    fn hashed(&self, input: SimpleInput) -> SimpleInput<u64> {
+        SimpleInput {
+            interval: input.interval,
+            max_requests: input.max_requests,
+            key: self.hash_key(&input.key),
+            cost: input.cost,
+        }
+    }
+}
+
+impl<B, S> Backend<SimpleInput> for HashedKeyBackend<B, S>
+where
+    B: Backend<SimpleInput<u64>>,
+    S: BuildHasher + Clone + 'static,
+{
+    type Output = B::Output;
+    type RollbackToken = B::RollbackToken;
+    type Error = B::Error;
+
+    async fn request(
+        &self,
+        input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        self.inner.request(self.hashed(input)).await
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        self.inner.rollback(token).await
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        self.inner.rollback_cost(token)
+    }
+
+    async fn request_many(
+        &self,
+        inputs: Vec<SimpleInput>,
+    ) -> Result<Vec<(bool, Self::Output, Self::RollbackToken)>, Self::Error> {
+        let inputs = inputs.into_iter().map(|input| self.hashed(input)).collect();
+        self.inner.request_many(inputs).await
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.inner.health().await
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use std::hash::BuildHasherDefault;
+    use std::time::Duration;
+
+    fn input(key: &str) -> SimpleInput {
+        SimpleInput {
+            interval: Duration::from_secs(60),
+            max_requests: 1,
+            key: key.to_owned(),
+            cost: 1,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_hashed_keys() {
+        let memory = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .track_top_offenders(10)
+            .build_keyed::<u64>();
+        let backend = HashedKeyBackend::new(memory);
+
+        let (allow, _, token) = backend.request(input("KEY1")).await.unwrap();
+        assert!(allow);
+        assert_eq!(token.key, backend.hash_key("KEY1"));
+        let (allow, _, denied_token) = backend.request(input("KEY1")).await.unwrap();
+        assert!(!allow);
+        assert!(backend.request(input("KEY2")).await.unwrap().0);
+        assert_eq!(
+            backend.inner().top_offenders(1),
+            [(backend.hash_key("KEY1"), 1)]
+        );
+        backend.rollback(token).await.unwrap();
+        backend.rollback(denied_token).await.unwrap();
+        assert!(backend.request(input("KEY1")).await.unwrap().0);
+
+        // Clones share the hasher
+        let clone = backend.clone();
+        assert_eq!(clone.hash_key("KEY1"), backend.hash_key("KEY1"));
+    }
+
+    #[actix_web::test]
+    async fn test_with_hasher() {
+        let hasher = BuildHasherDefault::<std::collections::hash_map::DefaultHasher>::default();
+        let memory = InMemoryBackend::builder().with_gc_interval(None);
+        let a = HashedKeyBackend::with_hasher(memory.build_keyed::<u64>(), hasher.clone());
+        let memory = InMemoryBackend::builder().with_gc_interval(None);
+        let b = HashedKeyBackend::with_hasher(memory.build_keyed::<u64>(), hasher);
+        assert_eq!(a.hash_key("KEY1"), b.hash_key("KEY1"));
+        assert_ne!(a.hash_key("KEY1"), a.hash_key("KEY2"));
+    }
+}
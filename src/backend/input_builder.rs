@@ -1,47 +1,372 @@
-use crate::backend::SimpleInput;
+#[cfg(feature = "bypass")]
+use crate::backend::bypass::BypassTokens;
+use crate::backend::overrides::OverrideStore;
+use crate::backend::rate::{parse_rate, ParseRateError};
+use crate::backend::schedule::Schedule;
+use crate::backend::tier::TierResolver;
+use crate::backend::{PolicyHandle, PolicyMap, SimpleInput};
+use crate::Exempt;
 use actix_web::dev::ServiceRequest;
-use actix_web::ResponseError;
-use std::future::{ready, Ready};
-use std::net::{AddrParseError, IpAddr, Ipv6Addr};
-use std::time::Duration;
+use actix_web::http::header::{
+    ACCEPT, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_TYPE, COOKIE, ORIGIN, X_FORWARDED_FOR,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{HttpMessage, ResponseError};
+use futures::future::{Either, LocalBoxFuture};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::fmt::Write;
+use std::future::{ready, Future, Ready};
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
+// The largest buffer preallocated for a key, longer keys grow as they are built
+const MAX_KEY_CAPACITY: usize = 256;
+
 type CustomFn = Box<dyn Fn(&ServiceRequest) -> Result<String, actix_web::Error>>;
-type ExtCustomFn = Box<dyn Fn(&ServiceRequest) -> Result<(String, Option<Duration>, Option<u64>), actix_web::Error>>;
+type KeyHashFn = Box<dyn Fn(&str) -> String>;
+type ExcludeFn = Box<dyn Fn(&ServiceRequest) -> bool>;
+type ComponentFn = Box<dyn Fn(&ServiceRequest) -> Result<Option<String>, actix_web::Error>>;
+type PolicyFn = Box<dyn Fn(&ServiceRequest) -> Result<PolicyDecision, actix_web::Error>>;
+type PriorityFn = Box<dyn Fn(&ServiceRequest) -> Priority>;
+
+type AsyncFn =
+    Box<dyn Fn(&ServiceRequest) -> LocalBoxFuture<'static, Result<String, actix_web::Error>>>;
 
-pub type SimpleInputFuture = Ready<Result<SimpleInput, actix_web::Error>>;
+/// The future returned by the [SimpleInputFunctionBuilder] input function.
+///
+/// This is immediately ready unless [SimpleInputFunctionBuilder::custom_async_fn] was used.
+pub type SimpleInputFuture = Either<
+    Ready<Result<SimpleInput, actix_web::Error>>,
+    LocalBoxFuture<'static, Result<SimpleInput, actix_web::Error>>,
+>;
 
 /// Utility to create a input function that produces a [SimpleInput].
 ///
 /// You should take care to ensure that you are producing unique keys per backend.
 ///
 /// This will not be of any use if you want to use dynamic interval/request policies
-/// or perform an asynchronous option; you should instead write your own input function.
+/// that can't be expressed with [SimpleInputFunctionBuilder::policy_fn]; you should instead
+/// write your own input function.
 pub struct SimpleInputFunctionBuilder {
     interval: Duration,
     max_requests: u64,
-    real_ip_key: bool,
-    peer_ip_key: bool,
+    schedule: Option<Schedule>,
+    excluded_paths: Vec<String>,
+    exclude_fn: Option<ExcludeFn>,
+    content_types: Vec<String>,
+    ignore_preflight: bool,
+    real_ip_key: Option<IpPrefix>,
+    peer_ip_key: Option<IpPrefix>,
+    // The trusted proxy subnets (or the first that could not be parsed), and the client's prefix
+    trusted_proxy_ip_key: Option<(Result<Vec<Subnet>, String>, IpPrefix)>,
+    missing_ip: MissingKeyPolicy,
     path_key: bool,
+    method_key: bool,
+    merge_head_into_get: bool,
+    components: Vec<ComponentFn>,
     custom_key: Option<String>,
     custom_fn: Option<CustomFn>,
-    ext_custom_fn: Option<ExtCustomFn>,
+    policy_fn: Option<PolicyFn>,
+    async_fns: Vec<AsyncFn>,
+    tier_resolver: Option<Rc<dyn TierResolver>>,
+    override_store: Option<Rc<dyn OverrideStore>>,
+    priority: Option<(PriorityFn, f64)>,
+    #[cfg(feature = "bypass")]
+    bypass_tokens: Option<BypassTokens>,
+    #[cfg(feature = "graphql")]
+    graphql_operation: Option<MissingKeyPolicy>,
+    key_hash_fn: Option<KeyHashFn>,
+    key_prefix: Option<String>,
+    separator: char,
+    global_key: bool,
+    // The longest key built so far (up to MAX_KEY_CAPACITY), to size the buffer of the next
+    key_capacity: Cell<usize>,
+}
+
+/// The result of a [SimpleInputFunctionBuilder::policy_fn], controlling how a particular request
+/// is rate limited.
+///
+/// Fields left as [None] fall back to the values configured on the builder.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyDecision {
+    /// A component to add to the rate limiting key.
+    pub key: String,
+    /// Overrides the rate limiting interval.
+    pub interval: Option<Duration>,
+    /// Overrides the total requests to be allowed within the interval.
+    pub max_requests: Option<u64>,
+    /// Overrides the cost of the request (by default each request costs 1).
+    pub cost: Option<u64>,
+    /// Exempts the request from rate limiting entirely.
+    pub exempt: bool,
+}
+
+impl PolicyDecision {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    pub fn max_requests(mut self, max_requests: u64) -> Self {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    pub fn cost(mut self, cost: u64) -> Self {
+        self.cost = Some(cost);
+        self
+    }
+
+    pub fn exempt(mut self) -> Self {
+        self.exempt = true;
+        self
+    }
+}
+
+/// The priority of a request, see [SimpleInputFunctionBuilder::priority_fn].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Denied once the usage of the key crosses the threshold.
+    Low,
+    /// May use the whole limit.
+    High,
+}
+
+// The input for a request, while the key components are being gathered.
+struct PartialInput {
+    interval: Duration,
+    max_requests: u64,
+    cost: u64,
+    low_priority: bool,
+    #[cfg(feature = "bypass")]
+    bypass_token: Option<String>,
+    key: KeyBuffer,
+}
+
+/// The DER encoded client certificate of an mTLS connection.
+///
+/// This must be inserted into the connection data by your TLS acceptor, using
+/// [HttpServer::on_connect](https://docs.rs/actix-web/4/actix_web/struct.HttpServer.html#method.on_connect),
+/// (or otherwise into the request extensions) to be used with
+/// [SimpleInputFunctionBuilder::client_cert_key].
+///
+/// # Example
+/// ```ignore
+/// HttpServer::new(app)
+///     .on_connect(|conn, data| {
+///         if let Some(tls) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+///             let (_, session) = tls.get_ref();
+///             if let Some(cert) = session.peer_certificates().and_then(|c| c.first()) {
+///                 data.insert(PeerCertificate(cert.to_vec()));
+///             }
+///         }
+///     })
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCertificate(pub Vec<u8>);
+
+/// What to do when a request is missing a value required by a key component.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MissingKeyPolicy {
+    /// Reject the request with a `400 Bad Request` response.
+    Error,
+    /// Leave the component out of the key.
+    Skip,
+    /// Use a fixed value for the component instead.
+    Fallback(String),
+}
+
+impl MissingKeyPolicy {
+    // Applies the policy if the component `name` has no value.
+    fn apply(&self, value: Option<String>, name: &str) -> Result<Option<String>, Error> {
+        match value {
+            Some(value) => Ok(Some(value)),
+            None => Ok(self.fallback(name)?.map(str::to_owned)),
+        }
+    }
+
+    // The value to use in place of the missing component `name`, if any.
+    fn fallback(&self, name: &str) -> Result<Option<&str>, Error> {
+        match self {
+            MissingKeyPolicy::Error => Err(Error::MissingComponent(name.to_owned())),
+            MissingKeyPolicy::Skip => Ok(None),
+            MissingKeyPolicy::Fallback(value) => Ok(Some(value)),
+        }
+    }
 }
 
 impl SimpleInputFunctionBuilder {
+    /// Allow `max_requests` per second, shorthand for [SimpleInputFunctionBuilder::new].
+    pub fn per_second(max_requests: u64) -> Self {
+        Self::new(Duration::from_secs(1), max_requests)
+    }
+
+    /// Allow `max_requests` per minute, shorthand for [SimpleInputFunctionBuilder::new].
+    pub fn per_minute(max_requests: u64) -> Self {
+        Self::new(Duration::from_secs(60), max_requests)
+    }
+
+    /// Allow `max_requests` per hour, shorthand for [SimpleInputFunctionBuilder::new].
+    pub fn per_hour(max_requests: u64) -> Self {
+        Self::new(Duration::from_secs(60 * 60), max_requests)
+    }
+
+    /// Allow requests at a rate such as `"100/1m"`, see [parse_rate](crate::backend::parse_rate).
+    ///
+    /// # Example
+    /// ```
+    /// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+    /// let input = SimpleInputFunctionBuilder::from_rate("100/1m")
+    ///     .unwrap()
+    ///     .real_ip_key()
+    ///     .build();
+    /// ```
+    pub fn from_rate(rate: &str) -> Result<Self, ParseRateError> {
+        let (interval, max_requests) = parse_rate(rate)?;
+        Ok(Self::new(interval, max_requests))
+    }
+
     pub fn new(interval: Duration, max_requests: u64) -> Self {
         Self {
             interval,
             max_requests,
-            real_ip_key: false,
-            peer_ip_key: false,
+            schedule: None,
+            excluded_paths: Vec::new(),
+            exclude_fn: None,
+            content_types: Vec::new(),
+            ignore_preflight: false,
+            real_ip_key: None,
+            peer_ip_key: None,
+            trusted_proxy_ip_key: None,
+            missing_ip: MissingKeyPolicy::Error,
             path_key: false,
+            method_key: false,
+            merge_head_into_get: false,
+            components: Vec::new(),
             custom_key: None,
             custom_fn: None,
-            ext_custom_fn: None,
+            policy_fn: None,
+            async_fns: Vec::new(),
+            tier_resolver: None,
+            override_store: None,
+            priority: None,
+            #[cfg(feature = "bypass")]
+            bypass_tokens: None,
+            #[cfg(feature = "graphql")]
+            graphql_operation: None,
+            key_hash_fn: None,
+            key_prefix: None,
+            separator: '-',
+            global_key: false,
+            key_capacity: Cell::new(0),
         }
     }
 
+    /// Vary the interval and max requests by the time of day and day of the week, see [Schedule].
+    ///
+    /// Outside of the schedule's windows the limits given to
+    /// [SimpleInputFunctionBuilder::new] are used, and any limits set by
+    /// [SimpleInputFunctionBuilder::policy_fn] or [SimpleInputFunctionBuilder::tier_resolver]
+    /// take precedence.
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Exempt requests to the given paths from rate limiting entirely.
+    ///
+    /// Paths may contain `*` wildcards which match any sequence of characters (including `/`),
+    /// e.g. `/static/*`.
+    ///
+    /// # Example
+    /// ```
+    /// # use std::time::Duration;
+    /// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 100)
+    ///     .exclude_paths(["/healthz", "/metrics", "/static/*"])
+    ///     .peer_ip_key()
+    ///     .build();
+    /// ```
+    pub fn exclude_paths<I, S>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.excluded_paths
+            .extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Exempt requests from rate limiting entirely when the predicate returns true.
+    ///
+    /// This may be used for more complex matching, such as regular expressions.
+    pub fn exclude_if<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> bool + 'static,
+    {
+        self.exclude_fn = Some(Box::new(f));
+        self
+    }
+
+    /// Only rate limit requests whose `Content-Type` or `Accept` header names one of the given
+    /// media types, exempting everything else; e.g. to charge JSON API calls, but not the static
+    /// assets or CORS preflight requests served by the same scope.
+    ///
+    /// Media types are matched case-insensitively, ignoring parameters such as `charset`, and
+    /// may contain `*` wildcards, e.g. `application/*+json`. Media ranges sent by the client, such
+    /// as `Accept: */*` or `Accept: application/*`, match every type they cover, and requests with
+    /// neither header are still rate limited.
+    ///
+    /// # Security
+    ///
+    /// Both headers are chosen by the client, which can send a non-matching type to avoid the
+    /// limit. This is only suitable where the handlers reject such requests anyway, e.g. a JSON
+    /// API whose extractors require `Content-Type: application/json`; otherwise exempt the other
+    /// requests by path with [SimpleInputFunctionBuilder::exclude_paths].
+    ///
+    /// # Example
+    /// ```
+    /// # use std::time::Duration;
+    /// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 100)
+    ///     .only_content_types(["application/json", "application/*+json"])
+    ///     .peer_ip_key()
+    ///     .build();
+    /// ```
+    pub fn only_content_types<I, S>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.content_types.extend(
+            types
+                .into_iter()
+                .map(|media_type| media_type.into().to_ascii_lowercase()),
+        );
+        self
+    }
+
+    /// Exempt CORS preflight requests from rate limiting entirely, so that browsers don't use up
+    /// twice the quota of clients calling a cross-origin API.
+    ///
+    /// A preflight is an `OPTIONS` request with both `Origin` and
+    /// `Access-Control-Request-Method` headers.
+    pub fn ignore_preflight(mut self) -> Self {
+        self.ignore_preflight = true;
+        self
+    }
+
     /// Adds the client's real IP to the rate limiting key.
     ///
     /// # Security
@@ -55,7 +380,19 @@ impl SimpleInputFunctionBuilder {
     ///
     /// IPv6 addresses will be grouped into a single key per /64
     pub fn real_ip_key(mut self) -> Self {
-        self.real_ip_key = true;
+        self.real_ip_key = Some(IpPrefix::default());
+        self
+    }
+
+    /// Same as [SimpleInputFunctionBuilder::real_ip_key], but grouping addresses into a single key
+    /// per subnet of the given prefix lengths, e.g. `(56, 32)` to group IPv6 addresses per /56
+    /// while keeping individual IPv4 addresses.
+    ///
+    /// # Panics
+    ///
+    /// If the IPv6 prefix is greater than 128, or the IPv4 prefix is greater than 32.
+    pub fn real_ip_key_with_prefix(mut self, ipv6_prefix: u8, ipv4_prefix: u8) -> Self {
+        self.real_ip_key = Some(IpPrefix::new(ipv6_prefix, ipv4_prefix));
         self
     }
 
@@ -67,7 +404,95 @@ impl SimpleInputFunctionBuilder {
     ///
     /// IPv6 addresses will be grouped into a single key per /64
     pub fn peer_ip_key(mut self) -> Self {
-        self.peer_ip_key = true;
+        self.peer_ip_key = Some(IpPrefix::default());
+        self
+    }
+
+    /// Same as [SimpleInputFunctionBuilder::peer_ip_key], but grouping addresses into a single key
+    /// per subnet of the given prefix lengths.
+    ///
+    /// # Panics
+    ///
+    /// If the IPv6 prefix is greater than 128, or the IPv4 prefix is greater than 32.
+    pub fn peer_ip_key_with_prefix(mut self, ipv6_prefix: u8, ipv4_prefix: u8) -> Self {
+        self.peer_ip_key = Some(IpPrefix::new(ipv6_prefix, ipv4_prefix));
+        self
+    }
+
+    /// What to do when a request has no client address for [SimpleInputFunctionBuilder::real_ip_key],
+    /// [SimpleInputFunctionBuilder::peer_ip_key] or
+    /// [SimpleInputFunctionBuilder::trusted_proxy_ip_key], such as one received over a Unix socket,
+    /// or a test request without a peer address.
+    ///
+    /// The default is [MissingKeyPolicy::Error].
+    pub fn missing_ip(mut self, policy: MissingKeyPolicy) -> Self {
+        self.missing_ip = policy;
+        self
+    }
+
+    /// Adds the client's IP to the rate limiting key, as determined by walking the
+    /// `X-Forwarded-For` header from right to left, skipping over the given trusted proxies.
+    ///
+    /// The connection peer is treated as the right-most hop, the first address (from the right)
+    /// that is not within one of the trusted proxy subnets is used as the client IP. Unlike
+    /// [SimpleInputFunctionBuilder::real_ip_key] this can't be spoofed by a client adding its own
+    /// `X-Forwarded-For` entries, no matter how many proxies the request passed through.
+    ///
+    /// Each trusted proxy must be a valid IP address or CIDR subnet, otherwise
+    /// [SimpleInputFunctionBuilder::try_build] returns an [InputBuildError::InvalidTrustedProxy].
+    ///
+    /// # IPv6
+    ///
+    /// IPv6 addresses will be grouped into a single key per /64
+    ///
+    /// # Example
+    /// ```
+    /// # use std::time::Duration;
+    /// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 100)
+    ///     .trusted_proxy_ip_key(["10.0.0.0/8", "fd00::/8"])
+    ///     .build();
+    /// ```
+    pub fn trusted_proxy_ip_key<I, S>(self, trusted_proxies: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.trusted_proxy_ip_key_inner(trusted_proxies, IpPrefix::default())
+    }
+
+    /// Same as [SimpleInputFunctionBuilder::trusted_proxy_ip_key], but grouping client addresses
+    /// into a single key per subnet of the given prefix lengths.
+    ///
+    /// # Panics
+    ///
+    /// If the IPv6 prefix is greater than 128, or the IPv4 prefix is greater than 32.
+    pub fn trusted_proxy_ip_key_with_prefix<I, S>(
+        self,
+        trusted_proxies: I,
+        ipv6_prefix: u8,
+        ipv4_prefix: u8,
+    ) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.trusted_proxy_ip_key_inner(trusted_proxies, IpPrefix::new(ipv6_prefix, ipv4_prefix))
+    }
+
+    fn trusted_proxy_ip_key_inner<I, S>(mut self, trusted_proxies: I, prefix: IpPrefix) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let trusted = trusted_proxies
+            .into_iter()
+            .map(|s| {
+                let s = s.as_ref();
+                s.parse::<Subnet>().map_err(|_| s.to_owned())
+            })
+            .collect();
+        self.trusted_proxy_ip_key = Some((trusted, prefix));
         self
     }
 
@@ -77,12 +502,231 @@ impl SimpleInputFunctionBuilder {
         self
     }
 
+    /// Add the matched route pattern (e.g. `/users/{id}`) to the rate limiting key.
+    ///
+    /// Unlike [SimpleInputFunctionBuilder::path_key] all requests to the same resource share a
+    /// single key, regardless of the path parameters. The missing policy is applied to requests
+    /// that don't match any route, a fixed [MissingKeyPolicy::Fallback] is recommended so that
+    /// scanners can't create an unbounded number of keys.
+    pub fn match_pattern_key(mut self, missing: MissingKeyPolicy) -> Self {
+        self.components.push(Box::new(move |req| {
+            Ok(missing.apply(req.match_pattern(), "match pattern")?)
+        }));
+        self
+    }
+
+    /// Add the requested host (lowercased) to the rate limiting key, so that each virtual host or
+    /// tenant subdomain is limited separately.
+    ///
+    /// # Security
+    ///
+    /// This calls [ConnectionInfo::host()](actix_web::dev::ConnectionInfo::host) internally, which
+    /// may be derived from the `Forwarded` or `X-Forwarded-Host` headers.
+    pub fn host_key(mut self) -> Self {
+        self.components
+            .push(Box::new(|req| Ok(Some(host_value(req)))));
+        self
+    }
+
+    /// Add the SHA-256 fingerprint (lowercase hex) of the mTLS client certificate to the rate
+    /// limiting key.
+    ///
+    /// The certificate must be provided as a [PeerCertificate] in the connection data.
+    pub fn client_cert_key(mut self, missing: MissingKeyPolicy) -> Self {
+        self.components.push(Box::new(move |req| {
+            let fingerprint = match req.conn_data::<PeerCertificate>() {
+                Some(cert) => Some(sha256_hex(&cert.0)),
+                None => req
+                    .extensions()
+                    .get::<PeerCertificate>()
+                    .map(|cert| sha256_hex(&cert.0)),
+            };
+            Ok(missing.apply(fingerprint, "client certificate")?)
+        }));
+        self
+    }
+
+    /// Add the request method to the rate limiting key, so that e.g. `GET` and `POST` requests to
+    /// the same path are counted separately.
+    pub fn method_key(mut self) -> Self {
+        self.method_key = true;
+        self
+    }
+
+    /// When used with [SimpleInputFunctionBuilder::method_key], `HEAD` requests will share the
+    /// same key as `GET` requests.
+    pub fn merge_head_into_get(mut self) -> Self {
+        self.merge_head_into_get = true;
+        self
+    }
+
+    /// Add the value of a request header to the rate limiting key, e.g. an API key.
+    ///
+    /// Headers that are not valid UTF-8 are treated as missing.
+    ///
+    /// # Example
+    /// ```
+    /// # use std::time::Duration;
+    /// # use actix_extensible_rate_limit::backend::{MissingKeyPolicy, SimpleInputFunctionBuilder};
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 100)
+    ///     .header_key("X-Api-Key", MissingKeyPolicy::Fallback("anonymous".to_owned()))
+    ///     .build();
+    /// ```
+    pub fn header_key(mut self, name: &str, missing: MissingKeyPolicy) -> Self {
+        let name = name.to_owned();
+        self.components.push(Box::new(move |req| {
+            Ok(missing.apply(header_value(req, &name), &name)?)
+        }));
+        self
+    }
+
+    /// Add the value of a query string parameter to the rate limiting key, e.g. for legacy clients
+    /// that pass their credentials in the URL.
+    ///
+    /// The value is URL decoded, if the parameter is repeated then the first value is used.
+    pub fn query_key(mut self, name: &str, missing: MissingKeyPolicy) -> Self {
+        let name = name.to_owned();
+        self.components.push(Box::new(move |req| {
+            Ok(missing.apply(query_value(req, &name), &name)?)
+        }));
+        self
+    }
+
+    /// Add the value of a request cookie to the rate limiting key, e.g. a session cookie.
+    ///
+    /// This allows anonymous clients that share an IP address (e.g. behind a NAT) to be limited
+    /// separately. Note that clients are free to drop or change their cookies, so this should be
+    /// combined with another (e.g. IP based) limiter.
+    pub fn cookie_key(mut self, name: &str, missing: MissingKeyPolicy) -> Self {
+        let name = name.to_owned();
+        self.components.push(Box::new(move |req| {
+            Ok(missing.apply(cookie_value(req, &name), &name)?)
+        }));
+        self
+    }
+
+    /// Add a component derived from a value in the request extensions to the rate limiting key.
+    ///
+    /// This is intended for use with authentication middleware (e.g. actix-identity or
+    /// actix-web-httpauth) that inserts the authenticated user into the request extensions. That
+    /// middleware must run before the rate limiter, i.e. it must be registered with `.wrap()`
+    /// after the rate limiter.
+    ///
+    /// # Example
+    /// ```
+    /// # use std::time::Duration;
+    /// # use actix_extensible_rate_limit::backend::{MissingKeyPolicy, SimpleInputFunctionBuilder};
+    /// struct User {
+    ///     id: u64,
+    /// }
+    ///
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 100)
+    ///     .extension_key(|user: &User| user.id.to_string(), MissingKeyPolicy::Error)
+    ///     .build();
+    /// ```
+    pub fn extension_key<T, F>(mut self, f: F, missing: MissingKeyPolicy) -> Self
+    where
+        T: 'static,
+        F: Fn(&T) -> String + 'static,
+    {
+        self.components.push(Box::new(move |req| {
+            let value = req.extensions().get::<T>().map(&f);
+            Ok(missing.apply(value, std::any::type_name::<T>())?)
+        }));
+        self
+    }
+
+    /// Add a value from the [actix-session](actix_session) session state to the rate limiting key,
+    /// e.g. a user ID that was stored when the user logged in.
+    ///
+    /// The `SessionMiddleware` must run before the rate limiter, i.e. it must be registered with
+    /// `.wrap()` after the rate limiter. String and numeric values are supported, any other value
+    /// type is treated as missing.
+    #[cfg(feature = "session")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "session")))]
+    pub fn session_key(mut self, name: &str, missing: MissingKeyPolicy) -> Self {
+        use actix_session::SessionExt;
+        let name = name.to_owned();
+        self.components.push(Box::new(move |req| {
+            let value = match req.get_session().get::<serde_json::Value>(&name) {
+                Ok(Some(serde_json::Value::String(s))) => Some(s),
+                Ok(Some(serde_json::Value::Number(n))) => Some(n.to_string()),
+                _ => None,
+            };
+            Ok(missing.apply(value, &name)?)
+        }));
+        self
+    }
+
+    /// Add a claim from the `Authorization: Bearer` JWT to the rate limiting key, e.g. `sub`.
+    ///
+    /// # Security
+    ///
+    /// The token is decoded **without** verifying its signature, so a client can choose any value
+    /// for the claim. This is intended for use behind a gateway that has already verified the
+    /// token, otherwise use [SimpleInputFunctionBuilder::jwt_verified_claim_key] instead.
+    ///
+    /// String and numeric claims are supported, any other claim type is treated as missing, as
+    /// are requests without a well formed bearer token.
+    #[cfg(feature = "jwt")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "jwt")))]
+    pub fn jwt_claim_key(mut self, claim: &str, missing: MissingKeyPolicy) -> Self {
+        let claim = claim.to_owned();
+        self.components.push(Box::new(move |req| {
+            Ok(missing.apply(jwt::unverified_claim(req, &claim), &claim)?)
+        }));
+        self
+    }
+
+    /// Add a component derived from verified JWT claims to the rate limiting key.
+    ///
+    /// The claims type `T` must have been inserted into the request extensions by an
+    /// authentication middleware that runs before the rate limiter.
+    #[cfg(feature = "jwt")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "jwt")))]
+    pub fn jwt_verified_claim_key<T, F>(mut self, f: F, missing: MissingKeyPolicy) -> Self
+    where
+        T: 'static,
+        F: Fn(&T) -> Option<String> + 'static,
+    {
+        self.components.push(Box::new(move |req| {
+            let value = req.extensions().get::<T>().and_then(&f);
+            Ok(missing.apply(value, "verified claims")?)
+        }));
+        self
+    }
+
+    /// Add the name of the GraphQL operation to the rate limiting key, and charge its estimated
+    /// complexity as the cost of the request, see
+    /// [GraphQlOperation](crate::graphql::GraphQlOperation).
+    ///
+    /// The operation must have been inserted into the request extensions by the
+    /// [GraphQlOperations](crate::graphql::GraphQlOperations) middleware. Anonymous operations
+    /// are treated as missing, and requests without an operation cost 1. A cost set by
+    /// [SimpleInputFunctionBuilder::policy_fn] takes precedence.
+    #[cfg(feature = "graphql")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "graphql")))]
+    pub fn graphql_operation_key(mut self, missing: MissingKeyPolicy) -> Self {
+        self.graphql_operation = Some(missing);
+        self
+    }
+
     /// Add a custom component to the rate limiting key
     pub fn custom_key(mut self, key: &str) -> Self {
         self.custom_key = Some(key.to_owned());
         self
     }
 
+    /// Deliberately share a single limit between all clients, when no other key components are
+    /// configured.
+    ///
+    /// Without this, building a function with no key components is an error, as it is more
+    /// likely that one was forgotten; see [SimpleInputFunctionBuilder::try_build].
+    pub fn global_key(mut self) -> Self {
+        self.global_key = true;
+        self
+    }
+
     /// Dynamically add a custom component to the rate limiting key
     pub fn custom_fn<F>(mut self, f: F) -> Self
     where
@@ -95,6 +739,8 @@ impl SimpleInputFunctionBuilder {
     /// Similar to `custom_fn`, but providing the option to return alternative `interval`
     /// and `max_requests` for a particular key.
     ///
+    /// See [SimpleInputFunctionBuilder::policy_fn], which this is a shorthand for.
+    ///
     /// This method can be used to implement dynamic rate limits for different endpoints
     /// or groups of endpoints, but care must be taken to ensure separate keys are used for
     /// each combination of limits/backend, otherwise the results will likely not match
@@ -121,102 +767,1620 @@ impl SimpleInputFunctionBuilder {
     ///      Ok((key.to_owned(), interval, max_requests))
     /// });
     /// ```
-    pub fn ext_custom_fn<F>(mut self, f: F) -> Self
+    pub fn ext_custom_fn<F>(self, f: F) -> Self
     where
-        F: Fn(&ServiceRequest) -> Result<(String, Option<Duration>, Option<u64>), actix_web::Error> + 'static,
+        F: Fn(&ServiceRequest) -> Result<(String, Option<Duration>, Option<u64>), actix_web::Error>
+            + 'static,
     {
-        self.ext_custom_fn = Some(Box::new(f));
-        self
+        self.policy_fn(move |req| {
+            let (key, interval, max_requests) = f(req)?;
+            Ok(PolicyDecision {
+                key,
+                interval,
+                max_requests,
+                ..Default::default()
+            })
+        })
     }
 
-    pub fn build(self) -> impl Fn(&ServiceRequest) -> SimpleInputFuture + 'static {
-        move |req| {
-            ready((|| {
-                let mut interval = self.interval;
-                let mut max_requests = self.max_requests;
-                let mut components = Vec::new();
-                let info = req.connection_info();
-                if let Some(custom) = &self.custom_key {
-                    components.push(custom.clone());
-                }
-                if self.real_ip_key {
-                    components.push(ip_key(info.realip_remote_addr().unwrap())?)
-                }
-                if self.peer_ip_key {
-                    components.push(ip_key(info.peer_addr().unwrap())?)
-                }
-                if self.path_key {
-                    components.push(req.path().to_owned());
-                }
-                if let Some(f) = &self.custom_fn {
-                    components.push(f(req)?)
-                }
-                if let Some(f) = &self.ext_custom_fn {
-                    let (component, ext_interval, ext_max_requests) = f(req)?;
+    /// Dynamically decide the policy for a request: a custom key component, and optionally
+    /// an alternative `interval`, `max_requests`, or `cost`, or to exempt the request entirely.
+    ///
+    /// As with [SimpleInputFunctionBuilder::ext_custom_fn] care must be taken to ensure separate
+    /// keys are used for each combination of limits/backend.
+    ///
+    /// # Example
+    /// ```
+    /// # use std::time::Duration;
+    /// # use actix_extensible_rate_limit::backend::{PolicyDecision, SimpleInputFunctionBuilder};
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 100)
+    ///     .peer_ip_key()
+    ///     .policy_fn(|req| {
+    ///         Ok(match req.path() {
+    ///             "/healthz" => PolicyDecision::new("health").exempt(),
+    ///             "/search" => PolicyDecision::new("search").cost(5),
+    ///             "/login" => PolicyDecision::new("login").max_requests(5),
+    ///             _ => PolicyDecision::new("default"),
+    ///         })
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn policy_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Result<PolicyDecision, actix_web::Error> + 'static,
+    {
+        self.policy_fn = Some(Box::new(f));
+        self
+    }
 
-                    interval = ext_interval.unwrap_or(interval);
-                    max_requests = ext_max_requests.unwrap_or(max_requests);
+    /// Use a declarative [PolicyMap] to choose the limits and key for each request.
+    ///
+    /// This is implemented using [SimpleInputFunctionBuilder::policy_fn], so replaces any
+    /// function previously set there.
+    pub fn policy_map(self, policies: PolicyMap) -> Self {
+        self.policy_fn(move |req| policies.decide(req))
+    }
 
-                    components.push(component)
-                }
-                let key = components.join("-");
+    /// Use a [PolicyHandle] to choose the limits and key for each request, allowing the policies
+    /// to be replaced at runtime.
+    ///
+    /// This is implemented using [SimpleInputFunctionBuilder::policy_fn], so replaces any
+    /// function previously set there.
+    pub fn policy_handle(self, handle: PolicyHandle) -> Self {
+        self.policy_fn(move |req| handle.decide(req))
+    }
 
-                Ok(SimpleInput {
-                    interval,
-                    max_requests,
-                    key,
-                })
-            })())
-        }
+    /// Dynamically add a custom component to the rate limiting key, using an asynchronous
+    /// function, e.g. to look up the caller's account in a database.
+    ///
+    /// The returned future must be `'static`, so anything needed from the request should be
+    /// extracted before the `async` block. Asynchronous components are added to the key after all
+    /// the synchronous components, in the order they were registered.
+    ///
+    /// # Example
+    /// ```
+    /// # use std::time::Duration;
+    /// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+    /// # async fn lookup_account(api_key: Option<String>) -> Result<String, actix_web::Error> {
+    /// #     Ok(String::new())
+    /// # }
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 100)
+    ///     .custom_async_fn(|req| {
+    ///         let api_key = req
+    ///             .headers()
+    ///             .get("x-api-key")
+    ///             .and_then(|v| v.to_str().ok())
+    ///             .map(ToOwned::to_owned);
+    ///         async move { lookup_account(api_key).await }
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn custom_async_fn<F, O>(mut self, f: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> O + 'static,
+        O: Future<Output = Result<String, actix_web::Error>> + 'static,
+    {
+        self.async_fns.push(Box::new(move |req| Box::pin(f(req))));
+        self
     }
-}
 
-#[derive(Debug, Error)]
-enum Error {
-    #[error("Unable to parse remote IP address: {0}")]
-    InvalidIpError(
-        #[source]
-        #[from]
-        AddrParseError,
-    ),
-}
+    /// Look up the interval and max requests for each key using a [TierResolver], e.g. to give
+    /// each customer the limits of their plan.
+    ///
+    /// The resolver is given the rate limiting key after all other components have been added,
+    /// but before [SimpleInputFunctionBuilder::hash_key] or
+    /// [SimpleInputFunctionBuilder::key_prefix] are applied. The tier's limits take precedence
+    /// over any set by [SimpleInputFunctionBuilder::policy_fn].
+    ///
+    /// # Example
+    /// ```
+    /// # use std::time::Duration;
+    /// # use actix_extensible_rate_limit::backend::{MissingKeyPolicy, SimpleInputFunctionBuilder};
+    /// # use actix_extensible_rate_limit::backend::tier::{CachedTierResolver, TierResolver};
+    /// # fn example(accounts: impl TierResolver + 'static) {
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 100)
+    ///     .header_key("x-api-key", MissingKeyPolicy::Error)
+    ///     .tier_resolver(CachedTierResolver::new(accounts, Duration::from_secs(300)))
+    ///     .build();
+    /// # }
+    /// ```
+    pub fn tier_resolver<R: TierResolver + 'static>(mut self, resolver: R) -> Self {
+        self.tier_resolver = Some(Rc::new(resolver));
+        self
+    }
 
-impl ResponseError for Error {}
+    /// Look up ad-hoc [LimitOverride](crate::backend::overrides::LimitOverride)s for the
+    /// rate limiting key in an [OverrideStore], which replace the interval and max requests given
+    /// by any other means until they expire; e.g. for support to temporarily raise a customer's
+    /// limit.
+    ///
+    /// Overrides are looked up by the final key, after any prefix or hashing, as listed by the
+    /// [admin](crate::admin) endpoints. The store is consulted for every request.
+    pub fn override_store<S: OverrideStore + 'static>(mut self, store: S) -> Self {
+        self.override_store = Some(Rc::new(store));
+        self
+    }
 
-// Groups IPv6 addresses together, see:
-// https://adam-p.ca/blog/2022/02/ipv6-rate-limiting/
-// https://support.cloudflare.com/hc/en-us/articles/115001635128-Configuring-Cloudflare-Rate-Limiting
-fn ip_key(ip_str: &str) -> Result<String, Error> {
-    let ip = ip_str.parse::<IpAddr>()?;
-    Ok(match ip {
-        IpAddr::V4(v4) => v4.to_string(),
-        IpAddr::V6(v6) => {
+    /// Shed low priority traffic first, reserving headroom for high priority requests: once the
+    /// usage of a key crosses `threshold` (a fraction of the limit, e.g. `0.8`), requests that
+    /// `f` gives [Priority::Low] are denied, while [Priority::High] requests may use the rest of
+    /// the limit.
+    ///
+    /// Both priorities are counted against the same key. The limit of low priority requests is
+    /// reduced to the threshold, so that is the limit their rate limit headers report.
+    ///
+    /// # Example
+    /// ```
+    /// # use std::time::Duration;
+    /// # use actix_extensible_rate_limit::backend::{Priority, SimpleInputFunctionBuilder};
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(1), 1000)
+    ///     .custom_key("shop")
+    ///     .priority_fn(0.8, |req| {
+    ///         if req.path().starts_with("/checkout") {
+    ///             Priority::High
+    ///         } else {
+    ///             Priority::Low
+    ///         }
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn priority_fn<F>(mut self, threshold: f64, f: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Priority + 'static,
+    {
+        self.priority = Some((Box::new(f), threshold.clamp(0.0, 1.0)));
+        self
+    }
+
+    /// Exempt requests carrying a valid [BypassTokens] token for their rate limiting key, or
+    /// relax their limit if [BypassTokens::max_requests] is set; e.g. to give a batch job
+    /// elevated quota for a day, without changing any configuration.
+    ///
+    /// Tokens are checked against the final key, after any prefix or hashing.
+    #[cfg(feature = "bypass")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bypass")))]
+    pub fn bypass_tokens(mut self, tokens: BypassTokens) -> Self {
+        self.bypass_tokens = Some(tokens);
+        self
+    }
+
+    /// Prepend a namespace to the rate limiting key, e.g. `"api:v2:"`.
+    ///
+    /// The prefix is always placed first, exactly as given (no separator is added), and is applied
+    /// after [SimpleInputFunctionBuilder::hash_key], so that multiple services or limiters can
+    /// share a single backend without their keys colliding.
+    pub fn key_prefix(mut self, prefix: &str) -> Self {
+        self.key_prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Override the separator placed between key components, the default is `-`.
+    ///
+    /// Any occurrences of the separator (or of the `\` escape character) within a component are
+    /// escaped with a `\`, so that different combinations of components can never produce the
+    /// same key.
+    pub fn key_separator(mut self, separator: char) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Hash the final rate limiting key using SHA-256, encoded as lowercase hex.
+    ///
+    /// This ensures that personal data such as IP addresses or emails is never sent to the
+    /// backend store, and that all keys have the same bounded length.
+    pub fn hash_key(self) -> Self {
+        self.hash_key_with(|key| sha256_hex(key.as_bytes()))
+    }
+
+    /// Hash the final rate limiting key using a custom function.
+    ///
+    /// See [SimpleInputFunctionBuilder::hash_key].
+    pub fn hash_key_with<H>(mut self, hasher: H) -> Self
+    where
+        H: Fn(&str) -> String + 'static,
+    {
+        self.key_hash_fn = Some(Box::new(hasher));
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If the configuration is invalid, see [SimpleInputFunctionBuilder::try_build].
+    pub fn build(self) -> impl Fn(&ServiceRequest) -> SimpleInputFuture + 'static {
+        match self.try_build() {
+            Ok(input_fn) => input_fn,
+            Err(e) => panic!("Invalid rate limit input configuration: {e}"),
+        }
+    }
+
+    /// Build the input function, returning an error if no key components were configured, as
+    /// every client would then share a single limit; use [SimpleInputFunctionBuilder::global_key]
+    /// if that is intended. Intervals too large for a backend to represent are also rejected.
+    pub fn try_build(
+        self,
+    ) -> Result<impl Fn(&ServiceRequest) -> SimpleInputFuture + 'static, InputBuildError> {
+        if !self.global_key && !self.has_key_components() {
+            return Err(InputBuildError::NoKeyComponents);
+        }
+        if !interval_fits(self.interval) {
+            return Err(InputBuildError::IntervalTooLarge(self.interval));
+        }
+        if let Some((Err(proxy), _)) = &self.trusted_proxy_ip_key {
+            return Err(InputBuildError::InvalidTrustedProxy(proxy.clone()));
+        }
+        let builder = Rc::new(self);
+        Ok(move |req: &ServiceRequest| -> SimpleInputFuture {
+            let mut partial = match builder.sync_components(req) {
+                Ok(partial) => partial,
+                Err(e) => return Either::Left(ready(Err(e))),
+            };
+            #[cfg(feature = "bypass")]
+            {
+                partial.bypass_token = builder
+                    .bypass_tokens
+                    .as_ref()
+                    .and_then(|tokens| tokens.token(req));
+            }
+            if builder.async_fns.is_empty()
+                && builder.tier_resolver.is_none()
+                && builder.override_store.is_none()
+            {
+                return Either::Left(ready(builder.input(partial)));
+            }
+            let pending = builder.async_fns.iter().map(|f| f(req)).collect::<Vec<_>>();
+            let builder = builder.clone();
+            Either::Right(Box::pin(async move {
+                for component in futures::future::try_join_all(pending).await? {
+                    partial.key.push(&component);
+                }
+                if let Some(resolver) = &builder.tier_resolver {
+                    let tier = resolver.resolve(partial.key.components()).await?;
+                    partial.interval = tier.interval;
+                    partial.max_requests = tier.max_requests;
+                }
+                let mut input = builder.input(partial)?;
+                if let Some(store) = &builder.override_store {
+                    if let Some(limit) = store.get(&input.key).await? {
+                        input.interval = limit.interval;
+                        input.max_requests = limit.max_requests;
+                    }
+                }
+                Ok(input)
+            }))
+        })
+    }
+
+    fn has_key_components(&self) -> bool {
+        #[cfg(feature = "graphql")]
+        if self.graphql_operation.is_some() {
+            return true;
+        }
+        self.custom_key.is_some()
+            || self.real_ip_key.is_some()
+            || self.peer_ip_key.is_some()
+            || self.trusted_proxy_ip_key.is_some()
+            || self.path_key
+            || self.method_key
+            || !self.components.is_empty()
+            || self.custom_fn.is_some()
+            || self.policy_fn.is_some()
+            || !self.async_fns.is_empty()
+    }
+
+    // Evaluates all the synchronous key components.
+    fn sync_components(&self, req: &ServiceRequest) -> Result<PartialInput, actix_web::Error> {
+        if self.is_excluded(req) {
+            return Err(Exempt.into());
+        }
+        let (interval, max_requests) = self
+            .schedule
+            .as_ref()
+            .and_then(Schedule::current)
+            .unwrap_or((self.interval, self.max_requests));
+        let mut partial = PartialInput {
+            interval,
+            max_requests,
+            cost: 1,
+            low_priority: self
+                .priority
+                .as_ref()
+                .is_some_and(|(f, _)| f(req) == Priority::Low),
+            #[cfg(feature = "bypass")]
+            bypass_token: None,
+            key: KeyBuffer::new(
+                // A hashed key is prefixed afterwards
+                self.key_prefix
+                    .as_deref()
+                    .filter(|_| self.key_hash_fn.is_none()),
+                self.separator,
+                self.key_capacity.get(),
+            ),
+        };
+        let key = &mut partial.key;
+        if let Some(custom) = &self.custom_key {
+            key.push(custom);
+        }
+        {
+            // The connection info borrows the request extensions, so must be released
+            // before calling any component functions.
+            let info = req.connection_info();
+            if let Some(prefix) = &self.real_ip_key {
+                self.push_ip(key, info.realip_remote_addr(), prefix, "client address")?;
+            }
+            if let Some(prefix) = &self.peer_ip_key {
+                self.push_ip(key, info.peer_addr(), prefix, "peer address")?;
+            }
+        }
+        if let Some((Ok(trusted), prefix)) = &self.trusted_proxy_ip_key {
+            match req.peer_addr() {
+                Some(peer) => {
+                    let client = forwarded_client_ip(req, peer.ip(), trusted)?;
+                    key.start_component();
+                    let _ = write!(key, "{}", ip_addr_key(client, prefix));
+                }
+                None => {
+                    if let Some(fallback) = self.missing_ip.fallback("peer address")? {
+                        key.push(fallback);
+                    }
+                }
+            }
+        }
+        if self.path_key {
+            key.push(req.path());
+        }
+        if self.method_key {
+            key.push(method_str(req, self.merge_head_into_get));
+        }
+        for f in &self.components {
+            if let Some(component) = f(req)? {
+                key.push(&component);
+            }
+        }
+        #[cfg(feature = "graphql")]
+        if let Some(missing) = &self.graphql_operation {
+            let extensions = req.extensions();
+            let operation = extensions.get::<crate::graphql::GraphQlOperation>();
+            match operation.and_then(|o| o.name.as_deref()) {
+                Some(name) => partial.key.push(name),
+                None => {
+                    if let Some(name) = missing.fallback("GraphQL operation")? {
+                        partial.key.push(name);
+                    }
+                }
+            }
+            partial.cost = operation.map_or(1, |o| o.complexity);
+        }
+        if let Some(f) = &self.custom_fn {
+            partial.key.push(&f(req)?)
+        }
+        if let Some(f) = &self.policy_fn {
+            let decision = f(req)?;
+            if decision.exempt {
+                return Err(Exempt.into());
+            }
+            partial.key.push(&decision.key);
+            partial.interval = decision.interval.unwrap_or(partial.interval);
+            partial.max_requests = decision.max_requests.unwrap_or(partial.max_requests);
+            partial.cost = decision.cost.unwrap_or(partial.cost);
+        }
+        Ok(partial)
+    }
+
+    // Adds the IP key component, applying the missing IP policy if there is no address.
+    fn push_ip(
+        &self,
+        key: &mut KeyBuffer,
+        ip: Option<&str>,
+        prefix: &IpPrefix,
+        name: &str,
+    ) -> Result<(), Error> {
+        match ip {
+            Some(ip) => {
+                let ip = parse_ip_key(ip, prefix)?;
+                key.start_component();
+                let _ = write!(key, "{ip}");
+            }
+            None => {
+                if let Some(fallback) = self.missing_ip.fallback(name)? {
+                    key.push(fallback);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_excluded(&self, req: &ServiceRequest) -> bool {
+        let path = req.path();
+        self.excluded_paths
+            .iter()
+            .any(|pattern| glob_match(pattern, path))
+            || self.exclude_fn.as_ref().is_some_and(|f| f(req))
+            || !self.content_types.is_empty() && self.mismatches_content_type(req)
+            || self.ignore_preflight && is_preflight(req)
+    }
+
+    // Whether the request names media types, none of which match; requests that don't name any
+    // are counted, so that leaving out the headers doesn't avoid the limit
+    fn mismatches_content_type(&self, req: &ServiceRequest) -> bool {
+        let headers = req.headers();
+        let mut media_types = headers
+            .get_all(CONTENT_TYPE)
+            .chain(headers.get_all(ACCEPT))
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|media_type| {
+                let essence = media_type.split(';').next().unwrap_or_default();
+                essence.trim().to_ascii_lowercase()
+            })
+            .filter(|media_type| !media_type.is_empty())
+            .peekable();
+        media_types.peek().is_some()
+            && !media_types.any(|media_type| {
+                self.content_types
+                    .iter()
+                    .any(|pattern| media_range_match(pattern, &media_type))
+            })
+    }
+
+    fn input(&self, partial: PartialInput) -> Result<SimpleInput, actix_web::Error> {
+        // The interval may have been chosen per request, e.g. by a policy
+        if !interval_fits(partial.interval) {
+            return Err(Error::IntervalTooLarge(partial.interval).into());
+        }
+        // Capped, so that a single request with a huge component doesn't enlarge every later key
+        let capacity = partial.key.len().min(MAX_KEY_CAPACITY);
+        self.key_capacity.set(self.key_capacity.get().max(capacity));
+        let key = match &self.key_hash_fn {
+            Some(hasher) => {
+                let mut key = hasher(partial.key.components());
+                if let Some(prefix) = &self.key_prefix {
+                    key.insert_str(0, prefix);
+                }
+                key
+            }
+            None => partial.key.into_string(),
+        };
+        let max_requests = match &self.priority {
+            Some((_, threshold)) if partial.low_priority => {
+                (partial.max_requests as f64 * threshold).floor() as u64
+            }
+            _ => partial.max_requests,
+        };
+        let input = SimpleInput {
+            interval: partial.interval,
+            max_requests,
+            key,
+            cost: partial.cost,
+        };
+        #[cfg(feature = "bypass")]
+        let input = self.bypass(input, partial.bypass_token.as_deref())?;
+        Ok(input)
+    }
+
+    // Exempts the request, or relaxes its limit, if it carries a valid bypass token.
+    #[cfg(feature = "bypass")]
+    fn bypass(
+        &self,
+        mut input: SimpleInput,
+        token: Option<&str>,
+    ) -> Result<SimpleInput, actix_web::Error> {
+        let (Some(tokens), Some(token)) = (&self.bypass_tokens, token) else {
+            return Ok(input);
+        };
+        if tokens.verify(token, &input.key) {
+            match tokens.relaxed_max_requests() {
+                Some(max_requests) => input.max_requests = max_requests,
+                None => return Err(Exempt.into()),
+            }
+        }
+        Ok(input)
+    }
+}
+
+#[cfg(feature = "jwt")]
+mod jwt {
+    use actix_web::dev::ServiceRequest;
+    use actix_web::http::header::AUTHORIZATION;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use serde_json::Value;
+
+    // Extracts a claim from the bearer token payload, without verifying the signature.
+    pub(super) fn unverified_claim(req: &ServiceRequest, claim: &str) -> Option<String> {
+        let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, token) = header.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        let payload = token.trim().split('.').nth(1)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+        let claims: Value = serde_json::from_slice(&payload).ok()?;
+        match claims.get(claim)? {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// A [SimpleInputFunctionBuilder] configuration that would not work as intended, see
+/// [SimpleInputFunctionBuilder::try_build].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum InputBuildError {
+    #[error("no rate limit key components were configured, use global_key() to share a single limit between all clients")]
+    NoKeyComponents,
+    #[error("the interval of {0:?} is too large")]
+    IntervalTooLarge(Duration),
+    #[error("invalid trusted proxy {0:?}, expected an IP address or CIDR subnet")]
+    InvalidTrustedProxy(String),
+}
+
+// Whether a window of the interval starting now can be represented, which backends rely on, both
+// as an instant and in nanoseconds
+fn interval_fits(interval: Duration) -> bool {
+    Instant::now().checked_add(interval).is_some() && u64::try_from(interval.as_nanos()).is_ok()
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("Unable to parse remote IP address: {0}")]
+    InvalidIp(
+        #[source]
+        #[from]
+        AddrParseError,
+    ),
+    #[error("Missing rate limit key component: {0}")]
+    MissingComponent(String),
+    #[error("Invalid X-Forwarded-For header")]
+    InvalidForwardedFor,
+    #[error("Rate limit interval of {0:?} is too large")]
+    IntervalTooLarge(Duration),
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::InvalidIp(_) | Error::IntervalTooLarge(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::MissingComponent(_) | Error::InvalidForwardedFor => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+pub(super) fn header_value(req: &ServiceRequest, name: &str) -> Option<String> {
+    let value = req.headers().get(name)?.to_str().ok()?;
+    Some(value.to_owned())
+}
+
+pub(super) fn query_value(req: &ServiceRequest, name: &str) -> Option<String> {
+    form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+pub(super) fn cookie_value(req: &ServiceRequest, name: &str) -> Option<String> {
+    req.headers()
+        .get_all(COOKIE)
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v.trim_matches('"').to_owned())
+}
+
+pub(super) fn host_value(req: &ServiceRequest) -> String {
+    req.connection_info().host().to_ascii_lowercase()
+}
+
+fn is_preflight(req: &ServiceRequest) -> bool {
+    let headers = req.headers();
+    req.method() == Method::OPTIONS
+        && headers.contains_key(ORIGIN)
+        && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+pub(super) fn method_value(req: &ServiceRequest, merge_head_into_get: bool) -> String {
+    method_str(req, merge_head_into_get).to_owned()
+}
+
+fn method_str(req: &ServiceRequest, merge_head_into_get: bool) -> &str {
+    let method = req.method();
+    if merge_head_into_get && method == Method::HEAD {
+        Method::GET.as_str()
+    } else {
+        method.as_str()
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+// Matches a path against a pattern, where `*` matches any sequence of characters.
+pub(super) fn glob_match(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = path.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        // There were no wildcards
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+// Matches a configured media type pattern against a request media type, which may itself be a
+// media range such as `*/*` or `application/*` covering the pattern.
+fn media_range_match(pattern: &str, media_type: &str) -> bool {
+    match media_type.split_once('/') {
+        Some(("*", "*")) => true,
+        Some((type_, "*")) => glob_match(pattern.split('/').next().unwrap_or_default(), type_),
+        _ => glob_match(pattern, media_type),
+    }
+}
+
+// Builds the rate limiting key in a single buffer, escaping any occurrences of the separator or
+// escape character as each component is written.
+struct KeyBuffer {
+    key: String,
+    // The length of the prefix, which is written as is
+    start: usize,
+    separator: char,
+    empty: bool,
+}
+
+impl KeyBuffer {
+    fn new(prefix: Option<&str>, separator: char, capacity: usize) -> Self {
+        let prefix = prefix.unwrap_or_default();
+        let mut key = String::with_capacity(capacity.max(prefix.len() + 32));
+        key.push_str(prefix);
+        Self {
+            key,
+            start: prefix.len(),
+            separator,
+            empty: true,
+        }
+    }
+
+    // Begins a new component, which is then written with `fmt::Write`.
+    fn start_component(&mut self) {
+        if !self.empty {
+            self.key.push(self.separator);
+        }
+        self.empty = false;
+    }
+
+    fn push(&mut self, component: &str) {
+        self.start_component();
+        let _ = self.write_str(component);
+    }
+
+    // The components written so far, without the prefix.
+    fn components(&self) -> &str {
+        &self.key[self.start..]
+    }
+
+    fn len(&self) -> usize {
+        self.key.len()
+    }
+
+    fn into_string(self) -> String {
+        self.key
+    }
+}
+
+impl Write for KeyBuffer {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        if !s.contains([self.separator, '\\']) {
+            self.key.push_str(s);
+            return Ok(());
+        }
+        for c in s.chars() {
+            if c == self.separator || c == '\\' {
+                self.key.push('\\');
+            }
+            self.key.push(c);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IpPrefix {
+    v6: u8,
+    v4: u8,
+}
+
+impl IpPrefix {
+    fn new(v6: u8, v4: u8) -> Self {
+        assert!(v6 <= 128, "IPv6 prefix must be at most 128");
+        assert!(v4 <= 32, "IPv4 prefix must be at most 32");
+        Self { v6, v4 }
+    }
+}
+
+impl Default for IpPrefix {
+    fn default() -> Self {
+        Self { v6: 64, v4: 32 }
+    }
+}
+
+// Groups IPv6 addresses together, see:
+// https://adam-p.ca/blog/2022/02/ipv6-rate-limiting/
+// https://support.cloudflare.com/hc/en-us/articles/115001635128-Configuring-Cloudflare-Rate-Limiting
+pub(crate) fn ip_key(ip_str: &str, prefix: &IpPrefix) -> Result<String, Error> {
+    Ok(parse_ip_key(ip_str, prefix)?.to_string())
+}
+
+fn parse_ip_key(ip_str: &str, prefix: &IpPrefix) -> Result<IpKey, Error> {
+    Ok(ip_addr_key(ip_str.parse::<IpAddr>()?, prefix))
+}
+
+fn ip_addr_key(ip: IpAddr, prefix: &IpPrefix) -> IpKey {
+    let v4_key = |v4: Ipv4Addr| {
+        if prefix.v4 == 32 {
+            return IpKey(IpAddr::V4(v4), None);
+        }
+        let mask = u32::MAX.checked_shl(32 - prefix.v4 as u32).unwrap_or(0);
+        IpKey(Ipv4Addr::from(u32::from(v4) & mask).into(), Some(prefix.v4))
+    };
+    match ip {
+        IpAddr::V4(v4) => v4_key(v4),
+        IpAddr::V6(v6) => {
             if let Some(v4) = v6.to_ipv4() {
-                return Ok(v4.to_string());
+                return v4_key(v4);
+            }
+            if prefix.v6 == 128 {
+                return IpKey(IpAddr::V6(v6), None);
             }
-            let zeroes = [0u16; 4];
-            let concat = [&v6.segments()[0..4], &zeroes].concat();
-            let concat: [u16; 8] = concat.try_into().unwrap();
-            let subnet = Ipv6Addr::from(concat);
-            format!("{}/64", subnet)
+            let mask = u128::MAX.checked_shl(128 - prefix.v6 as u32).unwrap_or(0);
+            IpKey(
+                Ipv6Addr::from(u128::from(v6) & mask).into(),
+                Some(prefix.v6),
+            )
         }
-    })
+    }
+}
+
+// An IP address key component, the subnet of the address if grouped by a prefix.
+struct IpKey(IpAddr, Option<u8>);
+
+impl std::fmt::Display for IpKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.1 {
+            Some(prefix) => write!(f, "{}/{}", self.0, prefix),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// An IP address range in CIDR notation.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Subnet {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    pub(super) fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network = addr.trim().parse::<IpAddr>().map_err(|_| ())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().map_err(|_| ())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(());
+        }
+        Ok(Self { network, prefix })
+    }
+}
+
+// Walks the X-Forwarded-For chain from the right, returning the first untrusted address.
+fn forwarded_client_ip(
+    req: &ServiceRequest,
+    peer: IpAddr,
+    trusted: &[Subnet],
+) -> Result<IpAddr, Error> {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|subnet| subnet.contains(ip));
+    if !is_trusted(peer) {
+        return Ok(peer);
+    }
+    let hops = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .map(|header| header.to_str().map_err(|_| Error::InvalidForwardedFor))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut client = peer;
+    for hop in hops.iter().flat_map(|h| h.split(',')).rev() {
+        client = parse_hop(hop.trim())?;
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    Ok(client)
+}
+
+// An X-Forwarded-For entry may include a port number.
+fn parse_hop(hop: &str) -> Result<IpAddr, Error> {
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .map_err(|_| Error::InvalidForwardedFor)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_web::test::TestRequest;
+
+    const MINUTE: Duration = Duration::from_secs(60);
 
     #[test]
     fn test_ip_key() {
         // Check that IPv4 addresses are preserved
-        assert_eq!(ip_key("142.250.187.206").unwrap(), "142.250.187.206");
+        let prefix = IpPrefix::default();
+        assert_eq!(
+            ip_key("142.250.187.206", &prefix).unwrap(),
+            "142.250.187.206"
+        );
         // Check that IPv4 mapped addresses are preserved
-        assert_eq!(ip_key("::FFFF:142.250.187.206").unwrap(), "142.250.187.206");
+        assert_eq!(
+            ip_key("::FFFF:142.250.187.206", &prefix).unwrap(),
+            "142.250.187.206"
+        );
         // Check that IPv6 addresses are grouped into /64 subnets
         assert_eq!(
-            ip_key("2a00:1450:4009:81f::200e").unwrap(),
+            ip_key("2a00:1450:4009:81f::200e", &prefix).unwrap(),
             "2a00:1450:4009:81f::/64"
         );
     }
+
+    #[test]
+    fn test_ip_key_with_prefix() {
+        let prefix = IpPrefix::new(56, 24);
+        assert_eq!(
+            ip_key("142.250.187.206", &prefix).unwrap(),
+            "142.250.187.0/24"
+        );
+        assert_eq!(
+            ip_key("2a00:1450:4009:81f::200e", &prefix).unwrap(),
+            "2a00:1450:4009:800::/56"
+        );
+        let prefix = IpPrefix::new(128, 0);
+        assert_eq!(ip_key("142.250.187.206", &prefix).unwrap(), "0.0.0.0/0");
+        assert_eq!(
+            ip_key("2a00:1450:4009:81f::200e", &prefix).unwrap(),
+            "2a00:1450:4009:81f::200e"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_method_key() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .path_key()
+            .method_key()
+            .build();
+        let req = TestRequest::post().uri("/path").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/path-POST");
+        let req = TestRequest::default()
+            .method(Method::HEAD)
+            .uri("/path")
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/path-HEAD");
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .method_key()
+            .merge_head_into_get()
+            .build();
+        assert_eq!(input_fn(&req).await.unwrap().key, "GET");
+    }
+
+    #[actix_web::test]
+    async fn test_host_key() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .host_key()
+            .path_key()
+            .build();
+        let req = TestRequest::with_uri("/path")
+            .insert_header(("Host", "Tenant.Example.com"))
+            .to_srv_request();
+        assert_eq!(
+            input_fn(&req).await.unwrap().key,
+            "/path-tenant.example.com"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_client_cert_key() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .client_cert_key(MissingKeyPolicy::Error)
+            .build();
+        let req = TestRequest::default().to_srv_request();
+        assert!(input_fn(&req).await.is_err());
+        req.extensions_mut()
+            .insert(PeerCertificate(b"abc".to_vec()));
+        assert_eq!(
+            input_fn(&req).await.unwrap().key,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_header_key() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .header_key("x-api-key", MissingKeyPolicy::Error)
+            .build();
+        let req = TestRequest::default()
+            .insert_header(("x-api-key", "abc"))
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "abc");
+        let req = TestRequest::default().to_srv_request();
+        let err = input_fn(&req).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .custom_key("api")
+            .header_key("x-api-key", MissingKeyPolicy::Skip)
+            .build();
+        assert_eq!(input_fn(&req).await.unwrap().key, "api");
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .header_key("x-api-key", MissingKeyPolicy::Fallback("anon".to_owned()))
+            .build();
+        assert_eq!(input_fn(&req).await.unwrap().key, "anon");
+    }
+
+    #[actix_web::test]
+    async fn test_missing_ip() {
+        let req = TestRequest::default().to_srv_request();
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .peer_ip_key()
+            .build();
+        let err = input_fn(&req).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .real_ip_key()
+            .missing_ip(MissingKeyPolicy::Fallback("unknown".to_owned()))
+            .build();
+        assert_eq!(input_fn(&req).await.unwrap().key, "unknown");
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .peer_ip_key()
+            .path_key()
+            .missing_ip(MissingKeyPolicy::Skip)
+            .build();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/");
+    }
+
+    #[cfg(feature = "jwt")]
+    #[actix_web::test]
+    async fn test_jwt_claim_key() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .jwt_claim_key("sub", MissingKeyPolicy::Fallback("anon".to_owned()))
+            .build();
+        // {"alg":"HS256","typ":"JWT"}.{"sub":"1234567890","name":"John Doe","iat":1516239022}
+        let token = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.\
+            eyJzdWIiOiIxMjM0NTY3ODkwIiwibmFtZSI6IkpvaG4gRG9lIiwiaWF0IjoxNTE2MjM5MDIyfQ.\
+            SflKxwRJSMeKKF2QT4fwpMeJf36POk6yJV_adQssw5c";
+        let req = TestRequest::default()
+            .insert_header(("Authorization", format!("Bearer {token}")))
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "1234567890");
+        let req = TestRequest::default()
+            .insert_header(("Authorization", "Bearer not-a-token"))
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "anon");
+
+        struct Claims {
+            sub: String,
+        }
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .jwt_verified_claim_key(|c: &Claims| Some(c.sub.clone()), MissingKeyPolicy::Error)
+            .build();
+        let req = TestRequest::default().to_srv_request();
+        assert!(input_fn(&req).await.is_err());
+        req.extensions_mut().insert(Claims {
+            sub: "user".to_owned(),
+        });
+        assert_eq!(input_fn(&req).await.unwrap().key, "user");
+    }
+
+    #[actix_web::test]
+    async fn test_query_key() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .query_key("api_key", MissingKeyPolicy::Fallback("none".to_owned()))
+            .build();
+        let req = TestRequest::with_uri("/path?page=2&api_key=a%20b%2Bc").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "a b+c");
+        let req = TestRequest::with_uri("/path?page=2").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "none");
+    }
+
+    #[actix_web::test]
+    async fn test_cookie_key() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .cookie_key("session_id", MissingKeyPolicy::Error)
+            .build();
+        let req = TestRequest::default()
+            .insert_header(("Cookie", "theme=dark; session_id=abc123"))
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "abc123");
+        let req = TestRequest::default()
+            .insert_header(("Cookie", "theme=dark"))
+            .to_srv_request();
+        assert!(input_fn(&req).await.is_err());
+    }
+
+    #[cfg(feature = "session")]
+    #[actix_web::test]
+    async fn test_session_key() {
+        use actix_session::SessionExt;
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .session_key("user_id", MissingKeyPolicy::Fallback("anon".to_owned()))
+            .build();
+        let req = TestRequest::default().to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "anon");
+        req.get_session().insert("user_id", 42).unwrap();
+        assert_eq!(input_fn(&req).await.unwrap().key, "42");
+    }
+
+    #[actix_web::test]
+    async fn test_trusted_proxy_ip_key() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .trusted_proxy_ip_key(["10.0.0.0/8", "192.168.1.1"])
+            .build();
+        let request = |peer: &str, xff: Option<&str>| {
+            let mut req = TestRequest::default().peer_addr(peer.parse().unwrap());
+            if let Some(xff) = xff {
+                req = req.insert_header(("X-Forwarded-For", xff));
+            }
+            req.to_srv_request()
+        };
+        // Untrusted peers can't set their own IP
+        let req = request("1.1.1.1:80", Some("2.2.2.2"));
+        assert_eq!(input_fn(&req).await.unwrap().key, "1.1.1.1");
+        // Spoofed entries to the left of the real client are ignored
+        let req = request("10.0.0.1:80", Some("2.2.2.2, 3.3.3.3, 192.168.1.1"));
+        assert_eq!(input_fn(&req).await.unwrap().key, "3.3.3.3");
+        // If every hop is trusted, the left-most is used
+        let req = request("10.0.0.1:80", Some("10.1.1.1:1234, 192.168.1.1"));
+        assert_eq!(input_fn(&req).await.unwrap().key, "10.1.1.1");
+        let req = request("10.0.0.1:80", None);
+        assert_eq!(input_fn(&req).await.unwrap().key, "10.0.0.1");
+        let req = request("10.0.0.1:80", Some("garbage"));
+        assert!(input_fn(&req).await.is_err());
+        // Without a peer address, the missing IP policy applies
+        let req = TestRequest::default().to_srv_request();
+        let err = input_fn(&req).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .trusted_proxy_ip_key(["10.0.0.0/8"])
+            .missing_ip(MissingKeyPolicy::Fallback("unknown".to_owned()))
+            .build();
+        assert_eq!(input_fn(&req).await.unwrap().key, "unknown");
+
+        let result = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .trusted_proxy_ip_key(["10.0.0.0/8", "10.0.0.0/33"])
+            .try_build();
+        assert_eq!(
+            result.err(),
+            Some(InputBuildError::InvalidTrustedProxy(
+                "10.0.0.0/33".to_owned()
+            ))
+        );
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .trusted_proxy_ip_key_with_prefix(["10.0.0.0/8"], 56, 24)
+            .build();
+        let req = request("10.0.0.1:80", Some("2a00:1450:4009:81f::200e"));
+        assert_eq!(input_fn(&req).await.unwrap().key, "2a00:1450:4009:800::/56");
+        let req = request("10.0.0.1:80", Some("142.250.187.206"));
+        assert_eq!(input_fn(&req).await.unwrap().key, "142.250.187.0/24");
+    }
+
+    #[actix_web::test]
+    async fn test_hash_key() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .custom_key("abc")
+            .hash_key()
+            .build();
+        let req = TestRequest::default().to_srv_request();
+        assert_eq!(
+            input_fn(&req).await.unwrap().key,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_key_prefix() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .key_prefix("api:v2:")
+            .path_key()
+            .custom_key("abc")
+            .build();
+        let req = TestRequest::with_uri("/path").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "api:v2:abc-/path");
+    }
+
+    #[actix_web::test]
+    async fn test_key_separator() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .custom_key("a-b")
+            .path_key()
+            .build();
+        let req = TestRequest::with_uri("/c").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "a\\-b-/c");
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .custom_key("a")
+            .path_key()
+            .build();
+        let req = TestRequest::with_uri("/b-/c").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "a-/b\\-/c");
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .key_separator(':')
+            .custom_key("a-b")
+            .path_key()
+            .build();
+        let req = TestRequest::with_uri("/c:d").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "a-b:/c\\:d");
+
+        // Components written directly into the key are escaped too, but not the prefix
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .key_separator(':')
+            .key_prefix("api:")
+            .peer_ip_key()
+            .method_key()
+            .build();
+        let req = TestRequest::default()
+            .peer_addr("[2a00:1450:4009:81f::200e]:8080".parse().unwrap())
+            .to_srv_request();
+        assert_eq!(
+            input_fn(&req).await.unwrap().key,
+            "api:2a00\\:1450\\:4009\\:81f\\:\\:/64:GET"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_key_capacity() {
+        let builder = SimpleInputFunctionBuilder::new(MINUTE, 5).path_key();
+        let long = format!("/{}", "a".repeat(4096));
+        for path in ["/short", &long, "/short"] {
+            let req = TestRequest::with_uri(path).to_srv_request();
+            let partial = builder.sync_components(&req).unwrap();
+            assert_eq!(builder.input(partial).unwrap().key, path);
+        }
+        assert_eq!(builder.key_capacity.get(), MAX_KEY_CAPACITY);
+    }
+
+    #[actix_web::test]
+    async fn test_custom_async_fn() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .custom_async_fn(|req| {
+                let path = req.path().to_owned();
+                async move {
+                    actix_web::rt::task::yield_now().await;
+                    Ok(format!("async{path}"))
+                }
+            })
+            .custom_key("sync")
+            .build();
+        let req = TestRequest::with_uri("/path").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "sync-async/path");
+    }
+
+    #[actix_web::test]
+    async fn test_schedule() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .schedule(Schedule::new().window("*", MINUTE * 2, 50))
+            .global_key()
+            .build();
+        let req = TestRequest::default().to_srv_request();
+        let input = input_fn(&req).await.unwrap();
+        assert_eq!(input.max_requests, 50);
+        assert_eq!(input.interval, MINUTE * 2);
+    }
+
+    #[actix_web::test]
+    async fn test_global_key() {
+        assert_eq!(
+            SimpleInputFunctionBuilder::new(MINUTE, 5)
+                .key_prefix("api:")
+                .try_build()
+                .err(),
+            Some(InputBuildError::NoKeyComponents)
+        );
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .key_prefix("api:")
+            .global_key()
+            .try_build()
+            .unwrap();
+        let req = TestRequest::default().to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "api:");
+    }
+
+    #[actix_web::test]
+    async fn test_interval_too_large() {
+        assert_eq!(
+            SimpleInputFunctionBuilder::new(Duration::MAX, 5)
+                .peer_ip_key()
+                .try_build()
+                .err(),
+            Some(InputBuildError::IntervalTooLarge(Duration::MAX))
+        );
+        // Beyond the ~584 years that fit in u64 nanoseconds
+        let millennium = Duration::from_secs(1000 * 365 * 24 * 60 * 60);
+        assert_eq!(
+            SimpleInputFunctionBuilder::new(millennium, 5)
+                .peer_ip_key()
+                .try_build()
+                .err(),
+            Some(InputBuildError::IntervalTooLarge(millennium))
+        );
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .policy_fn(|_| Ok(PolicyDecision::new("huge").interval(Duration::MAX)))
+            .build();
+        let req = TestRequest::default().to_srv_request();
+        let err = input_fn(&req).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_tier_resolver() {
+        use crate::backend::tier::Tier;
+
+        struct Plans;
+
+        #[async_trait::async_trait(?Send)]
+        impl TierResolver for Plans {
+            async fn resolve(&self, key: &str) -> Result<Tier, actix_web::Error> {
+                Ok(match key {
+                    "/pro" => Tier::new("pro", MINUTE * 2, 1000),
+                    _ => Tier::new("free", MINUTE, 10),
+                })
+            }
+        }
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .path_key()
+            .tier_resolver(Plans)
+            .hash_key()
+            .build();
+        let req = TestRequest::with_uri("/pro").to_srv_request();
+        let input = input_fn(&req).await.unwrap();
+        assert_eq!(input.max_requests, 1000);
+        assert_eq!(input.interval, MINUTE * 2);
+        let req = TestRequest::with_uri("/free").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().max_requests, 10);
+    }
+
+    #[actix_web::test]
+    async fn test_priority_fn() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 10)
+            .custom_key("shop")
+            .priority_fn(0.8, |req| {
+                if req.path() == "/checkout" {
+                    Priority::High
+                } else {
+                    Priority::Low
+                }
+            })
+            .build();
+        let req = TestRequest::with_uri("/browse").to_srv_request();
+        let low = input_fn(&req).await.unwrap();
+        let req = TestRequest::with_uri("/checkout").to_srv_request();
+        let high = input_fn(&req).await.unwrap();
+        assert_eq!(low.max_requests, 8);
+        assert_eq!(high.max_requests, 10);
+        assert_eq!(low.key, high.key);
+    }
+
+    #[cfg(feature = "bypass")]
+    #[actix_web::test]
+    async fn test_bypass_tokens() {
+        use crate::backend::bypass::{BypassTokens, BYPASS_HEADER};
+
+        let tokens = BypassTokens::new(b"secret");
+        let token = tokens.issue("batch", MINUTE);
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .header_key("x-client", MissingKeyPolicy::Error)
+            .bypass_tokens(tokens.clone())
+            .build();
+        let req = |client: &str| {
+            TestRequest::default()
+                .insert_header(("x-client", client))
+                .insert_header((BYPASS_HEADER, token.as_str()))
+                .to_srv_request()
+        };
+        let err = input_fn(&req("batch")).await.unwrap_err();
+        assert!(err.as_error::<Exempt>().is_some());
+        assert_eq!(input_fn(&req("other")).await.unwrap().max_requests, 5);
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .header_key("x-client", MissingKeyPolicy::Error)
+            .bypass_tokens(tokens.max_requests(1000))
+            .build();
+        assert_eq!(input_fn(&req("batch")).await.unwrap().max_requests, 1000);
+    }
+
+    #[cfg(feature = "graphql")]
+    #[actix_web::test]
+    async fn test_graphql_operation_key() {
+        use crate::graphql::GraphQlOperation;
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 100)
+            .custom_key("api")
+            .graphql_operation_key(MissingKeyPolicy::Fallback("anonymous".to_owned()))
+            .build();
+        let req = TestRequest::default().to_srv_request();
+        req.extensions_mut().insert(GraphQlOperation::parse(
+            "query Feed { posts { id title } }",
+            None,
+        ));
+        let input = input_fn(&req).await.unwrap();
+        assert_eq!(input.key, "api-Feed");
+        assert_eq!(input.cost, 3);
+        let req = TestRequest::default().to_srv_request();
+        let input = input_fn(&req).await.unwrap();
+        assert_eq!(input.key, "api-anonymous");
+        assert_eq!(input.cost, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_override_store() {
+        use crate::backend::overrides::{LimitOverride, MemoryOverrideStore};
+        use std::time::SystemTime;
+
+        let store = MemoryOverrideStore::new();
+        let expires = SystemTime::now() + MINUTE;
+        let limit = LimitOverride::new(MINUTE * 60, 10_000, expires);
+        store.set("api:customer", limit).await.unwrap();
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .header_key("x-customer", MissingKeyPolicy::Error)
+            .key_prefix("api:")
+            .override_store(store)
+            .build();
+        let req = |customer: &str| {
+            TestRequest::default()
+                .insert_header(("x-customer", customer))
+                .to_srv_request()
+        };
+        let input = input_fn(&req("customer")).await.unwrap();
+        assert_eq!(input.max_requests, 10_000);
+        assert_eq!(input.interval, MINUTE * 60);
+        assert_eq!(input_fn(&req("other")).await.unwrap().max_requests, 5);
+    }
+
+    #[actix_web::test]
+    async fn test_extension_key() {
+        struct User(u64);
+
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .extension_key(|user: &User| user.0.to_string(), MissingKeyPolicy::Error)
+            .build();
+        let req = TestRequest::default().to_srv_request();
+        assert!(input_fn(&req).await.is_err());
+        req.extensions_mut().insert(User(7));
+        assert_eq!(input_fn(&req).await.unwrap().key, "7");
+    }
+
+    #[actix_web::test]
+    async fn test_match_pattern_key() {
+        use actix_web::dev::Service;
+        use actix_web::{test, web, App, HttpResponse};
+
+        let input_fn = Rc::new(
+            SimpleInputFunctionBuilder::new(MINUTE, 5)
+                .match_pattern_key(MissingKeyPolicy::Fallback("unmatched".to_owned()))
+                .build(),
+        );
+        let app = test::init_service(
+            App::new()
+                .route("/users/{id}", web::get().to(HttpResponse::Ok))
+                .wrap_fn(move |req, srv| {
+                    let input = input_fn(&req);
+                    let fut = srv.call(req);
+                    async move {
+                        let key = input.await.unwrap().key;
+                        let mut res = fut.await?;
+                        res.headers_mut()
+                            .insert("key".try_into().unwrap(), key.try_into().unwrap());
+                        Ok(res)
+                    }
+                }),
+        )
+        .await;
+        let res = test::call_service(&app, TestRequest::with_uri("/users/123").to_request()).await;
+        assert_eq!(res.headers().get("key").unwrap(), "/users/{id}");
+        let res = test::call_service(&app, TestRequest::with_uri("/other").to_request()).await;
+        assert_eq!(res.headers().get("key").unwrap(), "unmatched");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("/healthz", "/healthz"));
+        assert!(!glob_match("/healthz", "/healthz/"));
+        assert!(glob_match("/static/*", "/static/css/app.css"));
+        assert!(!glob_match("/static/*", "/api/static/"));
+        assert!(glob_match("/*/docs/*.html", "/v1/docs/index.html"));
+        assert!(!glob_match("/*/docs/*.html", "/v1/docs/index.json"));
+        assert!(glob_match("*", "/anything"));
+    }
+
+    #[actix_web::test]
+    async fn test_exclude_paths() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .exclude_paths(["/healthz", "/static/*"])
+            .exclude_if(|req| req.headers().contains_key("x-internal"))
+            .path_key()
+            .build();
+        let is_exempt = |res: Result<SimpleInput, actix_web::Error>| {
+            res.unwrap_err().as_error::<Exempt>().is_some()
+        };
+        let req = TestRequest::with_uri("/healthz").to_srv_request();
+        assert!(is_exempt(input_fn(&req).await));
+        let req = TestRequest::with_uri("/static/app.js").to_srv_request();
+        assert!(is_exempt(input_fn(&req).await));
+        let req = TestRequest::with_uri("/api")
+            .insert_header(("x-internal", "1"))
+            .to_srv_request();
+        assert!(is_exempt(input_fn(&req).await));
+        let req = TestRequest::with_uri("/api").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/api");
+    }
+
+    #[actix_web::test]
+    async fn test_only_content_types() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .only_content_types(["application/json", "application/*+json"])
+            .path_key()
+            .build();
+        let is_exempt = |res: Result<SimpleInput, actix_web::Error>| {
+            res.unwrap_err().as_error::<Exempt>().is_some()
+        };
+        let req = TestRequest::with_uri("/api")
+            .insert_header(("content-type", "Application/JSON; charset=utf-8"))
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/api");
+        let req = TestRequest::with_uri("/api")
+            .insert_header(("accept", "text/html, application/problem+json;q=0.9"))
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/api");
+        let req = TestRequest::with_uri("/app.js")
+            .insert_header(("accept", "text/javascript"))
+            .to_srv_request();
+        assert!(is_exempt(input_fn(&req).await));
+        // Media ranges cover the configured types
+        let req = TestRequest::with_uri("/api")
+            .insert_header(("accept", "*/*"))
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/api");
+        let req = TestRequest::with_uri("/api")
+            .insert_header(("accept", "Application/*;q=0.8"))
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/api");
+        let req = TestRequest::with_uri("/app.css")
+            .insert_header(("accept", "text/*"))
+            .to_srv_request();
+        assert!(is_exempt(input_fn(&req).await));
+        let req = TestRequest::with_uri("/api")
+            .insert_header(("content-type", "text/plain"))
+            .insert_header(("accept", "*/*"))
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/api");
+        // Leaving out both headers doesn't avoid the limit
+        let req = TestRequest::with_uri("/api").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/api");
+        let req = TestRequest::with_uri("/api")
+            .method(Method::OPTIONS)
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/api");
+        let req = TestRequest::with_uri("/api")
+            .insert_header(("accept", ""))
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "/api");
+    }
+
+    #[actix_web::test]
+    async fn test_ignore_preflight() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .ignore_preflight()
+            .method_key()
+            .build();
+        let req = TestRequest::with_uri("/api")
+            .method(Method::OPTIONS)
+            .insert_header(("origin", "https://example.com"))
+            .insert_header(("access-control-request-method", "POST"))
+            .to_srv_request();
+        let err = input_fn(&req).await.unwrap_err();
+        assert!(err.as_error::<Exempt>().is_some());
+        // Plain OPTIONS requests are still counted
+        let req = TestRequest::with_uri("/api")
+            .method(Method::OPTIONS)
+            .to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, "OPTIONS");
+    }
+
+    #[actix_web::test]
+    async fn test_policy_fn() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 5)
+            .policy_fn(|req| {
+                Ok(match req.path() {
+                    "/exempt" => PolicyDecision::new("exempt").exempt(),
+                    "/expensive" => PolicyDecision::new("expensive")
+                        .cost(10)
+                        .max_requests(100)
+                        .interval(MINUTE * 2),
+                    _ => PolicyDecision::new("default"),
+                })
+            })
+            .build();
+        let req = TestRequest::with_uri("/exempt").to_srv_request();
+        let err = input_fn(&req).await.unwrap_err();
+        assert!(err.as_error::<Exempt>().is_some());
+        let req = TestRequest::with_uri("/expensive").to_srv_request();
+        let input = input_fn(&req).await.unwrap();
+        assert_eq!(input.key, "expensive");
+        assert_eq!(input.cost, 10);
+        assert_eq!(input.max_requests, 100);
+        assert_eq!(input.interval, MINUTE * 2);
+        let req = TestRequest::with_uri("/other").to_srv_request();
+        let input = input_fn(&req).await.unwrap();
+        assert_eq!(input.key, "default");
+        assert_eq!(input.cost, 1);
+        assert_eq!(input.max_requests, 5);
+    }
 }
@@ -0,0 +1,227 @@
+use crate::backend::{Backend, Health, SimpleBackend, SimpleInput, SimpleOutput};
+use crate::metrics;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+type OnCall = dyn Fn(BackendCall, Duration) + Send + Sync;
+
+/// A call made through an [InstrumentedBackend].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendCall {
+    /// A request was processed.
+    Request { allowed: bool },
+    /// A request failed.
+    RequestFailed,
+    /// A request was rolled back.
+    Rollback,
+    /// A rollback failed.
+    RollbackFailed,
+}
+
+impl BackendCall {
+    /// The `operation` label: `request` or `rollback`.
+    pub fn operation(&self) -> &'static str {
+        match self {
+            BackendCall::Request { .. } | BackendCall::RequestFailed => "request",
+            BackendCall::Rollback | BackendCall::RollbackFailed => "rollback",
+        }
+    }
+
+    /// The `result` label: `allowed`, `denied`, `ok` or `error`.
+    pub fn result(&self) -> &'static str {
+        match self {
+            BackendCall::Request { allowed: true } => "allowed",
+            BackendCall::Request { allowed: false } => "denied",
+            BackendCall::Rollback => "ok",
+            BackendCall::RequestFailed | BackendCall::RollbackFailed => "error",
+        }
+    }
+}
+
+/// Wraps any [Backend], recording the latency and result of each call; e.g. to compare the
+/// error rate and deny ratio of two backends.
+///
+/// With the `metrics` feature, calls are recorded as
+/// [INSTRUMENTED_CALLS](metrics::INSTRUMENTED_CALLS) and
+/// [INSTRUMENTED_DURATION](metrics::INSTRUMENTED_DURATION). They can also be passed to a callback
+/// with [InstrumentedBackend::on_call].
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::InstrumentedBackend;
+/// # async {
+/// let backend = InstrumentedBackend::new(InMemoryBackend::builder().build())
+///     .name("memory")
+///     .on_call(|call, duration| log::debug!("{call:?} took {duration:?}"));
+/// # };
+/// ```
+#[derive(Clone)]
+pub struct InstrumentedBackend<B> {
+    inner: B,
+    name: &'static str,
+    on_call: Option<Arc<OnCall>>,
+}
+
+impl<B> InstrumentedBackend<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            name: "default",
+            on_call: None,
+        }
+    }
+
+    /// The value of the `backend` metrics label, to tell apart multiple instrumented backends.
+    ///
+    /// Defaults to `default`.
+    pub fn name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// Called after every call to the backend, with the time it took.
+    pub fn on_call<F>(mut self, f: F) -> Self
+    where
+        F: Fn(BackendCall, Duration) + Send + Sync + 'static,
+    {
+        self.on_call = Some(Arc::new(f));
+        self
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    fn record(&self, call: BackendCall, duration: Duration) {
+        metrics::record_instrumented_call(self.name, call.operation(), call.result(), duration);
+        if let Some(on_call) = &self.on_call {
+            on_call(call, duration);
+        }
+    }
+}
+
+impl<B, I> Backend<I> for InstrumentedBackend<B>
+where
+    B: Backend<I>,
+    I: 'static,
+{
+    type Output = B::Output;
+    type RollbackToken = B::RollbackToken;
+    type Error = B::Error;
+
+    async fn request(
+        &self,
+        input: I,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        let started = Instant::now();
+        let result = self.inner.request(input).await;
+        let call = match &result {
+            Ok((allowed, _, _)) => BackendCall::Request { allowed: *allowed },
+            Err(_) => BackendCall::RequestFailed,
+        };
+        self.record(call, started.elapsed());
+        result
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        let started = Instant::now();
+        let result = self.inner.rollback(token).await;
+        let call = match &result {
+            Ok(()) => BackendCall::Rollback,
+            Err(_) => BackendCall::RollbackFailed,
+        };
+        self.record(call, started.elapsed());
+        result
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        self.inner.rollback_cost(token)
+    }
+
+    /// Records a call for each input, each with the duration of the whole batch.
+    async fn request_many(
+        &self,
+        inputs: Vec<I>,
+    ) -> Result<Vec<(bool, Self::Output, Self::RollbackToken)>, Self::Error> {
+        let count = inputs.len();
+        let started = Instant::now();
+        let result = self.inner.request_many(inputs).await;
+        let duration = started.elapsed();
+        match &result {
+            Ok(results) => {
+                for (allowed, _, _) in results {
+                    self.record(BackendCall::Request { allowed: *allowed }, duration);
+                }
+            }
+            Err(_) => {
+                for _ in 0..count {
+                    self.record(BackendCall::RequestFailed, duration);
+                }
+            }
+        }
+        result
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.inner.health().await
+    }
+}
+
+impl<B: SimpleBackend> SimpleBackend for InstrumentedBackend<B> {
+    async fn remove_key(&self, key: &str) -> Result<(), Self::Error> {
+        self.inner.remove_key(key).await
+    }
+
+    async fn peek(&self, input: &SimpleInput) -> Result<(bool, SimpleOutput), Self::Error> {
+        self.inner.peek(input).await
+    }
+
+    async fn set_key(&self, key: &str, count: u64, ttl: Duration) -> Result<(), Self::Error> {
+        self.inner.set_key(key, count, ttl).await
+    }
+
+    async fn remove_keys(&self, prefix: &str) -> Result<u64, Self::Error> {
+        self.inner.remove_keys(prefix).await
+    }
+
+    async fn clear(&self) -> Result<u64, Self::Error> {
+        self.inner.clear().await
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use std::sync::Mutex;
+
+    #[actix_web::test]
+    async fn test_instrumented() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let backend = InstrumentedBackend::new(InMemoryBackend::builder().build())
+            .name("memory")
+            .on_call(move |call, _| recorded.lock().unwrap().push(call));
+        let input = SimpleInput {
+            interval: Duration::from_secs(60),
+            max_requests: 1,
+            key: "KEY1".to_string(),
+            cost: 1,
+        };
+        let (_, _, token) = backend.request(input.clone()).await.unwrap();
+        backend.request(input).await.unwrap();
+        backend.rollback(token).await.unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                BackendCall::Request { allowed: true },
+                BackendCall::Request { allowed: false },
+                BackendCall::Rollback,
+            ]
+        );
+        assert_eq!(BackendCall::Request { allowed: false }.result(), "denied");
+        assert_eq!(BackendCall::RollbackFailed.operation(), "rollback");
+    }
+}
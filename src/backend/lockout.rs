@@ -0,0 +1,249 @@
+use crate::backend::clock::{Clock, TokioClock};
+use crate::backend::{Backend, Health, SimpleInput, SimpleOutput};
+use actix_web::rt::time::Instant;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// The number of keys tracked before forgotten lockouts are first pruned
+const PRUNE_AT: usize = 1024;
+
+/// Wraps a [Backend], locking a key out for exponentially longer each time it goes over its
+/// limit; e.g. to slow down password guessing.
+///
+/// The first time a key is denied it is locked out for the `base` duration (or until the limit
+/// resets, whichever is later), and each further lockout doubles the duration, up to `max`.
+/// Requests for a locked out key are denied without being counted. A key's lockouts are
+/// forgotten once it hasn't been locked out for `max`.
+///
+/// The lockouts are held in memory, and shared by all clones of the backend; they aren't shared
+/// between processes, even if the wrapped backend is.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::LockoutBackend;
+/// # use std::time::Duration;
+/// # async {
+/// let backend = LockoutBackend::new(
+///     InMemoryBackend::builder().build(),
+///     Duration::from_secs(60),
+///     Duration::from_secs(3600),
+/// );
+/// # };
+/// ```
+#[derive(Clone)]
+pub struct LockoutBackend<B> {
+    inner: B,
+    base: Duration,
+    max: Duration,
+    lockouts: Arc<Mutex<Lockouts>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct Lockouts {
+    keys: HashMap<String, Lockout>,
+    // Forgotten lockouts are pruned in batches, once the map has doubled in size since the last
+    prune_at: usize,
+}
+
+struct Lockout {
+    strikes: u32,
+    until: Instant,
+}
+
+impl<B> LockoutBackend<B> {
+    pub fn new(inner: B, base: Duration, max: Duration) -> Self {
+        Self {
+            inner,
+            base,
+            max: max.max(base),
+            lockouts: Arc::new(Mutex::new(Lockouts {
+                keys: HashMap::new(),
+                prune_at: PRUNE_AT,
+            })),
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    /// Read the time from `clock` rather than tokio, e.g. a
+    /// [ManualClock](crate::backend::clock::ManualClock) shared with the wrapped backend in tests.
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    // The lockout for a key's nth strike, starting from 1
+    fn duration(&self, strikes: u32) -> Duration {
+        self.base
+            .checked_mul(2u32.saturating_pow(strikes - 1))
+            .map_or(self.max, |duration| duration.min(self.max))
+    }
+}
+
+impl<B> Backend<SimpleInput> for LockoutBackend<B>
+where
+    B: Backend<SimpleInput, Output = SimpleOutput>,
+{
+    type Output = SimpleOutput;
+    /// [None] if the key was locked out, and nothing was counted.
+    type RollbackToken = Option<B::RollbackToken>;
+    type Error = B::Error;
+
+    async fn request(
+        &self,
+        input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        let now = self.clock.now();
+        let locked_until = {
+            let mut lockouts = self.lockouts.lock().unwrap();
+            match lockouts.keys.get(&input.key) {
+                Some(lockout) if lockout.until > now => Some(lockout.until),
+                Some(lockout) if lockout.until + self.max <= now => {
+                    lockouts.keys.remove(&input.key);
+                    None
+                }
+                _ => None,
+            }
+        };
+        if let Some(until) = locked_until {
+            let output = SimpleOutput {
+                limit: input.max_requests,
+                remaining: 0,
+                reset: until,
+            };
+            return Ok((false, output, None));
+        }
+
+        let key = input.key.clone();
+        let (allow, mut output, token) = self.inner.request(input).await?;
+        if !allow {
+            let mut lockouts = self.lockouts.lock().unwrap();
+            if lockouts.keys.len() >= lockouts.prune_at {
+                lockouts
+                    .keys
+                    .retain(|_, lockout| lockout.until + self.max > now);
+                lockouts.prune_at = (lockouts.keys.len() * 2).max(PRUNE_AT);
+            }
+            let strikes = lockouts
+                .keys
+                .get(&key)
+                .filter(|lockout| lockout.until + self.max > now)
+                .map_or(0, |l| l.strikes)
+                .saturating_add(1);
+            let until = (now + self.duration(strikes)).max(output.reset);
+            lockouts.keys.insert(key, Lockout { strikes, until });
+            output.reset = until;
+        }
+        Ok((allow, output, Some(token)))
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        match token {
+            Some(token) => self.inner.rollback(token).await,
+            None => Ok(()),
+        }
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        match token {
+            Some(token) => self.inner.rollback_cost(token),
+            None => Some(0),
+        }
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.inner.health().await
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::clock::ManualClock;
+    use crate::backend::memory::InMemoryBackend;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn input() -> SimpleInput {
+        SimpleInput {
+            interval: Duration::from_secs(1),
+            max_requests: 1,
+            key: "user".to_owned(),
+            cost: 1,
+        }
+    }
+
+    #[test]
+    fn test_duration() {
+        let backend = LockoutBackend::new((), MINUTE, MINUTE * 5);
+        assert_eq!(backend.duration(1), MINUTE);
+        assert_eq!(backend.duration(2), MINUTE * 2);
+        assert_eq!(backend.duration(3), MINUTE * 4);
+        assert_eq!(backend.duration(4), MINUTE * 5);
+        assert_eq!(backend.duration(u32::MAX), MINUTE * 5);
+    }
+
+    fn make_backend(clock: &ManualClock) -> LockoutBackend<InMemoryBackend> {
+        let inner = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .with_clock(clock.clone())
+            .build();
+        LockoutBackend::new(inner, MINUTE, MINUTE * 10).with_clock(clock.clone())
+    }
+
+    #[actix_web::test]
+    async fn test_lockout() {
+        let clock = ManualClock::new();
+        let backend = make_backend(&clock);
+        assert!(backend.request(input()).await.unwrap().0);
+        let (allow, output, _) = backend.request(input()).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.reset, clock.now() + MINUTE);
+
+        // Still locked out once the limit has reset
+        clock.advance(Duration::from_secs(30));
+        let (allow, _, token) = backend.request(input()).await.unwrap();
+        assert!(!allow);
+        assert!(token.is_none());
+
+        // Each lockout is twice as long as the last
+        clock.advance(Duration::from_secs(31));
+        assert!(backend.request(input()).await.unwrap().0);
+        let (allow, output, _) = backend.request(input()).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.reset, clock.now() + MINUTE * 2);
+
+        // Until the key hasn't been locked out for `max`
+        clock.advance(MINUTE * 12);
+        assert!(backend.request(input()).await.unwrap().0);
+        let (allow, output, _) = backend.request(input()).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.reset, clock.now() + MINUTE);
+    }
+
+    #[actix_web::test]
+    async fn test_prune() {
+        let clock = ManualClock::new();
+        let backend = make_backend(&clock);
+        let deny = |key: usize| SimpleInput {
+            max_requests: 0,
+            key: key.to_string(),
+            ..input()
+        };
+        for key in 0..PRUNE_AT {
+            backend.request(deny(key)).await.unwrap();
+        }
+        // Forgotten lockouts are only pruned once the map has grown past the threshold
+        clock.advance(MINUTE * 11);
+        backend.request(deny(PRUNE_AT)).await.unwrap();
+        let lockouts = backend.lockouts.lock().unwrap();
+        assert_eq!(lockouts.keys.len(), 1);
+        assert_eq!(lockouts.prune_at, PRUNE_AT);
+    }
+}
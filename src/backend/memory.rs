@@ -1,54 +1,446 @@
-use crate::backend::{Backend, SimpleBackend, SimpleInput, SimpleOutput};
+use crate::backend::clock::{Clock, TokioClock};
+use crate::backend::{
+    Backend, BackendError, BanStore, InspectableBackend, KeyStatus, ReservableBackend,
+    SimpleBackend, SimpleInput, SimpleOutput, SimpleReservation, SimpleRollbackToken,
+};
+use crate::supervisor::{ShutdownSignal, Supervisor};
 use actix_web::rt::task::JoinHandle;
 use actix_web::rt::time::Instant;
-use async_trait::async_trait;
-use dashmap::DashMap;
-use std::convert::Infallible;
-use std::sync::Arc;
+use dashmap::{DashMap, SharedValue};
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
 
 pub const DEFAULT_GC_INTERVAL_SECONDS: u64 = 60 * 10;
 
+// The minimum time between garbage collections triggered by the size of the map
+const MIN_TRIGGERED_GC_INTERVAL: Duration = Duration::from_secs(1);
+
+// With lazy expiry, one shard of the map is swept every this many requests on each thread
+const LAZY_EXPIRY_PERIOD: usize = 64;
+
 /// A Fixed Window rate limiter [Backend] that uses [Dashmap](dashmap::DashMap) to store keys
 /// in memory.
+///
+/// Keys are [String]s unless built with [Builder::build_keyed]; the administrative traits such
+/// as [SimpleBackend] are only implemented for [String] keys.
 #[derive(Clone)]
-pub struct InMemoryBackend {
-    map: Arc<DashMap<String, Value>>,
-    gc_handle: Option<Arc<JoinHandle<()>>>,
+pub struct InMemoryBackend<K = String, S = RandomState> {
+    map: Arc<DashMap<K, Value, S>>,
+    // Banned keys, and when their ban expires
+    bans: Arc<DashMap<String, Instant>>,
+    top_offenders: Option<Arc<Mutex<SpaceSaving<K>>>>,
+    // Wakes the garbage collector early, once the map holds more than the given number of keys
+    gc_trigger: Option<(Arc<Notify>, usize)>,
+    max_keys: Option<usize>,
+    lazy_expiry: bool,
+    carry_over: Option<CarryOver>,
+    gc_stats: Arc<GcStats>,
+    clock: Arc<dyn Clock>,
+    gc_handle: Option<Arc<GcTask>>,
+    // Held so that a supervised garbage collector isn't stopped by the caller dropping their handle
+    _supervisor: Option<Supervisor>,
 }
 
 struct Value {
     ttl: Instant,
     count: u64,
+    // The unused quota carried over from the previous window, allowed in addition to the limit
+    banked: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CarryOver {
+    fraction: f64,
+    max_burst: u64,
+}
+
+#[derive(Default)]
+struct GcStats {
+    runs: AtomicU64,
+    last_evicted: AtomicU64,
+}
+
+/// A copy of an [InMemoryBackend]'s buckets, see [InMemoryBackend::export].
+///
+/// With the `serde` feature, this can be serialized, e.g. to a file, to be restored by
+/// [InMemoryBackend::import] after a restart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemorySnapshot<K = String> {
+    pub entries: Vec<SnapshotEntry<K>>,
+}
+
+/// A bucket in a [MemorySnapshot].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SnapshotEntry<K = String> {
+    pub key: K,
+    pub count: u64,
+    /// The time remaining until the bucket expires, at the time of the export.
+    pub ttl: Duration,
+}
+
+/// A snapshot of an [InMemoryBackend]'s size, see [InMemoryBackend::stats].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// The number of keys in the map, including expired keys not yet garbage collected.
+    pub keys: usize,
+    /// The approximate memory allocated by the map, in bytes.
+    ///
+    /// This accounts for the map's capacity rather than the number of keys, but not for any heap
+    /// memory owned by the keys themselves, such as the contents of [String] keys.
+    pub approximate_bytes: usize,
+    /// The number of times the garbage collector has run.
+    pub gc_runs: u64,
+    /// The number of expired keys removed by the most recent garbage collection.
+    pub last_gc_evicted: u64,
+}
+
+/// Approximate counts of the most frequent keys, using a fixed amount of memory.
+///
+/// This is the Space-Saving algorithm: once full, a new key replaces the key with the lowest
+/// count, inheriting its count. Counts can therefore be overestimated by at most the lowest
+/// tracked count, but any key that occurs more often than that is guaranteed to be tracked.
+///
+/// Keys are grouped into buckets by count (the stream-summary structure), so that both an
+/// increment and finding the key with the lowest count are cheap, however many keys are tracked.
+struct SpaceSaving<K> {
+    capacity: usize,
+    // The count of each key, and its position in the bucket for that count
+    counts: HashMap<K, (u64, usize)>,
+    buckets: BTreeMap<u64, Vec<K>>,
+}
+
+impl<K: Eq + Hash + Clone> SpaceSaving<K> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: HashMap::with_capacity(capacity),
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    fn increment(&mut self, key: &K) {
+        if let Some(&(count, index)) = self.counts.get(key) {
+            let key = self.take(count, index);
+            self.insert(key, count + 1);
+            return;
+        }
+        let mut count = 1;
+        if self.counts.len() >= self.capacity {
+            let mut lowest = self.buckets.first_entry().expect("capacity is non-zero");
+            let min_count = *lowest.key();
+            let min_key = lowest.get_mut().pop().expect("buckets are never empty");
+            if lowest.get().is_empty() {
+                lowest.remove();
+            }
+            self.counts.remove(&min_key);
+            count += min_count;
+        }
+        self.insert(key.clone(), count);
+    }
+
+    // Removes the key at `index` from the bucket for `count`
+    fn take(&mut self, count: u64, index: usize) -> K {
+        let bucket = self.buckets.get_mut(&count).expect("key is in its bucket");
+        let key = bucket.swap_remove(index);
+        if let Some(moved) = bucket.get(index) {
+            self.counts.get_mut(moved).expect("key is tracked").1 = index;
+        }
+        if bucket.is_empty() {
+            self.buckets.remove(&count);
+        }
+        key
+    }
+
+    fn insert(&mut self, key: K, count: u64) {
+        let bucket = self.buckets.entry(count).or_default();
+        self.counts.insert(key.clone(), (count, bucket.len()));
+        bucket.push(key);
+    }
+
+    fn top(&self, n: usize) -> Vec<(K, u64)> {
+        self.buckets
+            .iter()
+            .rev()
+            .flat_map(|(count, keys)| keys.iter().map(|key| (key.clone(), *count)))
+            .take(n)
+            .collect()
+    }
 }
 
 impl InMemoryBackend {
     pub fn builder() -> Builder {
         Builder {
             gc_interval: Some(Duration::from_secs(DEFAULT_GC_INTERVAL_SECONDS)),
+            gc_max_keys: None,
+            max_keys: None,
+            lazy_expiry: false,
+            carry_over: None,
+            supervisor: None,
+            top_offenders: None,
+            hasher: RandomState::new(),
+            shard_amount: None,
+            clock: Arc::new(TokioClock),
         }
     }
+}
 
-    fn garbage_collector(map: Arc<DashMap<String, Value>>, interval: Duration) -> JoinHandle<()> {
-        assert!(
-            interval.as_secs_f64() > 0f64,
-            "GC interval must be non-zero"
-        );
-        actix_web::rt::spawn(async move {
-            loop {
-                let now = Instant::now();
-                map.retain(|_k, v| v.ttl > now);
-                actix_web::rt::time::sleep_until(now + interval).await;
+impl<K, S> InMemoryBackend<K, S>
+where
+    K: Eq + Hash + Clone + 'static,
+    S: BuildHasher + Clone + 'static,
+{
+    // Sweep one shard every LAZY_EXPIRY_PERIOD requests, moving on to the next shard each time
+    fn sweep(&self, now: Instant) {
+        thread_local! {
+            static REQUESTS: Cell<usize> = const { Cell::new(0) };
+        }
+        let requests = REQUESTS.with(|requests| {
+            let n = requests.get().wrapping_add(1);
+            requests.set(n);
+            n
+        });
+        if !requests.is_multiple_of(LAZY_EXPIRY_PERIOD) {
+            return;
+        }
+        let shards = self.map.shards();
+        let index = (requests / LAZY_EXPIRY_PERIOD) % shards.len();
+        if let Some(mut shard) = shards[index].try_write() {
+            shard.retain(|_k, v| v.get().ttl > now);
+        }
+        if index == 0 {
+            self.bans.retain(|_k, expiry| *expiry > now);
+        }
+    }
+
+    // The quota carried into a new window, from the bucket of the previous window; keys without a
+    // bucket have been idle, so their previous window was entirely unused
+    fn carried(&self, previous: Option<&Value>, input: &SimpleInput<K>, now: Instant) -> u64 {
+        let Some(carry_over) = self.carry_over else {
+            return 0;
+        };
+        let unused = match previous {
+            Some(v)
+                if v.ttl
+                    .checked_add(input.interval)
+                    .is_none_or(|end| now < end) =>
+            {
+                input
+                    .max_requests
+                    .saturating_add(v.banked)
+                    .saturating_sub(v.count)
+            }
+            _ => input.max_requests,
+        };
+        ((unused as f64 * carry_over.fraction) as u64).min(carry_over.max_burst)
+    }
+
+    // Bring the map back under max_keys, first by removing expired keys, then by evicting the
+    // keys closest to expiring (other than the key that was just inserted), with some slack so
+    // that the next few new keys don't have to scan the map again
+    fn evict(&self, inserted: &K, max_keys: usize, now: Instant) {
+        let mut ttls = self
+            .map
+            .iter()
+            .filter(|entry| entry.ttl > now && entry.key() != inserted)
+            .map(|entry| entry.ttl)
+            .collect::<Vec<_>>();
+        let excess = (ttls.len() + 1).saturating_sub(max_keys);
+        let evict = match excess {
+            0 => 0,
+            excess => (excess + (max_keys / 100).max(1)).min(ttls.len()),
+        };
+        // Evict the keys expiring before the cutoff, and as many expiring at it as needed
+        let (cutoff, mut at_cutoff) = match evict {
+            0 => (now, 0),
+            evict => {
+                let (_, &mut cutoff, _) = ttls.select_nth_unstable(evict - 1);
+                let before = ttls.iter().filter(|&&ttl| ttl < cutoff).count();
+                (cutoff, evict - before)
+            }
+        };
+        self.map.retain(|k, v| {
+            if k == inserted {
+                true
+            } else if v.ttl <= now || v.ttl < cutoff {
+                false
+            } else if v.ttl == cutoff && at_cutoff > 0 {
+                at_cutoff -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Stop the garbage collector, waiting for it to finish.
+    ///
+    /// This affects every clone of the backend, after which expired keys are no longer removed.
+    /// Otherwise the garbage collector is stopped once the last clone is dropped. A garbage
+    /// collector run under a [Supervisor] is unaffected, see [Supervisor::shutdown()] instead.
+    pub async fn close(&self) {
+        if let Some(gc) = &self.gc_handle {
+            gc.close().await;
+        }
+    }
+
+    /// Copy the current (unexpired) buckets, e.g. to be restored with [InMemoryBackend::import]
+    /// after a graceful restart, so that counts aren't reset by every deployment.
+    ///
+    /// Bans are not included.
+    pub fn export(&self) -> MemorySnapshot<K> {
+        let now = self.clock.now();
+        let entries = self
+            .map
+            .iter()
+            .filter(|entry| entry.ttl > now)
+            .map(|entry| SnapshotEntry {
+                key: entry.key().clone(),
+                count: entry.count,
+                ttl: entry.ttl - now,
+            })
+            .collect();
+        MemorySnapshot { entries }
+    }
+
+    /// Restore the buckets from a [MemorySnapshot], overwriting any existing buckets with the same
+    /// keys.
+    ///
+    /// The TTLs are counted from the time of the import, so any time between the export and the
+    /// import is not counted.
+    ///
+    /// Returns an error, without restoring any buckets, if a TTL is too large to be represented;
+    /// e.g. from a corrupted snapshot.
+    pub fn import(&self, snapshot: MemorySnapshot<K>) -> Result<(), BackendError> {
+        let now = self.clock.now();
+        let entries = snapshot
+            .entries
+            .into_iter()
+            .filter(|entry| !entry.ttl.is_zero())
+            .map(|entry| Ok((entry.key, entry.count, expiry(now, entry.ttl, "TTL")?)))
+            .collect::<Result<Vec<_>, BackendError>>()?;
+        for (key, count, ttl) in entries {
+            self.map.insert(
+                key,
+                Value {
+                    ttl,
+                    count,
+                    banked: 0,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the map and garbage collector statistics, e.g. for capacity planning.
+    ///
+    /// With the `metrics` feature, these are also published by the garbage collector as
+    /// [MEMORY_KEYS](crate::metrics::MEMORY_KEYS), [MEMORY_BYTES](crate::metrics::MEMORY_BYTES),
+    /// [MEMORY_GC_RUNS](crate::metrics::MEMORY_GC_RUNS) and
+    /// [MEMORY_EVICTED](crate::metrics::MEMORY_EVICTED).
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            keys: self.map.len(),
+            approximate_bytes: Self::approximate_bytes(&self.map),
+            gc_runs: self.gc_stats.runs.load(Ordering::Relaxed),
+            last_gc_evicted: self.gc_stats.last_evicted.load(Ordering::Relaxed),
+        }
+    }
+
+    fn approximate_bytes(map: &DashMap<K, Value, S>) -> usize {
+        // Each slot of a shard holds a key and value, plus a control byte
+        let slot = std::mem::size_of::<K>() + std::mem::size_of::<Value>() + 1;
+        map.shards()
+            .iter()
+            .map(|shard| shard.read().capacity() * slot)
+            .sum()
+    }
+
+    /// Returns up to `n` of the keys that have been denied the most often, with their
+    /// (approximate) number of denials, most denied first.
+    ///
+    /// Keys with the same count are returned in no particular order.
+    ///
+    /// Always empty unless enabled with [Builder::track_top_offenders].
+    pub fn top_offenders(&self, n: usize) -> Vec<(K, u64)> {
+        match &self.top_offenders {
+            Some(tracker) => tracker.lock().unwrap().top(n),
+            None => Vec::new(),
+        }
+    }
+
+    async fn garbage_collector(
+        map: Arc<DashMap<K, Value, S>>,
+        bans: Arc<DashMap<String, Instant>>,
+        stats: Arc<GcStats>,
+        clock: Arc<dyn Clock>,
+        interval: Duration,
+        trigger: Option<Arc<Notify>>,
+        mut shutdown: Option<ShutdownSignal>,
+    ) {
+        loop {
+            let now = Instant::now();
+            let expired = clock.now();
+            let (mut evicted, mut remaining) = (0, 0);
+            // Lock and clean one shard at a time, yielding in between, so that requests are not
+            // held up while a large map is collected
+            for shard in map.shards() {
+                {
+                    let mut shard = shard.write();
+                    let before = shard.len();
+                    shard.retain(|_k, v| v.get().ttl > expired);
+                    evicted += before - shard.len();
+                    remaining += shard.len();
+                }
+                tokio::task::yield_now().await;
+            }
+            stats.runs.fetch_add(1, Ordering::Relaxed);
+            stats.last_evicted.store(evicted as u64, Ordering::Relaxed);
+            crate::metrics::record_memory_gc(evicted, remaining, Self::approximate_bytes(&map));
+            bans.retain(|_k, expiry| *expiry > expired);
+            let wait = async {
+                let triggered = async {
+                    match &trigger {
+                        Some(trigger) => trigger.notified().await,
+                        None => std::future::pending().await,
+                    }
+                    actix_web::rt::time::sleep_until(now + MIN_TRIGGERED_GC_INTERVAL).await
+                };
+                tokio::select! {
+                    _ = actix_web::rt::time::sleep_until(now + interval) => {}
+                    _ = triggered => {}
+                }
+            };
+            if !wait_unless_shutdown(wait, &mut shutdown).await {
+                break;
             }
-        })
+        }
     }
 }
 
-pub struct Builder {
+pub struct Builder<S = RandomState> {
     gc_interval: Option<Duration>,
+    gc_max_keys: Option<usize>,
+    max_keys: Option<usize>,
+    lazy_expiry: bool,
+    carry_over: Option<CarryOver>,
+    supervisor: Option<Supervisor>,
+    top_offenders: Option<usize>,
+    hasher: S,
+    shard_amount: Option<usize>,
+    clock: Arc<dyn Clock>,
 }
 
-impl Builder {
+impl<S: BuildHasher + Clone + 'static> Builder<S> {
     /// Override the default garbage collector interval.
     ///
     /// Set to None to disable garbage collection.
@@ -59,77 +451,572 @@ impl Builder {
         self
     }
 
-    pub fn build(self) -> InMemoryBackend {
-        let map = Arc::new(DashMap::<String, Value>::new());
-        let gc_handle = self.gc_interval.map(|gc_interval| {
-            Arc::new(InMemoryBackend::garbage_collector(map.clone(), gc_interval))
+    /// Also run the garbage collector as soon as the map holds more than `max_keys` keys (but at
+    /// most once per second), rather than waiting for the next interval; e.g. so that a scanner
+    /// generating random keys can't grow the map unboundedly between collections.
+    ///
+    /// This has no effect if garbage collection is disabled.
+    pub fn with_gc_max_keys(mut self, max_keys: usize) -> Self {
+        self.gc_max_keys = Some(max_keys);
+        self
+    }
+
+    /// Never store more than `max_keys` keys; e.g. so that a client sending requests with
+    /// random keys can't exhaust the server's memory.
+    ///
+    /// When a new key would exceed the limit, expired keys are removed first, and if that isn't
+    /// enough, the keys closest to expiring are evicted (losing their counts). To avoid scanning
+    /// the map for every new key, evictions free an extra 1% of `max_keys` (at least one key).
+    ///
+    /// # Panics
+    ///
+    /// If `max_keys` is zero.
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        assert!(max_keys > 0, "Max keys must be non-zero");
+        self.max_keys = Some(max_keys);
+        self
+    }
+
+    /// Remove expired keys while processing requests, rather than with a garbage collector, so
+    /// that no background task is spawned; e.g. for serverless or test environments.
+    ///
+    /// Every 64 requests (on each thread), one shard of the map is swept, skipping it if it is in
+    /// use. The garbage collector options are ignored.
+    pub fn with_lazy_expiry(mut self) -> Self {
+        self.lazy_expiry = true;
+        self
+    }
+
+    /// Carry a `fraction` of the quota left unused in each window over into the next window, up
+    /// to `max_burst` requests; e.g. so that clients with bursty but low average traffic aren't
+    /// denied by a strict fixed window.
+    ///
+    /// The carried quota is allowed in addition to the `max_requests` of the next window, and is
+    /// included in the limit reported by the [SimpleOutput]. A key that is new, or whose bucket
+    /// has expired for longer than a whole window, is treated as having left the entire previous
+    /// window unused.
+    ///
+    /// # Panics
+    ///
+    /// If `fraction` is not between 0 and 1.
+    pub fn with_carry_over(mut self, fraction: f64, max_burst: u64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "Carry over fraction must be between 0 and 1"
+        );
+        self.carry_over = Some(CarryOver {
+            fraction,
+            max_burst,
+        });
+        self
+    }
+
+    /// Run the garbage collector under a [Supervisor].
+    ///
+    /// The garbage collector will then keep running until [Supervisor::shutdown()] is called,
+    /// rather than being aborted when the backend is dropped. The backend holds a clone of the
+    /// supervisor, so it needn't be kept if it is never shut down.
+    pub fn with_supervisor(mut self, supervisor: Supervisor) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    /// Track the keys that are denied most often, see [InMemoryBackend::top_offenders].
+    ///
+    /// At most `capacity` keys are tracked; counts are exact until more than `capacity` different
+    /// keys have been denied, and approximate afterwards. A capacity of several times the number
+    /// of offenders you want to report gives good accuracy.
+    ///
+    /// # Panics
+    ///
+    /// If the capacity is zero.
+    pub fn track_top_offenders(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "Top offenders capacity must be non-zero");
+        self.top_offenders = Some(capacity);
+        self
+    }
+
+    /// Hash keys with `hasher` rather than the standard library's SipHash; e.g. a faster,
+    /// non-cryptographic hasher such as `ahash`, when keys can't be chosen by an attacker to cause
+    /// collisions.
+    pub fn with_hasher<H: BuildHasher + Clone + 'static>(self, hasher: H) -> Builder<H> {
+        Builder {
+            gc_interval: self.gc_interval,
+            gc_max_keys: self.gc_max_keys,
+            max_keys: self.max_keys,
+            lazy_expiry: self.lazy_expiry,
+            carry_over: self.carry_over,
+            supervisor: self.supervisor,
+            top_offenders: self.top_offenders,
+            hasher,
+            shard_amount: self.shard_amount,
+            clock: self.clock,
+        }
+    }
+
+    /// Override the number of shards the map is split into, each with its own lock.
+    ///
+    /// Defaults to 4 times the number of CPUs (rounded up to a power of two); more shards reduce
+    /// contention between requests for different keys on machines with many cores.
+    ///
+    /// # Panics
+    ///
+    /// If `shard_amount` is not a power of two greater than 1.
+    pub fn with_shard_amount(mut self, shard_amount: usize) -> Self {
+        assert!(
+            shard_amount > 1 && shard_amount.is_power_of_two(),
+            "Shard amount must be a power of two greater than 1"
+        );
+        self.shard_amount = Some(shard_amount);
+        self
+    }
+
+    /// Read the time from `clock` rather than tokio, e.g. a [ManualClock] to control the time in
+    /// tests.
+    ///
+    /// The garbage collector still runs on tokio's timer, but removes keys that have expired
+    /// according to the clock.
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn build(self) -> InMemoryBackend<String, S>
+    where
+        S: Send + Sync,
+    {
+        self.build_keyed()
+    }
+
+    /// Build a backend that uses keys of type `K` rather than [String], for [SimpleInput]s with
+    /// the same key type.
+    ///
+    /// With `Arc<str>` keys, e.g. from a custom input function, the map and the
+    /// [SimpleRollbackToken] share the input's allocation, so a request for a new key is counted
+    /// without copying it.
+    ///
+    /// # Example
+    /// ```
+    /// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+    /// # use actix_extensible_rate_limit::backend::{Backend, SimpleInput};
+    /// # use std::time::Duration;
+    /// # async {
+    /// let backend = InMemoryBackend::builder().build_keyed::<(u64, u16)>();
+    /// let input = SimpleInput {
+    ///     interval: Duration::from_secs(60),
+    ///     max_requests: 5,
+    ///     key: (42, 8080),
+    ///     cost: 1,
+    /// };
+    /// let (allow, _, _) = backend.request(input).await.unwrap();
+    /// # };
+    /// ```
+    pub fn build_keyed<K>(self) -> InMemoryBackend<K, S>
+    where
+        K: Eq + Hash + Clone + Send + Sync + 'static,
+        S: Send + Sync,
+    {
+        let map = Arc::new(match self.shard_amount {
+            Some(shard_amount) => DashMap::with_hasher_and_shard_amount(self.hasher, shard_amount),
+            None => DashMap::with_hasher(self.hasher),
         });
-        InMemoryBackend { map, gc_handle }
+        let bans = Arc::new(DashMap::new());
+        let gc_stats = Arc::new(GcStats::default());
+        let mut gc_handle = None;
+        let mut gc_trigger = None;
+        if let Some(gc_interval) = self.gc_interval.filter(|_| !self.lazy_expiry) {
+            assert!(
+                gc_interval.as_secs_f64() > 0f64,
+                "GC interval must be non-zero"
+            );
+            gc_trigger = self
+                .gc_max_keys
+                .map(|max_keys| (Arc::new(Notify::new()), max_keys));
+            let gc_map = map.clone();
+            let gc_bans = bans.clone();
+            let stats = gc_stats.clone();
+            let clock = self.clock.clone();
+            let trigger = gc_trigger.as_ref().map(|(trigger, _)| trigger.clone());
+            let supervisor = self.supervisor.clone();
+            gc_handle = Some(Arc::new(GcTask::spawn(supervisor, move |shutdown| {
+                InMemoryBackend::<K, S>::garbage_collector(
+                    gc_map,
+                    gc_bans,
+                    stats,
+                    clock,
+                    gc_interval,
+                    trigger,
+                    shutdown,
+                )
+            })));
+        }
+        InMemoryBackend {
+            map,
+            bans,
+            top_offenders: self
+                .top_offenders
+                .map(|capacity| Arc::new(Mutex::new(SpaceSaving::new(capacity)))),
+            gc_trigger,
+            max_keys: self.max_keys,
+            lazy_expiry: self.lazy_expiry,
+            carry_over: self.carry_over,
+            gc_stats,
+            clock: self.clock,
+            gc_handle,
+            _supervisor: self.supervisor,
+        }
     }
 }
 
-#[async_trait(?Send)]
-impl Backend<SimpleInput> for InMemoryBackend {
+// The instant `duration` after `now`, or an error if it is too far in the future to be represented
+pub(crate) fn expiry(
+    now: Instant,
+    duration: Duration,
+    what: &str,
+) -> Result<Instant, BackendError> {
+    now.checked_add(duration)
+        .ok_or_else(|| BackendError::Overflow(format!("{what} of {duration:?} is too large")))
+}
+
+// `duration` in nanoseconds, or an error if it is too long to be represented
+pub(crate) fn nanos(duration: Duration, what: &str) -> Result<u64, BackendError> {
+    u64::try_from(duration.as_nanos())
+        .map_err(|_| BackendError::Overflow(format!("{what} of {duration:?} is too large")))
+}
+
+// The nanoseconds from `epoch` to `instant`, for timestamps stored in a u64
+pub(crate) fn nanos_since(epoch: Instant, instant: Instant) -> u64 {
+    u64::try_from(instant.duration_since(epoch).as_nanos()).expect("Instant unexpectedly large")
+}
+
+impl<K, S> Backend<SimpleInput<K>> for InMemoryBackend<K, S>
+where
+    K: Eq + Hash + Clone + 'static,
+    S: BuildHasher + Clone + 'static,
+{
     type Output = SimpleOutput;
-    type RollbackToken = String;
-    type Error = Infallible;
+    type RollbackToken = SimpleRollbackToken<K>;
+    type Error = BackendError;
 
     async fn request(
         &self,
-        input: SimpleInput,
+        input: SimpleInput<K>,
     ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
-        let now = Instant::now();
-        let mut count = 1;
-        let mut expiry = now
-            .checked_add(input.interval)
-            .expect("Interval unexpectedly large");
-        self.map
-            .entry(input.key.clone())
-            .and_modify(|v| {
+        if let Some(gc) = &self.gc_handle {
+            gc.start();
+        }
+        let now = self.clock.now();
+        let mut count = input.cost;
+        let mut banked = 0;
+        let mut inserted = false;
+        let mut expiry = expiry(now, input.interval, "Interval")?;
+        {
+            // Find or insert the bucket with a single lookup, only an inserted key needs to be
+            // cloned, the key is otherwise moved into the token
+            let mut shard = self.map.shards()[self.map.determine_map(&input.key)].write();
+            let (_, v) = shard
+                .raw_entry_mut()
+                .from_key(&input.key)
+                .or_insert_with(|| {
+                    inserted = true;
+                    banked = self.carried(None, &input, now);
+                    // If the bucket doesn't exist, create it with a count of 1, and set the TTL.
+                    let value = Value {
+                        ttl: expiry,
+                        count,
+                        banked,
+                    };
+                    (input.key.clone(), SharedValue::new(value))
+                });
+            if !inserted {
+                let v = v.get_mut();
                 // If this bucket hasn't yet expired, increment and extract the count/expiry
                 if v.ttl > now {
-                    v.count += 1;
+                    v.count = v.count.saturating_add(input.cost);
                     count = v.count;
                     expiry = v.ttl;
                 } else {
                     // If this bucket has expired we will reset the count to 1 and set a new TTL.
+                    v.banked = self.carried(Some(v), &input, now);
                     v.ttl = expiry;
                     v.count = count;
                 }
-            })
-            .or_insert_with(|| Value {
-                // If the bucket doesn't exist, create it with a count of 1, and set the TTL.
-                ttl: expiry,
-                count,
-            });
-        let allow = count <= input.max_requests;
+                banked = v.banked;
+            }
+        }
+        if let Some((trigger, max_keys)) = &self.gc_trigger {
+            if inserted && self.map.len() > *max_keys {
+                trigger.notify_one();
+            }
+        }
+        if let Some(max_keys) = self.max_keys {
+            if inserted && self.map.len() > max_keys {
+                self.evict(&input.key, max_keys, now);
+            }
+        }
+        if self.lazy_expiry {
+            self.sweep(now);
+        }
+        let limit = input.max_requests.saturating_add(banked);
+        let allow = count <= limit;
+        if !allow {
+            if let Some(tracker) = &self.top_offenders {
+                tracker.lock().unwrap().increment(&input.key);
+            }
+        }
         let output = SimpleOutput {
-            limit: input.max_requests,
-            remaining: input.max_requests.saturating_sub(count),
+            limit,
+            remaining: limit.saturating_sub(count),
             reset: expiry,
         };
-        Ok((allow, output, input.key))
+        let token = SimpleRollbackToken {
+            key: input.key,
+            cost: input.cost,
+        };
+        Ok((allow, output, token))
     }
 
     async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
-        self.map.entry(token).and_modify(|v| {
-            v.count = v.count.saturating_sub(1);
+        self.map.entry(token.key).and_modify(|v| {
+            v.count = v.count.saturating_sub(token.cost);
         });
         Ok(())
     }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        Some(token.cost)
+    }
 }
 
-#[async_trait(?Send)]
-impl SimpleBackend for InMemoryBackend {
+impl<S: BuildHasher + Clone + 'static> SimpleBackend for InMemoryBackend<String, S> {
     async fn remove_key(&self, key: &str) -> Result<(), Self::Error> {
         self.map.remove(key);
         Ok(())
     }
+
+    async fn peek(&self, input: &SimpleInput) -> Result<(bool, SimpleOutput), Self::Error> {
+        let now = self.clock.now();
+        let (count, banked, reset) = match self.map.get(&input.key) {
+            Some(v) if v.ttl > now => (v.count, v.banked, v.ttl),
+            v => (
+                0,
+                self.carried(v.as_deref(), input, now),
+                expiry(now, input.interval, "Interval")?,
+            ),
+        };
+        let limit = input.max_requests.saturating_add(banked);
+        let allow = count.saturating_add(input.cost) <= limit;
+        let output = SimpleOutput {
+            limit,
+            remaining: limit.saturating_sub(count),
+            reset,
+        };
+        Ok((allow, output))
+    }
+
+    async fn set_key(&self, key: &str, count: u64, ttl: Duration) -> Result<(), Self::Error> {
+        if ttl.is_zero() {
+            return self.remove_key(key).await;
+        }
+        let ttl = expiry(self.clock.now(), ttl, "TTL")?;
+        self.map.insert(
+            key.to_owned(),
+            Value {
+                ttl,
+                count,
+                banked: 0,
+            },
+        );
+        Ok(())
+    }
+
+    async fn remove_keys(&self, prefix: &str) -> Result<u64, Self::Error> {
+        let before = self.map.len();
+        self.map.retain(|k, _v| !k.starts_with(prefix));
+        Ok(before.saturating_sub(self.map.len()) as u64)
+    }
+}
+
+impl<S: BuildHasher + Clone + 'static> InspectableBackend for InMemoryBackend<String, S> {
+    async fn key_status(&self, key: &str) -> Result<Option<KeyStatus>, Self::Error> {
+        let now = self.clock.now();
+        Ok(self
+            .map
+            .get(key)
+            .filter(|v| v.ttl > now)
+            .map(|v| KeyStatus {
+                count: v.count,
+                ttl: v.ttl - now,
+            }))
+    }
+
+    async fn list_keys(&self, prefix: &str) -> Result<Vec<String>, Self::Error> {
+        let now = self.clock.now();
+        let mut keys = self
+            .map
+            .iter()
+            .filter(|entry| entry.ttl > now && entry.key().starts_with(prefix))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn key_count(&self) -> Result<u64, Self::Error> {
+        let now = self.clock.now();
+        Ok(self.map.iter().filter(|entry| entry.ttl > now).count() as u64)
+    }
+}
+
+impl<S: BuildHasher + Clone + 'static> ReservableBackend for InMemoryBackend<String, S> {
+    type Reservation = SimpleReservation;
+
+    async fn reserve(
+        &self,
+        input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::Reservation), Self::Error> {
+        let (allow, output, token) = self.request(input).await?;
+        let reservation = SimpleReservation {
+            key: token.key,
+            cost: token.cost,
+            reset: output.reset,
+        };
+        Ok((allow, output, reservation))
+    }
+
+    async fn commit(
+        &self,
+        reservation: Self::Reservation,
+        actual_cost: u64,
+    ) -> Result<(), Self::Error> {
+        let now = self.clock.now();
+        if let Some(mut value) = self.map.get_mut(&reservation.key) {
+            // Only settle the interval that was charged, not one opened since
+            if value.ttl == reservation.reset && value.ttl > now {
+                value.count =
+                    (value.count.saturating_sub(reservation.cost)).saturating_add(actual_cost);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: BuildHasher + Clone + 'static> BanStore for InMemoryBackend<String, S> {
+    type Error = BackendError;
+
+    async fn ban(&self, key: &str, duration: Duration) -> Result<(), Self::Error> {
+        let expiry = expiry(self.clock.now(), duration, "Ban duration")?;
+        self.bans.insert(key.to_owned(), expiry);
+        Ok(())
+    }
+
+    async fn unban(&self, key: &str) -> Result<(), Self::Error> {
+        self.bans.remove(key);
+        Ok(())
+    }
+
+    async fn is_banned(&self, key: &str) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        Ok(self.bans.get(key).is_some_and(|expiry| *expiry > now))
+    }
+}
+
+// Spawns the garbage collector, returning its handle unless it is owned by a supervisor
+pub(crate) type StartGc = Box<dyn FnOnce() -> Option<JoinHandle<()>> + Send>;
+
+// The garbage collector task, shared by every clone of a backend, and aborted once the last clone
+// is dropped.
+//
+// The task is spawned onto the current tokio runtime (which need not be an Actix runtime), or if
+// the backend is built outside of any runtime, by the first request made within one.
+pub(crate) struct GcTask {
+    // Set once the task no longer needs starting, so that requests can check without locking
+    started: AtomicBool,
+    state: Mutex<GcState>,
+}
+
+struct GcState {
+    start: Option<StartGc>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl GcTask {
+    pub(crate) fn new(start: StartGc) -> Self {
+        let task = Self {
+            started: AtomicBool::new(false),
+            state: Mutex::new(GcState {
+                start: Some(start),
+                handle: None,
+            }),
+        };
+        task.start();
+        task
+    }
+
+    // Spawn `gc` under `supervisor`, or if there is none, abort it with the last clone of the
+    // backend
+    pub(crate) fn spawn<F, Fut>(supervisor: Option<Supervisor>, gc: F) -> Self
+    where
+        F: FnOnce(Option<ShutdownSignal>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self::new(Box::new(move || match supervisor {
+            Some(supervisor) => {
+                supervisor.spawn_send(move |shutdown| gc(Some(shutdown)));
+                None
+            }
+            None => Some(tokio::spawn(gc(None))),
+        }))
+    }
+
+    pub(crate) fn start(&self) {
+        if self.started.load(Ordering::Acquire) || Handle::try_current().is_err() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(start) = state.start.take() {
+            state.handle = start();
+        }
+        self.started.store(true, Ordering::Release);
+    }
+
+    pub(crate) async fn close(&self) {
+        let handle = {
+            let mut state = self.state.lock().unwrap();
+            state.start = None;
+            self.started.store(true, Ordering::Release);
+            state.handle.take()
+        };
+        if let Some(handle) = handle {
+            handle.abort();
+            // The only possible error is that the task was cancelled
+            let _ = handle.await;
+        }
+    }
+}
+
+// Wait for `wait` to complete, returning false instead if the supervisor is shut down first
+pub(crate) async fn wait_unless_shutdown(
+    wait: impl Future<Output = ()>,
+    shutdown: &mut Option<ShutdownSignal>,
+) -> bool {
+    match shutdown {
+        None => {
+            wait.await;
+            true
+        }
+        Some(shutdown) => {
+            tokio::select! {
+                _ = wait => true,
+                _ = shutdown.recv() => false,
+            }
+        }
+    }
 }
 
-impl Drop for InMemoryBackend {
+impl Drop for GcTask {
     fn drop(&mut self) {
-        if let Some(handle) = &self.gc_handle {
+        if let Some(handle) = self.state.get_mut().unwrap().handle.take() {
             handle.abort();
         }
     }
@@ -138,9 +1025,19 @@ impl Drop for InMemoryBackend {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::clock::ManualClock;
+    use crate::backend::KeyedInput;
+    use crate::HeaderCompatibleOutput;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
 
     const MINUTE: Duration = Duration::from_secs(60);
 
+    // The garbage collector yields between shards, wait until it is idle again
+    async fn finish_gc() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
     #[actix_web::test]
     async fn test_allow_deny() {
         tokio::time::pause();
@@ -149,6 +1046,7 @@ mod tests {
             interval: MINUTE,
             max_requests: 5,
             key: "KEY1".to_string(),
+            cost: 1,
         };
         for _ in 0..5 {
             // First 5 should be allowed
@@ -160,6 +1058,28 @@ mod tests {
         assert!(!allow);
     }
 
+    #[actix_web::test]
+    async fn test_interval_overflow() {
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let input = SimpleInput {
+            interval: Duration::MAX,
+            max_requests: 1,
+            key: "KEY1".to_string(),
+            cost: 1,
+        };
+        let err = backend.request(input.clone()).await.unwrap_err();
+        assert_eq!(err.kind(), "overflow");
+        assert!(backend.peek(&input).await.is_err());
+        assert!(backend.set_key("KEY1", 1, Duration::MAX).await.is_err());
+        assert!(backend.ban("KEY1", Duration::MAX).await.is_err());
+        // The backend remains usable
+        let input = SimpleInput {
+            interval: MINUTE,
+            ..input
+        };
+        assert!(backend.request(input).await.unwrap().0);
+    }
+
     #[actix_web::test]
     async fn test_reset() {
         tokio::time::pause();
@@ -168,6 +1088,7 @@ mod tests {
             interval: MINUTE,
             max_requests: 1,
             key: "KEY1".to_string(),
+            cost: 1,
         };
         // Make first request, should be allowed
         let (allow, _, _) = backend.request(input.clone()).await.unwrap();
@@ -194,6 +1115,7 @@ mod tests {
                 interval: MINUTE,
                 max_requests: 1,
                 key: "KEY1".to_string(),
+                cost: 1,
             })
             .await
             .unwrap();
@@ -202,6 +1124,7 @@ mod tests {
                 interval: MINUTE * 2,
                 max_requests: 1,
                 key: "KEY2".to_string(),
+                cost: 1,
             })
             .await
             .unwrap();
@@ -210,21 +1133,413 @@ mod tests {
         // Advance time such that the garbage collector runs,
         // expired KEY1 should be cleaned, but KEY2 should remain.
         tokio::time::advance(MINUTE).await;
+        finish_gc().await;
         assert!(!backend.map.contains_key("KEY1"));
         assert!(backend.map.contains_key("KEY2"));
     }
 
     #[actix_web::test]
-    async fn test_output() {
+    async fn test_gc_max_keys() {
         tokio::time::pause();
-        let backend = InMemoryBackend::builder().build();
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(Some(MINUTE * 60))
+            .with_gc_max_keys(2)
+            .build();
+        // Let the garbage collector make its first run
+        tokio::task::yield_now().await;
+        for key in ["KEY1", "KEY2"] {
+            let input = SimpleInput {
+                interval: Duration::from_secs(1),
+                max_requests: 1,
+                key: key.to_string(),
+                cost: 1,
+            };
+            backend.request(input).await.unwrap();
+        }
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(backend.map.len(), 2);
         let input = SimpleInput {
             interval: MINUTE,
-            max_requests: 2,
-            key: "KEY1".to_string(),
+            max_requests: 1,
+            key: "KEY3".to_string(),
+            cost: 1,
         };
-        // First of 2 should be allowed.
-        let (allow, output, _) = backend.request(input.clone()).await.unwrap();
+        backend.request(input).await.unwrap();
+        finish_gc().await;
+        assert_eq!(backend.map.len(), 1);
+        assert!(backend.map.contains_key("KEY3"));
+    }
+
+    #[actix_web::test]
+    async fn test_hasher() {
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .with_hasher(BuildHasherDefault::<DefaultHasher>::default())
+            .with_shard_amount(64)
+            .build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 1,
+            key: "KEY1".to_string(),
+            cost: 1,
+        };
+        let (allow, _, _) = backend.request(input.clone()).await.unwrap();
+        assert!(allow);
+        let (allow, _, _) = backend.request(input).await.unwrap();
+        assert!(!allow);
+        assert_eq!(backend.map.shards().len(), 64);
+        backend.remove_key("KEY1").await.unwrap();
+        assert!(backend.map.is_empty());
+    }
+
+    #[test]
+    fn test_build_outside_runtime() {
+        let backend = InMemoryBackend::builder().build();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            assert_eq!(backend.stats().gc_runs, 0);
+            // The garbage collector is started by the first request, on a plain tokio runtime
+            let input = SimpleInput {
+                interval: MINUTE,
+                max_requests: 1,
+                key: "KEY1".to_string(),
+                cost: 1,
+            };
+            backend.request(input).await.unwrap();
+            finish_gc().await;
+            assert_eq!(backend.stats().gc_runs, 1);
+        });
+    }
+
+    #[actix_web::test]
+    async fn test_lazy_expiry() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder()
+            .with_lazy_expiry()
+            .with_shard_amount(2)
+            .build();
+        assert!(backend.gc_handle.is_none());
+        let input = SimpleInput {
+            interval: Duration::from_secs(1),
+            max_requests: 1,
+            key: "KEY1".to_string(),
+            cost: 1,
+        };
+        backend.request(input).await.unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        // Enough requests to sweep both shards
+        for _ in 0..LAZY_EXPIRY_PERIOD * 2 {
+            let input = SimpleInput {
+                interval: MINUTE,
+                max_requests: 1,
+                key: "KEY2".to_string(),
+                cost: 1,
+            };
+            backend.request(input).await.unwrap();
+        }
+        assert!(!backend.map.contains_key("KEY1"));
+        assert!(backend.map.contains_key("KEY2"));
+    }
+
+    #[actix_web::test]
+    async fn test_gc_outlives_clones() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(Some(MINUTE))
+            .build();
+        finish_gc().await;
+        // Dropping a clone doesn't stop the garbage collector
+        drop(backend.clone());
+        tokio::time::advance(MINUTE).await;
+        finish_gc().await;
+        assert_eq!(backend.stats().gc_runs, 2);
+        // But closing the backend does
+        backend.close().await;
+        tokio::time::advance(MINUTE).await;
+        finish_gc().await;
+        assert_eq!(backend.stats().gc_runs, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .with_clock(clock.clone())
+            .build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 1,
+            key: "KEY1".to_string(),
+            cost: 1,
+        };
+        let (allow, output, _) = backend.request(input.clone()).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.reset, clock.now() + MINUTE);
+        clock.advance(MINUTE - Duration::from_nanos(1));
+        let (allow, _, _) = backend.request(input.clone()).await.unwrap();
+        assert!(!allow);
+        // The window ends exactly on the boundary
+        clock.advance(Duration::from_nanos(1));
+        let (allow, _, _) = backend.request(input).await.unwrap();
+        assert!(allow);
+    }
+
+    #[actix_web::test]
+    async fn test_carry_over() {
+        let clock = ManualClock::new();
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .with_clock(clock.clone())
+            .with_carry_over(0.75, 3)
+            .build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 4,
+            key: "KEY1".to_string(),
+            cost: 1,
+        };
+        // A new key has been idle, so gets the maximum burst
+        let (_, output, _) = backend.request(input.clone()).await.unwrap();
+        assert_eq!(output.limit, 7);
+        for _ in 0..6 {
+            backend.request(input.clone()).await.unwrap();
+        }
+        let (allow, _, _) = backend.request(input.clone()).await.unwrap();
+        assert!(!allow);
+        // Nothing was left unused
+        clock.advance(MINUTE);
+        let (_, output, _) = backend.request(input.clone()).await.unwrap();
+        assert_eq!(output.limit, 4);
+        assert_eq!(output.remaining, 3);
+        // Three quarters of the 3 left unused is carried over, rounded down
+        clock.advance(MINUTE);
+        let (allow, output, _) = backend.request(input.clone()).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.limit, 6);
+        let (_, output) = backend.peek(&input).await.unwrap();
+        assert_eq!(output.remaining, 5);
+        // A window that has been skipped entirely was left unused
+        clock.advance(MINUTE * 2);
+        let (_, output, _) = backend.request(input).await.unwrap();
+        assert_eq!(output.limit, 7);
+    }
+
+    #[actix_web::test]
+    async fn test_export_import() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        for (key, interval) in [("KEY1", MINUTE), ("KEY2", Duration::from_secs(1))] {
+            let input = SimpleInput {
+                interval,
+                max_requests: 5,
+                key: key.to_string(),
+                cost: 2,
+            };
+            backend.request(input).await.unwrap();
+        }
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let snapshot = backend.export();
+        assert_eq!(
+            snapshot.entries,
+            [SnapshotEntry {
+                key: "KEY1".to_string(),
+                count: 2,
+                ttl: Duration::from_secs(50),
+            }]
+        );
+        #[cfg(feature = "serde")]
+        let snapshot = serde_json::from_value(serde_json::to_value(snapshot).unwrap()).unwrap();
+
+        let restored = InMemoryBackend::builder().with_gc_interval(None).build();
+        restored.import(snapshot).unwrap();
+        let status = restored.key_status("KEY1").await.unwrap().unwrap();
+        assert_eq!(status.count, 2);
+        assert_eq!(status.ttl, Duration::from_secs(50));
+
+        // A snapshot with a TTL too large to represent is rejected as a whole
+        let corrupted = MemorySnapshot {
+            entries: vec![
+                SnapshotEntry {
+                    key: "KEY2".to_string(),
+                    count: 1,
+                    ttl: MINUTE,
+                },
+                SnapshotEntry {
+                    key: "KEY3".to_string(),
+                    count: 1,
+                    ttl: Duration::MAX,
+                },
+            ],
+        };
+        assert_eq!(restored.import(corrupted).unwrap_err().kind(), "overflow");
+        assert!(!restored.map.contains_key("KEY2"));
+    }
+
+    #[actix_web::test]
+    async fn test_stats() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(Some(MINUTE))
+            .build();
+        finish_gc().await;
+        for key in ["KEY1", "KEY2"] {
+            let input = SimpleInput {
+                interval: Duration::from_secs(1),
+                max_requests: 1,
+                key: key.to_string(),
+                cost: 1,
+            };
+            backend.request(input).await.unwrap();
+        }
+        let stats = backend.stats();
+        assert_eq!(stats.keys, 2);
+        assert!(stats.approximate_bytes > 0);
+        assert_eq!(stats.gc_runs, 1);
+        assert_eq!(stats.last_gc_evicted, 0);
+        tokio::time::advance(MINUTE).await;
+        finish_gc().await;
+        let stats = backend.stats();
+        assert_eq!(stats.keys, 0);
+        assert_eq!(stats.gc_runs, 2);
+        assert_eq!(stats.last_gc_evicted, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_max_keys() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .with_max_keys(2)
+            .build();
+        for (key, interval) in [("KEY1", MINUTE * 2), ("KEY2", MINUTE), ("KEY3", MINUTE * 3)] {
+            let input = SimpleInput {
+                interval,
+                max_requests: 1,
+                key: key.to_string(),
+                cost: 1,
+            };
+            backend.request(input).await.unwrap();
+        }
+        // KEY2 expires first, so is evicted, along with KEY1 to leave room for the next key
+        assert_eq!(backend.map.len(), 1);
+        assert!(backend.map.contains_key("KEY3"));
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 1,
+            key: "KEY5".to_string(),
+            cost: 1,
+        };
+        backend.request(input).await.unwrap();
+        assert_eq!(backend.map.len(), 2);
+        // Expired keys (KEY5) are removed before any others
+        tokio::time::advance(MINUTE * 2).await;
+        let input = SimpleInput {
+            interval: Duration::from_secs(1),
+            max_requests: 1,
+            key: "KEY4".to_string(),
+            cost: 1,
+        };
+        backend.request(input).await.unwrap();
+        assert_eq!(backend.map.len(), 2);
+        assert!(backend.map.contains_key("KEY3"));
+        assert!(backend.map.contains_key("KEY4"));
+    }
+
+    #[actix_web::test]
+    async fn test_max_keys_same_expiry() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .with_max_keys(200)
+            .build();
+        for i in 0..201 {
+            let input = SimpleInput {
+                interval: MINUTE,
+                max_requests: 1,
+                key: format!("KEY{i}"),
+                cost: 1,
+            };
+            backend.request(input).await.unwrap();
+        }
+        // Only the excess and the 1% slack are evicted, even though every key expires together
+        assert_eq!(backend.map.len(), 198);
+        assert!(backend.map.contains_key("KEY200"));
+    }
+
+    #[actix_web::test]
+    async fn test_supervised_garbage_collection() {
+        tokio::time::pause();
+        let supervisor = Supervisor::new();
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(Some(MINUTE))
+            .with_supervisor(supervisor.clone())
+            .build();
+        backend
+            .request(SimpleInput {
+                interval: MINUTE,
+                max_requests: 1,
+                key: "KEY1".to_string(),
+                cost: 1,
+            })
+            .await
+            .unwrap();
+        tokio::time::advance(MINUTE).await;
+        finish_gc().await;
+        assert!(!backend.map.contains_key("KEY1"));
+        // Once shut down the garbage collector should no longer run
+        supervisor.shutdown().await;
+        backend
+            .request(SimpleInput {
+                interval: MINUTE,
+                max_requests: 1,
+                key: "KEY2".to_string(),
+                cost: 1,
+            })
+            .await
+            .unwrap();
+        tokio::time::advance(MINUTE * 2).await;
+        assert!(backend.map.contains_key("KEY2"));
+    }
+
+    #[actix_web::test]
+    async fn test_supervisor_dropped() {
+        tokio::time::pause();
+        // No other handle to the supervisor is kept
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(Some(MINUTE))
+            .with_supervisor(Supervisor::new())
+            .build();
+        backend
+            .request(SimpleInput {
+                interval: MINUTE,
+                max_requests: 1,
+                key: "KEY1".to_string(),
+                cost: 1,
+            })
+            .await
+            .unwrap();
+        finish_gc().await;
+        tokio::time::advance(MINUTE).await;
+        finish_gc().await;
+        assert!(!backend.map.contains_key("KEY1"));
+    }
+
+    #[actix_web::test]
+    async fn test_output() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder().build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 2,
+            key: "KEY1".to_string(),
+            cost: 1,
+        };
+        // First of 2 should be allowed.
+        let (allow, output, _) = backend.request(input.clone()).await.unwrap();
         assert!(allow);
         assert_eq!(output.remaining, 1);
         assert_eq!(output.limit, 2);
@@ -251,6 +1566,7 @@ mod tests {
             interval: MINUTE,
             max_requests: 5,
             key: "KEY1".to_string(),
+            cost: 1,
         };
         let (_, output, rollback) = backend.request(input.clone()).await.unwrap();
         assert_eq!(output.remaining, 4);
@@ -260,6 +1576,31 @@ mod tests {
         assert_eq!(output.remaining, 4);
     }
 
+    #[actix_web::test]
+    async fn test_cost() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder().build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 10,
+            key: "KEY1".to_string(),
+            cost: 4,
+        };
+        let (allow, output, rollback) = backend.request(input.clone()).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 6);
+        let (allow, output, _) = backend.request(input.clone()).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 2);
+        // Would exceed the limit
+        let (allow, output, _) = backend.request(input.clone()).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.remaining, 0);
+        // Rolling back should refund the full cost
+        backend.rollback(rollback).await.unwrap();
+        assert_eq!(backend.map.get("KEY1").unwrap().count, 8);
+    }
+
     #[actix_web::test]
     async fn test_remove_key() {
         tokio::time::pause();
@@ -268,6 +1609,7 @@ mod tests {
             interval: MINUTE,
             max_requests: 1,
             key: "KEY1".to_string(),
+            cost: 1,
         };
         let (allow, _, _) = backend.request(input.clone()).await.unwrap();
         assert!(allow);
@@ -278,4 +1620,263 @@ mod tests {
         let (allow, _, _) = backend.request(input).await.unwrap();
         assert!(allow);
     }
+
+    #[actix_web::test]
+    async fn test_remove_keys() {
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        for key in ["user:1", "user:2", "other"] {
+            let input = SimpleInput {
+                interval: MINUTE,
+                max_requests: 5,
+                key: key.to_string(),
+                cost: 1,
+            };
+            backend.request(input).await.unwrap();
+        }
+        backend.ban("user:1", MINUTE).await.unwrap();
+        assert_eq!(backend.remove_keys("user:").await.unwrap(), 2);
+        assert!(!backend.map.contains_key("user:1"));
+        assert!(backend.map.contains_key("other"));
+        assert_eq!(backend.clear().await.unwrap(), 1);
+        assert!(backend.map.is_empty());
+        // Bans are kept
+        assert!(backend.is_banned("user:1").await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_inspect() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        for (key, interval) in [
+            ("user:1", MINUTE),
+            ("user:2", MINUTE * 2),
+            ("other", MINUTE),
+        ] {
+            let input = SimpleInput {
+                interval,
+                max_requests: 5,
+                key: key.to_string(),
+                cost: 2,
+            };
+            backend.request(input).await.unwrap();
+        }
+        assert_eq!(
+            backend.key_status("user:1").await.unwrap(),
+            Some(KeyStatus {
+                count: 2,
+                ttl: MINUTE
+            })
+        );
+        assert_eq!(backend.key_status("user:3").await.unwrap(), None);
+        assert_eq!(
+            backend.list_keys("user:").await.unwrap(),
+            ["user:1", "user:2"]
+        );
+        assert_eq!(backend.key_count().await.unwrap(), 3);
+        // Expired keys are not included
+        tokio::time::advance(MINUTE).await;
+        assert_eq!(backend.key_status("user:1").await.unwrap(), None);
+        assert_eq!(backend.list_keys("user:").await.unwrap(), ["user:2"]);
+        assert_eq!(backend.key_count().await.unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_peek() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 2,
+            key: "KEY1".to_string(),
+            cost: 1,
+        };
+        let (allow, output) = backend.peek(&input).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 2);
+        assert_eq!(output.seconds_until_reset(), 60);
+        backend.request(input.clone()).await.unwrap();
+        backend.request(input.clone()).await.unwrap();
+        let (allow, output) = backend.peek(&input).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.remaining, 0);
+        // Peeking does not count towards the limit
+        assert_eq!(backend.key_status("KEY1").await.unwrap().unwrap().count, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_set_key() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 5,
+            key: "KEY1".to_string(),
+            cost: 1,
+        };
+        backend.request(input.clone()).await.unwrap();
+        backend.set_key("KEY1", 4, MINUTE * 2).await.unwrap();
+        let (allow, output, _) = backend.request(input.clone()).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 0);
+        assert_eq!(output.seconds_until_reset(), 120);
+        backend.set_key("KEY1", 0, Duration::ZERO).await.unwrap();
+        assert_eq!(backend.key_status("KEY1").await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_ban() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        backend.ban("KEY1", MINUTE).await.unwrap();
+        assert!(backend.is_banned("KEY1").await.unwrap());
+        assert!(!backend.is_banned("KEY2").await.unwrap());
+        tokio::time::advance(MINUTE).await;
+        assert!(!backend.is_banned("KEY1").await.unwrap());
+        backend.ban("KEY1", MINUTE).await.unwrap();
+        backend.unban("KEY1").await.unwrap();
+        assert!(!backend.is_banned("KEY1").await.unwrap());
+    }
+
+    #[test]
+    fn test_space_saving() {
+        let mut tracker = SpaceSaving::new(2);
+        for key in ["a", "a", "a", "b", "c", "a"].map(String::from) {
+            tracker.increment(&key);
+        }
+        // "c" replaced "b", inheriting its count
+        assert_eq!(tracker.top(5), [("a".to_string(), 4), ("c".to_string(), 2)]);
+        assert_eq!(tracker.top(1).len(), 1);
+
+        let mut tracker = SpaceSaving::new(3);
+        for key in [1, 2, 3, 1, 2, 1, 4, 4, 5] {
+            tracker.increment(&key);
+        }
+        // 4 replaced 3, then 5 replaced 2 at the lowest count of 2
+        let mut top = tracker.top(5);
+        top.sort();
+        assert_eq!(top, [(1, 3), (4, 3), (5, 3)]);
+        for (key, (count, index)) in &tracker.counts {
+            assert_eq!(tracker.buckets[count][*index], *key);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_top_offenders() {
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .track_top_offenders(10)
+            .build();
+        for (key, requests) in [("KEY1", 5), ("KEY2", 3), ("KEY3", 1)] {
+            let input = SimpleInput {
+                interval: MINUTE,
+                max_requests: 1,
+                key: key.to_string(),
+                cost: 1,
+            };
+            for _ in 0..requests {
+                backend.request(input.clone()).await.unwrap();
+            }
+        }
+        assert_eq!(
+            backend.top_offenders(10),
+            [("KEY1".to_string(), 4), ("KEY2".to_string(), 2)]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_request_many() {
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let inputs = [("KEY1", 1), ("KEY2", 5)]
+            .into_iter()
+            .map(|(key, max_requests)| SimpleInput {
+                interval: MINUTE,
+                max_requests,
+                key: key.to_string(),
+                cost: 1,
+            })
+            .collect::<Vec<_>>();
+        let results = backend.request_many(inputs.clone()).await.unwrap();
+        assert!(results.iter().all(|(allow, _, _)| *allow));
+        let results = backend.request_many(inputs).await.unwrap();
+        assert!(!results[0].0);
+        assert!(results[1].0);
+        assert_eq!(results[1].1.remaining, 3);
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_commit() {
+        tokio::time::pause();
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 10,
+            key: "KEY1".to_string(),
+            cost: 5,
+        };
+        let (allow, output, reservation) = backend.reserve(input.clone()).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 5);
+        // Refund
+        backend.commit(reservation, 2).await.unwrap();
+        assert_eq!(backend.key_status("KEY1").await.unwrap().unwrap().count, 2);
+        // Charge extra
+        let (_, _, reservation) = backend.reserve(input.clone()).await.unwrap();
+        backend.commit(reservation, 8).await.unwrap();
+        assert_eq!(backend.key_status("KEY1").await.unwrap().unwrap().count, 10);
+        // Nothing changes after the interval resets
+        let (_, _, reservation) = backend.reserve(input.clone()).await.unwrap();
+        tokio::time::advance(MINUTE).await;
+        backend.commit(reservation, 0).await.unwrap();
+        assert_eq!(backend.map.get("KEY1").unwrap().count, 15);
+        // Even once a later request has opened a new interval
+        let (_, _, reservation) = backend.reserve(input.clone()).await.unwrap();
+        tokio::time::advance(MINUTE).await;
+        backend.request(input).await.unwrap();
+        backend.commit(reservation, 0).await.unwrap();
+        assert_eq!(backend.key_status("KEY1").await.unwrap().unwrap().count, 5);
+    }
+
+    #[actix_web::test]
+    async fn test_keyed() {
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .track_top_offenders(10)
+            .build_keyed::<(u64, u16)>();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 1,
+            key: (42, 8080),
+            cost: 1,
+        };
+        let (allow, _, token) = backend.request(input.clone()).await.unwrap();
+        assert!(allow);
+        assert_eq!(token.key, (42, 8080));
+        let (allow, _, denied_token) = backend.request(input.clone()).await.unwrap();
+        assert!(!allow);
+        assert_eq!(backend.top_offenders(1), [((42, 8080), 1)]);
+        backend.rollback(token).await.unwrap();
+        backend.rollback(denied_token).await.unwrap();
+        let (allow, _, _) = backend.request(input).await.unwrap();
+        assert!(allow);
+    }
+
+    #[actix_web::test]
+    async fn test_shared_keys() {
+        let backend = InMemoryBackend::builder()
+            .with_gc_interval(None)
+            .build_keyed::<Arc<str>>();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 5,
+            key: Arc::<str>::from("KEY1"),
+            cost: 1,
+        };
+        assert_eq!(input.key(), "KEY1");
+        for _ in 0..2 {
+            let (_, _, token) = backend.request(input.clone()).await.unwrap();
+            assert!(Arc::ptr_eq(&token.key, &input.key));
+        }
+        let stored = backend.map.iter().next().unwrap().key().clone();
+        assert!(Arc::ptr_eq(&stored, &input.key));
+    }
 }
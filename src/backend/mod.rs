@@ -1,30 +1,91 @@
+mod boxed;
+pub mod clock;
+mod coalescing;
+mod deny_cache;
+mod error;
+mod fair_share;
+mod hashed;
 mod input_builder;
+mod instrumented;
+mod lockout;
+pub mod overrides;
+mod partitioned;
+mod policy;
+pub mod provider;
+pub(crate) mod rate;
+pub mod schedule;
+pub mod tier;
+
+#[cfg(feature = "dashmap")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dashmap")))]
+pub mod atomic;
+
+#[cfg(feature = "bypass")]
+#[cfg_attr(docsrs, doc(cfg(feature = "bypass")))]
+pub mod bypass;
+
+#[cfg(feature = "cluster")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cluster")))]
+pub mod cluster;
+
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub mod config;
 
 #[cfg(feature = "dashmap")]
 #[cfg_attr(docsrs, doc(cfg(feature = "dashmap")))]
 pub mod memory;
 
+#[cfg(feature = "moka")]
+#[cfg_attr(docsrs, doc(cfg(feature = "moka")))]
+pub mod moka;
+
 #[cfg(feature = "redis")]
 #[cfg_attr(docsrs, doc(cfg(feature = "redis")))]
 pub mod redis;
 
-pub use input_builder::{SimpleInputFunctionBuilder, SimpleInputFuture};
+#[cfg(feature = "dashmap")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dashmap")))]
+pub mod sliding;
+
+#[cfg(feature = "dashmap")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dashmap")))]
+pub mod token_bucket;
+
+pub use boxed::{ArcBackend, BoxBackend};
+pub use coalescing::CoalescingBackend;
+pub use deny_cache::DenyCacheBackend;
+pub use error::BackendError;
+pub use fair_share::FairShareBackend;
+pub use hashed::HashedKeyBackend;
+#[cfg(feature = "macros")]
+pub(crate) use input_builder::Error as InputError;
+pub(crate) use input_builder::{ip_key, IpPrefix};
+pub use input_builder::{
+    InputBuildError, MissingKeyPolicy, PeerCertificate, PolicyDecision, Priority,
+    SimpleInputFunctionBuilder, SimpleInputFuture,
+};
+pub use instrumented::{BackendCall, InstrumentedBackend};
+pub use lockout::LockoutBackend;
+pub use partitioned::{InstanceCount, PartitionedBackend};
+pub use policy::{KeyStrategy, MatchMode, Policy, PolicyHandle, PolicyMap, DEFAULT_POLICY_NAME};
+pub use rate::{parse_rate, ParseRateError};
 
 use crate::HeaderCompatibleOutput;
 use actix_web::rt::time::Instant;
 use async_trait::async_trait;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 /// Describes an implementation of a rate limiting store and algorithm.
 ///
-/// To implement your own rate limiting backend it is recommended to use
-/// [async_trait](https://github.com/dtolnay/async-trait), and add the `#[async_trait(?Send)]`
-/// attribute onto your trait implementation.
+/// The trait uses native `async fn`, so implementations need no extra attributes. The futures are
+/// not required to be [Send], as actix-web runs each worker on a single thread; see
+/// [SendBackend] for backends that need them to be.
 ///
 /// A Backend is required to implement [Clone], usually this means wrapping your data store within
 /// an [Arc](std::sync::Arc), although many connection pools already do so internally; there is no
 /// need to wrap it twice.
-#[async_trait(?Send)]
+#[allow(async_fn_in_trait)]
 pub trait Backend<I: 'static = SimpleInput>: Clone {
     type Output;
     type RollbackToken;
@@ -56,19 +117,189 @@ pub trait Backend<I: 'static = SimpleInput>: Clone {
     ///
     /// * `token`: The token returned from the initial call to [Backend::request()].
     async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error>;
+
+    /// The cost that rolling back `token` would refund, if known; used to adjust the remaining
+    /// requests reported after a rollback, see
+    /// [add_headers](crate::RateLimiterBuilder::add_headers).
+    ///
+    /// The default implementation returns [None], in which case a cost of 1 is assumed.
+    fn rollback_cost(&self, _token: &Self::RollbackToken) -> Option<u64> {
+        None
+    }
+
+    /// Process several inputs for the same incoming request, e.g. a global, a per-IP and a
+    /// per-endpoint limit, returning a result for each in the same order.
+    ///
+    /// The default implementation calls [Backend::request] for each input in turn, stopping at
+    /// the first error; backends may override it to use fewer round trips.
+    async fn request_many(
+        &self,
+        inputs: Vec<I>,
+    ) -> Result<Vec<(bool, Self::Output, Self::RollbackToken)>, Self::Error> {
+        let mut results = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            results.push(self.request(input).await?);
+        }
+        Ok(results)
+    }
+
+    /// Checks that the backend is able to process requests, e.g. that its data store is
+    /// reachable, see [readiness_route](crate::health::readiness_route).
+    ///
+    /// The default implementation always succeeds with zero latency, which is suitable for
+    /// backends that do not depend on anything external.
+    async fn health(&self) -> Result<Health, Self::Error> {
+        Ok(Health {
+            latency: Duration::ZERO,
+        })
+    }
+}
+
+/// A variant of [Backend] whose futures are [Send], for backends built on clients that require
+/// it, e.g. those that spawn their requests onto a multi-threaded runtime.
+///
+/// Use `#[async_trait]` (without `?Send`) on your trait implementation, then wrap the backend in
+/// a [SendBackendAdapter] to give it to the [RateLimiter](crate::RateLimiter).
+#[async_trait]
+pub trait SendBackend<I: Send + 'static = SimpleInput>: Clone + Send + Sync {
+    type Output: Send;
+    type RollbackToken: Send;
+    type Error: Send;
+
+    /// See [Backend::request].
+    async fn request(
+        &self,
+        input: I,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error>;
+
+    /// See [Backend::rollback].
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error>;
+
+    /// See [Backend::rollback_cost].
+    fn rollback_cost(&self, _token: &Self::RollbackToken) -> Option<u64> {
+        None
+    }
+
+    /// See [Backend::health].
+    async fn health(&self) -> Result<Health, Self::Error> {
+        Ok(Health {
+            latency: Duration::ZERO,
+        })
+    }
+}
+
+/// Implements [Backend] for a [SendBackend].
+#[derive(Debug, Clone)]
+pub struct SendBackendAdapter<B>(pub B);
+
+impl<I, B> Backend<I> for SendBackendAdapter<B>
+where
+    I: Send + 'static,
+    B: SendBackend<I>,
+{
+    type Output = B::Output;
+    type RollbackToken = B::RollbackToken;
+    type Error = B::Error;
+
+    async fn request(
+        &self,
+        input: I,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        self.0.request(input).await
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        self.0.rollback(token).await
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        self.0.rollback_cost(token)
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.0.health().await
+    }
+}
+
+/// The result of a successful [Backend::health] check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// The round trip time to the backend's data store.
+    pub latency: Duration,
 }
 
 /// A default [Backend] Input structure.
 ///
 /// This may not be suitable for all use-cases.
+///
+/// Keys are [String]s by default, but backends may support other key types, e.g. a tuple or a
+/// prehashed `u64`, to avoid formatting a string for every request; see
+/// [memory::Builder::build_keyed].
 #[derive(Debug, Clone)]
-pub struct SimpleInput {
+pub struct SimpleInput<K = String> {
     /// The rate limiting interval.
     pub interval: Duration,
     /// The total requests to be allowed within the interval.
     pub max_requests: u64,
     /// The rate limit key to be used for this request.
-    pub key: String,
+    pub key: K,
+    /// The amount this request counts towards the limit, usually 1.
+    pub cost: u64,
+}
+
+/// A [Backend] input that identifies the rate limit key it applies to.
+///
+/// This is required by features of the middleware that report on individual keys, such as
+/// [RateLimiterBuilder::deny_events](crate::RateLimiterBuilder::deny_events).
+pub trait KeyedInput {
+    fn key(&self) -> &str;
+
+    /// The amount the request counts towards the limit, 1 unless overridden.
+    fn cost(&self) -> u64 {
+        1
+    }
+}
+
+impl<K: AsRef<str>> KeyedInput for SimpleInput<K> {
+    fn key(&self) -> &str {
+        self.key.as_ref()
+    }
+
+    fn cost(&self) -> u64 {
+        self.cost
+    }
+}
+
+/// A default [Backend::RollbackToken] for backends that use [SimpleInput].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimpleRollbackToken<K = String> {
+    /// The rate limit key that was charged.
+    pub key: K,
+    /// The amount that was charged, and that should be refunded.
+    pub cost: u64,
+}
+
+/// A [Backend::RollbackToken] that can roll back part of the charged cost, as required by
+/// [RateLimiterBuilder::refund_handle](crate::RateLimiterBuilder::refund_handle).
+pub trait PartialRollbackToken {
+    /// The cost that would be rolled back by this token.
+    fn cost(&self) -> u64;
+
+    /// Create a token that rolls back only `cost`.
+    fn with_cost(&self, cost: u64) -> Self;
+}
+
+impl<K: Clone> PartialRollbackToken for SimpleRollbackToken<K> {
+    fn cost(&self) -> u64 {
+        self.cost
+    }
+
+    fn with_cost(&self, cost: u64) -> Self {
+        Self {
+            key: self.key.clone(),
+            cost,
+        }
+    }
 }
 
 /// A default [Backend::Output] structure.
@@ -81,16 +312,129 @@ pub struct SimpleOutput {
     /// Number of requests that will be permitted until the limit resets.
     pub remaining: u64,
     /// Time at which the rate limit resets.
+    ///
+    /// See [SimpleOutput::reset_at] for the wall-clock time.
     pub reset: Instant,
 }
 
+impl SimpleOutput {
+    /// The wall-clock time at which the rate limit resets, see [clock::system_time].
+    pub fn reset_at(&self) -> SystemTime {
+        clock::system_time(self.reset)
+    }
+}
+
 /// Additional functions for a [Backend] that uses [SimpleInput] and [SimpleOutput].
-#[async_trait(?Send)]
+#[allow(async_fn_in_trait)]
 pub trait SimpleBackend: Backend<SimpleInput, Output = SimpleOutput> {
     /// Removes the bucket for a given rate limit key.
     ///
     /// Intended to be used to reset a key before changing the interval.
     async fn remove_key(&self, key: &str) -> Result<(), Self::Error>;
+
+    /// Reports whether a request would be allowed, and the quota that would remain, without
+    /// counting it; e.g. to show a user how many requests they have left.
+    ///
+    /// The remaining quota in the output is that before this request would be counted.
+    async fn peek(&self, input: &SimpleInput) -> Result<(bool, SimpleOutput), Self::Error>;
+
+    /// Overwrites the count and TTL of a key, e.g. to pre-charge or forgive part of a customer's
+    /// quota.
+    ///
+    /// A zero TTL removes the key.
+    async fn set_key(&self, key: &str, count: u64, ttl: Duration) -> Result<(), Self::Error>;
+
+    /// Removes the buckets for every key starting with `prefix`, returning the number removed;
+    /// e.g. to reset all of a tenant's keys. Bans are not affected.
+    async fn remove_keys(&self, prefix: &str) -> Result<u64, Self::Error>;
+
+    /// Removes every bucket, returning the number removed; e.g. to reset state between tests.
+    /// Bans are not affected.
+    async fn clear(&self) -> Result<u64, Self::Error> {
+        self.remove_keys("").await
+    }
+}
+
+/// A [Backend] that can charge an estimated cost up front, and settle the actual cost once it is
+/// known; e.g. the number of rows returned or bytes streamed by a handler.
+///
+/// A reservation that is never committed remains charged at the estimated cost.
+#[allow(async_fn_in_trait)]
+pub trait ReservableBackend<I: 'static = SimpleInput>: Backend<I> {
+    type Reservation;
+
+    /// Process an incoming request as in [Backend::request], charging the input's estimated cost.
+    async fn reserve(
+        &self,
+        input: I,
+    ) -> Result<(bool, Self::Output, Self::Reservation), Self::Error>;
+
+    /// Replace the estimated cost of a reservation with the actual cost, refunding or charging
+    /// the difference.
+    ///
+    /// Nothing is changed if the interval has since reset.
+    async fn commit(
+        &self,
+        reservation: Self::Reservation,
+        actual_cost: u64,
+    ) -> Result<(), Self::Error>;
+}
+
+/// A default [ReservableBackend::Reservation] for backends that use [SimpleInput].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimpleReservation {
+    /// The rate limit key that was charged.
+    pub key: String,
+    /// The estimated cost that was charged.
+    pub cost: u64,
+    /// The time at which the charged interval resets, so that a later interval isn't settled.
+    pub reset: Instant,
+}
+
+/// Manually banning keys for a period of time, independent of their rate limits.
+///
+/// When given to [RateLimiterBuilder::ban_store](crate::RateLimiterBuilder::ban_store), requests
+/// with a banned key are denied before the backend is consulted.
+#[allow(async_fn_in_trait)]
+pub trait BanStore {
+    type Error;
+
+    /// Ban a key for the given duration, replacing any existing ban.
+    async fn ban(&self, key: &str, duration: Duration) -> Result<(), Self::Error>;
+
+    /// Remove any ban on a key.
+    async fn unban(&self, key: &str) -> Result<(), Self::Error>;
+
+    /// Returns true if the key is currently banned.
+    async fn is_banned(&self, key: &str) -> Result<bool, Self::Error>;
+}
+
+/// The current state of a rate limit key, see [InspectableBackend].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyStatus {
+    /// The number of requests counted in the current interval.
+    pub count: u64,
+    /// Time until the key expires, and the count resets.
+    pub ttl: Duration,
+}
+
+/// Administrative functions for a [SimpleBackend], e.g. to answer whether a particular customer
+/// is being rate limited right now.
+///
+/// As with [SimpleBackend::remove_key], keys are given and returned without any prefix the
+/// backend itself may apply.
+#[allow(async_fn_in_trait)]
+pub trait InspectableBackend: SimpleBackend {
+    /// Returns the current state of a key, or [None] if it has no requests counted.
+    async fn key_status(&self, key: &str) -> Result<Option<KeyStatus>, Self::Error>;
+
+    /// Returns all the keys that begin with `prefix`.
+    ///
+    /// This may be slow for backends holding a large number of keys.
+    async fn list_keys(&self, prefix: &str) -> Result<Vec<String>, Self::Error>;
+
+    /// Returns the total number of keys.
+    async fn key_count(&self) -> Result<u64, Self::Error>;
 }
 
 impl HeaderCompatibleOutput for SimpleOutput {
@@ -111,6 +455,10 @@ impl HeaderCompatibleOutput for SimpleOutput {
             .as_millis() as f64;
         (millis / 1000f64).ceil() as u64
     }
+
+    fn reset_at(&self) -> SystemTime {
+        SimpleOutput::reset_at(self)
+    }
 }
 
 #[cfg(test)]
@@ -128,5 +476,45 @@ mod tests {
         tokio::time::advance(Duration::from_secs_f64(29.9)).await;
         // Verify rounded upwards from 30.1
         assert_eq!(output.seconds_until_reset(), 31);
+        assert_eq!(
+            output.reset_at(),
+            clock::system_time(Instant::now()) + Duration::from_secs_f64(30.1)
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct SpawningBackend(std::sync::Arc<std::sync::atomic::AtomicU64>);
+
+    #[async_trait]
+    impl SendBackend<u64> for SpawningBackend {
+        type Output = u64;
+        type RollbackToken = ();
+        type Error = tokio::task::JoinError;
+
+        async fn request(&self, max: u64) -> Result<(bool, u64, ()), Self::Error> {
+            let counter = self.0.clone();
+            let count = tokio::spawn(async move {
+                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1
+            })
+            .await?;
+            Ok((count <= max, count, ()))
+        }
+
+        async fn rollback(&self, _: ()) -> Result<(), Self::Error> {
+            self.0.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_send_backend() {
+        async fn request<B: Backend<u64, Output = u64, Error = tokio::task::JoinError>>(
+            backend: &B,
+        ) -> bool {
+            backend.request(1).await.unwrap().0
+        }
+        let backend = SendBackendAdapter(SpawningBackend::default());
+        assert!(request(&backend).await);
+        assert!(!request(&backend).await);
     }
 }
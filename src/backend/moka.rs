@@ -0,0 +1,286 @@
+use crate::backend::{
+    Backend, BackendError, SimpleBackend, SimpleInput, SimpleOutput, SimpleRollbackToken,
+};
+use ::moka::ops::compute::Op;
+use ::moka::sync::Cache;
+use ::moka::Expiry;
+use actix_web::rt::time::Instant;
+use std::time::Duration;
+
+/// A Fixed Window rate limiter [Backend] that stores keys in a [moka](https://docs.rs/moka)
+/// cache.
+///
+/// Each key expires from the cache when its window ends, so unlike the
+/// [InMemoryBackend](crate::backend::memory::InMemoryBackend) there is no garbage collector task
+/// to manage. The cache can also be bounded with [Builder::max_capacity], beyond which the keys
+/// least likely to be used again are evicted (using TinyLFU).
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::moka::MokaBackend;
+/// let backend = MokaBackend::builder().max_capacity(100_000).build();
+/// ```
+#[derive(Clone)]
+pub struct MokaBackend {
+    cache: Cache<String, Value>,
+}
+
+#[derive(Clone)]
+struct Value {
+    count: u64,
+    reset: Instant,
+    // The TTL to give the entry, when this value starts a new window (or is set by an admin)
+    ttl: Option<Duration>,
+}
+
+// Expires each entry at the end of its window
+struct WindowExpiry;
+
+impl Expiry<String, Value> for WindowExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        value: &Value,
+        _created_at: std::time::Instant,
+    ) -> Option<Duration> {
+        value.ttl
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &Value,
+        _updated_at: std::time::Instant,
+        duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        value.ttl.or(duration_until_expiry)
+    }
+}
+
+impl MokaBackend {
+    pub fn builder() -> Builder {
+        Builder { max_capacity: None }
+    }
+}
+
+pub struct Builder {
+    max_capacity: Option<u64>,
+}
+
+impl Builder {
+    /// Bound the number of keys held in the cache.
+    ///
+    /// Once full, keys are evicted even though their window hasn't ended, losing their counts.
+    pub fn max_capacity(mut self, max_capacity: u64) -> Self {
+        self.max_capacity = Some(max_capacity);
+        self
+    }
+
+    pub fn build(self) -> MokaBackend {
+        let mut builder = Cache::builder().expire_after(WindowExpiry);
+        if let Some(max_capacity) = self.max_capacity {
+            builder = builder.max_capacity(max_capacity);
+        }
+        MokaBackend {
+            cache: builder.build(),
+        }
+    }
+}
+
+// The instant `duration` after `now`, or an error if it is too far in the future to be represented
+fn expiry(now: Instant, duration: Duration, what: &str) -> Result<Instant, BackendError> {
+    now.checked_add(duration)
+        .ok_or_else(|| BackendError::Overflow(format!("{what} of {duration:?} is too large")))
+}
+
+impl Backend<SimpleInput> for MokaBackend {
+    type Output = SimpleOutput;
+    type RollbackToken = SimpleRollbackToken;
+    type Error = BackendError;
+
+    async fn request(
+        &self,
+        input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        let now = Instant::now();
+        let reset = expiry(now, input.interval, "Interval")?;
+        let value = self
+            .cache
+            .entry(input.key.clone())
+            .and_upsert_with(|existing| match existing.map(|e| e.into_value()) {
+                Some(value) if value.reset > now => Value {
+                    count: value.count.saturating_add(input.cost),
+                    reset: value.reset,
+                    ttl: None,
+                },
+                // The key doesn't exist, or the cache hasn't yet noticed that it has expired
+                _ => Value {
+                    count: input.cost,
+                    reset,
+                    ttl: Some(input.interval),
+                },
+            })
+            .into_value();
+        let allow = value.count <= input.max_requests;
+        let output = SimpleOutput {
+            limit: input.max_requests,
+            remaining: input.max_requests.saturating_sub(value.count),
+            reset: value.reset,
+        };
+        let token = SimpleRollbackToken {
+            key: input.key,
+            cost: input.cost,
+        };
+        Ok((allow, output, token))
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        self.cache
+            .entry(token.key)
+            .and_compute_with(|existing| match existing {
+                Some(entry) => {
+                    let value = entry.into_value();
+                    Op::Put(Value {
+                        count: value.count.saturating_sub(token.cost),
+                        reset: value.reset,
+                        ttl: None,
+                    })
+                }
+                None => Op::Nop,
+            });
+        Ok(())
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        Some(token.cost)
+    }
+}
+
+impl SimpleBackend for MokaBackend {
+    async fn remove_key(&self, key: &str) -> Result<(), Self::Error> {
+        self.cache.invalidate(key);
+        Ok(())
+    }
+
+    async fn peek(&self, input: &SimpleInput) -> Result<(bool, SimpleOutput), Self::Error> {
+        let now = Instant::now();
+        let (count, reset) = match self.cache.get(&input.key) {
+            Some(v) if v.reset > now => (v.count, v.reset),
+            _ => (0, expiry(now, input.interval, "Interval")?),
+        };
+        let allow = count.saturating_add(input.cost) <= input.max_requests;
+        let output = SimpleOutput {
+            limit: input.max_requests,
+            remaining: input.max_requests.saturating_sub(count),
+            reset,
+        };
+        Ok((allow, output))
+    }
+
+    async fn set_key(&self, key: &str, count: u64, ttl: Duration) -> Result<(), Self::Error> {
+        if ttl.is_zero() {
+            return self.remove_key(key).await;
+        }
+        let reset = expiry(Instant::now(), ttl, "TTL")?;
+        let value = Value {
+            count,
+            reset,
+            ttl: Some(ttl),
+        };
+        self.cache.insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    async fn remove_keys(&self, prefix: &str) -> Result<u64, Self::Error> {
+        let keys = self
+            .cache
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        let mut removed = 0;
+        for key in keys {
+            if self.cache.remove(key.as_str()).is_some() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(key: &str, interval: Duration) -> SimpleInput {
+        SimpleInput {
+            interval,
+            max_requests: 2,
+            key: key.to_string(),
+            cost: 1,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_allow_deny() {
+        let backend = MokaBackend::builder().build();
+        let minute = Duration::from_secs(60);
+        for remaining in [1, 0] {
+            let (allow, output, _) = backend.request(input("KEY1", minute)).await.unwrap();
+            assert!(allow);
+            assert_eq!(output.remaining, remaining);
+        }
+        let (allow, _, token) = backend.request(input("KEY1", minute)).await.unwrap();
+        assert!(!allow);
+        backend.rollback(token).await.unwrap();
+        let (_, output) = backend.peek(&input("KEY1", minute)).await.unwrap();
+        assert_eq!(output.remaining, 0);
+        backend.remove_key("KEY1").await.unwrap();
+        let (allow, _, _) = backend.request(input("KEY1", minute)).await.unwrap();
+        assert!(allow);
+    }
+
+    #[actix_web::test]
+    async fn test_interval_overflow() {
+        let backend = MokaBackend::builder().build();
+        let err = backend
+            .request(input("KEY1", Duration::MAX))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), "overflow");
+        assert!(backend.peek(&input("KEY1", Duration::MAX)).await.is_err());
+        assert!(backend.set_key("KEY1", 1, Duration::MAX).await.is_err());
+        let (allow, _, _) = backend
+            .request(input("KEY1", Duration::from_secs(60)))
+            .await
+            .unwrap();
+        assert!(allow);
+    }
+
+    #[actix_web::test]
+    async fn test_expiry() {
+        let backend = MokaBackend::builder().build();
+        let interval = Duration::from_millis(100);
+        for _ in 0..3 {
+            backend.request(input("KEY1", interval)).await.unwrap();
+        }
+        tokio::time::sleep(interval * 2).await;
+        assert!(backend.cache.get("KEY1").is_none());
+        let (allow, output, _) = backend.request(input("KEY1", interval)).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_max_capacity() {
+        let backend = MokaBackend::builder().max_capacity(1).build();
+        let minute = Duration::from_secs(60);
+        for key in ["KEY1", "KEY2", "KEY3"] {
+            backend.request(input(key, minute)).await.unwrap();
+        }
+        backend.cache.run_pending_tasks();
+        assert!(backend.cache.entry_count() <= 1);
+        let remaining = backend.cache.iter().count() as u64;
+        assert_eq!(backend.clear().await.unwrap(), remaining);
+    }
+}
@@ -0,0 +1,250 @@
+//! Ad-hoc, temporary limits for individual keys, e.g. granting a customer a higher limit until
+//! the end of a migration.
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A limit replacing the usual one for a single key until it expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitOverride {
+    /// The rate limiting interval.
+    pub interval: Duration,
+    /// The total requests to be allowed within the interval.
+    pub max_requests: u64,
+    /// When the override stops applying.
+    pub expires: SystemTime,
+}
+
+impl LimitOverride {
+    pub fn new(interval: Duration, max_requests: u64, expires: SystemTime) -> Self {
+        Self {
+            interval,
+            max_requests,
+            expires,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires <= SystemTime::now()
+    }
+}
+
+/// Stores [LimitOverride]s, which are consulted before the usual limit of a key.
+///
+/// Use with
+/// [SimpleInputFunctionBuilder::override_store](crate::backend::SimpleInputFunctionBuilder::override_store),
+/// and manage them with
+/// [admin_scope_with_overrides](crate::admin::admin_scope_with_overrides).
+#[async_trait(?Send)]
+pub trait OverrideStore {
+    /// The override for `key`, unless there is none or it has expired.
+    async fn get(&self, key: &str) -> Result<Option<LimitOverride>, actix_web::Error>;
+
+    /// Set the override for `key`, replacing any existing one.
+    async fn set(&self, key: &str, limit: LimitOverride) -> Result<(), actix_web::Error>;
+
+    /// Remove any override for `key`.
+    async fn remove(&self, key: &str) -> Result<(), actix_web::Error>;
+}
+
+/// An [OverrideStore] held in memory, shared between all clones.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryOverrideStore {
+    overrides: Arc<Mutex<HashMap<String, LimitOverride>>>,
+}
+
+impl MemoryOverrideStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait(?Send)]
+impl OverrideStore for MemoryOverrideStore {
+    async fn get(&self, key: &str) -> Result<Option<LimitOverride>, actix_web::Error> {
+        let mut overrides = self.overrides.lock().unwrap();
+        match overrides.get(key) {
+            Some(limit) if limit.is_expired() => {
+                overrides.remove(key);
+                Ok(None)
+            }
+            limit => Ok(limit.cloned()),
+        }
+    }
+
+    async fn set(&self, key: &str, limit: LimitOverride) -> Result<(), actix_web::Error> {
+        let mut overrides = self.overrides.lock().unwrap();
+        overrides.retain(|_, limit| !limit.is_expired());
+        overrides.insert(key.to_owned(), limit);
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), actix_web::Error> {
+        self.overrides.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "redis")]
+pub use redis_store::RedisOverrideStore;
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use super::{LimitOverride, OverrideStore};
+    use crate::backend::BackendError;
+    use async_trait::async_trait;
+    use redis::aio::ConnectionManager;
+    use redis::AsyncCommands;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// An [OverrideStore] held in Redis, so that overrides apply to every instance.
+    ///
+    /// Each override is stored in its own key, `{prefix}{key}`, which Redis removes once the
+    /// override expires.
+    #[cfg_attr(docsrs, doc(cfg(feature = "redis")))]
+    #[derive(Clone)]
+    pub struct RedisOverrideStore {
+        connection: ConnectionManager,
+        prefix: String,
+    }
+
+    impl RedisOverrideStore {
+        /// Store overrides under the default prefix, `"override:"`.
+        pub fn new(connection: ConnectionManager) -> Self {
+            Self {
+                connection,
+                prefix: "override:".to_owned(),
+            }
+        }
+
+        /// Override the prefix of the Redis keys.
+        pub fn prefix(mut self, prefix: &str) -> Self {
+            self.prefix = prefix.to_owned();
+            self
+        }
+
+        fn key(&self, key: &str) -> String {
+            format!("{}{key}", self.prefix)
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl OverrideStore for RedisOverrideStore {
+        async fn get(&self, key: &str) -> Result<Option<LimitOverride>, actix_web::Error> {
+            let mut con = self.connection.clone();
+            let value: Option<String> = con.get(self.key(key)).await.map_err(BackendError::from)?;
+            Ok(value
+                .as_deref()
+                .and_then(decode)
+                .filter(|l| !l.is_expired()))
+        }
+
+        async fn set(&self, key: &str, limit: LimitOverride) -> Result<(), actix_web::Error> {
+            let mut con = self.connection.clone();
+            let expires_ms = millis_since_epoch(limit.expires);
+            redis::pipe()
+                .atomic()
+                .set(self.key(key), encode(&limit))
+                .ignore()
+                .cmd("PEXPIREAT")
+                .arg(self.key(key))
+                .arg(expires_ms)
+                .ignore()
+                .query_async::<_, ()>(&mut con)
+                .await
+                .map_err(BackendError::from)?;
+            Ok(())
+        }
+
+        async fn remove(&self, key: &str) -> Result<(), actix_web::Error> {
+            let mut con = self.connection.clone();
+            con.del::<_, ()>(self.key(key))
+                .await
+                .map_err(BackendError::from)?;
+            Ok(())
+        }
+    }
+
+    fn millis_since_epoch(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    // Stored as "{interval_ms} {max_requests} {expires_ms}"
+    fn encode(limit: &LimitOverride) -> String {
+        format!(
+            "{} {} {}",
+            limit.interval.as_millis(),
+            limit.max_requests,
+            millis_since_epoch(limit.expires)
+        )
+    }
+
+    fn decode(value: &str) -> Option<LimitOverride> {
+        let mut parts = value.split(' ').map(str::parse::<u64>);
+        let interval = Duration::from_millis(parts.next()?.ok()?);
+        let max_requests = parts.next()?.ok()?;
+        let expires = UNIX_EPOCH + Duration::from_millis(parts.next()?.ok()?);
+        Some(LimitOverride::new(interval, max_requests, expires))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_encode() {
+            let limit = LimitOverride::new(
+                Duration::from_secs(3600),
+                10_000,
+                UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            );
+            assert_eq!(decode(&encode(&limit)), Some(limit));
+            assert_eq!(decode("1 2"), None);
+        }
+
+        #[actix_web::test]
+        async fn test_redis_override_store() {
+            let host = option_env!("REDIS_HOST").unwrap_or("127.0.0.1");
+            let port = option_env!("REDIS_PORT").unwrap_or("6379");
+            let client = redis::Client::open(format!("redis://{host}:{port}")).unwrap();
+            let store = RedisOverrideStore::new(ConnectionManager::new(client).await.unwrap())
+                .prefix("test_override_store:");
+            let limit = LimitOverride::new(
+                Duration::from_secs(3600),
+                10_000,
+                SystemTime::now() + Duration::from_secs(60),
+            );
+            store.set("customer", limit.clone()).await.unwrap();
+            let stored = store.get("customer").await.unwrap().unwrap();
+            assert_eq!(stored.max_requests, limit.max_requests);
+            store.remove("customer").await.unwrap();
+            assert!(store.get("customer").await.unwrap().is_none());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_memory_override_store() {
+        let store = MemoryOverrideStore::new();
+        let limit = LimitOverride::new(
+            Duration::from_secs(3600),
+            10_000,
+            SystemTime::now() + Duration::from_secs(60),
+        );
+        store.set("customer", limit.clone()).await.unwrap();
+        assert_eq!(store.get("customer").await.unwrap(), Some(limit));
+        store.remove("customer").await.unwrap();
+        assert_eq!(store.get("customer").await.unwrap(), None);
+
+        let expired = LimitOverride::new(Duration::from_secs(3600), 10_000, SystemTime::now());
+        store.set("customer", expired).await.unwrap();
+        assert_eq!(store.get("customer").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::backend::{Backend, Health, SimpleInput, SimpleOutput};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The number of instances that a limit is partitioned between, shared by each
+/// [PartitionedBackend] it was given to, and which can be updated at runtime as replicas scale.
+#[derive(Debug, Clone)]
+pub struct InstanceCount(Arc<AtomicU64>);
+
+impl InstanceCount {
+    /// A count of `instances`, a count of zero is treated as 1.
+    pub fn new(instances: u64) -> Self {
+        Self(Arc::new(AtomicU64::new(instances.max(1))))
+    }
+
+    /// Read the count from the environment variable `name`, e.g. set from the replica count of a
+    /// deployment.
+    ///
+    /// Returns [None] if the variable is not set, or is not a number.
+    pub fn from_env(name: &str) -> Option<Self> {
+        let instances = std::env::var(name).ok()?.trim().parse().ok()?;
+        Some(Self::new(instances))
+    }
+
+    /// The current number of instances.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Update the number of instances, subsequent requests will use the new partition.
+    pub fn set(&self, instances: u64) {
+        let previous = self.0.swap(instances.max(1), Ordering::Relaxed);
+        if previous != instances.max(1) {
+            log::info!(
+                "Rate limits are now partitioned between {} instances",
+                instances.max(1)
+            );
+        }
+    }
+}
+
+/// Wraps a [Backend], dividing each request's `max_requests` by the number of instances, so that
+/// N replicas each using an [InMemoryBackend](crate::backend::memory::InMemoryBackend)
+/// approximate a global limit without shared storage.
+///
+/// The partition is rounded up, and is at least 1, so the global limit may be exceeded by up to
+/// one request per instance. It only holds if requests are spread evenly between the instances,
+/// e.g. by a round robin load balancer.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::{InstanceCount, PartitionedBackend};
+/// let instances = InstanceCount::from_env("REPLICAS").unwrap_or_else(|| InstanceCount::new(1));
+/// let backend = PartitionedBackend::new(InMemoryBackend::builder().build(), instances.clone());
+/// // Later, when the deployment is scaled:
+/// instances.set(4);
+/// ```
+#[derive(Clone)]
+pub struct PartitionedBackend<B> {
+    inner: B,
+    instances: InstanceCount,
+}
+
+impl<B> PartitionedBackend<B> {
+    pub fn new(inner: B, instances: InstanceCount) -> Self {
+        Self { inner, instances }
+    }
+
+    /// The number of instances the limit is partitioned between.
+    pub fn instances(&self) -> &InstanceCount {
+        &self.instances
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+}
+
+impl<B> Backend<SimpleInput> for PartitionedBackend<B>
+where
+    B: Backend<SimpleInput, Output = SimpleOutput>,
+{
+    type Output = SimpleOutput;
+    type RollbackToken = B::RollbackToken;
+    type Error = B::Error;
+
+    async fn request(
+        &self,
+        mut input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        input.max_requests = input.max_requests.div_ceil(self.instances.get()).max(1);
+        self.inner.request(input).await
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        self.inner.rollback(token).await
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        self.inner.rollback_cost(token)
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.inner.health().await
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use std::time::Duration;
+
+    fn input(max_requests: u64) -> SimpleInput {
+        SimpleInput {
+            interval: Duration::from_secs(60),
+            max_requests,
+            key: "KEY1".to_string(),
+            cost: 1,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_partitioned() {
+        let instances = InstanceCount::new(3);
+        let memory = InMemoryBackend::builder().with_gc_interval(None).build();
+        let backend = PartitionedBackend::new(memory, instances.clone());
+
+        // Rounded up
+        let (_, output, _) = backend.request(input(10)).await.unwrap();
+        assert_eq!(output.limit, 4);
+        // At least 1
+        let (_, output, _) = backend.request(input(2)).await.unwrap();
+        assert_eq!(output.limit, 1);
+
+        // Scaled down at runtime
+        instances.set(2);
+        let (_, output, _) = backend.request(input(10)).await.unwrap();
+        assert_eq!(output.limit, 5);
+        // Zero is treated as 1
+        instances.set(0);
+        assert_eq!(backend.instances().get(), 1);
+        let (_, output, _) = backend.request(input(10)).await.unwrap();
+        assert_eq!(output.limit, 10);
+    }
+}
@@ -0,0 +1,432 @@
+use crate::backend::input_builder::{
+    cookie_value, glob_match, header_value, host_value, ip_key, method_value, query_value,
+    IpPrefix, Subnet,
+};
+use crate::backend::rate::{parse_rate, ParseRateError};
+use crate::backend::PolicyDecision;
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Method;
+use arc_swap::ArcSwap;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+/// A component of the rate limiting key used by a [Policy].
+///
+/// Components whose value is missing from the request are left out of the key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize))]
+#[cfg_attr(feature = "config", serde(rename_all = "snake_case"))]
+pub enum KeyStrategy {
+    /// The client's real IP, see [SimpleInputFunctionBuilder::real_ip_key](crate::backend::SimpleInputFunctionBuilder::real_ip_key).
+    RealIp,
+    /// The connection peer IP, see [SimpleInputFunctionBuilder::peer_ip_key](crate::backend::SimpleInputFunctionBuilder::peer_ip_key).
+    PeerIp,
+    /// The request path.
+    Path,
+    /// The matched route pattern.
+    MatchPattern,
+    /// The request method.
+    Method,
+    /// The requested host.
+    Host,
+    /// The value of a request header.
+    Header(String),
+    /// The value of a query string parameter.
+    Query(String),
+    /// The value of a cookie.
+    Cookie(String),
+}
+
+impl KeyStrategy {
+    fn component(&self, req: &ServiceRequest) -> Result<Option<String>, actix_web::Error> {
+        Ok(match self {
+            KeyStrategy::RealIp => match req.connection_info().realip_remote_addr() {
+                Some(ip) => Some(ip_key(ip, &IpPrefix::default())?),
+                None => None,
+            },
+            KeyStrategy::PeerIp => match req.connection_info().peer_addr() {
+                Some(ip) => Some(ip_key(ip, &IpPrefix::default())?),
+                None => None,
+            },
+            KeyStrategy::Path => Some(req.path().to_owned()),
+            KeyStrategy::MatchPattern => req.match_pattern(),
+            KeyStrategy::Method => Some(method_value(req, false)),
+            KeyStrategy::Host => Some(host_value(req)),
+            KeyStrategy::Header(name) => header_value(req, name),
+            KeyStrategy::Query(name) => query_value(req, name),
+            KeyStrategy::Cookie(name) => cookie_value(req, name),
+        })
+    }
+}
+
+/// The limits applied to requests that match a rule in a [PolicyMap].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize))]
+#[cfg_attr(
+    feature = "config",
+    serde(try_from = "crate::backend::config::PolicyFields")
+)]
+pub struct Policy {
+    /// The rate limiting interval.
+    pub interval: Duration,
+    /// The total requests to be allowed within the interval.
+    pub max_requests: u64,
+    /// The components of the rate limiting key.
+    pub key: Vec<KeyStrategy>,
+    /// Exempt matching requests from rate limiting entirely.
+    pub exempt: bool,
+}
+
+impl Policy {
+    pub fn new(interval: Duration, max_requests: u64) -> Self {
+        Self {
+            interval,
+            max_requests,
+            key: Vec::new(),
+            exempt: false,
+        }
+    }
+
+    /// A policy for a rate such as `"100/1m"`, see [parse_rate](crate::backend::parse_rate).
+    pub fn from_rate(rate: &str) -> Result<Self, ParseRateError> {
+        let (interval, max_requests) = parse_rate(rate)?;
+        Ok(Self::new(interval, max_requests))
+    }
+
+    /// A policy that exempts matching requests from rate limiting.
+    pub fn exempt() -> Self {
+        Self {
+            exempt: true,
+            ..Self::new(Duration::ZERO, 0)
+        }
+    }
+
+    /// Add a component to the rate limiting key.
+    pub fn key(mut self, strategy: KeyStrategy) -> Self {
+        self.key.push(strategy);
+        self
+    }
+}
+
+/// How a [PolicyMap] chooses between multiple matching rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize))]
+#[cfg_attr(feature = "config", serde(rename_all = "snake_case"))]
+pub enum MatchMode {
+    /// The first matching rule (in the order they were added) is used.
+    #[default]
+    FirstMatch,
+    /// The matching rule with the longest path before any wildcard is used, ties are resolved
+    /// by the order the rules were added.
+    LongestPrefix,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    name: String,
+    method: Option<Method>,
+    path: String,
+    policy: Policy,
+}
+
+/// A declarative table of rate limiting policies for different routes.
+///
+/// Rules are matched by (optionally) method, and a path pattern in which `*` matches any sequence
+/// of characters. Requests that don't match any rule use the default policy.
+///
+/// Each rule is given its own bucket: the rule name is always the first component of the key, so
+/// rule names must be unique.
+///
+/// Use with [SimpleInputFunctionBuilder::policy_map](crate::backend::SimpleInputFunctionBuilder::policy_map).
+///
+/// # Example
+/// ```
+/// # use std::time::Duration;
+/// # use actix_extensible_rate_limit::backend::{KeyStrategy, Policy, PolicyMap, SimpleInputFunctionBuilder};
+/// # use actix_web::http::Method;
+/// let minute = Duration::from_secs(60);
+/// let policies = PolicyMap::new(Policy::new(minute, 100).key(KeyStrategy::PeerIp))
+///     .rule("health", None, "/healthz", Policy::exempt())
+///     .rule(
+///         "login",
+///         Some(Method::POST),
+///         "/login",
+///         Policy::new(minute, 5).key(KeyStrategy::PeerIp),
+///     )
+///     .rule(
+///         "api",
+///         None,
+///         "/api/*",
+///         Policy::new(minute, 1000).key(KeyStrategy::Header("x-api-key".to_owned())),
+///     );
+/// let input = SimpleInputFunctionBuilder::new(minute, 100)
+///     .policy_map(policies)
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct PolicyMap {
+    rules: Vec<Rule>,
+    default: Policy,
+    mode: MatchMode,
+    exempt_subnets: Vec<Subnet>,
+}
+
+/// The name used as the key component for requests that match the default policy.
+pub const DEFAULT_POLICY_NAME: &str = "default";
+
+impl PolicyMap {
+    /// Create a new table, with the policy used for requests that don't match any rule.
+    pub fn new(default: Policy) -> Self {
+        Self {
+            rules: Vec::new(),
+            default,
+            mode: MatchMode::default(),
+            exempt_subnets: Vec::new(),
+        }
+    }
+
+    /// Exempt requests from these IP addresses or CIDR subnets from rate limiting entirely.
+    ///
+    /// This is checked against the connection peer address.
+    ///
+    /// # Panics
+    ///
+    /// If any of the subnets is not a valid IP address or CIDR subnet.
+    pub fn exempt_ips<I, S>(mut self, subnets: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.exempt_subnets.extend(subnets.into_iter().map(|s| {
+            let s = s.as_ref();
+            s.parse::<Subnet>()
+                .unwrap_or_else(|_| panic!("Invalid exempt subnet: {s}"))
+        }));
+        self
+    }
+
+    /// Add a rule, matching an optional method and a path pattern.
+    pub fn rule(mut self, name: &str, method: Option<Method>, path: &str, policy: Policy) -> Self {
+        self.rules.push(Rule {
+            name: name.to_owned(),
+            method,
+            path: path.to_owned(),
+            policy,
+        });
+        self
+    }
+
+    /// Choose how to select between multiple matching rules, the default is
+    /// [MatchMode::FirstMatch].
+    pub fn match_mode(mut self, mode: MatchMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Find the name and policy for a request.
+    pub fn find(&self, method: &Method, path: &str) -> (&str, &Policy) {
+        let mut matches = self.rules.iter().filter(|rule| {
+            rule.method.as_ref().is_none_or(|m| m == method) && glob_match(&rule.path, path)
+        });
+        let rule = match self.mode {
+            MatchMode::FirstMatch => matches.next(),
+            MatchMode::LongestPrefix => {
+                matches.fold(None, |best: Option<&Rule>, rule| match best {
+                    Some(best) if literal_prefix(&best.path) >= literal_prefix(&rule.path) => {
+                        Some(best)
+                    }
+                    _ => Some(rule),
+                })
+            }
+        };
+        match rule {
+            Some(rule) => (&rule.name, &rule.policy),
+            None => (DEFAULT_POLICY_NAME, &self.default),
+        }
+    }
+
+    pub(super) fn decide(&self, req: &ServiceRequest) -> Result<PolicyDecision, actix_web::Error> {
+        let (name, policy) = self.find(req.method(), req.path());
+        let exempt_ip = req.peer_addr().is_some_and(|peer| {
+            let ip = peer.ip();
+            self.exempt_subnets.iter().any(|subnet| subnet.contains(ip))
+        });
+        if policy.exempt || exempt_ip {
+            return Ok(PolicyDecision::new(name).exempt());
+        }
+        let mut components = vec![name.to_owned()];
+        for strategy in &policy.key {
+            if let Some(component) = strategy.component(req)? {
+                components.push(component);
+            }
+        }
+        Ok(PolicyDecision::new(components.join(":"))
+            .interval(policy.interval)
+            .max_requests(policy.max_requests))
+    }
+}
+
+/// A shared handle to a [PolicyMap] that can be replaced at runtime, without restarting the
+/// server, e.g. from a file watcher or an admin endpoint.
+///
+/// Requests that are already in flight finish with the policies they started with.
+///
+/// The handle is cheap to clone, all clones refer to the same table.
+///
+/// Use with [SimpleInputFunctionBuilder::policy_handle](crate::backend::SimpleInputFunctionBuilder::policy_handle).
+///
+/// # Example
+/// ```
+/// # use std::time::Duration;
+/// # use actix_extensible_rate_limit::backend::{Policy, PolicyHandle, PolicyMap, SimpleInputFunctionBuilder};
+/// let minute = Duration::from_secs(60);
+/// let handle = PolicyHandle::new(PolicyMap::new(Policy::new(minute, 100)));
+/// let input = SimpleInputFunctionBuilder::new(minute, 100)
+///     .policy_handle(handle.clone())
+///     .build();
+/// // Later, during an incident:
+/// handle.store(PolicyMap::new(Policy::new(minute, 10)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct PolicyHandle {
+    policies: Arc<ArcSwap<PolicyMap>>,
+}
+
+impl PolicyHandle {
+    pub fn new(policies: PolicyMap) -> Self {
+        Self {
+            policies: Arc::new(ArcSwap::from_pointee(policies)),
+        }
+    }
+
+    /// Returns the current policy table.
+    pub fn load(&self) -> Arc<PolicyMap> {
+        self.policies.load_full()
+    }
+
+    /// Replace the policy table, subsequent requests will use the new policies.
+    pub fn store(&self, policies: PolicyMap) {
+        self.policies.store(Arc::new(policies));
+        log::info!("Rate limit policies have been replaced");
+    }
+
+    /// Replace the policy table with one loaded from a config file, see
+    /// [PolicyConfig::load](crate::backend::config::PolicyConfig::load).
+    ///
+    /// If the file can't be loaded the current policies are kept.
+    #[cfg(feature = "config")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+    pub fn reload_from(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), crate::backend::config::ConfigError> {
+        let policies = crate::backend::config::PolicyConfig::load(path)?.into_policy_map()?;
+        self.store(policies);
+        Ok(())
+    }
+
+    pub(super) fn decide(&self, req: &ServiceRequest) -> Result<PolicyDecision, actix_web::Error> {
+        self.policies.load().decide(req)
+    }
+
+    pub(super) fn downgrade(&self) -> WeakPolicyHandle {
+        WeakPolicyHandle(Arc::downgrade(&self.policies))
+    }
+}
+
+pub(super) struct WeakPolicyHandle(Weak<ArcSwap<PolicyMap>>);
+
+impl WeakPolicyHandle {
+    pub(super) fn upgrade(&self) -> Option<PolicyHandle> {
+        self.0.upgrade().map(|policies| PolicyHandle { policies })
+    }
+}
+
+fn literal_prefix(pattern: &str) -> usize {
+    pattern.find('*').unwrap_or(pattern.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SimpleInputFunctionBuilder;
+    use crate::Exempt;
+    use actix_web::test::TestRequest;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn policy_map() -> PolicyMap {
+        PolicyMap::new(Policy::new(MINUTE, 100))
+            .rule("health", None, "/healthz", Policy::exempt())
+            .rule("api", None, "/api/*", Policy::new(MINUTE, 50))
+            .rule(
+                "users",
+                Some(Method::POST),
+                "/api/users/*",
+                Policy::new(MINUTE, 5).key(KeyStrategy::Header("x-api-key".to_owned())),
+            )
+    }
+
+    #[actix_web::test]
+    async fn test_exempt_ips() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 1)
+            .policy_map(policy_map().exempt_ips(["10.0.0.0/8"]))
+            .build();
+        let req = TestRequest::with_uri("/api")
+            .peer_addr("10.1.2.3:1234".parse().unwrap())
+            .to_srv_request();
+        let err = input_fn(&req).await.unwrap_err();
+        assert!(err.as_error::<Exempt>().is_some());
+        let req = TestRequest::with_uri("/api")
+            .peer_addr("11.1.2.3:1234".parse().unwrap())
+            .to_srv_request();
+        assert!(input_fn(&req).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_policy_handle() {
+        let handle = PolicyHandle::new(policy_map());
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 1)
+            .policy_handle(handle.clone())
+            .build();
+        let req = TestRequest::with_uri("/other").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().max_requests, 100);
+        handle.store(PolicyMap::new(Policy::new(MINUTE, 10)));
+        assert_eq!(input_fn(&req).await.unwrap().max_requests, 10);
+        let req = TestRequest::with_uri("/healthz").to_srv_request();
+        assert_eq!(input_fn(&req).await.unwrap().key, DEFAULT_POLICY_NAME);
+    }
+
+    #[test]
+    fn test_match_mode() {
+        let map = policy_map();
+        assert_eq!(map.find(&Method::POST, "/api/users/1").0, "api");
+        assert_eq!(map.find(&Method::GET, "/other").0, DEFAULT_POLICY_NAME);
+        let map = map.match_mode(MatchMode::LongestPrefix);
+        assert_eq!(map.find(&Method::POST, "/api/users/1").0, "users");
+        assert_eq!(map.find(&Method::GET, "/api/users/1").0, "api");
+    }
+
+    #[actix_web::test]
+    async fn test_policy_map() {
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 1)
+            .policy_map(policy_map().match_mode(MatchMode::LongestPrefix))
+            .build();
+        let req = TestRequest::with_uri("/healthz").to_srv_request();
+        let err = input_fn(&req).await.unwrap_err();
+        assert!(err.as_error::<Exempt>().is_some());
+
+        let req = TestRequest::post()
+            .uri("/api/users/1")
+            .insert_header(("x-api-key", "abc"))
+            .to_srv_request();
+        let input = input_fn(&req).await.unwrap();
+        assert_eq!(input.key, "users:abc");
+        assert_eq!(input.max_requests, 5);
+
+        let req = TestRequest::with_uri("/other").to_srv_request();
+        let input = input_fn(&req).await.unwrap();
+        assert_eq!(input.key, "default");
+        assert_eq!(input.max_requests, 100);
+    }
+}
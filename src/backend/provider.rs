@@ -0,0 +1,318 @@
+//! Periodically refreshing policies from a remote source.
+use crate::backend::{PolicyHandle, PolicyMap};
+use crate::supervisor::ShutdownSignal;
+use crate::Supervisor;
+use async_trait::async_trait;
+use std::fmt::Display;
+use std::time::Duration;
+
+/// A source of rate limiting policies, e.g. a central control plane.
+///
+/// Use with a [PolicyRefresher] to keep a [PolicyHandle] up to date.
+#[async_trait(?Send)]
+pub trait PolicyProvider: 'static {
+    type Error: Display;
+
+    /// Fetch the current policy table.
+    async fn fetch(&self) -> Result<PolicyMap, Self::Error>;
+}
+
+/// Periodically fetches policies from a [PolicyProvider], and stores them in a [PolicyHandle].
+///
+/// If a fetch fails, the error is logged and the current policies are kept.
+///
+/// # Example
+/// ```no_run
+/// # use std::time::Duration;
+/// # use actix_extensible_rate_limit::backend::{Policy, PolicyHandle, PolicyMap};
+/// # use actix_extensible_rate_limit::backend::provider::{PolicyProvider, PolicyRefresher};
+/// # async fn example(provider: impl PolicyProvider) {
+/// let handle = PolicyHandle::new(PolicyMap::new(Policy::new(Duration::from_secs(60), 100)));
+/// PolicyRefresher::new(handle.clone(), provider)
+///     .interval(Duration::from_secs(30))
+///     .spawn();
+/// # }
+/// ```
+pub struct PolicyRefresher<P> {
+    handle: PolicyHandle,
+    provider: P,
+    interval: Duration,
+    supervisor: Option<Supervisor>,
+}
+
+impl<P: PolicyProvider> PolicyRefresher<P> {
+    /// Create a refresher, that fetches every 60 seconds by default.
+    pub fn new(handle: PolicyHandle, provider: P) -> Self {
+        Self {
+            handle,
+            provider,
+            interval: Duration::from_secs(60),
+            supervisor: None,
+        }
+    }
+
+    /// Override how often the policies are fetched.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Run the refresh task under a [Supervisor], so that it can be stopped with
+    /// [Supervisor::shutdown()].
+    ///
+    /// Without a supervisor the task stops once every clone of the [PolicyHandle] has been
+    /// dropped.
+    pub fn with_supervisor(mut self, supervisor: Supervisor) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    /// Spawn the refresh task onto the current Actix runtime, the first fetch happens immediately.
+    ///
+    /// # Panics
+    ///
+    /// If the interval is zero.
+    pub fn spawn(self) {
+        assert!(
+            !self.interval.is_zero(),
+            "Refresh interval must be non-zero"
+        );
+        match self.supervisor.clone() {
+            Some(supervisor) => supervisor.spawn(move |signal| self.run(Some(signal))),
+            None => {
+                actix_web::rt::spawn(self.run(None));
+            }
+        }
+    }
+
+    async fn run(self, mut shutdown: Option<ShutdownSignal>) {
+        let PolicyRefresher {
+            handle,
+            provider,
+            interval,
+            ..
+        } = self;
+        // Only hold a weak reference, so that the task doesn't keep the policies alive.
+        let weak = handle.downgrade();
+        drop(handle);
+        loop {
+            let policies = provider.fetch().await;
+            let Some(current) = weak.upgrade() else {
+                break;
+            };
+            match policies {
+                Ok(policies) => current.store(policies),
+                Err(e) => log::error!("Unable to refresh rate limit policies: {e}"),
+            }
+            drop(current);
+            let sleep = actix_web::rt::time::sleep(interval);
+            match &mut shutdown {
+                None => sleep.await,
+                Some(shutdown) => {
+                    tokio::select! {
+                        _ = sleep => {}
+                        _ = shutdown.recv() => break,
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "redis", feature = "config"))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "redis", feature = "config"))))]
+pub use self::redis::{RedisPolicyError, RedisPolicyProvider};
+
+#[cfg(all(feature = "redis", feature = "config"))]
+mod redis {
+    use super::PolicyProvider;
+    use crate::backend::config::{ConfigError, PolicyConfig, RuleConfig};
+    use crate::backend::{MatchMode, Policy, PolicyMap};
+    use async_trait::async_trait;
+    use redis::aio::ConnectionManager;
+    use redis::AsyncCommands;
+    use serde::Deserialize;
+    use thiserror::Error;
+
+    #[derive(Debug, Error)]
+    pub enum RedisPolicyError {
+        #[error("Redis error: {0}")]
+        Redis(#[from] redis::RedisError),
+        #[error("Invalid policy in field {field:?}: {source}")]
+        Field {
+            field: String,
+            #[source]
+            source: toml::de::Error,
+        },
+        #[error("Missing the {0:?} policy")]
+        MissingDefault(&'static str),
+        #[error(transparent)]
+        Config(#[from] ConfigError),
+    }
+
+    #[derive(Deserialize)]
+    struct RemoteRule {
+        #[serde(default)]
+        method: Option<String>,
+        path: String,
+        #[serde(flatten)]
+        policy: Policy,
+    }
+
+    /// A [PolicyProvider] that reads policies from a Redis hash.
+    ///
+    /// The `default` field holds the default policy, and every other field is a rule named after
+    /// the field. Values are TOML tables, in the same format as
+    /// [PolicyConfig](crate::backend::config::PolicyConfig):
+    ///
+    /// ```text
+    /// HSET policies default 'interval = "1m"
+    /// max_requests = 100
+    /// key = ["peer_ip"]'
+    /// HSET policies tenant-a 'path = "/tenants/a/*"
+    /// interval = "1m"
+    /// max_requests = 1000'
+    /// ```
+    ///
+    /// As hash fields are unordered, rules are added in order of their name; the default match
+    /// mode is therefore [MatchMode::LongestPrefix].
+    #[derive(Clone)]
+    pub struct RedisPolicyProvider {
+        connection: ConnectionManager,
+        key: String,
+        match_mode: MatchMode,
+    }
+
+    impl RedisPolicyProvider {
+        pub fn new(connection: ConnectionManager, key: impl Into<String>) -> Self {
+            Self {
+                connection,
+                key: key.into(),
+                match_mode: MatchMode::LongestPrefix,
+            }
+        }
+
+        pub fn match_mode(mut self, mode: MatchMode) -> Self {
+            self.match_mode = mode;
+            self
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl PolicyProvider for RedisPolicyProvider {
+        type Error = RedisPolicyError;
+
+        async fn fetch(&self) -> Result<PolicyMap, Self::Error> {
+            let mut con = self.connection.clone();
+            let mut fields: Vec<(String, String)> = con.hgetall(&self.key).await?;
+            fields.sort();
+            let mut default = None;
+            let mut rules = Vec::new();
+            for (field, value) in fields {
+                let invalid = |source| RedisPolicyError::Field {
+                    field: field.clone(),
+                    source,
+                };
+                if field == crate::backend::DEFAULT_POLICY_NAME {
+                    default = Some(toml::from_str::<Policy>(&value).map_err(invalid)?);
+                } else {
+                    let rule = toml::from_str::<RemoteRule>(&value).map_err(invalid)?;
+                    rules.push(RuleConfig {
+                        name: field,
+                        method: rule.method,
+                        path: rule.path,
+                        policy: rule.policy,
+                    });
+                }
+            }
+            let config = PolicyConfig {
+                default: default.ok_or(RedisPolicyError::MissingDefault(
+                    crate::backend::DEFAULT_POLICY_NAME,
+                ))?,
+                match_mode: self.match_mode,
+                exempt_ips: Vec::new(),
+                rules,
+            };
+            Ok(config.into_policy_map()?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Policy;
+    use actix_web::http::Method;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct MockProvider {
+        calls: Rc<Cell<u64>>,
+    }
+
+    #[async_trait(?Send)]
+    impl PolicyProvider for MockProvider {
+        type Error = &'static str;
+
+        async fn fetch(&self) -> Result<PolicyMap, Self::Error> {
+            let calls = self.calls.get() + 1;
+            self.calls.set(calls);
+            if calls == 2 {
+                return Err("unavailable");
+            }
+            Ok(PolicyMap::new(Policy::new(Duration::from_secs(60), calls)))
+        }
+    }
+
+    fn max_requests(handle: &PolicyHandle) -> u64 {
+        handle.load().find(&Method::GET, "/").1.max_requests
+    }
+
+    #[actix_web::test]
+    async fn test_refresh() {
+        tokio::time::pause();
+        let calls = Rc::new(Cell::new(0));
+        let supervisor = Supervisor::new();
+        let handle = PolicyHandle::new(PolicyMap::new(Policy::new(Duration::from_secs(60), 0)));
+        PolicyRefresher::new(
+            handle.clone(),
+            MockProvider {
+                calls: calls.clone(),
+            },
+        )
+        .interval(Duration::from_secs(10))
+        .with_supervisor(supervisor.clone())
+        .spawn();
+        actix_web::rt::task::yield_now().await;
+        assert_eq!(max_requests(&handle), 1);
+        // A failed fetch keeps the current policies
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert_eq!(calls.get(), 2);
+        assert_eq!(max_requests(&handle), 1);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(max_requests(&handle), 3);
+        supervisor.shutdown().await;
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_stops_when_handle_dropped() {
+        tokio::time::pause();
+        let calls = Rc::new(Cell::new(0));
+        let handle = PolicyHandle::new(PolicyMap::new(Policy::new(Duration::from_secs(60), 0)));
+        PolicyRefresher::new(
+            handle.clone(),
+            MockProvider {
+                calls: calls.clone(),
+            },
+        )
+        .interval(Duration::from_secs(10))
+        .spawn();
+        actix_web::rt::task::yield_now().await;
+        assert_eq!(calls.get(), 1);
+        drop(handle);
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        assert_eq!(calls.get(), 2);
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+use thiserror::Error;
+
+/// The error returned by [parse_rate].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Invalid rate {0:?}, expected a number of requests per interval, e.g. \"100/1m\"")]
+pub struct ParseRateError(String);
+
+/// Parse a rate such as `"100/1m"`, `"5/s"` or `"10000/24h"` into an interval and the number of
+/// requests allowed within it.
+///
+/// The interval is a number followed by a unit of `ms`, `s`, `m`, `h` or `d`; the number may be
+/// left out if it is 1, and the unit if it is seconds.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::parse_rate;
+/// # use std::time::Duration;
+/// assert_eq!(parse_rate("100/1m"), Ok((Duration::from_secs(60), 100)));
+/// assert_eq!(parse_rate("5/s"), Ok((Duration::from_secs(1), 5)));
+/// ```
+pub fn parse_rate(s: &str) -> Result<(Duration, u64), ParseRateError> {
+    let invalid = || ParseRateError(s.to_owned());
+    let (max_requests, interval) = s.split_once('/').ok_or_else(invalid)?;
+    let max_requests = max_requests.trim().parse().map_err(|_| invalid())?;
+    let interval = interval.trim();
+    if interval.is_empty() {
+        return Err(invalid());
+    }
+    let interval = if interval.starts_with(|c: char| c.is_ascii_digit()) {
+        parse_duration(interval)
+    } else {
+        parse_duration(&format!("1{}", interval))
+    };
+    match interval {
+        Some(interval) if !interval.is_zero() => Ok((interval, max_requests)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parse a duration such as `"500ms"`, `"30s"`, `"5m"`, `"1h"` or `"1d"`.
+///
+/// A number without a unit is interpreted as seconds.
+///
+/// This is a `const fn`, so that the `rate_limit` macro can check its `per` argument at compile
+/// time with the same grammar.
+pub const fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim_ascii().as_bytes();
+    let mut split = 0;
+    let mut value: u64 = 0;
+    while split < s.len() && s[split].is_ascii_digit() {
+        value = match value.checked_mul(10) {
+            Some(value) => match value.checked_add((s[split] - b'0') as u64) {
+                Some(value) => value,
+                None => return None,
+            },
+            None => return None,
+        };
+        split += 1;
+    }
+    if split == 0 {
+        return None;
+    }
+    let (_, unit) = s.split_at(split);
+    let secs = match unit.trim_ascii() {
+        b"ms" => return Some(Duration::from_millis(value)),
+        b"" | b"s" => 1,
+        b"m" => 60,
+        b"h" => 60 * 60,
+        b"d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    match value.checked_mul(secs) {
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(86400)));
+        assert_eq!(parse_duration(" 10 s "), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("0s"), Some(Duration::ZERO));
+        assert_eq!(parse_duration("1w"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("+5s"), None);
+        assert_eq!(parse_duration("18446744073709551616ms"), None);
+        assert_eq!(parse_duration("18446744073709551615m"), None);
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("100/1m"), Ok((Duration::from_secs(60), 100)));
+        assert_eq!(parse_rate("5/s"), Ok((Duration::from_secs(1), 5)));
+        assert_eq!(
+            parse_rate("10000/24h"),
+            Ok((Duration::from_secs(86400), 10000))
+        );
+        assert_eq!(
+            parse_rate(" 20 / 500ms "),
+            Ok((Duration::from_millis(500), 20))
+        );
+        assert_eq!(parse_rate("3/h"), Ok((Duration::from_secs(3600), 3)));
+        assert_eq!(parse_rate("30/10"), Ok((Duration::from_secs(10), 30)));
+        for invalid in ["100", "100/", "/1m", "x/1m", "100/0s", "100/1w", "-1/1m"] {
+            assert_eq!(
+                parse_rate(invalid),
+                Err(ParseRateError(invalid.to_owned())),
+                "{}",
+                invalid
+            );
+        }
+    }
+}
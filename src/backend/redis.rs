@@ -1,50 +1,136 @@
-use crate::backend::{Backend, SimpleBackend, SimpleInput, SimpleOutput};
+use crate::backend::clock::{Clock, TokioClock};
+use crate::backend::{
+    Backend, BackendError, BanStore, Health, InspectableBackend, KeyStatus, ReservableBackend,
+    SimpleBackend, SimpleInput, SimpleOutput, SimpleReservation, SimpleRollbackToken,
+};
 use actix_web::rt::time::Instant;
-use actix_web::{HttpResponse, ResponseError};
-use async_trait::async_trait;
-use redis::aio::ConnectionManager;
-use redis::AsyncCommands;
+use once_cell::sync::Lazy;
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::{AsyncCommands, AsyncIter, Script};
 use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::Arc;
 use std::time::Duration;
-use thiserror::Error;
 
-// https://github.com/mitsuhiko/redis-rs/issues/353
-macro_rules! async_transaction {
-    ($conn:expr, $keys:expr, $body:expr) => {
-        loop {
-            redis::cmd("WATCH").arg($keys).query_async($conn).await?;
+// Counts a request against each key, starting a new window if the key doesn't exist, returning a
+// flat list of the count and TTL of each key. ARGV holds the cost and window length (in seconds)
+// for each key.
+// https://github.com/actix/actix-extras/blob/master/actix-limitation/src/lib.rs#L123
+static REQUEST_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        local result = {}
+        for i, key in ipairs(KEYS) do
+            redis.call('SET', key, 0, 'EX', ARGV[i * 2], 'NX')
+            result[i * 2 - 1] = redis.call('INCRBY', key, ARGV[i * 2 - 1])
+            result[i * 2] = redis.call('TTL', key)
+        end
+        return result
+        ",
+    )
+});
 
-            if let Some(response) = $body {
-                redis::cmd("UNWATCH").query_async($conn).await?;
-                break response;
-            }
-        }
-    };
+// Subtracts ARGV[1] from the key, without going below zero, or recreating it if it has expired.
+static ROLLBACK_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        local count = tonumber(redis.call('GET', KEYS[1]))
+        if count and count >= 1 then
+            redis.call('DECRBY', KEYS[1], math.min(tonumber(ARGV[1]), count))
+        end
+        ",
+    )
+});
+
+// Replaces the charged cost ARGV[1] with the actual cost ARGV[2], without going below zero, or
+// recreating the key if it has expired.
+static COMMIT_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        local count = tonumber(redis.call('GET', KEYS[1]))
+        if count then
+            local delta = tonumber(ARGV[2]) - tonumber(ARGV[1])
+            if delta > 0 then
+                redis.call('INCRBY', KEYS[1], delta)
+            elseif delta < 0 then
+                redis.call('DECRBY', KEYS[1], math.min(-delta, count))
+            end
+        end
+        ",
+    )
+});
+
+/// Bans are stored in keys with this prefix (after the [Builder::key_prefix]), so it must not be
+/// used by any rate limit keys.
+pub const BAN_KEY_PREFIX: &str = "ban:";
+
+// Where the backend takes its connections from
+#[derive(Clone)]
+enum Connections {
+    Manager(ConnectionManager),
+    #[cfg(feature = "redis-deadpool")]
+    Deadpool(deadpool_redis::Pool),
+    #[cfg(feature = "redis-bb8")]
+    Bb8(bb8::Pool<bb8_redis::RedisConnectionManager>),
 }
 
-#[derive(Debug, Error)]
-pub enum Error {
-    #[error("Redis error: {0}")]
-    Redis(
-        #[source]
-        #[from]
-        redis::RedisError,
-    ),
-    #[error("Unexpected negative TTL response")]
-    NegativeTtl,
+// A connection taken from [Connections], which is returned to its pool (if any) when dropped
+enum Connection {
+    Manager(ConnectionManager),
+    #[cfg(feature = "redis-deadpool")]
+    Deadpool(deadpool_redis::Connection),
+    #[cfg(feature = "redis-bb8")]
+    Bb8(bb8::PooledConnection<'static, bb8_redis::RedisConnectionManager>),
 }
 
-impl ResponseError for Error {
-    fn error_response(&self) -> HttpResponse {
-        HttpResponse::InternalServerError().finish()
+impl ConnectionLike for Connection {
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        match self {
+            Connection::Manager(con) => con.req_packed_command(cmd),
+            #[cfg(feature = "redis-deadpool")]
+            Connection::Deadpool(con) => con.req_packed_command(cmd),
+            #[cfg(feature = "redis-bb8")]
+            Connection::Bb8(con) => con.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        match self {
+            Connection::Manager(con) => con.req_packed_commands(cmd, offset, count),
+            #[cfg(feature = "redis-deadpool")]
+            Connection::Deadpool(con) => con.req_packed_commands(cmd, offset, count),
+            #[cfg(feature = "redis-bb8")]
+            Connection::Bb8(con) => con.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Manager(con) => con.get_db(),
+            #[cfg(feature = "redis-deadpool")]
+            Connection::Deadpool(con) => con.get_db(),
+            #[cfg(feature = "redis-bb8")]
+            Connection::Bb8(con) => con.get_db(),
+        }
     }
 }
 
 /// A Fixed Window rate limiter [Backend] that uses stores data in Redis.
 #[derive(Clone)]
 pub struct RedisBackend {
-    connection: ConnectionManager,
+    connections: Connections,
     key_prefix: Option<String>,
+    ttl_jitter: u64,
+    clock: Arc<dyn Clock>,
 }
 
 impl RedisBackend {
@@ -52,7 +138,9 @@ impl RedisBackend {
     ///
     /// # Arguments
     ///
-    /// * `pool`: [A Redis connection pool](https://github.com/importcjj/mobc-redis)
+    /// * `connection`: A multiplexed Redis connection, shared by every request. Under heavy load
+    ///   a slow command holds up every command queued behind it on the connection, in which case
+    ///   use a pool instead, see [RedisBackend::builder_deadpool] and [RedisBackend::builder_bb8].
     ///
     /// # Examples
     ///
@@ -66,10 +154,138 @@ impl RedisBackend {
     /// # };
     /// ```
     pub fn builder(connection: ConnectionManager) -> Builder {
+        Self::builder_with(Connections::Manager(connection))
+    }
+
+    /// Create a RedisBackendBuilder that takes a connection from a
+    /// [deadpool-redis](https://docs.rs/deadpool-redis) pool for each command.
+    ///
+    /// The pool's size and timeouts are configured on the pool itself; a timeout waiting for a
+    /// connection is reported as [BackendError::Timeout], and connections are checked with a
+    /// `PING` by the pool before they are reused.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use actix_extensible_rate_limit::backend::redis::RedisBackend;
+    /// # use deadpool_redis::{Config, PoolConfig, Runtime, Timeouts};
+    /// # use std::time::Duration;
+    /// let mut config = Config::from_url("redis://127.0.0.1/");
+    /// config.pool = Some(PoolConfig {
+    ///     max_size: 16,
+    ///     timeouts: Timeouts::wait_millis(100),
+    /// });
+    /// let pool = config.create_pool(Some(Runtime::Tokio1)).unwrap();
+    /// let backend = RedisBackend::builder_deadpool(pool).build();
+    /// ```
+    #[cfg(feature = "redis-deadpool")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "redis-deadpool")))]
+    pub fn builder_deadpool(pool: deadpool_redis::Pool) -> Builder {
+        Self::builder_with(Connections::Deadpool(pool))
+    }
+
+    /// Create a RedisBackendBuilder that takes a connection from a
+    /// [bb8-redis](https://docs.rs/bb8-redis) pool for each command.
+    ///
+    /// The pool's size and timeouts are configured on the pool itself; a timeout waiting for a
+    /// connection is reported as [BackendError::Timeout], and connections are checked with a
+    /// `PING` by the pool before they are reused (unless disabled with `test_on_check_out`).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use actix_extensible_rate_limit::backend::redis::RedisBackend;
+    /// # use bb8_redis::RedisConnectionManager;
+    /// # use std::time::Duration;
+    /// # async {
+    /// let manager = RedisConnectionManager::new("redis://127.0.0.1/").unwrap();
+    /// let pool = bb8::Pool::builder()
+    ///     .max_size(16)
+    ///     .connection_timeout(Duration::from_millis(100))
+    ///     .build(manager)
+    ///     .await
+    ///     .unwrap();
+    /// let backend = RedisBackend::builder_bb8(pool).build();
+    /// # };
+    /// ```
+    #[cfg(feature = "redis-bb8")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "redis-bb8")))]
+    pub fn builder_bb8(pool: bb8::Pool<bb8_redis::RedisConnectionManager>) -> Builder {
+        Self::builder_with(Connections::Bb8(pool))
+    }
+
+    fn builder_with(connections: Connections) -> Builder {
         Builder {
-            connection,
+            connections,
             key_prefix: None,
+            ttl_jitter: 0,
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    // Returns a connection to run a command on, waiting for one from the pool if there is one
+    async fn connection(&self) -> Result<Connection, BackendError> {
+        Ok(match &self.connections {
+            Connections::Manager(con) => Connection::Manager(con.clone()),
+            #[cfg(feature = "redis-deadpool")]
+            Connections::Deadpool(pool) => Connection::Deadpool(pool.get().await?),
+            #[cfg(feature = "redis-bb8")]
+            Connections::Bb8(pool) => Connection::Bb8(pool.get_owned().await?),
+        })
+    }
+
+    fn make_ban_key(&self, key: &str) -> String {
+        format!(
+            "{}{BAN_KEY_PREFIX}{key}",
+            self.key_prefix.as_deref().unwrap_or_default()
+        )
+    }
+
+    // Returns the full names of the rate limit keys beginning with `prefix`, leaving out bans
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>, BackendError> {
+        let pattern = prefix_pattern(&self.make_key(prefix));
+        let bans = self.make_key(BAN_KEY_PREFIX);
+        let mut con = self.connection().await?;
+        let mut iter: AsyncIter<String> = con.scan_match(pattern).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            if !key.starts_with(bans.as_ref()) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Adds a request's key and arguments to an invocation of the [REQUEST_SCRIPT].
+    fn add_request(&self, invocation: &mut redis::ScriptInvocation, input: &SimpleInput) {
+        invocation
+            .key(self.make_key(&input.key).as_ref())
+            .arg(input.cost)
+            .arg(input.interval.as_secs() + jitter(self.ttl_jitter, &input.key));
+    }
+
+    fn make_result(
+        &self,
+        input: SimpleInput,
+        count: u64,
+        ttl: i64,
+    ) -> Result<(bool, SimpleOutput, SimpleRollbackToken), BackendError> {
+        if ttl < 0 {
+            return Err(BackendError::Serialization(
+                "Unexpected negative TTL response".into(),
+            ));
         }
+        let allow = count <= input.max_requests;
+        let output = SimpleOutput {
+            limit: input.max_requests,
+            remaining: input.max_requests.saturating_sub(count),
+            reset: self.clock.now() + Duration::from_secs(ttl as u64),
+        };
+        let token = SimpleRollbackToken {
+            key: input.key,
+            cost: input.cost,
+        };
+        Ok((allow, output, token))
     }
 
     fn make_key<'t>(&self, key: &'t str) -> Cow<'t, str> {
@@ -81,8 +297,10 @@ impl RedisBackend {
 }
 
 pub struct Builder {
-    connection: ConnectionManager,
+    connections: Connections,
     key_prefix: Option<String>,
+    ttl_jitter: u64,
+    clock: Arc<dyn Clock>,
 }
 
 impl Builder {
@@ -95,89 +313,274 @@ impl Builder {
         self
     }
 
+    /// Extend the TTL of each new window by a random number of whole seconds, up to `max`.
+    ///
+    /// This spreads out the expiry of keys created in the same second, so that they don't all
+    /// reset at once and stampede Redis at the window boundary. The cost is that each window is
+    /// up to `max` longer than the configured interval.
+    pub fn ttl_jitter(mut self, max: Duration) -> Self {
+        self.ttl_jitter = max.as_secs();
+        self
+    }
+
+    /// Read the time from `clock` rather than tokio, when converting the TTLs reported by Redis
+    /// into [SimpleOutput::reset] times.
+    ///
+    /// Windows are still timed by Redis itself.
+    pub fn clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
     pub fn build(self) -> RedisBackend {
         RedisBackend {
-            connection: self.connection,
+            connections: self.connections,
             key_prefix: self.key_prefix,
+            ttl_jitter: self.ttl_jitter,
+            clock: self.clock,
         }
     }
 }
 
-#[async_trait(?Send)]
 impl Backend<SimpleInput> for RedisBackend {
     type Output = SimpleOutput;
-    type RollbackToken = String;
-    type Error = Error;
+    type RollbackToken = SimpleRollbackToken;
+    type Error = BackendError;
 
     async fn request(
         &self,
         input: SimpleInput,
     ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
-        let key = self.make_key(&input.key);
-        // https://github.com/actix/actix-extras/blob/master/actix-limitation/src/lib.rs#L123
-        let mut pipe = redis::pipe();
-        pipe.atomic()
-            .cmd("SET") // Set key and value
-            .arg(key.as_ref())
-            .arg(0i64)
-            .arg("EX") // Set the specified expire time, in seconds.
-            .arg(input.interval.as_secs())
-            .arg("NX") // Only set the key if it does not already exist.
-            .ignore() // --- ignore returned value of SET command ---
-            .cmd("INCR") // Increment key
-            .arg(key.as_ref())
-            .cmd("TTL") // Return time-to-live of key
-            .arg(key.as_ref());
+        let mut invocation = REQUEST_SCRIPT.prepare_invoke();
+        self.add_request(&mut invocation, &input);
+        let mut con = self.connection().await?;
+        let (count, ttl): (u64, i64) = invocation.invoke_async(&mut con).await?;
+        self.make_result(input, count, ttl)
+    }
 
-        let mut con = self.connection.clone();
-        let (count, ttl): (u64, i64) = pipe.query_async(&mut con).await?;
-        if ttl < 0 {
-            return Err(Self::Error::NegativeTtl);
+    /// Sends every input in a single script invocation.
+    async fn request_many(
+        &self,
+        inputs: Vec<SimpleInput>,
+    ) -> Result<Vec<(bool, Self::Output, Self::RollbackToken)>, Self::Error> {
+        if inputs.is_empty() {
+            return Ok(Vec::new());
         }
-
-        let allow = count <= input.max_requests;
-        let output = SimpleOutput {
-            limit: input.max_requests,
-            remaining: input.max_requests.saturating_sub(count),
-            reset: Instant::now() + Duration::from_secs(ttl as u64),
-        };
-        Ok((allow, output, input.key))
+        let mut invocation = REQUEST_SCRIPT.prepare_invoke();
+        for input in &inputs {
+            self.add_request(&mut invocation, input);
+        }
+        let mut con = self.connection().await?;
+        // A flat list of the count and TTL for each input
+        let values: Vec<i64> = invocation.invoke_async(&mut con).await?;
+        inputs
+            .into_iter()
+            .zip(values.chunks_exact(2))
+            .map(|(input, values)| self.make_result(input, values[0].max(0) as u64, values[1]))
+            .collect()
     }
 
     async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
-        let key = self.make_key(&token);
-        let mut con = self.connection.clone();
-        async_transaction!(&mut con, &[key.as_ref()], {
-            let old_val: Option<u64> = con.get(key.as_ref()).await?;
-            if let Some(old_val) = old_val {
-                if old_val >= 1 {
-                    redis::pipe()
-                        .atomic()
-                        .decr::<_, u64>(key.as_ref(), 1)
-                        .ignore()
-                        .query_async::<_, Option<()>>(&mut con)
-                        .await?
-                } else {
-                    Some(())
-                }
-            } else {
-                Some(())
-            }
-        });
+        let mut con = self.connection().await?;
+        ROLLBACK_SCRIPT
+            .key(self.make_key(&token.key).as_ref())
+            .arg(token.cost)
+            .invoke_async::<_, ()>(&mut con)
+            .await?;
         Ok(())
     }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        Some(token.cost)
+    }
+
+    /// Sends a `PING`, reporting its round trip time.
+    async fn health(&self) -> Result<Health, Self::Error> {
+        let mut con = self.connection().await?;
+        let start = Instant::now();
+        redis::cmd("PING").query_async::<_, ()>(&mut con).await?;
+        Ok(Health {
+            latency: start.elapsed(),
+        })
+    }
 }
 
-#[async_trait(?Send)]
 impl SimpleBackend for RedisBackend {
     /// Note that the key prefix (if set) is automatically included, you do not need to prepend
     /// it yourself.
     async fn remove_key(&self, key: &str) -> Result<(), Self::Error> {
         let key = self.make_key(key);
-        let mut con = self.connection.clone();
-        con.del(key.as_ref()).await?;
+        let mut con = self.connection().await?;
+        con.del::<_, ()>(key.as_ref()).await?;
+        Ok(())
+    }
+
+    /// Uses SCAN, deleting the matching keys in batches. Without a key prefix, `clear()` removes
+    /// every key in the database other than bans, not only those created by this backend.
+    async fn remove_keys(&self, prefix: &str) -> Result<u64, Self::Error> {
+        let keys = self.scan_keys(prefix).await?;
+        let mut con = self.connection().await?;
+        let mut removed = 0;
+        for batch in keys.chunks(100) {
+            removed += con.del::<_, u64>(batch).await?;
+        }
+        Ok(removed)
+    }
+
+    async fn peek(&self, input: &SimpleInput) -> Result<(bool, SimpleOutput), Self::Error> {
+        let key = self.make_key(&input.key);
+        let mut con = self.connection().await?;
+        let (count, ttl): (Option<u64>, i64) = redis::pipe()
+            .get(key.as_ref())
+            .ttl(key.as_ref())
+            .query_async(&mut con)
+            .await?;
+        let (count, ttl) = match count {
+            Some(count) if ttl >= 0 => (count, Duration::from_secs(ttl as u64)),
+            _ => (0, input.interval),
+        };
+        let allow = count.saturating_add(input.cost) <= input.max_requests;
+        let output = SimpleOutput {
+            limit: input.max_requests,
+            remaining: input.max_requests.saturating_sub(count),
+            reset: self.clock.now() + ttl,
+        };
+        Ok((allow, output))
+    }
+
+    async fn set_key(&self, key: &str, count: u64, ttl: Duration) -> Result<(), Self::Error> {
+        if ttl.is_zero() {
+            return self.remove_key(key).await;
+        }
+        let key = self.make_key(key);
+        let mut con = self.connection().await?;
+        redis::cmd("SET")
+            .arg(key.as_ref())
+            .arg(count)
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async::<_, ()>(&mut con)
+            .await?;
+        Ok(())
+    }
+}
+
+impl ReservableBackend for RedisBackend {
+    type Reservation = SimpleReservation;
+
+    async fn reserve(
+        &self,
+        input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::Reservation), Self::Error> {
+        let (allow, output, token) = self.request(input).await?;
+        let reservation = SimpleReservation {
+            key: token.key,
+            cost: token.cost,
+            reset: output.reset,
+        };
+        Ok((allow, output, reservation))
+    }
+
+    async fn commit(
+        &self,
+        reservation: Self::Reservation,
+        actual_cost: u64,
+    ) -> Result<(), Self::Error> {
+        let mut con = self.connection().await?;
+        COMMIT_SCRIPT
+            .key(self.make_key(&reservation.key).as_ref())
+            .arg(reservation.cost)
+            .arg(actual_cost)
+            .invoke_async::<_, ()>(&mut con)
+            .await?;
+        Ok(())
+    }
+}
+
+impl BanStore for RedisBackend {
+    type Error = BackendError;
+
+    async fn ban(&self, key: &str, duration: Duration) -> Result<(), Self::Error> {
+        let mut con = self.connection().await?;
+        redis::cmd("SET")
+            .arg(self.make_ban_key(key))
+            .arg(1)
+            .arg("PX")
+            .arg((duration.as_millis() as u64).max(1))
+            .query_async::<_, ()>(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    async fn unban(&self, key: &str) -> Result<(), Self::Error> {
+        let mut con = self.connection().await?;
+        con.del::<_, ()>(self.make_ban_key(key)).await?;
         Ok(())
     }
+
+    async fn is_banned(&self, key: &str) -> Result<bool, Self::Error> {
+        let mut con = self.connection().await?;
+        Ok(con.exists(self.make_ban_key(key)).await?)
+    }
+}
+
+// Returns a random number of seconds in 0..=max to add to the TTL of a new window for `key`.
+fn jitter(max: u64, key: &str) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+    // Each RandomState is seeded differently, so this varies between calls for the same key
+    RandomState::new().hash_one(key) % (max + 1)
+}
+
+// Returns a SCAN pattern matching all keys that begin with `prefix`.
+fn prefix_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    pattern
+}
+
+impl InspectableBackend for RedisBackend {
+    async fn key_status(&self, key: &str) -> Result<Option<KeyStatus>, Self::Error> {
+        let key = self.make_key(key);
+        let mut con = self.connection().await?;
+        let (count, ttl): (Option<u64>, i64) = redis::pipe()
+            .get(key.as_ref())
+            .cmd("PTTL")
+            .arg(key.as_ref())
+            .query_async(&mut con)
+            .await?;
+        Ok(count.map(|count| KeyStatus {
+            count,
+            ttl: Duration::from_millis(ttl.max(0) as u64),
+        }))
+    }
+
+    /// Uses SCAN, so is safe to run against a production instance, but the results may be
+    /// inconsistent if keys are added or removed while scanning. Bans are not included.
+    async fn list_keys(&self, prefix: &str) -> Result<Vec<String>, Self::Error> {
+        let strip = self.key_prefix.as_deref().map_or(0, str::len);
+        let mut keys = self
+            .scan_keys(prefix)
+            .await?
+            .into_iter()
+            .map(|key| key[strip..].to_owned())
+            .collect::<Vec<_>>();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Uses SCAN, as in [InspectableBackend::list_keys]. Without a key prefix, this counts every key
+    /// in the database other than bans, not only those created by this backend.
+    async fn key_count(&self) -> Result<u64, Self::Error> {
+        Ok(self.scan_keys("").await?.len() as u64)
+    }
 }
 
 #[cfg(test)]
@@ -205,6 +608,7 @@ mod tests {
             interval: MINUTE,
             max_requests: 5,
             key: "test_allow_deny".to_string(),
+            cost: 1,
         };
         for _ in 0..5 {
             // First 5 should be allowed
@@ -223,6 +627,7 @@ mod tests {
             interval: Duration::from_secs(3),
             max_requests: 1,
             key: "test_reset".to_string(),
+            cost: 1,
         };
         // Make first request, should be allowed
         let (allow, _, _) = backend.request(input.clone()).await.unwrap();
@@ -243,6 +648,7 @@ mod tests {
             interval: MINUTE,
             max_requests: 2,
             key: "test_output".to_string(),
+            cost: 1,
         };
         // First of 2 should be allowed.
         let (allow, output, _) = backend.request(input.clone()).await.unwrap();
@@ -271,6 +677,7 @@ mod tests {
             interval: MINUTE,
             max_requests: 5,
             key: "test_rollback".to_string(),
+            cost: 1,
         };
         let (_, output, rollback) = backend.request(input.clone()).await.unwrap();
         assert_eq!(output.remaining, 4);
@@ -285,10 +692,13 @@ mod tests {
     #[actix_web::test]
     async fn test_rollback_key_gone() {
         let backend = make_backend("test_rollback_key_gone").await.build();
-        let mut con = backend.connection.clone();
+        let mut con = backend.connection().await.unwrap();
         // The rollback could happen after the key has already expired
         backend
-            .rollback("test_rollback_key_gone".to_string())
+            .rollback(SimpleRollbackToken {
+                key: "test_rollback_key_gone".to_string(),
+                cost: 1,
+            })
             .await
             .unwrap();
         // In which case nothing should happen
@@ -305,6 +715,7 @@ mod tests {
             interval: MINUTE,
             max_requests: 1,
             key: "test_remove_key".to_string(),
+            cost: 1,
         };
         let (allow, _, _) = backend.request(input.clone()).await.unwrap();
         assert!(allow);
@@ -316,17 +727,43 @@ mod tests {
         assert!(allow);
     }
 
+    #[actix_web::test]
+    async fn test_remove_keys() {
+        let backend = make_backend("test_remove_keys:1")
+            .await
+            .key_prefix(Some("test_remove_keys:"))
+            .build();
+        for key in ["1", "2"] {
+            let input = SimpleInput {
+                interval: MINUTE,
+                max_requests: 1,
+                key: key.to_string(),
+                cost: 1,
+            };
+            backend.request(input).await.unwrap();
+        }
+        backend.ban("1", MINUTE).await.unwrap();
+        assert_eq!(backend.remove_keys("1").await.unwrap(), 1);
+        assert_eq!(backend.clear().await.unwrap(), 1);
+        // Bans are neither listed nor counted as rate limit keys
+        assert!(backend.list_keys("").await.unwrap().is_empty());
+        assert_eq!(backend.key_count().await.unwrap(), 0);
+        assert!(backend.is_banned("1").await.unwrap());
+        backend.unban("1").await.unwrap();
+    }
+
     #[actix_web::test]
     async fn test_key_prefix() {
         let backend = make_backend("prefix:test_key_prefix")
             .await
             .key_prefix(Some("prefix:"))
             .build();
-        let mut con = backend.connection.clone();
+        let mut con = backend.connection().await.unwrap();
         let input = SimpleInput {
             interval: MINUTE,
             max_requests: 5,
             key: "test_key_prefix".to_string(),
+            cost: 1,
         };
         backend.request(input.clone()).await.unwrap();
         assert!(con
@@ -340,4 +777,248 @@ mod tests {
             .await
             .unwrap());
     }
+
+    // Makes requests concurrently, so that they are spread over the pool's connections
+    #[cfg(any(feature = "redis-deadpool", feature = "redis-bb8"))]
+    async fn test_pool(backend: RedisBackend, key: &str) {
+        backend.remove_key(key).await.unwrap();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 5,
+            key: key.to_string(),
+            cost: 1,
+        };
+        let requests = (0..6).map(|_| backend.request(input.clone()));
+        let results = futures::future::try_join_all(requests).await.unwrap();
+        assert_eq!(results.iter().filter(|(allow, _, _)| *allow).count(), 5);
+        let status = backend.key_status(key).await.unwrap().unwrap();
+        assert_eq!(status.count, 6);
+    }
+
+    #[cfg(feature = "redis-deadpool")]
+    #[actix_web::test]
+    async fn test_deadpool() {
+        let host = option_env!("REDIS_HOST").unwrap_or("127.0.0.1");
+        let port = option_env!("REDIS_PORT").unwrap_or("6379");
+        let pool = deadpool_redis::Config::from_url(format!("redis://{host}:{port}"))
+            .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+            .unwrap();
+        test_pool(
+            RedisBackend::builder_deadpool(pool).build(),
+            "test_deadpool",
+        )
+        .await;
+    }
+
+    #[cfg(feature = "redis-bb8")]
+    #[actix_web::test]
+    async fn test_bb8() {
+        let host = option_env!("REDIS_HOST").unwrap_or("127.0.0.1");
+        let port = option_env!("REDIS_PORT").unwrap_or("6379");
+        let manager =
+            bb8_redis::RedisConnectionManager::new(format!("redis://{host}:{port}")).unwrap();
+        let pool = bb8::Pool::builder()
+            .max_size(3)
+            .build(manager)
+            .await
+            .unwrap();
+        test_pool(RedisBackend::builder_bb8(pool).build(), "test_bb8").await;
+    }
+
+    #[cfg(feature = "redis-deadpool")]
+    #[actix_web::test]
+    async fn test_deadpool_errors() {
+        use deadpool_redis::{Config, PoolConfig, Runtime, Timeouts};
+        // Nothing listens on port 1, so creating a connection fails
+        let mut config = Config::from_url("redis://127.0.0.1:1");
+        config.pool = Some(PoolConfig {
+            max_size: 1,
+            timeouts: Timeouts::wait_millis(100),
+        });
+        let pool = config.create_pool(Some(Runtime::Tokio1)).unwrap();
+        let backend = RedisBackend::builder_deadpool(pool.clone()).build();
+        let err = backend.health().await.unwrap_err();
+        assert_eq!(err.kind(), "connection");
+        // Waiting for a connection from a closed pool
+        pool.close();
+        let err = backend.health().await.unwrap_err();
+        assert_eq!(err.kind(), "connection");
+    }
+
+    #[cfg(feature = "redis-bb8")]
+    #[actix_web::test]
+    async fn test_bb8_timeout() {
+        // Nothing listens on port 1, so no connection is available before the timeout
+        let manager = bb8_redis::RedisConnectionManager::new("redis://127.0.0.1:1").unwrap();
+        let pool = bb8::Pool::builder()
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(manager);
+        let backend = RedisBackend::builder_bb8(pool).build();
+        let err = backend.health().await.unwrap_err();
+        assert!(matches!(err, BackendError::Timeout), "{err}");
+    }
+
+    #[actix_web::test]
+    async fn test_ttl_jitter() {
+        let backend = make_backend("test_ttl_jitter")
+            .await
+            .ttl_jitter(Duration::from_secs(30))
+            .build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 5,
+            key: "test_ttl_jitter".to_string(),
+            cost: 1,
+        };
+        let (_, output, _) = backend.request(input).await.unwrap();
+        let ttl = output.seconds_until_reset();
+        assert!((59..=90).contains(&ttl), "{ttl}");
+    }
+
+    #[test]
+    fn test_jitter() {
+        assert_eq!(jitter(0, "key"), 0);
+        let values = (0..100).map(|_| jitter(3, "key")).collect::<Vec<_>>();
+        assert!(values.iter().all(|&v| v <= 3));
+        assert!(values.iter().any(|&v| v != values[0]));
+    }
+
+    #[test]
+    fn test_prefix_pattern() {
+        assert_eq!(prefix_pattern("a:b"), "a:b*");
+        assert_eq!(prefix_pattern("a*[?]\\"), "a\\*\\[\\?\\]\\\\*");
+    }
+
+    #[actix_web::test]
+    async fn test_inspect() {
+        let backend = make_backend("inspect:test_inspect")
+            .await
+            .key_prefix(Some("inspect:"))
+            .build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 5,
+            key: "test_inspect".to_string(),
+            cost: 2,
+        };
+        backend.request(input).await.unwrap();
+        let status = backend.key_status("test_inspect").await.unwrap().unwrap();
+        assert_eq!(status.count, 2);
+        assert!(status.ttl <= MINUTE);
+        assert_eq!(backend.list_keys("test_").await.unwrap(), ["test_inspect"]);
+        assert!(backend.key_count().await.unwrap() >= 1);
+        backend.remove_key("test_inspect").await.unwrap();
+        assert_eq!(backend.key_status("test_inspect").await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_peek() {
+        let backend = make_backend("test_peek").await.build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 2,
+            key: "test_peek".to_string(),
+            cost: 1,
+        };
+        let (allow, output) = backend.peek(&input).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 2);
+        backend.request(input.clone()).await.unwrap();
+        backend.request(input.clone()).await.unwrap();
+        let (allow, output) = backend.peek(&input).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.remaining, 0);
+        // Peeking does not count towards the limit
+        let status = backend.key_status("test_peek").await.unwrap().unwrap();
+        assert_eq!(status.count, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_set_key() {
+        let backend = make_backend("test_set_key").await.build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 5,
+            key: "test_set_key".to_string(),
+            cost: 1,
+        };
+        backend.request(input.clone()).await.unwrap();
+        backend
+            .set_key("test_set_key", 4, MINUTE * 2)
+            .await
+            .unwrap();
+        let (allow, output, _) = backend.request(input.clone()).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 0);
+        assert!(output.seconds_until_reset() > 60);
+        backend
+            .set_key("test_set_key", 0, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(backend.key_status("test_set_key").await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_ban() {
+        let backend = make_backend("ban:test_ban").await.build();
+        backend.ban("test_ban", MINUTE).await.unwrap();
+        assert!(backend.is_banned("test_ban").await.unwrap());
+        backend.unban("test_ban").await.unwrap();
+        assert!(!backend.is_banned("test_ban").await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_health() {
+        let backend = make_backend("test_health").await.build();
+        let health = backend.health().await.unwrap();
+        assert!(health.latency < Duration::from_secs(1));
+    }
+
+    #[actix_web::test]
+    async fn test_request_many() {
+        let backend = make_backend("test_request_many_1").await.build();
+        let mut con = backend.connection().await.unwrap();
+        con.del::<_, ()>("test_request_many_2").await.unwrap();
+        let inputs = [("test_request_many_1", 1), ("test_request_many_2", 5)]
+            .into_iter()
+            .map(|(key, max_requests)| SimpleInput {
+                interval: MINUTE,
+                max_requests,
+                key: key.to_string(),
+                cost: 1,
+            })
+            .collect::<Vec<_>>();
+        let results = backend.request_many(inputs.clone()).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(allow, _, _)| *allow));
+        let results = backend.request_many(inputs).await.unwrap();
+        assert!(!results[0].0);
+        assert!(results[1].0);
+        assert_eq!(results[1].1.remaining, 3);
+        assert_eq!(results[1].2.key, "test_request_many_2");
+        assert!(backend.request_many(Vec::new()).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_commit() {
+        let backend = make_backend("test_reserve_commit").await.build();
+        let input = SimpleInput {
+            interval: MINUTE,
+            max_requests: 10,
+            key: "test_reserve_commit".to_string(),
+            cost: 5,
+        };
+        let (allow, output, reservation) = backend.reserve(input.clone()).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 5);
+        // Refund
+        backend.commit(reservation, 2).await.unwrap();
+        let status = backend.key_status("test_reserve_commit").await.unwrap();
+        assert_eq!(status.unwrap().count, 2);
+        // Charge extra
+        let (_, _, reservation) = backend.reserve(input).await.unwrap();
+        backend.commit(reservation, 8).await.unwrap();
+        let status = backend.key_status("test_reserve_commit").await.unwrap();
+        assert_eq!(status.unwrap().count, 10);
+    }
 }
@@ -0,0 +1,238 @@
+//! Limits that vary by the time of day and day of the week.
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A list of time windows, each with their own limits, e.g. stricter limits during peak hours.
+///
+/// Windows are written as `"<days> <start>-<end>"`, where days is `*`, a day (`mon`), a range
+/// (`mon-fri`), or a comma separated list of either (`mon,wed,fri-sun`), and the times are
+/// `HH:MM` in 24 hour time. Either part may be left out, to match all day, or every day.
+///
+/// A window whose end is before its start crosses midnight, e.g. `"* 22:00-06:00"`, and matches
+/// on the days listed for the time being evaluated. The first matching window is used, requests
+/// outside of every window use the limits configured on the builder.
+///
+/// Use with [SimpleInputFunctionBuilder::schedule](crate::backend::SimpleInputFunctionBuilder::schedule).
+///
+/// # Example
+/// ```
+/// # use std::time::Duration;
+/// # use actix_extensible_rate_limit::backend::schedule::Schedule;
+/// let minute = Duration::from_secs(60);
+/// let schedule = Schedule::new()
+///     .window("mon-fri 09:00-17:00", minute, 100)
+///     .window("* 22:00-06:00", minute, 10_000)
+///     .utc_offset_minutes(-5 * 60);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    windows: Vec<(Window, Duration, u64)>,
+    utc_offset_minutes: i32,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a window, and the limits to be used within it.
+    ///
+    /// # Panics
+    ///
+    /// If the window is not valid, see [Window].
+    pub fn window(mut self, window: &str, interval: Duration, max_requests: u64) -> Self {
+        let parsed = window
+            .parse::<Window>()
+            .unwrap_or_else(|e| panic!("Invalid schedule window {window:?}: {e}"));
+        self.windows.push((parsed, interval, max_requests));
+        self
+    }
+
+    /// The offset from UTC of the timezone windows are written in, the default is UTC.
+    pub fn utc_offset_minutes(mut self, minutes: i32) -> Self {
+        self.utc_offset_minutes = minutes;
+        self
+    }
+
+    /// Returns the interval and max requests of the window that is active now, if any.
+    pub fn current(&self) -> Option<(Duration, u64)> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.at(now)
+    }
+
+    // `since_epoch` is the time since the unix epoch, in UTC.
+    fn at(&self, since_epoch: Duration) -> Option<(Duration, u64)> {
+        let local = since_epoch.as_secs() as i64 / 60 + self.utc_offset_minutes as i64;
+        let minutes = local.rem_euclid(MINUTES_PER_DAY as i64) as u32;
+        // The epoch was a Thursday
+        let day = (local.div_euclid(MINUTES_PER_DAY as i64) + 3).rem_euclid(7) as u8;
+        self.windows
+            .iter()
+            .find(|(window, _, _)| window.contains(day, minutes))
+            .map(|(_, interval, max_requests)| (*interval, *max_requests))
+    }
+}
+
+/// A time window within a [Schedule].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    // Bit 0 is Monday
+    days: u8,
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    fn contains(&self, day: u8, minutes: u32) -> bool {
+        let in_time = if self.start <= self.end {
+            self.start <= minutes && minutes < self.end
+        } else {
+            minutes >= self.start || minutes < self.end
+        };
+        self.days & (1 << day) != 0 && in_time
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum WindowError {
+    #[error("Unknown day: {0:?}")]
+    InvalidDay(String),
+    #[error("Invalid time, expected HH:MM: {0:?}")]
+    InvalidTime(String),
+    #[error("Expected \"<days> <start>-<end>\"")]
+    InvalidFormat,
+}
+
+impl FromStr for Window {
+    type Err = WindowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split_whitespace().collect::<Vec<_>>();
+        let (days, times) = match parts.as_slice() {
+            [days, times] => (*days, Some(*times)),
+            [part] if part.contains(':') => ("*", Some(*part)),
+            [days] => (*days, None),
+            _ => return Err(WindowError::InvalidFormat),
+        };
+        let (start, end) = match times {
+            Some(times) => {
+                let (start, end) = times.split_once('-').ok_or(WindowError::InvalidFormat)?;
+                (parse_time(start)?, parse_time(end)?)
+            }
+            None => (0, MINUTES_PER_DAY),
+        };
+        Ok(Window {
+            days: parse_days(days)?,
+            start,
+            end,
+        })
+    }
+}
+
+fn parse_days(s: &str) -> Result<u8, WindowError> {
+    if s == "*" {
+        return Ok(0x7f);
+    }
+    let day = |d: &str| {
+        DAYS.iter()
+            .position(|name| name.eq_ignore_ascii_case(d))
+            .ok_or_else(|| WindowError::InvalidDay(d.to_owned()))
+    };
+    let mut days = 0;
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (day(from)?, day(to)?);
+                let mut d = from;
+                loop {
+                    days |= 1 << d;
+                    if d == to {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            }
+            None => days |= 1 << day(part)?,
+        }
+    }
+    Ok(days)
+}
+
+fn parse_time(s: &str) -> Result<u32, WindowError> {
+    let invalid = || WindowError::InvalidTime(s.to_owned());
+    let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+    let hours = hours.parse::<u32>().map_err(|_| invalid())?;
+    let minutes = minutes.parse::<u32>().map_err(|_| invalid())?;
+    // 24:00 is allowed as the end of the day
+    if minutes >= 60 || hours * 60 + minutes > MINUTES_PER_DAY {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    // Monday 2024-01-01 00:00 UTC
+    const MONDAY: u64 = 1_704_067_200;
+
+    fn at(day: u64, hour: u64, minute: u64) -> Duration {
+        Duration::from_secs(MONDAY + day * 86400 + hour * 3600 + minute * 60)
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(
+            "mon-fri 09:00-17:30".parse::<Window>(),
+            Ok(Window {
+                days: 0b0011111,
+                start: 9 * 60,
+                end: 17 * 60 + 30
+            })
+        );
+        assert_eq!("fri-mon".parse::<Window>().unwrap().days, 0b1110001);
+        assert_eq!("sat,sun".parse::<Window>().unwrap().end, MINUTES_PER_DAY);
+        assert_eq!("22:00-06:00".parse::<Window>().unwrap().days, 0x7f);
+        assert_eq!(
+            "funday".parse::<Window>(),
+            Err(WindowError::InvalidDay("funday".to_owned()))
+        );
+        assert!("* 25:00-26:00".parse::<Window>().is_err());
+        assert!("* 09:00".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule::new()
+            .window("mon-fri 09:00-17:00", MINUTE, 100)
+            .window("* 22:00-06:00", MINUTE, 10_000);
+        assert_eq!(schedule.at(at(0, 9, 0)), Some((MINUTE, 100)));
+        assert_eq!(schedule.at(at(0, 17, 0)), None);
+        assert_eq!(schedule.at(at(5, 12, 0)), None);
+        assert_eq!(schedule.at(at(5, 23, 0)), Some((MINUTE, 10_000)));
+        assert_eq!(schedule.at(at(6, 5, 59)), Some((MINUTE, 10_000)));
+    }
+
+    #[test]
+    fn test_utc_offset() {
+        let schedule = Schedule::new()
+            .window("mon 09:00-10:00", MINUTE, 100)
+            .utc_offset_minutes(-5 * 60);
+        assert_eq!(schedule.at(at(0, 9, 30)), None);
+        assert_eq!(schedule.at(at(0, 14, 30)), Some((MINUTE, 100)));
+        // Sunday evening in UTC-5 is still Monday in UTC
+        let schedule = Schedule::new()
+            .window("sun", MINUTE, 1)
+            .utc_offset_minutes(-5 * 60);
+        assert_eq!(schedule.at(at(0, 1, 0)), Some((MINUTE, 1)));
+    }
+}
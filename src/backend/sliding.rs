@@ -0,0 +1,446 @@
+use crate::backend::clock::{Clock, TokioClock};
+use crate::backend::memory::{nanos, nanos_since, wait_unless_shutdown, GcTask};
+use crate::backend::{Backend, BackendError, SimpleInput, SimpleOutput, SimpleRollbackToken};
+use crate::supervisor::{ShutdownSignal, Supervisor};
+use actix_web::rt::time::Instant;
+use dashmap::DashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_GC_INTERVAL_SECONDS: u64 = 60 * 10;
+
+pub const DEFAULT_PRECISION: u32 = 10;
+
+type PrecisionFn = dyn Fn(Duration) -> u32 + Send + Sync;
+
+/// A Sliding Window rate limiter [Backend] that stores keys in memory.
+///
+/// Each key's interval is divided into a number of sub-windows (its precision), and a request is
+/// allowed if the total of the sub-windows within the last interval is below the limit. As each
+/// sub-window passes out of the interval, the requests counted in it are forgotten, so clients
+/// aren't able to send twice the limit across the boundary of two fixed windows.
+///
+/// A higher precision is more accurate, but each key stores a counter per sub-window. The
+/// precision can be chosen for each interval with [Builder::with_precision_fn], e.g. a few
+/// sub-windows for a 1 second interval, but one per hour for a 24 hour interval.
+///
+/// Denied requests are not counted. The reset time reported in the [SimpleOutput] is the earliest
+/// instant that a counted request leaves the interval, freeing some of the limit.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::sliding::SlidingWindowBackend;
+/// # use std::time::Duration;
+/// let backend = SlidingWindowBackend::builder()
+///     .with_precision_fn(|interval| {
+///         if interval >= Duration::from_secs(3600) {
+///             24
+///         } else {
+///             10
+///         }
+///     })
+///     .build();
+/// ```
+#[derive(Clone)]
+pub struct SlidingWindowBackend<K = String> {
+    map: Arc<DashMap<K, Window>>,
+    // Sub-windows are numbered by the nanoseconds since this instant
+    epoch: Instant,
+    precision: Arc<PrecisionFn>,
+    clock: Arc<dyn Clock>,
+    gc_handle: Option<Arc<GcTask>>,
+    // Held so that a supervised garbage collector isn't stopped by the caller dropping their handle
+    _supervisor: Option<Supervisor>,
+}
+
+struct Window {
+    // The width of each sub-window, in nanoseconds
+    width: u64,
+    // The number of the newest sub-window, counted in widths since the epoch
+    head: u64,
+    // The count of each sub-window, indexed by its number modulo the precision
+    counts: Box<[u64]>,
+}
+
+impl Window {
+    fn new(width: u64, precision: u32) -> Self {
+        Self {
+            width,
+            head: 0,
+            counts: vec![0; precision as usize].into_boxed_slice(),
+        }
+    }
+
+    fn precision(&self) -> u64 {
+        self.counts.len() as u64
+    }
+
+    // Move the window forward to the sub-window `slot`, clearing those that have left it
+    fn advance(&mut self, slot: u64) {
+        if slot <= self.head {
+            return;
+        }
+        let cleared = (slot - self.head).min(self.precision());
+        for n in slot + 1 - cleared..=slot {
+            self.counts[(n % self.precision()) as usize] = 0;
+        }
+        self.head = slot;
+    }
+
+    fn total(&self) -> u64 {
+        self.counts
+            .iter()
+            .fold(0u64, |total, count| total.saturating_add(*count))
+    }
+
+    // When the sub-window `slot` leaves the window, in nanoseconds since the epoch
+    fn leaves_at(&self, slot: u64) -> u64 {
+        slot.saturating_add(self.precision())
+            .saturating_mul(self.width)
+    }
+
+    // When the oldest sub-window with a count leaves the window
+    fn earliest_reset(&self) -> Option<u64> {
+        let oldest = self.head.saturating_sub(self.precision() - 1);
+        (oldest..=self.head)
+            .find(|slot| self.counts[(slot % self.precision()) as usize] > 0)
+            .map(|slot| self.leaves_at(slot))
+    }
+}
+
+impl SlidingWindowBackend {
+    pub fn builder() -> Builder {
+        Builder {
+            gc_interval: Some(Duration::from_secs(DEFAULT_GC_INTERVAL_SECONDS)),
+            precision: Arc::new(|_| DEFAULT_PRECISION),
+            clock: Arc::new(TokioClock),
+            supervisor: None,
+        }
+    }
+}
+
+impl<K: Eq + Hash + 'static> SlidingWindowBackend<K> {
+    /// Stop the garbage collector, waiting for it to finish, see
+    /// [InMemoryBackend::close](crate::backend::memory::InMemoryBackend::close).
+    pub async fn close(&self) {
+        if let Some(gc) = &self.gc_handle {
+            gc.close().await;
+        }
+    }
+
+    async fn garbage_collector(
+        map: Arc<DashMap<K, Window>>,
+        epoch: Instant,
+        clock: Arc<dyn Clock>,
+        interval: Duration,
+        mut shutdown: Option<ShutdownSignal>,
+    ) {
+        loop {
+            let now = nanos_since(epoch, clock.now());
+            map.retain(|_k, v| v.leaves_at(v.head) > now);
+            let wait = actix_web::rt::time::sleep(interval);
+            if !wait_unless_shutdown(wait, &mut shutdown).await {
+                break;
+            }
+        }
+    }
+}
+
+pub struct Builder {
+    gc_interval: Option<Duration>,
+    precision: Arc<PrecisionFn>,
+    clock: Arc<dyn Clock>,
+    supervisor: Option<Supervisor>,
+}
+
+impl Builder {
+    /// Override the default garbage collector interval.
+    ///
+    /// Set to None to disable garbage collection.
+    ///
+    /// The garbage collector periodically scans the internal map, removing keys without any
+    /// requests left in their window.
+    pub fn with_gc_interval(mut self, interval: Option<Duration>) -> Self {
+        self.gc_interval = interval;
+        self
+    }
+
+    /// Divide every interval into `precision` sub-windows, the default is 10.
+    ///
+    /// A precision of 1 is a fixed window.
+    ///
+    /// # Panics
+    ///
+    /// If `precision` is zero.
+    pub fn with_precision(self, precision: u32) -> Self {
+        assert!(precision > 0, "Precision must be non-zero");
+        self.with_precision_fn(move |_| precision)
+    }
+
+    /// Choose the number of sub-windows for each key from its interval.
+    ///
+    /// A precision of zero is treated as 1. If a key's interval or precision changes, its
+    /// window starts again.
+    pub fn with_precision_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Duration) -> u32 + Send + Sync + 'static,
+    {
+        self.precision = Arc::new(f);
+        self
+    }
+
+    /// Read the time from `clock` rather than tokio, see
+    /// [memory::Builder::with_clock](crate::backend::memory::Builder::with_clock).
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Run the garbage collector under a [Supervisor], see
+    /// [memory::Builder::with_supervisor](crate::backend::memory::Builder::with_supervisor).
+    pub fn with_supervisor(mut self, supervisor: Supervisor) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    pub fn build(self) -> SlidingWindowBackend<String> {
+        self.build_keyed()
+    }
+
+    /// Build a backend that uses keys of type `K` rather than [String], for [SimpleInput]s with
+    /// the same key type.
+    pub fn build_keyed<K>(self) -> SlidingWindowBackend<K>
+    where
+        K: Eq + Hash + Clone + Send + Sync + 'static,
+    {
+        let map = Arc::new(DashMap::new());
+        let epoch = self.clock.now();
+        let mut gc_handle = None;
+        if let Some(gc_interval) = self.gc_interval {
+            assert!(
+                gc_interval.as_secs_f64() > 0f64,
+                "GC interval must be non-zero"
+            );
+            let gc_map = map.clone();
+            let clock = self.clock.clone();
+            gc_handle = Some(Arc::new(GcTask::spawn(
+                self.supervisor.clone(),
+                move |shutdown| {
+                    SlidingWindowBackend::garbage_collector(
+                        gc_map,
+                        epoch,
+                        clock,
+                        gc_interval,
+                        shutdown,
+                    )
+                },
+            )));
+        }
+        SlidingWindowBackend {
+            map,
+            epoch,
+            precision: self.precision,
+            clock: self.clock,
+            gc_handle,
+            _supervisor: self.supervisor,
+        }
+    }
+}
+
+impl<K> Backend<SimpleInput<K>> for SlidingWindowBackend<K>
+where
+    K: Eq + Hash + Clone + 'static,
+{
+    type Output = SimpleOutput;
+    type RollbackToken = SimpleRollbackToken<K>;
+    type Error = BackendError;
+
+    async fn request(
+        &self,
+        input: SimpleInput<K>,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        if let Some(gc) = &self.gc_handle {
+            gc.start();
+        }
+        let now = nanos_since(self.epoch, self.clock.now());
+        let interval = nanos(input.interval, "Interval")?;
+        let precision = (self.precision)(input.interval).max(1);
+        let width = (interval / u64::from(precision)).max(1);
+        let slot = now / width;
+
+        let mut window = self
+            .map
+            .entry(input.key.clone())
+            .or_insert_with(|| Window::new(width, precision));
+        if window.width != width || window.precision() != u64::from(precision) {
+            *window = Window::new(width, precision);
+        }
+        window.advance(slot);
+        let mut count = window.total();
+        let allow = count.saturating_add(input.cost) <= input.max_requests;
+        if allow {
+            let index = (slot % window.precision()) as usize;
+            window.counts[index] = window.counts[index].saturating_add(input.cost);
+            count = count.saturating_add(input.cost);
+        }
+        let reset = window
+            .earliest_reset()
+            .unwrap_or_else(|| now.saturating_add(interval));
+        drop(window);
+
+        let output = SimpleOutput {
+            limit: input.max_requests,
+            remaining: input.max_requests.saturating_sub(count),
+            reset: self.epoch + Duration::from_nanos(reset),
+        };
+        let token = SimpleRollbackToken {
+            key: input.key,
+            cost: if allow { input.cost } else { 0 },
+        };
+        Ok((allow, output, token))
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        if let Some(mut window) = self.map.get_mut(&token.key) {
+            // The request was counted in the newest sub-window, unless it has since moved on
+            let mut remaining = token.cost;
+            let oldest = window.head.saturating_sub(window.precision() - 1);
+            for slot in (oldest..=window.head).rev() {
+                let index = (slot % window.precision()) as usize;
+                let refund = remaining.min(window.counts[index]);
+                window.counts[index] -= refund;
+                remaining -= refund;
+                if remaining == 0 {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        Some(token.cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::clock::ManualClock;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn input(key: &str) -> SimpleInput {
+        SimpleInput {
+            interval: MINUTE,
+            max_requests: 5,
+            key: key.to_string(),
+            cost: 1,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_sliding_window() {
+        let clock = ManualClock::new();
+        let backend = SlidingWindowBackend::builder()
+            .with_gc_interval(None)
+            .with_precision(6)
+            .with_clock(clock.clone())
+            .build();
+        let start = clock.now();
+        for _ in 0..3 {
+            assert!(backend.request(input("KEY1")).await.unwrap().0);
+        }
+        clock.advance(SECOND * 30);
+        for _ in 0..2 {
+            assert!(backend.request(input("KEY1")).await.unwrap().0);
+        }
+        let (allow, output, token) = backend.request(input("KEY1")).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.remaining, 0);
+        // The first 3 requests leave the window first
+        assert_eq!(output.reset, start + MINUTE);
+        // Denied requests aren't counted, so there is nothing to roll back
+        backend.rollback(token).await.unwrap();
+        clock.advance(SECOND * 30);
+        let (allow, output, _) = backend.request(input("KEY1")).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 2);
+        assert_eq!(output.reset, start + SECOND * 90);
+    }
+
+    #[actix_web::test]
+    async fn test_precision_fn() {
+        let clock = ManualClock::new();
+        let backend = SlidingWindowBackend::builder()
+            .with_gc_interval(None)
+            .with_precision_fn(|interval| if interval < MINUTE { 1 } else { 60 })
+            .with_clock(clock.clone())
+            .build();
+        backend.request(input("KEY1")).await.unwrap();
+        assert_eq!(backend.map.get("KEY1").unwrap().counts.len(), 60);
+        let short = SimpleInput {
+            interval: SECOND,
+            ..input("KEY2")
+        };
+        backend.request(short).await.unwrap();
+        assert_eq!(backend.map.get("KEY2").unwrap().counts.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_rollback() {
+        let backend = SlidingWindowBackend::builder().build();
+        let (_, output, token) = backend.request(input("KEY1")).await.unwrap();
+        assert_eq!(output.remaining, 4);
+        backend.rollback(token).await.unwrap();
+        let (_, output, _) = backend.request(input("KEY1")).await.unwrap();
+        assert_eq!(output.remaining, 4);
+    }
+
+    #[actix_web::test]
+    async fn test_interval_overflow() {
+        let backend = SlidingWindowBackend::builder().build();
+        let oversized = SimpleInput {
+            interval: Duration::MAX,
+            ..input("KEY1")
+        };
+        let err = backend.request(oversized).await.unwrap_err();
+        assert_eq!(err.kind(), "overflow");
+        assert!(backend.request(input("KEY1")).await.unwrap().0);
+    }
+
+    #[actix_web::test]
+    async fn test_garbage_collection() {
+        tokio::time::pause();
+        let backend = SlidingWindowBackend::builder()
+            .with_gc_interval(Some(MINUTE))
+            .build();
+        backend.request(input("KEY1")).await.unwrap();
+        assert!(backend.map.contains_key("KEY1"));
+        tokio::time::advance(MINUTE).await;
+        tokio::task::yield_now().await;
+        assert!(!backend.map.contains_key("KEY1"));
+    }
+
+    #[actix_web::test]
+    async fn test_supervised_garbage_collection() {
+        tokio::time::pause();
+        let supervisor = Supervisor::new();
+        let backend = SlidingWindowBackend::builder()
+            .with_gc_interval(Some(MINUTE))
+            .with_supervisor(supervisor.clone())
+            .build();
+        backend.request(input("KEY1")).await.unwrap();
+        tokio::time::advance(MINUTE).await;
+        tokio::task::yield_now().await;
+        assert!(!backend.map.contains_key("KEY1"));
+        // Once shut down the garbage collector should no longer run
+        supervisor.shutdown().await;
+        backend.request(input("KEY2")).await.unwrap();
+        tokio::time::advance(MINUTE * 2).await;
+        tokio::task::yield_now().await;
+        assert!(backend.map.contains_key("KEY2"));
+    }
+}
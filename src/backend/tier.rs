@@ -0,0 +1,173 @@
+//! Per-tenant plans, with their own limits.
+use actix_web::rt::time::Instant;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A named plan (e.g. free, pro or enterprise) with its own limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tier {
+    pub name: String,
+    /// The rate limiting interval.
+    pub interval: Duration,
+    /// The total requests to be allowed within the interval.
+    pub max_requests: u64,
+}
+
+impl Tier {
+    pub fn new(name: impl Into<String>, interval: Duration, max_requests: u64) -> Self {
+        Self {
+            name: name.into(),
+            interval,
+            max_requests,
+        }
+    }
+}
+
+/// Looks up the [Tier] for a rate limiting key, e.g. from a database of customer accounts.
+///
+/// Use with [SimpleInputFunctionBuilder::tier_resolver](crate::backend::SimpleInputFunctionBuilder::tier_resolver).
+#[async_trait(?Send)]
+pub trait TierResolver {
+    async fn resolve(&self, key: &str) -> Result<Tier, actix_web::Error>;
+}
+
+/// A [TierResolver] that caches the results of another resolver for a fixed time.
+///
+/// The cache is shared between all clones, so a single instance can be used by every worker.
+///
+/// # Example
+/// ```
+/// # use std::time::Duration;
+/// # use actix_extensible_rate_limit::backend::tier::{CachedTierResolver, Tier, TierResolver};
+/// struct Accounts;
+///
+/// #[async_trait::async_trait(?Send)]
+/// impl TierResolver for Accounts {
+///     async fn resolve(&self, key: &str) -> Result<Tier, actix_web::Error> {
+///         // Look up the account in a database...
+///         Ok(Tier::new("free", Duration::from_secs(60), 100))
+///     }
+/// }
+///
+/// let resolver = CachedTierResolver::new(Accounts, Duration::from_secs(300));
+/// ```
+#[derive(Clone)]
+pub struct CachedTierResolver<R> {
+    inner: R,
+    ttl: Duration,
+    capacity: usize,
+    cache: Arc<Mutex<HashMap<String, (Tier, Instant)>>>,
+}
+
+impl<R> CachedTierResolver<R> {
+    /// Cache tiers resolved by `inner` for `ttl`, keeping at most 10,000 entries.
+    pub fn new(inner: R, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            capacity: 10_000,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Override the maximum number of cached entries.
+    ///
+    /// Once full, expired entries are removed, and if there is still no space, new results are
+    /// not cached until existing entries expire.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Remove a key from the cache, e.g. after a customer changes plan.
+    pub fn invalidate(&self, key: &str) {
+        self.cache.lock().unwrap().remove(key);
+    }
+}
+
+#[async_trait(?Send)]
+impl<R: TierResolver> TierResolver for CachedTierResolver<R> {
+    async fn resolve(&self, key: &str) -> Result<Tier, actix_web::Error> {
+        let now = Instant::now();
+        if let Some((tier, expiry)) = self.cache.lock().unwrap().get(key) {
+            if *expiry > now {
+                return Ok(tier.clone());
+            }
+        }
+        let tier = self.inner.resolve(key).await?;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.capacity && !cache.contains_key(key) {
+            cache.retain(|_, (_, expiry)| *expiry > now);
+        }
+        if cache.len() < self.capacity || cache.contains_key(key) {
+            cache.insert(key.to_owned(), (tier.clone(), now + self.ttl));
+        }
+        Ok(tier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[derive(Clone)]
+    struct CountingResolver {
+        calls: Rc<Cell<u64>>,
+    }
+
+    #[async_trait(?Send)]
+    impl TierResolver for CountingResolver {
+        async fn resolve(&self, key: &str) -> Result<Tier, actix_web::Error> {
+            self.calls.set(self.calls.get() + 1);
+            Ok(match key {
+                "pro" => Tier::new("pro", MINUTE, 1000),
+                _ => Tier::new("free", MINUTE, 10),
+            })
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_cached_resolver() {
+        tokio::time::pause();
+        let calls = Rc::new(Cell::new(0));
+        let resolver = CachedTierResolver::new(
+            CountingResolver {
+                calls: calls.clone(),
+            },
+            MINUTE,
+        );
+        assert_eq!(resolver.resolve("pro").await.unwrap().max_requests, 1000);
+        assert_eq!(resolver.resolve("pro").await.unwrap().max_requests, 1000);
+        assert_eq!(calls.get(), 1);
+        resolver.invalidate("pro");
+        resolver.resolve("pro").await.unwrap();
+        assert_eq!(calls.get(), 2);
+        tokio::time::advance(MINUTE).await;
+        resolver.resolve("pro").await.unwrap();
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_capacity() {
+        let calls = Rc::new(Cell::new(0));
+        let resolver = CachedTierResolver::new(
+            CountingResolver {
+                calls: calls.clone(),
+            },
+            MINUTE,
+        )
+        .capacity(1);
+        resolver.resolve("a").await.unwrap();
+        resolver.resolve("b").await.unwrap();
+        resolver.resolve("b").await.unwrap();
+        assert_eq!(calls.get(), 3);
+        resolver.resolve("a").await.unwrap();
+        assert_eq!(calls.get(), 3);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::backend::clock::{Clock, TokioClock};
+use crate::backend::memory::{wait_unless_shutdown, GcTask};
+use crate::backend::{Backend, KeyedInput, SimpleOutput, SimpleRollbackToken};
+use crate::supervisor::{ShutdownSignal, Supervisor};
+use actix_web::rt::time::Instant;
+use dashmap::DashMap;
+use std::convert::Infallible;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_GC_INTERVAL_SECONDS: u64 = 60 * 10;
+
+// The longest wait reported for a bucket that refills too slowly to ever allow the request
+const MAX_WAIT: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+/// The rate at which a token bucket refills, and the most tokens it can hold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    /// Tokens added to the bucket per second.
+    pub per_second: f64,
+    /// The capacity of the bucket, i.e. the most requests allowed at once.
+    pub burst: u64,
+}
+
+impl Rate {
+    /// # Panics
+    ///
+    /// If `per_second` is not a positive, finite number.
+    pub fn new(per_second: f64, burst: u64) -> Self {
+        assert!(
+            per_second.is_finite() && per_second > 0.0,
+            "Rate must be positive"
+        );
+        Self { per_second, burst }
+    }
+}
+
+/// The input for a [TokenBucketBackend], e.g. "sustained 10 req/s with a burst of 100, and a
+/// peak of 50 req/s".
+#[derive(Debug, Clone)]
+pub struct TokenBucketInput<K = String> {
+    /// The long term rate, and the burst allowed above it.
+    pub sustained: Rate,
+    /// An optional second bucket that bounds how quickly the sustained burst may be used, e.g.
+    /// a rate of 50 per second with a burst of 5.
+    pub peak: Option<Rate>,
+    /// The rate limit key to be used for this request.
+    pub key: K,
+    /// The number of tokens this request takes from each bucket, usually 1.
+    pub cost: u64,
+}
+
+impl KeyedInput for TokenBucketInput {
+    fn key(&self) -> &str {
+        &self.key
+    }
+
+    fn cost(&self) -> u64 {
+        self.cost
+    }
+}
+
+/// A Token Bucket rate limiter [Backend] that stores keys in memory, with an optional second
+/// (peak rate) bucket, as in the two rate policies of telecom SLAs.
+///
+/// A request is allowed only if both buckets hold enough tokens for its cost, in which case it
+/// takes them from both at once. Denied requests take no tokens.
+///
+/// The [SimpleOutput] reports the sustained burst as the limit, the whole tokens left in the
+/// emptier bucket as the remaining requests, and as the reset time the instant that both buckets
+/// will next hold enough tokens for another request of the same cost.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::token_bucket::{Rate, TokenBucketBackend, TokenBucketInput};
+/// # use actix_extensible_rate_limit::backend::Backend;
+/// # async {
+/// let backend = TokenBucketBackend::builder().build();
+/// let input = TokenBucketInput {
+///     sustained: Rate::new(10.0, 100),
+///     peak: Some(Rate::new(50.0, 5)),
+///     key: "client".to_owned(),
+///     cost: 1,
+/// };
+/// let (allow, output, _) = backend.request(input).await.unwrap();
+/// # };
+/// ```
+#[derive(Clone)]
+pub struct TokenBucketBackend<K = String> {
+    map: Arc<DashMap<K, Buckets>>,
+    clock: Arc<dyn Clock>,
+    gc_handle: Option<Arc<GcTask>>,
+    // Held so that a supervised garbage collector isn't stopped by the caller dropping their handle
+    _supervisor: Option<Supervisor>,
+}
+
+struct Buckets {
+    sustained: f64,
+    peak: f64,
+    updated: Instant,
+    // Once both buckets are full the key can be forgotten
+    full_at: Instant,
+}
+
+impl TokenBucketBackend {
+    pub fn builder() -> Builder {
+        Builder {
+            gc_interval: Some(Duration::from_secs(DEFAULT_GC_INTERVAL_SECONDS)),
+            clock: Arc::new(TokioClock),
+            supervisor: None,
+        }
+    }
+}
+
+impl<K: Eq + Hash + 'static> TokenBucketBackend<K> {
+    /// Stop the garbage collector, waiting for it to finish, see
+    /// [InMemoryBackend::close](crate::backend::memory::InMemoryBackend::close).
+    pub async fn close(&self) {
+        if let Some(gc) = &self.gc_handle {
+            gc.close().await;
+        }
+    }
+
+    async fn garbage_collector(
+        map: Arc<DashMap<K, Buckets>>,
+        clock: Arc<dyn Clock>,
+        interval: Duration,
+        mut shutdown: Option<ShutdownSignal>,
+    ) {
+        loop {
+            let now = clock.now();
+            map.retain(|_k, v| v.full_at > now);
+            let wait = actix_web::rt::time::sleep(interval);
+            if !wait_unless_shutdown(wait, &mut shutdown).await {
+                break;
+            }
+        }
+    }
+}
+
+pub struct Builder {
+    gc_interval: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    supervisor: Option<Supervisor>,
+}
+
+impl Builder {
+    /// Override the default garbage collector interval.
+    ///
+    /// Set to None to disable garbage collection.
+    ///
+    /// The garbage collector periodically scans the internal map, removing keys whose buckets
+    /// have refilled.
+    pub fn with_gc_interval(mut self, interval: Option<Duration>) -> Self {
+        self.gc_interval = interval;
+        self
+    }
+
+    /// Read the time from `clock` rather than tokio, see
+    /// [memory::Builder::with_clock](crate::backend::memory::Builder::with_clock).
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Run the garbage collector under a [Supervisor], see
+    /// [memory::Builder::with_supervisor](crate::backend::memory::Builder::with_supervisor).
+    pub fn with_supervisor(mut self, supervisor: Supervisor) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    pub fn build(self) -> TokenBucketBackend<String> {
+        self.build_keyed()
+    }
+
+    /// Build a backend that uses keys of type `K` rather than [String], for
+    /// [TokenBucketInput]s with the same key type.
+    pub fn build_keyed<K>(self) -> TokenBucketBackend<K>
+    where
+        K: Eq + Hash + Clone + Send + Sync + 'static,
+    {
+        let map = Arc::new(DashMap::new());
+        let mut gc_handle = None;
+        if let Some(gc_interval) = self.gc_interval {
+            assert!(
+                gc_interval.as_secs_f64() > 0f64,
+                "GC interval must be non-zero"
+            );
+            let gc_map = map.clone();
+            let clock = self.clock.clone();
+            gc_handle = Some(Arc::new(GcTask::spawn(
+                self.supervisor.clone(),
+                move |shutdown| {
+                    TokenBucketBackend::garbage_collector(gc_map, clock, gc_interval, shutdown)
+                },
+            )));
+        }
+        TokenBucketBackend {
+            map,
+            clock: self.clock,
+            gc_handle,
+            _supervisor: self.supervisor,
+        }
+    }
+}
+
+// The tokens in a bucket after refilling it for `elapsed`
+fn refill(tokens: f64, rate: &Rate, elapsed: Duration) -> f64 {
+    (tokens + elapsed.as_secs_f64() * rate.per_second).min(rate.burst as f64)
+}
+
+// The time until a bucket holds `tokens`
+fn wait_for(current: f64, tokens: f64, rate: &Rate) -> Duration {
+    let deficit = tokens - current;
+    if deficit <= 0.0 {
+        return Duration::ZERO;
+    }
+    Duration::try_from_secs_f64(deficit / rate.per_second)
+        .unwrap_or(MAX_WAIT)
+        .min(MAX_WAIT)
+}
+
+impl<K> Backend<TokenBucketInput<K>> for TokenBucketBackend<K>
+where
+    K: Eq + Hash + Clone + 'static,
+{
+    type Output = SimpleOutput;
+    type RollbackToken = SimpleRollbackToken<K>;
+    type Error = Infallible;
+
+    async fn request(
+        &self,
+        input: TokenBucketInput<K>,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        if let Some(gc) = &self.gc_handle {
+            gc.start();
+        }
+        let now = self.clock.now();
+        let sustained = input.sustained;
+        let peak = input.peak;
+        let mut buckets = self
+            .map
+            .entry(input.key.clone())
+            .or_insert_with(|| Buckets {
+                sustained: sustained.burst as f64,
+                peak: peak.map_or(0.0, |peak| peak.burst as f64),
+                updated: now,
+                full_at: now,
+            });
+        let elapsed = now.saturating_duration_since(buckets.updated);
+        buckets.sustained = refill(buckets.sustained, &sustained, elapsed);
+        if let Some(peak) = &peak {
+            buckets.peak = refill(buckets.peak, peak, elapsed);
+        }
+        buckets.updated = now;
+
+        // Both buckets are checked and updated under the same lock
+        let cost = input.cost as f64;
+        let allow = buckets.sustained >= cost && (peak.is_none() || buckets.peak >= cost);
+        if allow {
+            buckets.sustained -= cost;
+            if peak.is_some() {
+                buckets.peak -= cost;
+            }
+        }
+        let mut retry = wait_for(buckets.sustained, cost, &sustained);
+        let mut full = wait_for(buckets.sustained, sustained.burst as f64, &sustained);
+        let mut remaining = buckets.sustained;
+        if let Some(peak) = &peak {
+            retry = retry.max(wait_for(buckets.peak, cost, peak));
+            full = full.max(wait_for(buckets.peak, peak.burst as f64, peak));
+            remaining = remaining.min(buckets.peak);
+        }
+        buckets.full_at = now + full;
+        drop(buckets);
+
+        let output = SimpleOutput {
+            limit: sustained.burst,
+            remaining: remaining.max(0.0) as u64,
+            reset: now + retry,
+        };
+        let token = SimpleRollbackToken {
+            key: input.key,
+            cost: if allow { input.cost } else { 0 },
+        };
+        Ok((allow, output, token))
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        // The buckets are capped at their burst the next time they are refilled
+        if let Some(mut buckets) = self.map.get_mut(&token.key) {
+            buckets.sustained += token.cost as f64;
+            buckets.peak += token.cost as f64;
+        }
+        Ok(())
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        Some(token.cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::clock::ManualClock;
+
+    fn input(peak: Option<Rate>) -> TokenBucketInput {
+        TokenBucketInput {
+            sustained: Rate::new(1.0, 10),
+            peak,
+            key: "KEY1".to_owned(),
+            cost: 1,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_sustained() {
+        let clock = ManualClock::new();
+        let backend = TokenBucketBackend::builder()
+            .with_gc_interval(None)
+            .with_clock(clock.clone())
+            .build();
+        for remaining in (0..10).rev() {
+            let (allow, output, _) = backend.request(input(None)).await.unwrap();
+            assert!(allow);
+            assert_eq!(output.limit, 10);
+            assert_eq!(output.remaining, remaining);
+        }
+        let (allow, output, token) = backend.request(input(None)).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.reset, clock.now() + Duration::from_secs(1));
+        // Denied requests take no tokens
+        assert_eq!(token.cost, 0);
+        clock.advance(Duration::from_secs(2));
+        let (allow, output, _) = backend.request(input(None)).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_peak() {
+        let clock = ManualClock::new();
+        let backend = TokenBucketBackend::builder()
+            .with_gc_interval(None)
+            .with_clock(clock.clone())
+            .build();
+        let peak = Some(Rate::new(10.0, 2));
+        for _ in 0..2 {
+            assert!(backend.request(input(peak)).await.unwrap().0);
+        }
+        // The sustained bucket has tokens left, but the peak bucket is empty
+        let (allow, output, _) = backend.request(input(peak)).await.unwrap();
+        assert!(!allow);
+        assert_eq!(output.reset, clock.now() + Duration::from_millis(100));
+        clock.advance(Duration::from_millis(100));
+        let (allow, output, token) = backend.request(input(peak)).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.remaining, 0);
+        backend.rollback(token).await.unwrap();
+        assert!(backend.request(input(peak)).await.unwrap().0);
+    }
+
+    #[actix_web::test]
+    async fn test_garbage_collection() {
+        tokio::time::pause();
+        let backend = TokenBucketBackend::builder()
+            .with_gc_interval(Some(Duration::from_secs(1)))
+            .build();
+        backend.request(input(None)).await.unwrap();
+        assert!(backend.map.contains_key("KEY1"));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        tokio::task::yield_now().await;
+        assert!(!backend.map.contains_key("KEY1"));
+    }
+
+    #[actix_web::test]
+    async fn test_supervised_garbage_collection() {
+        tokio::time::pause();
+        let supervisor = Supervisor::new();
+        let backend = TokenBucketBackend::builder()
+            .with_gc_interval(Some(Duration::from_secs(1)))
+            .with_supervisor(supervisor.clone())
+            .build();
+        backend.request(input(None)).await.unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        tokio::task::yield_now().await;
+        assert!(!backend.map.contains_key("KEY1"));
+        // Once shut down the garbage collector should no longer run
+        supervisor.shutdown().await;
+        backend.request(input(None)).await.unwrap();
+        tokio::time::advance(Duration::from_secs(2)).await;
+        tokio::task::yield_now().await;
+        assert!(backend.map.contains_key("KEY1"));
+    }
+}
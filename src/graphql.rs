@@ -0,0 +1,434 @@
+//! Rate limiting GraphQL APIs by operation, rather than by path, since every operation is usually
+//! sent to a single `/graphql` endpoint.
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::Method;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{HttpMessage, ResponseError};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use std::pin::Pin;
+use std::rc::Rc;
+
+const DEFAULT_MAX_BODY_SIZE: usize = 262_144;
+
+/// The GraphQL operation of a request, inserted into the request extensions by
+/// [GraphQlOperations], and used by
+/// [SimpleInputFunctionBuilder::graphql_operation_key](crate::backend::SimpleInputFunctionBuilder::graphql_operation_key).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphQlOperation {
+    /// The name of the operation, or [None] if it is anonymous.
+    pub name: Option<String>,
+    /// An estimate of the cost of the operation, the number of fields selected anywhere in the
+    /// document (at least 1).
+    ///
+    /// Fragments are counted once where they are defined, however many times they are spread.
+    pub complexity: u64,
+}
+
+impl GraphQlOperation {
+    /// Parse the operation from a GraphQL document, with the `operationName` given alongside it
+    /// (if any).
+    ///
+    /// Without an `operationName` the name of the first operation in the document is used.
+    /// Names that aren't valid GraphQL names are ignored.
+    pub fn parse(query: &str, operation_name: Option<&str>) -> Self {
+        let (first_name, complexity) = scan(query);
+        let name = match operation_name {
+            Some(name) => is_name(name).then(|| name.to_owned()),
+            None => first_name,
+        };
+        Self {
+            name,
+            complexity: complexity.max(1),
+        }
+    }
+
+    // Parses a JSON request body, e.g. `{"query": "...", "operationName": "..."}`.
+    fn from_json(body: &[u8]) -> Option<Self> {
+        let body: Value = serde_json::from_slice(body).ok()?;
+        let query = body.get("query")?.as_str()?;
+        let operation_name = body.get("operationName").and_then(Value::as_str);
+        Some(Self::parse(query, operation_name))
+    }
+
+    // Parses the `query` and `operationName` parameters of a GET request.
+    fn from_query_string(query_string: &str) -> Option<Self> {
+        let mut query = None;
+        let mut operation_name = None;
+        for (name, value) in form_urlencoded::parse(query_string.as_bytes()) {
+            match name.as_ref() {
+                "query" => query = Some(value),
+                "operationName" => operation_name = Some(value),
+                _ => {}
+            }
+        }
+        Some(Self::parse(&query?, operation_name.as_deref()))
+    }
+}
+
+/// Middleware that reads GraphQL requests and inserts their [GraphQlOperation] into the request
+/// extensions, for the rate limiter to use.
+///
+/// Supports `GET` requests with a `query` parameter, and `POST` requests with an
+/// `application/json` or `application/graphql` body. The body is buffered, and then passed on
+/// to the wrapped service unchanged. Bodies larger than the
+/// [GraphQlOperations::max_body_size] are rejected with `413 Payload Too Large`.
+///
+/// This must run before the rate limiter, i.e. it must be registered with `.wrap()` after the
+/// rate limiter.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::{MissingKeyPolicy, SimpleInputFunctionBuilder};
+/// # use actix_extensible_rate_limit::graphql::GraphQlOperations;
+/// # use actix_extensible_rate_limit::RateLimiter;
+/// # use actix_web::{web, App, HttpResponse};
+/// # use std::time::Duration;
+/// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 1000)
+///     .real_ip_key()
+///     .graphql_operation_key(MissingKeyPolicy::Fallback("anonymous".to_owned()))
+///     .build();
+/// let app = App::new().service(
+///     web::resource("/graphql")
+///         .wrap(RateLimiter::builder(InMemoryBackend::builder().build(), input).build())
+///         .wrap(GraphQlOperations::new())
+///         .to(|| async { HttpResponse::Ok().finish() }),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct GraphQlOperations {
+    max_body_size: usize,
+}
+
+impl GraphQlOperations {
+    pub fn new() -> Self {
+        Self {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+
+    /// The largest body that will be read, in bytes.
+    ///
+    /// Defaults to 256 KiB, the same as the actix-web payload extractors.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+}
+
+impl Default for GraphQlOperations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for GraphQlOperations
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = GraphQlOperationsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(GraphQlOperationsMiddleware {
+            service: Rc::new(service),
+            max_body_size: self.max_body_size,
+        })
+    }
+}
+
+pub struct GraphQlOperationsMiddleware<S> {
+    service: Rc<S>,
+    max_body_size: usize,
+}
+
+impl<S, B> Service<ServiceRequest> for GraphQlOperationsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let max_body_size = self.max_body_size;
+
+        Box::pin(async move {
+            let operation = if req.method() == Method::GET {
+                GraphQlOperation::from_query_string(req.query_string())
+            } else if req.method() == Method::POST {
+                let content_type = req
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.split(';').next())
+                    .map(|value| value.trim().to_ascii_lowercase());
+                match content_type.as_deref() {
+                    Some(content_type @ ("application/json" | "application/graphql")) => {
+                        let body = match read_body(req.take_payload(), max_body_size).await {
+                            Ok(body) => body,
+                            Err(e) => {
+                                return Ok(req
+                                    .into_response(e.error_response())
+                                    .map_into_right_body())
+                            }
+                        };
+                        let operation = if content_type == "application/json" {
+                            GraphQlOperation::from_json(&body)
+                        } else {
+                            std::str::from_utf8(&body)
+                                .ok()
+                                .map(|query| GraphQlOperation::parse(query, None))
+                        };
+                        req.set_payload(replay(body));
+                        operation
+                    }
+                    _ => None,
+                }
+            } else {
+                None
+            };
+            if let Some(operation) = operation {
+                req.extensions_mut().insert(operation);
+            }
+            let service_response = service.call(req).await?;
+            Ok(service_response.map_into_left_body())
+        })
+    }
+}
+
+async fn read_body(mut payload: Payload, max_body_size: usize) -> Result<Bytes, PayloadError> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > max_body_size {
+            return Err(PayloadError::Overflow);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+// A payload that yields the already buffered body.
+fn replay(body: Bytes) -> Payload {
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(futures::stream::once(async move { Ok(body) }));
+    Payload::from(stream)
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+// Scans a GraphQL document, without fully parsing it, for the name of the first operation and
+// the number of fields selected.
+fn scan(query: &str) -> (Option<String>, u64) {
+    let bytes = query.as_bytes();
+    let mut i = 0;
+    let mut braces = 0usize;
+    let mut parens = 0usize;
+    let mut first_name = None;
+    let mut fields = 0u64;
+    // Whether the previous token means the next name isn't a field, e.g. a directive
+    let mut skip_name = false;
+    // Whether the previous token was an operation keyword, so the next name is the operation's
+    let mut operation_keyword = false;
+    let mut seen_operation = false;
+    while i < bytes.len() {
+        let c = bytes[i];
+        match c {
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'"' if bytes[i..].starts_with(b"\"\"\"") => {
+                i += 3;
+                while i < bytes.len() && !bytes[i..].starts_with(b"\"\"\"") {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 3;
+                continue;
+            }
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+                continue;
+            }
+            b'(' => parens += 1,
+            b')' => parens = parens.saturating_sub(1),
+            b'{' if parens == 0 => {
+                braces += 1;
+                operation_keyword = false;
+                seen_operation = true;
+            }
+            b'}' if parens == 0 => braces = braces.saturating_sub(1),
+            b'@' => skip_name = true,
+            b'.' if bytes[i..].starts_with(b"...") => {
+                skip_name = true;
+                i += 3;
+                continue;
+            }
+            c if c == b'_' || c.is_ascii_alphabetic() => {
+                let start = i;
+                while i < bytes.len() && (bytes[i] == b'_' || bytes[i].is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                let name = &query[start..i];
+                if parens > 0 {
+                    continue;
+                }
+                if braces == 0 {
+                    if operation_keyword && first_name.is_none() {
+                        first_name = Some(name.to_owned());
+                    }
+                    operation_keyword =
+                        !seen_operation && matches!(name, "query" | "mutation" | "subscription");
+                } else if skip_name {
+                    // A fragment spread's name, or `on` and the type of an inline fragment
+                    skip_name = name == "on";
+                } else if !is_alias(&bytes[i..]) {
+                    fields += 1;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if !c.is_ascii_whitespace() && c != b',' && c != b'@' {
+            skip_name = false;
+        }
+        i += 1;
+    }
+    (first_name, fields)
+}
+
+// Whether the name just scanned is an alias, i.e. followed by a colon.
+fn is_alias(rest: &[u8]) -> bool {
+    rest.iter()
+        .find(|c| !c.is_ascii_whitespace())
+        .is_some_and(|c| *c == b':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App, HttpRequest, HttpResponse};
+
+    #[test]
+    fn test_parse() {
+        let operation = GraphQlOperation::parse(
+            r#"
+            # A comment with { braces }
+            query Hero($episode: Episode = JEDI, $filter: Filter = {first: 1}) {
+                hero(episode: $episode, name: "{ not a field }") {
+                    heroName: name
+                    friends @include(if: true) {
+                        name
+                        ...Details
+                        ... on Droid { primaryFunction }
+                    }
+                }
+            }
+            fragment Details on Character { id }
+            "#,
+            None,
+        );
+        assert_eq!(operation.name.as_deref(), Some("Hero"));
+        // hero, name, friends, name, primaryFunction, id
+        assert_eq!(operation.complexity, 6);
+
+        let operation = GraphQlOperation::parse("{ a b }", None);
+        assert_eq!(operation.name, None);
+        assert_eq!(operation.complexity, 2);
+
+        let operation = GraphQlOperation::parse("query A { a } mutation B { b }", Some("B"));
+        assert_eq!(operation.name.as_deref(), Some("B"));
+        let operation = GraphQlOperation::parse("query A { a } mutation B { b }", None);
+        assert_eq!(operation.name.as_deref(), Some("A"));
+        let operation = GraphQlOperation::parse("{ a }", Some("not-a-name"));
+        assert_eq!(operation.name, None);
+        assert_eq!(GraphQlOperation::parse("", None).complexity, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_middleware() {
+        async fn handler(req: HttpRequest, body: Bytes) -> HttpResponse {
+            let operation = req.extensions().get::<GraphQlOperation>().cloned();
+            HttpResponse::Ok().body(format!(
+                "{:?} {} {}",
+                operation.as_ref().and_then(|o| o.name.as_deref()),
+                operation.as_ref().map_or(0, |o| o.complexity),
+                body.len()
+            ))
+        }
+        let app = test::init_service(
+            App::new()
+                .wrap(GraphQlOperations::new().max_body_size(64))
+                .route("/graphql", web::to(handler)),
+        )
+        .await;
+
+        let body = r#"{"query": "query Me { me { id } }"}"#;
+        let req = TestRequest::post()
+            .uri("/graphql")
+            .insert_header((CONTENT_TYPE, "application/json"))
+            .set_payload(body)
+            .to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(res, format!("Some(\"Me\") 2 {}", body.len()));
+
+        let req = TestRequest::post()
+            .uri("/graphql")
+            .insert_header((CONTENT_TYPE, "application/graphql"))
+            .set_payload("{ a b c }")
+            .to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(res, "None 3 9");
+
+        let req = TestRequest::get()
+            .uri("/graphql?query=query%20Q%20%7B%20a%20%7D")
+            .to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(res, "Some(\"Q\") 1 0");
+
+        let req = TestRequest::post()
+            .uri("/graphql")
+            .insert_header((CONTENT_TYPE, "text/plain"))
+            .set_payload("{ a }")
+            .to_request();
+        let res = test::call_and_read_body(&app, req).await;
+        assert_eq!(res, "None 0 5");
+
+        let req = TestRequest::post()
+            .uri("/graphql")
+            .insert_header((CONTENT_TYPE, "application/graphql"))
+            .set_payload("{ a }".repeat(20))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 413);
+    }
+}
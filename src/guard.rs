@@ -0,0 +1,138 @@
+use crate::backend::{ip_key, Backend, IpPrefix, SimpleInput};
+use crate::Limiter;
+use actix_web::guard::{Guard, GuardContext};
+use futures::FutureExt;
+use std::fmt::Display;
+
+type KeyFn = dyn Fn(&GuardContext<'_>) -> Option<String>;
+
+/// A routing [Guard] that only matches requests within a limit, so that requests over the limit
+/// fall through to the next matching route (e.g. one serving a cached or degraded response)
+/// instead of being rejected.
+///
+/// Each time the guard is checked, a request is counted against the key returned by the key
+/// function. If the key function returns `None` the guard matches without counting anything.
+///
+/// Guards can't wait, so the backend must answer without suspending, as the in-memory backends
+/// do. If the backend fails or would have to wait (e.g. Redis), the error is logged and the guard
+/// matches.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::{Limiter, RateLimitGuard};
+/// # use actix_web::{web, App, HttpResponse};
+/// # use std::time::Duration;
+/// let limiter = Limiter::new(InMemoryBackend::builder().build(), Duration::from_secs(60), 10);
+/// let app = App::new().service(
+///     web::resource("/report")
+///         .route(
+///             web::get()
+///                 .guard(RateLimitGuard::peer_ip(limiter))
+///                 .to(|| async { HttpResponse::Ok().body("fresh report") }),
+///         )
+///         .route(web::get().to(|| async { HttpResponse::Ok().body("cached report") })),
+/// );
+/// ```
+pub struct RateLimitGuard<B> {
+    limiter: Limiter<B>,
+    key_fn: Box<KeyFn>,
+}
+
+impl<B> RateLimitGuard<B>
+where
+    B: Backend<SimpleInput>,
+{
+    /// Count requests against the key returned by `key_fn`.
+    pub fn new<F>(limiter: Limiter<B>, key_fn: F) -> Self
+    where
+        F: Fn(&GuardContext<'_>) -> Option<String> + 'static,
+    {
+        Self {
+            limiter,
+            key_fn: Box::new(key_fn),
+        }
+    }
+
+    /// Count requests against the IP address of the peer, grouping IPv6 addresses by their /64
+    /// prefix.
+    ///
+    /// See [SimpleInputFunctionBuilder::peer_ip_key](crate::backend::SimpleInputFunctionBuilder::peer_ip_key)
+    /// for the caveats.
+    pub fn peer_ip(limiter: Limiter<B>) -> Self {
+        Self::new(limiter, |ctx| {
+            let addr = ctx.head().peer_addr?;
+            ip_key(&addr.ip().to_string(), &IpPrefix::default()).ok()
+        })
+    }
+}
+
+impl<B> Guard for RateLimitGuard<B>
+where
+    B: Backend<SimpleInput>,
+    B::Error: Display,
+{
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        let Some(key) = (self.key_fn)(ctx) else {
+            return true;
+        };
+        match self.limiter.check(&key).now_or_never() {
+            Some(Ok((allowed, _, _))) => allowed,
+            Some(Err(e)) => {
+                log::error!("Rate limit guard failed: {e}");
+                true
+            }
+            None => {
+                log::error!("Rate limit guard requires a backend that doesn't suspend");
+                true
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use actix_web::test::{call_and_read_body, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_guard_falls_through() {
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let limiter = Limiter::new(backend, Duration::from_secs(60), 1);
+        let app = init_service(
+            App::new().service(
+                web::resource("/")
+                    .route(
+                        web::get()
+                            .guard(RateLimitGuard::peer_ip(limiter))
+                            .to(|| async { HttpResponse::Ok().body("fresh") }),
+                    )
+                    .route(web::get().to(|| async { HttpResponse::Ok().body("cached") })),
+            ),
+        )
+        .await;
+        let req = |ip: &str| {
+            TestRequest::get()
+                .peer_addr(format!("{ip}:8080").parse().unwrap())
+                .to_request()
+        };
+        assert_eq!(call_and_read_body(&app, req("127.0.0.1")).await, "fresh");
+        assert_eq!(call_and_read_body(&app, req("127.0.0.1")).await, "cached");
+        // Addresses in the same IPv6 /64 share a limit
+        assert_eq!(
+            call_and_read_body(&app, req("[2001:db8::1]")).await,
+            "fresh"
+        );
+        assert_eq!(
+            call_and_read_body(&app, req("[2001:db8::2]")).await,
+            "cached"
+        );
+        assert_eq!(
+            call_and_read_body(&app, req("[2001:db8:0:1::1]")).await,
+            "fresh"
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! A readiness probe that reports on the health of the rate limiting backend.
+use crate::backend::Backend;
+use actix_web::{web, HttpResponse, Route};
+use std::fmt::Display;
+
+/// Create an actix [Route] that checks [Backend::health], responding with 200 if the backend is
+/// healthy, or 503 if not.
+///
+/// When [fail_open](crate::RateLimiterBuilder::fail_open) is disabled, every request is rejected
+/// while the backend is unavailable, so this can be used as a Kubernetes readiness probe to stop
+/// routing traffic to an instance that has lost its connection to e.g. Redis.
+///
+/// The backend's latency is included in the body of a successful response, in milliseconds.
+///
+/// # Example
+/// ```no_run
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::health::readiness_route;
+/// # use actix_web::App;
+/// let backend = InMemoryBackend::builder().build();
+/// let app = App::new().route("/ready", readiness_route(backend.clone()));
+/// ```
+pub fn readiness_route<BE, I>(backend: BE) -> Route
+where
+    BE: Backend<I> + 'static,
+    BE::Error: Display,
+    I: 'static,
+{
+    web::get().to(move || {
+        let backend = backend.clone();
+        async move {
+            match backend.health().await {
+                Ok(health) => {
+                    HttpResponse::Ok().body(format!("ok: {}ms", health.latency.as_millis()))
+                }
+                Err(e) => {
+                    log::error!("Rate limit backend is unhealthy: {e}");
+                    HttpResponse::ServiceUnavailable().finish()
+                }
+            }
+        }
+    })
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn test_readiness_route() {
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let app =
+            test::init_service(App::new().route("/ready", readiness_route(backend.clone()))).await;
+        let req = TestRequest::get().uri("/ready").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        assert_eq!(body, "ok: 0ms");
+    }
+}
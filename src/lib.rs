@@ -28,8 +28,103 @@
 
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+#[cfg(feature = "admin")]
+#[cfg_attr(docsrs, doc(cfg(feature = "admin")))]
+pub mod admin;
 pub mod backend;
+#[cfg(feature = "graphql")]
+#[cfg_attr(docsrs, doc(cfg(feature = "graphql")))]
+pub mod graphql;
+mod guard;
+pub mod health;
+mod limiter;
+pub mod metrics;
 mod middleware;
+pub mod prelude;
+#[cfg(feature = "macros")]
+mod route;
+mod supervisor;
+#[cfg(feature = "test-util")]
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+pub mod testing;
+pub mod ws;
 
-pub use middleware::builder::{HeaderCompatibleOutput, RateLimiterBuilder};
-pub use middleware::RateLimiter;
+pub use guard::RateLimitGuard;
+pub use limiter::Limiter;
+pub use middleware::builder::{BuildError, HeaderCompatibleOutput, RateLimiterBuilder};
+pub use middleware::control::RateLimiterControl;
+pub use middleware::events::DenyEvent;
+pub use middleware::handle::RateLimitHandle;
+pub use middleware::login::LoginProtection;
+pub use middleware::recording::{JsonlSink, TrafficRecord, TrafficSink};
+pub use middleware::stack::{RateLimiterStack, StackBackend};
+pub use middleware::{BackendTimeout, Decision, Exempt, RateLimiter, Refund, TimeoutPolicy};
+pub use supervisor::Supervisor;
+
+/// Rate limit a single handler, without wrapping it in a separate [RateLimiter].
+///
+/// The limit is counted against the [ArcBackend](backend::ArcBackend) found in the app data
+/// (which may be shared by every rate limited handler), under a key made from the handler's path,
+/// e.g. `my_app::auth::login` (or the `name` argument), and the `key` argument:
+///
+/// - `per`: the interval, e.g. `"500ms"`, `"10s"`, `"5m"`, `"1h"` or `"1d"`, as in
+///   [parse_rate](backend::parse_rate); a number without a unit is seconds.
+/// - `max`: the number of requests allowed per interval.
+/// - `key`: `"peer_ip"` (the default), `"real_ip"`, `"path"`, or `"global"` for a single limit
+///   shared by every client.
+/// - `name`: overrides the handler's path in the key, e.g. to share a limit between handlers.
+///
+/// Denied requests get a 429 response with the same headers as
+/// [add_headers](RateLimiterBuilder::add_headers). If the backend is missing or fails, the
+/// request is rejected.
+///
+/// The handler must be an `async fn`, and its return type is wrapped in an [actix_web::Either].
+/// It can be combined with the actix-web routing macros, as long as they come first.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::{memory::InMemoryBackend, ArcBackend};
+/// # use actix_extensible_rate_limit::rate_limit;
+/// # use actix_web::{get, test, web, App, HttpResponse};
+/// #[get("/login")]
+/// #[rate_limit(per = "1m", max = 5, key = "peer_ip")]
+/// async fn login() -> HttpResponse {
+///     HttpResponse::Ok().finish()
+/// }
+/// # mod admin {
+/// #     use super::*;
+/// #     #[get("/admin/login")]
+/// #     #[rate_limit(per = "1m", max = 5, key = "peer_ip")]
+/// #     pub async fn login() -> HttpResponse {
+/// #         HttpResponse::Ok().finish()
+/// #     }
+/// # }
+///
+/// # #[actix_web::main]
+/// # async fn main() {
+/// let backend = ArcBackend::new(InMemoryBackend::builder().build());
+/// let app = App::new()
+///     .app_data(web::Data::new(backend))
+///     .service(login);
+/// # let app = app.service(admin::login);
+/// # let app = test::init_service(app).await;
+/// # for (uri, expected) in [("/login", 200); 5].into_iter().chain([("/login", 429), ("/admin/login", 200)]) {
+/// #     let req = test::TestRequest::get()
+/// #         .uri(uri)
+/// #         .peer_addr("127.0.0.1:8080".parse().unwrap())
+/// #         .to_request();
+/// #     assert_eq!(test::call_service(&app, req).await.status(), expected);
+/// # }
+/// # }
+/// ```
+#[cfg(feature = "macros")]
+#[cfg_attr(docsrs, doc(cfg(feature = "macros")))]
+pub use actix_extensible_rate_limit_macros::rate_limit;
+
+// Used by the code generated by the rate_limit macro, not part of the public API
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::backend::rate::parse_duration;
+    pub use crate::route::{check_route, RouteKey};
+}
@@ -0,0 +1,135 @@
+use crate::backend::{Backend, SimpleBackend, SimpleInput, SimpleOutput};
+use std::time::Duration;
+
+/// Applies a limit to keys given directly, rather than derived from a [ServiceRequest] by the
+/// middleware; e.g. to limit login attempts per username from inside a handler, messages on a
+/// WebSocket, or background jobs.
+///
+/// [ServiceRequest]: actix_web::dev::ServiceRequest
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::Limiter;
+/// # use std::time::Duration;
+/// # async {
+/// let limiter = Limiter::new(InMemoryBackend::builder().build(), Duration::from_secs(60), 5)
+///     .key_prefix("login:");
+/// let (allowed, output, _) = limiter.check("alice").await.unwrap();
+/// if !allowed {
+///     // Try again in output.seconds_until_reset()
+/// }
+/// # };
+/// ```
+#[derive(Clone)]
+pub struct Limiter<B> {
+    backend: B,
+    interval: Duration,
+    max_requests: u64,
+    key_prefix: Option<String>,
+}
+
+impl<B> Limiter<B>
+where
+    B: Backend<SimpleInput>,
+{
+    /// Allow `max_requests` per `interval` for each key.
+    pub fn new(backend: B, interval: Duration, max_requests: u64) -> Self {
+        Self {
+            backend,
+            interval,
+            max_requests,
+            key_prefix: None,
+        }
+    }
+
+    /// Prepend a namespace to every key, so that the backend can be shared with other limiters
+    /// (or the middleware) without their keys colliding.
+    pub fn key_prefix(mut self, prefix: &str) -> Self {
+        self.key_prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Count a single request against `key`, see [Backend::request].
+    pub async fn check(&self, key: &str) -> Result<(bool, B::Output, B::RollbackToken), B::Error> {
+        self.consume(key, 1).await
+    }
+
+    /// Count `cost` requests against `key`, see [Backend::request].
+    pub async fn consume(
+        &self,
+        key: &str,
+        cost: u64,
+    ) -> Result<(bool, B::Output, B::RollbackToken), B::Error> {
+        self.backend.request(self.input(key, cost)).await
+    }
+
+    /// Undo a [Limiter::check] or [Limiter::consume], see [Backend::rollback].
+    pub async fn rollback(&self, token: B::RollbackToken) -> Result<(), B::Error> {
+        self.backend.rollback(token).await
+    }
+
+    /// The wrapped backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    fn input(&self, key: &str, cost: u64) -> SimpleInput {
+        SimpleInput {
+            interval: self.interval,
+            max_requests: self.max_requests,
+            key: match &self.key_prefix {
+                Some(prefix) => format!("{prefix}{key}"),
+                None => key.to_owned(),
+            },
+            cost,
+        }
+    }
+}
+
+impl<B> Limiter<B>
+where
+    B: SimpleBackend,
+{
+    /// Whether a single request for `key` would be allowed, without counting it, see
+    /// [SimpleBackend::peek].
+    pub async fn peek(&self, key: &str) -> Result<(bool, SimpleOutput), B::Error> {
+        self.backend.peek(&self.input(key, 1)).await
+    }
+
+    /// Reset the count for `key`, e.g. after a successful login.
+    pub async fn reset(&self, key: &str) -> Result<(), B::Error> {
+        self.backend.remove_key(&self.input(key, 0).key).await
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use crate::backend::InspectableBackend;
+
+    #[actix_web::test]
+    async fn test_limiter() {
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let limiter =
+            Limiter::new(backend.clone(), Duration::from_secs(60), 3).key_prefix("login:");
+        let (allowed, output, _) = limiter.consume("alice", 2).await.unwrap();
+        assert!(allowed);
+        assert_eq!(output.remaining, 1);
+        let (allowed, _, token) = limiter.check("alice").await.unwrap();
+        assert!(allowed);
+        let status = backend.key_status("login:alice").await.unwrap().unwrap();
+        assert_eq!(status.count, 3);
+
+        let (allowed, _) = limiter.peek("alice").await.unwrap();
+        assert!(!allowed);
+        limiter.rollback(token).await.unwrap();
+        let (allowed, output) = limiter.peek("alice").await.unwrap();
+        assert!(allowed);
+        assert_eq!(output.remaining, 1);
+
+        limiter.reset("alice").await.unwrap();
+        assert!(backend.key_status("login:alice").await.unwrap().is_none());
+    }
+}
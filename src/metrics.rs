@@ -0,0 +1,121 @@
+//! Instrumentation through the [metrics](https://docs.rs/metrics) crate facade.
+//!
+//! With the `metrics` feature enabled, the following are recorded by the middleware and the
+//! provided backends, and can be exported by any `metrics` compatible recorder (e.g. Prometheus,
+//! StatsD, or OTLP).
+//!
+//! | Name | Type | Labels |
+//! |------|------|--------|
+//! | [REQUESTS] | Counter | `outcome`: one of the `OUTCOME_*` constants |
+//! | [BACKEND_DURATION] | Histogram (seconds) | |
+//! | [BACKEND_ERRORS] | Counter | `kind`: see [BackendError::kind](crate::backend::BackendError::kind) |
+//! | [ROLLBACKS] | Counter | `result`: `ok` or `error` |
+//! | [MEMORY_KEYS] | Gauge | |
+//! | [MEMORY_EVICTED] | Counter | |
+//! | [MEMORY_BYTES] | Gauge | |
+//! | [MEMORY_GC_RUNS] | Counter | |
+//! | [INSTRUMENTED_CALLS] | Counter | `backend`, `operation`, `result`, see [BackendCall](crate::backend::BackendCall) |
+//! | [INSTRUMENTED_DURATION] | Histogram (seconds) | `backend`, `operation` |
+use std::time::Duration;
+
+/// Requests seen by the middleware.
+pub const REQUESTS: &str = "actix_rate_limit_requests_total";
+/// The time taken for the backend to make a decision.
+pub const BACKEND_DURATION: &str = "actix_rate_limit_backend_duration_seconds";
+/// Failures of the backend with a [BackendError](crate::backend::BackendError).
+pub const BACKEND_ERRORS: &str = "actix_rate_limit_backend_errors_total";
+/// Rollbacks made after the response status matched the rollback condition.
+pub const ROLLBACKS: &str = "actix_rate_limit_rollbacks_total";
+/// The number of keys held by the in-memory backend, updated by the garbage collector.
+pub const MEMORY_KEYS: &str = "actix_rate_limit_memory_keys";
+/// Expired keys removed by the in-memory backend's garbage collector.
+pub const MEMORY_EVICTED: &str = "actix_rate_limit_memory_evicted_total";
+/// The approximate memory allocated by the in-memory backend's map, updated by the garbage
+/// collector, see [MemoryStats](crate::backend::memory::MemoryStats).
+pub const MEMORY_BYTES: &str = "actix_rate_limit_memory_bytes";
+/// Runs of the in-memory backend's garbage collector.
+pub const MEMORY_GC_RUNS: &str = "actix_rate_limit_memory_gc_runs_total";
+/// Calls made through an [InstrumentedBackend](crate::backend::InstrumentedBackend).
+pub const INSTRUMENTED_CALLS: &str = "actix_rate_limit_backend_calls_total";
+/// The duration of calls made through an
+/// [InstrumentedBackend](crate::backend::InstrumentedBackend).
+pub const INSTRUMENTED_DURATION: &str = "actix_rate_limit_backend_call_duration_seconds";
+
+/// The request was within the limit.
+pub const OUTCOME_ALLOWED: &str = "allowed";
+/// The request was over the limit, and was denied.
+pub const OUTCOME_DENIED: &str = "denied";
+/// The request was over the limit, but was allowed because enforcement was disabled.
+pub const OUTCOME_SHADOW_DENIED: &str = "shadow_denied";
+/// The request's key was banned.
+pub const OUTCOME_BANNED: &str = "banned";
+/// The request was exempt from rate limiting.
+pub const OUTCOME_EXEMPT: &str = "exempt";
+/// The input function failed.
+pub const OUTCOME_INPUT_ERROR: &str = "input_error";
+/// The backend failed, and the request was allowed because the middleware fails open.
+pub const OUTCOME_BACKEND_ERROR_ALLOWED: &str = "backend_error_allowed";
+/// The backend failed, and the request was rejected.
+pub const OUTCOME_BACKEND_ERROR: &str = "backend_error";
+/// The backend timed out, and the request was allowed by the
+/// [TimeoutPolicy](crate::TimeoutPolicy).
+pub const OUTCOME_TIMEOUT_ALLOWED: &str = "timeout_allowed";
+/// The backend timed out, and the request was rejected.
+pub const OUTCOME_TIMEOUT: &str = "timeout";
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_request(outcome: &'static str) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(REQUESTS, "outcome" => outcome).increment(1);
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_backend_duration(duration: Duration) {
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!(BACKEND_DURATION).record(duration.as_secs_f64());
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_backend_error(kind: &'static str) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(BACKEND_ERRORS, "kind" => kind).increment(1);
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_rollback(ok: bool) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(ROLLBACKS, "result" => if ok { "ok" } else { "error" }).increment(1);
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_instrumented_call(
+    backend: &'static str,
+    operation: &'static str,
+    result: &'static str,
+    duration: Duration,
+) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(
+            INSTRUMENTED_CALLS,
+            "backend" => backend,
+            "operation" => operation,
+            "result" => result
+        )
+        .increment(1);
+        ::metrics::histogram!(INSTRUMENTED_DURATION, "backend" => backend, "operation" => operation)
+            .record(duration.as_secs_f64());
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+#[cfg(feature = "dashmap")]
+pub(crate) fn record_memory_gc(evicted: usize, remaining: usize, bytes: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(MEMORY_GC_RUNS).increment(1);
+        ::metrics::counter!(MEMORY_EVICTED).increment(evicted as u64);
+        ::metrics::gauge!(MEMORY_KEYS).set(remaining as f64);
+        ::metrics::gauge!(MEMORY_BYTES).set(bytes as f64);
+    }
+}
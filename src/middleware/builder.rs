@@ -1,5 +1,14 @@
-use crate::backend::Backend;
-use crate::middleware::{AllowedTransformation, DeniedResponse, RateLimiter, RollbackCondition};
+use crate::backend::{Backend, BackendError, BanStore, KeyedInput, PartialRollbackToken};
+use crate::middleware::challenge::Challenge;
+use crate::middleware::control::RateLimiterControl;
+use crate::middleware::events::DenyEvent;
+use crate::middleware::handle::RateLimitHandle;
+use crate::middleware::recording::TrafficSink;
+use crate::middleware::{
+    AllowedTransformation, BackendErrorStatus, BannedResponse, Bans, Decision, DeniedHook,
+    DeniedResponse, DenyEvents, MakeRefundHandle, RateLimiter, Recorder, RefundMarker, RequestHook,
+    RollbackCondition, Throttle, TimeoutPolicy,
+};
 use actix_web::dev::ServiceRequest;
 use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
 use actix_web::http::StatusCode;
@@ -7,6 +16,9 @@ use actix_web::HttpResponse;
 use once_cell::sync::Lazy;
 use std::future::Future;
 use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::sync::broadcast;
 
 pub static X_RATELIMIT_LIMIT: Lazy<HeaderName> =
     Lazy::new(|| HeaderName::from_static("x-ratelimit-limit"));
@@ -21,9 +33,36 @@ pub struct RateLimiterBuilder<BE, BO, F> {
     backend: BE,
     input_fn: F,
     fail_open: bool,
+    backend_error_status: Option<Rc<BackendErrorStatus>>,
+    control: Option<RateLimiterControl>,
     allowed_transformation: Option<Rc<AllowedTransformation<BO>>>,
     denied_response: Rc<DeniedResponse<BO>>,
     rollback_condition: Option<Rc<RollbackCondition>>,
+    refund_marker: Option<Rc<RefundMarker>>,
+    on_request: Option<Rc<RequestHook<BO>>>,
+    on_denied: Option<Rc<DeniedHook<BO>>>,
+    deny_events: Option<Rc<DenyEvents<BO>>>,
+    bans: Option<Rc<Bans>>,
+    banned_response: Rc<BannedResponse>,
+    refund_handle: Option<Rc<MakeRefundHandle>>,
+    backend_timeout: Option<(Duration, TimeoutPolicy)>,
+    throttle: Option<Throttle<BO>>,
+    max_total_queued: Option<usize>,
+    challenge: Option<Rc<Challenge<BO>>>,
+    recorder: Option<Rc<Recorder>>,
+    custom_banned_response: bool,
+}
+
+/// A [RateLimiterBuilder] configuration that would not work as intended, see
+/// [RateLimiterBuilder::try_build].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum BuildError {
+    #[error("throttle_max_total_queued() has no effect unless throttle() is enabled")]
+    MaxTotalQueuedWithoutThrottle,
+    #[error("banned_response() has no effect without a ban_store()")]
+    BannedResponseWithoutBanStore,
+    #[error("backend_timeout() must be non-zero")]
+    ZeroBackendTimeout,
 }
 
 impl<BE, BI, BO, F, O> RateLimiterBuilder<BE, BO, F>
@@ -38,9 +77,24 @@ where
             backend,
             input_fn,
             fail_open: false,
+            backend_error_status: None,
+            control: None,
             allowed_transformation: None,
             denied_response: Rc::new(|_| HttpResponse::TooManyRequests().finish()),
             rollback_condition: None,
+            refund_marker: None,
+            on_request: None,
+            on_denied: None,
+            deny_events: None,
+            bans: None,
+            banned_response: Rc::new(|| HttpResponse::Forbidden().finish()),
+            refund_handle: None,
+            backend_timeout: None,
+            throttle: None,
+            max_total_queued: None,
+            challenge: None,
+            recorder: None,
+            custom_banned_response: false,
         }
     }
 
@@ -52,6 +106,96 @@ where
         self
     }
 
+    /// Choose the status of the response when the backend fails with a [BackendError], and the
+    /// middleware does not [fail open](RateLimiterBuilder::fail_open).
+    ///
+    /// By default the error's own status is used, see [BackendError].
+    ///
+    /// # Example
+    /// ```
+    /// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+    /// # use actix_extensible_rate_limit::backend::{BackendError, SimpleInputFunctionBuilder};
+    /// # use actix_extensible_rate_limit::RateLimiter;
+    /// # use actix_web::http::StatusCode;
+    /// # let input = SimpleInputFunctionBuilder::per_minute(60).real_ip_key().build();
+    /// let middleware = RateLimiter::builder(InMemoryBackend::builder().build(), input)
+    ///     .backend_error_status(|e| match e {
+    ///         BackendError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+    ///         _ => StatusCode::SERVICE_UNAVAILABLE,
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn backend_error_status<S>(mut self, status: S) -> Self
+    where
+        S: Fn(&BackendError) -> StatusCode + 'static,
+    {
+        self.backend_error_status = Some(Rc::new(status));
+        self
+    }
+
+    /// Bound how long the backend may take to make a decision, applying the [TimeoutPolicy] to
+    /// requests where it takes longer; e.g. so that a slow Redis doesn't delay every request.
+    ///
+    /// The backend may still count a request that timed out. By default there is no timeout.
+    pub fn backend_timeout(mut self, timeout: Duration, policy: TimeoutPolicy) -> Self {
+        self.backend_timeout = Some((timeout, policy));
+        self
+    }
+
+    /// Delay requests over the limit until the limit resets, instead of denying them; e.g. for
+    /// internal callers that would rather be slowed down than retry on 429s.
+    ///
+    /// The requests waiting for each key are let through in the order they arrived, and new
+    /// requests for a key queue behind those already waiting. A request is denied as usual if it
+    /// would have to wait longer than `max_wait` in total, or if `max_queued` requests for the
+    /// same key are already waiting on the worker. The input function is called again, and the
+    /// request counted again, each time it is retried.
+    ///
+    /// By default requests over the limit are denied immediately. Requests that can't be throttled
+    /// get the [RateLimiterBuilder::request_denied_response].
+    pub fn throttle(mut self, max_wait: Duration, max_queued: usize) -> Self
+    where
+        BI: KeyedInput,
+        BO: HeaderCompatibleOutput,
+    {
+        self.throttle = Some(Throttle::new(
+            max_wait,
+            max_queued,
+            Box::new(|input| {
+                input
+                    .downcast_ref::<BI>()
+                    .map(|input| input.key().to_owned())
+            }),
+            Box::new(|output| {
+                output
+                    .reset_at()
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+            }),
+        ));
+        self
+    }
+
+    /// Bound the number of requests waiting across all keys on the worker, see
+    /// [RateLimiterBuilder::throttle]; once reached, requests over the limit are denied
+    /// immediately.
+    ///
+    /// By default only the number waiting for each key is bounded. Requires
+    /// [RateLimiterBuilder::throttle] to be enabled.
+    pub fn throttle_max_total_queued(mut self, max_total_queued: usize) -> Self {
+        self.max_total_queued = Some(max_total_queued);
+        self
+    }
+
+    /// Attach a [RateLimiterControl] handle, allowing enforcement to be switched off (into shadow
+    /// mode) and back on again at runtime.
+    ///
+    /// By default rate limits are always enforced.
+    pub fn control(mut self, control: RateLimiterControl) -> Self {
+        self.control = Some(control);
+        self
+    }
+
     /// Sets the [RateLimiterBuilder::request_allowed_transformation] and
     /// [RateLimiterBuilder::request_denied_response] functions, such that the following headers
     /// are set in both the allowed and denied responses:
@@ -61,19 +205,22 @@ where
     /// - `x-ratelimit-reset` (seconds until the reset)
     /// - `retry-after` (denied only, seconds until the reset)
     ///
+    /// When the request is rolled back or refunded, the remaining count includes the refunded
+    /// cost, see [Backend::rollback_cost].
+    ///
     /// This function requires the Backend Output to implement [HeaderCompatibleOutput]
     pub fn add_headers(mut self) -> Self
     where
         BO: HeaderCompatibleOutput,
     {
-        self.allowed_transformation = Some(Rc::new(|map, output, rolled_back| {
+        self.allowed_transformation = Some(Rc::new(|map, output, _, refunded| {
             if let Some(status) = output {
                 map.insert(X_RATELIMIT_LIMIT.clone(), HeaderValue::from(status.limit()));
-                let remaining = if rolled_back {
-                    status.remaining() + 1
-                } else {
-                    status.remaining()
-                };
+                // The refunded cost is available again
+                let remaining = status
+                    .remaining()
+                    .saturating_add(refunded)
+                    .min(status.limit());
                 map.insert(X_RATELIMIT_REMAINING.clone(), HeaderValue::from(remaining));
                 map.insert(
                     X_RATELIMIT_RESET.clone(),
@@ -81,19 +228,7 @@ where
                 );
             }
         }));
-        self.denied_response = Rc::new(|status| {
-            let mut response = HttpResponse::TooManyRequests().finish();
-            let map = response.headers_mut();
-            map.insert(X_RATELIMIT_LIMIT.clone(), HeaderValue::from(status.limit()));
-            map.insert(
-                X_RATELIMIT_REMAINING.clone(),
-                HeaderValue::from(status.remaining()),
-            );
-            let seconds = status.seconds_until_reset();
-            map.insert(X_RATELIMIT_RESET.clone(), HeaderValue::from(seconds));
-            map.insert(RETRY_AFTER, HeaderValue::from(seconds));
-            response
-        });
+        self.denied_response = Rc::new(|status| denied_response_with_headers(status));
         self
     }
 
@@ -112,13 +247,20 @@ where
     where
         M: Fn(&mut HeaderMap, Option<&BO>, bool) + 'static,
     {
-        self.allowed_transformation = mutation.map(|m| Rc::new(m) as Rc<AllowedTransformation<BO>>);
+        self.allowed_transformation = mutation.map(|m| {
+            Rc::new(
+                move |map: &mut HeaderMap, output: Option<&BO>, rolled_back, _| {
+                    m(map, output, rolled_back)
+                },
+            ) as Rc<AllowedTransformation<BO>>
+        });
         self
     }
 
     /// In the event that the request is denied, configure the [HttpResponse] returned.
     ///
-    /// Defaults to an empty body with status 429.
+    /// Defaults to an empty body with status 429. With [RateLimiterBuilder::throttle], this is
+    /// only returned for requests that would wait too long or find the queue full.
     pub fn request_denied_response<R>(mut self, denied_response: R) -> Self
     where
         R: Fn(&BO) -> HttpResponse + 'static,
@@ -145,18 +287,325 @@ where
         self.rollback_condition(Some(|status: StatusCode| status.is_server_error()))
     }
 
+    /// Rollback the request count if the service response carries the [Refund](crate::Refund)
+    /// extension, or if `header` is given, the header set to `true` (which is removed before the
+    /// response is sent); e.g. so that handlers can mark cache hits or no-op requests as free.
+    ///
+    /// Applies in addition to any [RateLimiterBuilder::rollback_condition].
+    ///
+    /// # Example
+    /// ```
+    /// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+    /// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+    /// # use actix_extensible_rate_limit::RateLimiter;
+    /// # use actix_web::http::header::HeaderName;
+    /// # use std::time::Duration;
+    /// # async {
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 5)
+    ///     .real_ip_key()
+    ///     .build();
+    /// let middleware = RateLimiter::builder(InMemoryBackend::builder().build(), input)
+    ///     .rollback_on_refund(Some(HeaderName::from_static("x-ratelimit-refund")))
+    ///     .build();
+    /// # };
+    /// ```
+    pub fn rollback_on_refund(mut self, header: Option<HeaderName>) -> Self {
+        self.refund_marker = Some(Rc::new(RefundMarker { header }));
+        self
+    }
+
+    /// Called for every request that is rate limited, once the backend has made a decision, and
+    /// before the response is built; e.g. for custom logging or alerting.
+    ///
+    /// Requests that are [Exempt](crate::Exempt) or where the input function fails are not seen.
+    pub fn on_request<H>(mut self, hook: H) -> Self
+    where
+        H: Fn(&ServiceRequest, &Decision<BO>) + 'static,
+    {
+        self.on_request = Some(Rc::new(hook));
+        self
+    }
+
+    /// Called for every request that is denied, before the denied response is built; e.g. to
+    /// feed an abuse detection pipeline.
+    ///
+    /// Requests allowed through by a disabled [RateLimiterControl] are not seen.
+    pub fn on_denied<H>(mut self, hook: H) -> Self
+    where
+        H: Fn(&ServiceRequest, &BO) + 'static,
+    {
+        self.on_denied = Some(Rc::new(hook));
+        self
+    }
+
+    /// Publish a [DenyEvent] to a broadcast channel each time a request is denied, so that a
+    /// separate task can forward them elsewhere (e.g. to Kafka or a webhook) for abuse analysis.
+    ///
+    /// Publishing never blocks the request, see [DenyEvent::channel].
+    ///
+    /// # Example
+    /// ```
+    /// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+    /// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+    /// # use actix_extensible_rate_limit::{DenyEvent, RateLimiter};
+    /// # use std::time::Duration;
+    /// # async {
+    /// let (sender, mut receiver) = DenyEvent::channel(1024);
+    /// actix_web::rt::spawn(async move {
+    ///     while let Ok(event) = receiver.recv().await {
+    ///         log::warn!("Rate limit exceeded for {} on {}", event.key, event.path);
+    ///     }
+    /// });
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 5)
+    ///     .real_ip_key()
+    ///     .build();
+    /// let middleware = RateLimiter::builder(InMemoryBackend::builder().build(), input)
+    ///     .deny_events(sender.clone())
+    ///     .build();
+    /// # };
+    /// ```
+    pub fn deny_events(mut self, sender: broadcast::Sender<DenyEvent<BO>>) -> Self
+    where
+        BI: KeyedInput,
+        BO: Clone + 'static,
+    {
+        self.deny_events = Some(Rc::new(DenyEvents {
+            input_key: Box::new(|input| {
+                input
+                    .downcast_ref::<BI>()
+                    .map(|input| input.key().to_owned())
+            }),
+            publish: Box::new(move |key, path, output| {
+                // An error only means there are currently no receivers
+                let _ = sender.send(DenyEvent {
+                    key,
+                    path: path.to_owned(),
+                    ts: SystemTime::now(),
+                    output: output.clone(),
+                });
+            }),
+        }));
+        self
+    }
+
+    /// Record the key, cost, and decision of every request that reaches the backend to a
+    /// [TrafficSink], e.g. a [JsonlSink](crate::JsonlSink) writing to a file, so that limits can
+    /// be tuned against real traffic before they are enforced.
+    ///
+    /// The backend's decision is recorded even when enforcement is disabled through a
+    /// [RateLimiterControl]. Requests where the backend fails or times out are not recorded, and
+    /// throttled requests are recorded once, with their final decision.
+    pub fn record_traffic<S>(mut self, sink: S) -> Self
+    where
+        BI: KeyedInput,
+        S: TrafficSink + 'static,
+    {
+        self.recorder = Some(Rc::new(Recorder {
+            input_record: Box::new(|input| {
+                input
+                    .downcast_ref::<BI>()
+                    .map(|input| (input.key().to_owned(), input.cost()))
+            }),
+            sink: Box::new(sink),
+        }));
+        self
+    }
+
+    /// Escalate clients that keep going over the limit to a challenge, such as a redirect to a
+    /// CAPTCHA, instead of the [RateLimiterBuilder::request_denied_response].
+    ///
+    /// Once a key has been denied `after_denials` times within an interval, its further denials
+    /// are answered with `challenge_response`. Requests for which `verify` returns true, e.g.
+    /// because they carry a token from a solved challenge, bypass the rate limit as if they were
+    /// [Exempt](crate::Exempt). Anyone can send a token, so `verify` must check that it is
+    /// genuine and hasn't expired.
+    ///
+    /// Denials are counted separately on each worker.
+    ///
+    /// # Example
+    /// ```
+    /// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+    /// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+    /// # use actix_extensible_rate_limit::RateLimiter;
+    /// # use actix_web::http::header::LOCATION;
+    /// # use actix_web::HttpResponse;
+    /// # use std::time::Duration;
+    /// # fn verify_token(token: &[u8]) -> bool { false }
+    /// # async {
+    /// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 5)
+    ///     .real_ip_key()
+    ///     .build();
+    /// let middleware = RateLimiter::builder(InMemoryBackend::builder().build(), input)
+    ///     .challenge(
+    ///         3,
+    ///         |req| {
+    ///             req.headers()
+    ///                 .get("x-challenge-token")
+    ///                 .is_some_and(|token| verify_token(token.as_bytes()))
+    ///         },
+    ///         |_| {
+    ///             HttpResponse::SeeOther()
+    ///                 .insert_header((LOCATION, "/captcha"))
+    ///                 .finish()
+    ///         },
+    ///     )
+    ///     .build();
+    /// # };
+    /// ```
+    pub fn challenge<V, R>(mut self, after_denials: u32, verify: V, challenge_response: R) -> Self
+    where
+        BI: KeyedInput,
+        BO: HeaderCompatibleOutput,
+        V: Fn(&ServiceRequest) -> bool + 'static,
+        R: Fn(&BO) -> HttpResponse + 'static,
+    {
+        self.challenge = Some(Rc::new(Challenge::new(
+            after_denials,
+            Box::new(verify),
+            Box::new(challenge_response),
+            Box::new(|input| {
+                input
+                    .downcast_ref::<BI>()
+                    .map(|input| input.key().to_owned())
+            }),
+            Box::new(|output| output.reset_at()),
+        )));
+        self
+    }
+
+    /// Deny requests whose key has been banned in the [BanStore], before the backend is
+    /// consulted.
+    ///
+    /// The store is usually the same as the backend, e.g.
+    /// [InMemoryBackend](crate::backend::memory::InMemoryBackend).
+    ///
+    /// If the store fails, the request is handled according to [RateLimiterBuilder::fail_open].
+    pub fn ban_store<S>(mut self, store: S) -> Self
+    where
+        BI: KeyedInput,
+        S: BanStore + Clone + 'static,
+        S::Error: Into<actix_web::Error>,
+    {
+        self.bans = Some(Rc::new(Bans {
+            input_key: Box::new(|input| {
+                input
+                    .downcast_ref::<BI>()
+                    .map(|input| input.key().to_owned())
+            }),
+            is_banned: Box::new(move |key| {
+                let store = store.clone();
+                Box::pin(async move { store.is_banned(&key).await.map_err(Into::into) })
+            }),
+        }));
+        self
+    }
+
+    /// Configure the [HttpResponse] returned when a key is banned, see
+    /// [RateLimiterBuilder::ban_store].
+    ///
+    /// Defaults to an empty body with status 403.
+    pub fn banned_response<R>(mut self, banned_response: R) -> Self
+    where
+        R: Fn() -> HttpResponse + 'static,
+    {
+        self.banned_response = Rc::new(banned_response);
+        self.custom_banned_response = true;
+        self
+    }
+
+    /// Make a [RateLimitHandle] available to handlers, so that they can refund part of the cost
+    /// charged for the request.
+    ///
+    /// Any [RateLimiterBuilder::rollback_condition] only rolls back what has not already been
+    /// refunded.
+    pub fn refund_handle(mut self) -> Self
+    where
+        BE::RollbackToken: PartialRollbackToken + 'static,
+        BE::Error: Into<actix_web::Error>,
+    {
+        let backend = self.backend.clone();
+        self.refund_handle = Some(Rc::new(move |token| {
+            let token = token.downcast::<BE::RollbackToken>().ok()?;
+            let backend = backend.clone();
+            Some(RateLimitHandle::new(
+                token.cost(),
+                Box::new(move |amount| {
+                    let token = token.with_cost(amount);
+                    let backend = backend.clone();
+                    Box::pin(async move { backend.rollback(token).await.map_err(Into::into) })
+                }),
+            ))
+        }));
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If the configuration is invalid, see [RateLimiterBuilder::try_build].
     pub fn build(self) -> RateLimiter<BE, BO, F> {
-        RateLimiter {
+        match self.try_build() {
+            Ok(limiter) => limiter,
+            Err(e) => panic!("Invalid rate limiter configuration: {e}"),
+        }
+    }
+
+    /// Build the middleware, returning an error if options were combined in a way that would not
+    /// work as intended; e.g. an option that depends on another that wasn't enabled.
+    pub fn try_build(mut self) -> Result<RateLimiter<BE, BO, F>, BuildError> {
+        if let Some((timeout, _)) = self.backend_timeout {
+            if timeout.is_zero() {
+                return Err(BuildError::ZeroBackendTimeout);
+            }
+        }
+        if self.custom_banned_response && self.bans.is_none() {
+            return Err(BuildError::BannedResponseWithoutBanStore);
+        }
+        match (&mut self.throttle, self.max_total_queued) {
+            (Some(throttle), max_total_queued) => throttle.max_total_queued = max_total_queued,
+            (None, Some(_)) => return Err(BuildError::MaxTotalQueuedWithoutThrottle),
+            (None, None) => {}
+        }
+        Ok(RateLimiter {
             backend: self.backend,
             input_fn: Rc::new(self.input_fn),
             fail_open: self.fail_open,
+            backend_error_status: self.backend_error_status,
+            control: self.control,
             allowed_mutation: self.allowed_transformation,
             denied_response: self.denied_response,
             rollback_condition: self.rollback_condition,
-        }
+            refund_marker: self.refund_marker,
+            on_request: self.on_request,
+            on_denied: self.on_denied,
+            deny_events: self.deny_events,
+            bans: self.bans,
+            banned_response: self.banned_response,
+            refund_handle: self.refund_handle,
+            backend_timeout: self.backend_timeout,
+            throttle: self.throttle.map(Rc::new),
+            challenge: self.challenge,
+            recorder: self.recorder,
+        })
     }
 }
 
+// The response given by RateLimiterBuilder::add_headers when a request is denied
+pub(crate) fn denied_response_with_headers<BO: HeaderCompatibleOutput>(
+    status: &BO,
+) -> HttpResponse {
+    let mut response = HttpResponse::TooManyRequests().finish();
+    let map = response.headers_mut();
+    map.insert(X_RATELIMIT_LIMIT.clone(), HeaderValue::from(status.limit()));
+    map.insert(
+        X_RATELIMIT_REMAINING.clone(),
+        HeaderValue::from(status.remaining()),
+    );
+    let seconds = status.seconds_until_reset();
+    map.insert(X_RATELIMIT_RESET.clone(), HeaderValue::from(seconds));
+    map.insert(RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
 /// A trait that a [Backend::Output] should implement in order to use the
 /// [RateLimiterBuilder::add_headers] function.
 pub trait HeaderCompatibleOutput {
@@ -171,4 +620,12 @@ pub trait HeaderCompatibleOutput {
     /// This should be the number of seconds from now until the limit resets.\
     /// If the limit has already reset this should return 0.
     fn seconds_until_reset(&self) -> u64;
+
+    /// The wall-clock time at which the limit resets, e.g. for a header containing a Unix
+    /// timestamp.
+    ///
+    /// Defaults to [HeaderCompatibleOutput::seconds_until_reset] from now.
+    fn reset_at(&self) -> SystemTime {
+        SystemTime::now() + Duration::from_secs(self.seconds_until_reset())
+    }
 }
@@ -0,0 +1,90 @@
+use crate::middleware::InputKey;
+use actix_web::dev::ServiceRequest;
+use actix_web::HttpResponse;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+type VerifyToken = dyn Fn(&ServiceRequest) -> bool;
+type ChallengeResponse<BO> = dyn Fn(&BO) -> HttpResponse;
+type ResetAt<BO> = dyn Fn(&BO) -> SystemTime;
+
+// The number of keys tracked before expired denial counts are first pruned
+const PRUNE_AT: usize = 1024;
+
+/// Challenges clients that keep going over the limit, see
+/// [RateLimiterBuilder::challenge](crate::RateLimiterBuilder::challenge).
+pub(super) struct Challenge<BO> {
+    after_denials: u32,
+    pub(super) verify: Box<VerifyToken>,
+    pub(super) response: Box<ChallengeResponse<BO>>,
+    pub(super) input_key: Box<InputKey>,
+    reset_at: Box<ResetAt<BO>>,
+    // The denials for each key, and when they are forgotten
+    denials: RefCell<HashMap<String, (u32, SystemTime)>>,
+    prune_at: Cell<usize>,
+}
+
+impl<BO> Challenge<BO> {
+    pub(super) fn new(
+        after_denials: u32,
+        verify: Box<VerifyToken>,
+        response: Box<ChallengeResponse<BO>>,
+        input_key: Box<InputKey>,
+        reset_at: Box<ResetAt<BO>>,
+    ) -> Self {
+        Self {
+            after_denials,
+            verify,
+            response,
+            input_key,
+            reset_at,
+            denials: RefCell::new(HashMap::new()),
+            prune_at: Cell::new(PRUNE_AT),
+        }
+    }
+
+    /// Count a denial for `key` within the current interval, returning whether the client should
+    /// be challenged.
+    pub(super) fn deny(&self, key: &str, output: &BO) -> bool {
+        let now = SystemTime::now();
+        let mut denials = self.denials.borrow_mut();
+        if denials.len() >= self.prune_at.get() {
+            denials.retain(|_, (_, expiry)| *expiry > now);
+            self.prune_at.set((denials.len() * 2).max(PRUNE_AT));
+        }
+        let (count, expiry) = denials.entry(key.to_owned()).or_insert((0, now));
+        if *expiry <= now {
+            *count = 0;
+        }
+        *count = count.saturating_add(1);
+        *expiry = (self.reset_at)(output);
+        *count > self.after_denials
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_deny() {
+        let challenge = Challenge::<SystemTime>::new(
+            2,
+            Box::new(|_| false),
+            Box::new(|_| HttpResponse::Forbidden().finish()),
+            Box::new(|_| None),
+            Box::new(|reset_at| *reset_at),
+        );
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert!(!challenge.deny("key", &later));
+        assert!(!challenge.deny("key", &later));
+        assert!(challenge.deny("key", &later));
+        assert!(!challenge.deny("other", &later));
+
+        // The count is forgotten once the interval has reset
+        assert!(challenge.deny("key", &SystemTime::UNIX_EPOCH));
+        assert!(!challenge.deny("key", &later));
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A shared handle used to switch rate limit enforcement on and off at runtime.
+///
+/// While enforcement is disabled the [RateLimiter](crate::RateLimiter) runs in shadow mode: the
+/// backend is still consulted (so counters and headers remain accurate), but requests that would
+/// have been denied are allowed through, and logged instead.
+///
+/// The same handle can be given to multiple rate limiters, all clones refer to the same switch.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::RateLimiterControl;
+/// let control = RateLimiterControl::new();
+/// // Pass `control.clone()` to RateLimiterBuilder::control(), then during an incident:
+/// control.disable();
+/// assert!(!control.is_enforcing());
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimiterControl {
+    enforcing: Arc<AtomicBool>,
+}
+
+impl RateLimiterControl {
+    /// Creates a new handle, with enforcement enabled.
+    pub fn new() -> Self {
+        Self {
+            enforcing: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Resume denying requests that are over the limit.
+    pub fn enable(&self) {
+        self.set_enforcing(true);
+    }
+
+    /// Switch into shadow mode, allowing all requests through.
+    pub fn disable(&self) {
+        self.set_enforcing(false);
+    }
+
+    pub fn set_enforcing(&self, enforcing: bool) {
+        let previous = self.enforcing.swap(enforcing, Ordering::Relaxed);
+        if previous != enforcing {
+            log::warn!("Rate limit enforcement has been set to: {enforcing}");
+        }
+    }
+
+    /// Returns whether over limit requests are currently being denied.
+    pub fn is_enforcing(&self) -> bool {
+        self.enforcing.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for RateLimiterControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
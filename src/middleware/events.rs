@@ -0,0 +1,27 @@
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+/// Published by the [RateLimiter](crate::RateLimiter) each time a request is denied, see
+/// [RateLimiterBuilder::deny_events](crate::RateLimiterBuilder::deny_events).
+#[derive(Debug, Clone)]
+pub struct DenyEvent<BO> {
+    /// The rate limit key that was exceeded.
+    pub key: String,
+    /// The path of the denied request.
+    pub path: String,
+    /// When the request was denied.
+    pub ts: SystemTime,
+    /// The output of the backend.
+    pub output: BO,
+}
+
+impl<BO: Clone> DenyEvent<BO> {
+    /// Create a bounded channel for deny events.
+    ///
+    /// Sending never blocks the request: once `capacity` events are buffered, receivers that
+    /// fall behind will skip the oldest events (and see
+    /// [RecvError::Lagged](broadcast::error::RecvError::Lagged)).
+    pub fn channel(capacity: usize) -> (broadcast::Sender<Self>, broadcast::Receiver<Self>) {
+        broadcast::channel(capacity)
+    }
+}
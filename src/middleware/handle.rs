@@ -0,0 +1,89 @@
+use actix_web::dev::Payload;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use futures::future::LocalBoxFuture;
+use std::cell::Cell;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+type Refund = dyn Fn(u64) -> LocalBoxFuture<'static, Result<(), actix_web::Error>>;
+
+/// Allows a handler to refund part of the cost charged for the current request, e.g. when the
+/// response turned out to be cheap or was served from a cache.
+///
+/// Enabled with [RateLimiterBuilder::refund_handle](crate::RateLimiterBuilder::refund_handle),
+/// after which it can be extracted by any handler behind the middleware.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::RateLimitHandle;
+/// # use actix_web::HttpResponse;
+/// async fn handler(rate_limit: RateLimitHandle) -> actix_web::Result<HttpResponse> {
+///     let cached = true;
+///     if cached {
+///         rate_limit.refund(rate_limit.charged() - 1).await?;
+///     }
+///     Ok(HttpResponse::Ok().finish())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct RateLimitHandle {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    charged: Cell<u64>,
+    refund: Box<Refund>,
+}
+
+impl RateLimitHandle {
+    pub(crate) fn new(charged: u64, refund: Box<Refund>) -> Self {
+        Self {
+            inner: Rc::new(Inner {
+                charged: Cell::new(charged),
+                refund,
+            }),
+        }
+    }
+
+    /// The cost currently charged for the request, after any refunds.
+    pub fn charged(&self) -> u64 {
+        self.inner.charged.get()
+    }
+
+    /// Refund `amount` of the cost charged for the request, capped at [RateLimitHandle::charged].
+    pub async fn refund(&self, amount: u64) -> Result<(), actix_web::Error> {
+        let charged = self.charged();
+        let amount = amount.min(charged);
+        if amount == 0 {
+            return Ok(());
+        }
+        self.inner.charged.set(charged - amount);
+        let result = (self.inner.refund)(amount).await;
+        if result.is_err() {
+            self.inner.charged.set(self.charged() + amount);
+        }
+        result
+    }
+}
+
+impl fmt::Debug for RateLimitHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitHandle")
+            .field("charged", &self.charged())
+            .finish()
+    }
+}
+
+impl FromRequest for RateLimitHandle {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Self>().cloned().ok_or_else(|| {
+            log::error!("RateLimitHandle requested, but the rate limiter doesn't provide one");
+            ErrorInternalServerError("Rate limit handle is unavailable")
+        }))
+    }
+}
@@ -0,0 +1,144 @@
+use crate::backend::{
+    Backend, LockoutBackend, SimpleInput, SimpleInputFunctionBuilder, SimpleOutput,
+};
+use crate::middleware::builder::RateLimiterBuilder;
+use crate::middleware::stack::{RateLimiterStack, StackInputFuture};
+use crate::middleware::Exempt;
+use crate::StackBackend;
+use actix_web::dev::ServiceRequest;
+use actix_web::http::StatusCode;
+use std::time::Duration;
+
+type UsernameFn = dyn Fn(&ServiceRequest) -> Option<String>;
+
+/// A ready-made [RateLimiter](crate::RateLimiter) for credential endpoints, protecting against
+/// password guessing.
+///
+/// - Failed attempts are counted both per client IP address and per username, so that neither
+///   one client trying many usernames nor many clients trying one username get far.
+/// - Only failed attempts, those answered with `401 Unauthorized` or `403 Forbidden`, count
+///   towards the limits; every other response is rolled back.
+/// - Going over either limit locks the IP address or username out, for exponentially longer
+///   each time, see [LockoutBackend].
+///
+/// The username must be taken from something the middleware can see, such as a header, the
+/// query string or a request extension, as the request body hasn't been read yet. Requests
+/// without a username are only limited per IP address.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::LoginProtection;
+/// # use std::time::Duration;
+/// # async {
+/// let backend = InMemoryBackend::builder().build();
+/// let limiter = LoginProtection::new(backend, |req| {
+///     let username = req.headers().get("x-username")?.to_str().ok()?;
+///     Some(username.to_lowercase())
+/// })
+/// .per_username(Duration::from_secs(900), 5)
+/// .builder()
+/// .add_headers()
+/// .build();
+/// # };
+/// ```
+pub struct LoginProtection<B> {
+    backend: B,
+    username_fn: Box<UsernameFn>,
+    per_ip: (Duration, u64),
+    per_username: (Duration, u64),
+    lockout: (Duration, Duration),
+    real_ip_key: bool,
+    key_prefix: String,
+}
+
+impl<B> LoginProtection<B>
+where
+    B: Backend<SimpleInput, Output = SimpleOutput> + 'static,
+{
+    /// By default each IP address may fail 20 times, and each username 5 times, per 15 minutes,
+    /// and lockouts start at a minute, up to a day.
+    pub fn new<F>(backend: B, username_fn: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Option<String> + 'static,
+    {
+        Self {
+            backend,
+            username_fn: Box::new(username_fn),
+            per_ip: (Duration::from_secs(15 * 60), 20),
+            per_username: (Duration::from_secs(15 * 60), 5),
+            lockout: (Duration::from_secs(60), Duration::from_secs(24 * 60 * 60)),
+            real_ip_key: false,
+            key_prefix: "login:".to_owned(),
+        }
+    }
+
+    /// Override the number of failed attempts allowed per `interval` from each IP address.
+    pub fn per_ip(mut self, interval: Duration, max_failures: u64) -> Self {
+        self.per_ip = (interval, max_failures);
+        self
+    }
+
+    /// Override the number of failed attempts allowed per `interval` for each username.
+    pub fn per_username(mut self, interval: Duration, max_failures: u64) -> Self {
+        self.per_username = (interval, max_failures);
+        self
+    }
+
+    /// Override the duration of the first lockout, and the maximum it may double up to.
+    pub fn lockout(mut self, base: Duration, max: Duration) -> Self {
+        self.lockout = (base, max);
+        self
+    }
+
+    /// Key the per IP limit on the client's real IP address, rather than the peer's, see
+    /// [SimpleInputFunctionBuilder::real_ip_key] for the caveats.
+    pub fn real_ip_key(mut self) -> Self {
+        self.real_ip_key = true;
+        self
+    }
+
+    /// Override the namespace of the keys, the default is `"login:"`.
+    pub fn key_prefix(mut self, prefix: &str) -> Self {
+        self.key_prefix = prefix.to_owned();
+        self
+    }
+
+    /// Finish the configuration, returning a [RateLimiterBuilder] to configure the rest of the
+    /// middleware.
+    ///
+    /// The [rollback_condition](RateLimiterBuilder::rollback_condition) is already set, and
+    /// shouldn't be replaced.
+    pub fn builder(
+        self,
+    ) -> RateLimiterBuilder<
+        StackBackend<LockoutBackend<B>>,
+        SimpleOutput,
+        impl Fn(&ServiceRequest) -> StackInputFuture,
+    > {
+        let (interval, max_failures) = self.per_ip;
+        let ip = SimpleInputFunctionBuilder::new(interval, max_failures);
+        let ip = if self.real_ip_key {
+            ip.real_ip_key()
+        } else {
+            ip.peer_ip_key()
+        };
+        let ip = ip.key_prefix(&format!("{}ip:", self.key_prefix)).build();
+
+        let (interval, max_failures) = self.per_username;
+        let username_fn = self.username_fn;
+        let username = SimpleInputFunctionBuilder::new(interval, max_failures)
+            .custom_fn(move |req| username_fn(req).ok_or_else(|| Exempt.into()))
+            .key_prefix(&format!("{}user:", self.key_prefix))
+            .build();
+
+        let (base, max) = self.lockout;
+        RateLimiterStack::new(LockoutBackend::new(self.backend, base, max))
+            .layer(ip)
+            .layer(username)
+            .builder()
+            .rollback_condition(Some(|status| {
+                status != StatusCode::UNAUTHORIZED && status != StatusCode::FORBIDDEN
+            }))
+    }
+}
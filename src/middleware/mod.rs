@@ -1,30 +1,191 @@
 pub mod builder;
+mod challenge;
+pub mod control;
+pub mod events;
+pub mod handle;
+pub mod login;
+pub mod recording;
+pub mod stack;
 #[cfg(test)]
 mod tests;
+mod throttle;
 
-use crate::backend::Backend;
+use crate::backend::{
+    Backend, BackendError, SimpleInput, SimpleInputFunctionBuilder, SimpleInputFuture, SimpleOutput,
+};
+use crate::metrics;
 use actix_web::body::EitherBody;
 use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
-use actix_web::http::header::HeaderMap;
+use actix_web::http::header::{HeaderMap, HeaderName};
 use actix_web::http::StatusCode;
-use actix_web::HttpResponse;
+use actix_web::{HttpMessage, HttpResponse, ResponseError};
 use builder::RateLimiterBuilder;
+use challenge::Challenge;
+use control::RateLimiterControl;
 use futures::future::{ok, LocalBoxFuture, Ready};
+use handle::RateLimitHandle;
+use recording::{TrafficRecord, TrafficSink};
+use std::any::Any;
 use std::cell::RefCell;
+use std::time::{Duration, Instant, SystemTime};
 use std::{future::Future, rc::Rc};
+use thiserror::Error;
+use throttle::Throttle;
 
-type AllowedTransformation<BO> = dyn Fn(&mut HeaderMap, Option<&BO>, bool);
+// Also given the cost refunded for the request, so that the remaining count can be adjusted
+type AllowedTransformation<BO> = dyn Fn(&mut HeaderMap, Option<&BO>, bool, u64);
 type DeniedResponse<BO> = dyn Fn(&BO) -> HttpResponse;
 type RollbackCondition = dyn Fn(StatusCode) -> bool;
+type RequestHook<BO> = dyn Fn(&ServiceRequest, &Decision<BO>);
+type DeniedHook<BO> = dyn Fn(&ServiceRequest, &BO);
+type BackendErrorStatus = dyn Fn(&BackendError) -> StatusCode;
+// The backend input type is erased, so that it doesn't need to be a parameter of the middleware.
+type InputKey = dyn Fn(&dyn Any) -> Option<String>;
+type PublishDenyEvent<BO> = dyn Fn(String, &str, &BO);
+
+type IsBanned = dyn Fn(String) -> LocalBoxFuture<'static, Result<bool, actix_web::Error>>;
+type BannedResponse = dyn Fn() -> HttpResponse;
+// The rollback token type is erased for the same reason as the input.
+type MakeRefundHandle = dyn Fn(Box<dyn Any>) -> Option<RateLimitHandle>;
+type InputRecord = dyn Fn(&dyn Any) -> Option<(String, u64)>;
+
+struct DenyEvents<BO> {
+    input_key: Box<InputKey>,
+    publish: Box<PublishDenyEvent<BO>>,
+}
+
+struct Recorder {
+    // The key and cost of the input
+    input_record: Box<InputRecord>,
+    sink: Box<dyn TrafficSink>,
+}
+
+struct Bans {
+    input_key: Box<InputKey>,
+    is_banned: Box<IsBanned>,
+}
+
+/// The outcome of consulting the backend, given to [RateLimiterBuilder::on_request] hooks.
+#[derive(Debug)]
+pub enum Decision<'a, BO> {
+    /// The request is within the limit.
+    Allowed(&'a BO),
+    /// The request is over the limit, and will be denied.
+    Denied(&'a BO),
+    /// The request is over the limit, but will be allowed because enforcement has been disabled
+    /// through a [RateLimiterControl].
+    ShadowDenied(&'a BO),
+    /// The backend failed, the request will be allowed only if the middleware fails open.
+    BackendFailed { allowed: bool },
+    /// The backend took longer than the [RateLimiterBuilder::backend_timeout], the request will be
+    /// allowed according to the [TimeoutPolicy].
+    TimedOut { allowed: bool },
+    /// The key has been banned through a [BanStore](crate::backend::BanStore), the backend was
+    /// not consulted.
+    Banned,
+}
+
+/// What to do with a request when the backend takes longer than the
+/// [RateLimiterBuilder::backend_timeout].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPolicy {
+    /// Allow the request, without any rate limit headers.
+    Allow,
+    /// Reject the request with a [BackendTimeout] error.
+    Deny,
+}
+
+/// The error response given when the backend times out, and the [TimeoutPolicy] is to deny.
+#[derive(Debug, Clone, Copy, Error)]
+#[error("Rate limit backend timed out")]
+pub struct BackendTimeout;
+
+impl ResponseError for BackendTimeout {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// An error that an input function can return to exempt a request from rate limiting.
+///
+/// The request is passed straight to the wrapped service, without consulting the backend, and
+/// without any rate limit headers being added to the response.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::Exempt;
+/// # use actix_web::dev::ServiceRequest;
+/// # use std::future::{ready, Ready};
+/// fn input_fn(req: &ServiceRequest) -> Ready<Result<String, actix_web::Error>> {
+///     if req.path() == "/healthz" {
+///         return ready(Err(Exempt.into()));
+///     }
+///     ready(Ok(req.path().to_owned()))
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Error)]
+#[error("Request is exempt from rate limiting")]
+pub struct Exempt;
+
+impl ResponseError for Exempt {}
+
+/// A response extension that a handler can insert to have the charge for the request rolled
+/// back, e.g. because the response was served from a cache.
+///
+/// Only honoured when enabled with [RateLimiterBuilder::rollback_on_refund].
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::Refund;
+/// # use actix_web::{HttpMessage, HttpResponse};
+/// async fn handler() -> HttpResponse {
+///     let mut response = HttpResponse::Ok().finish();
+///     response.extensions_mut().insert(Refund);
+///     response
+/// }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Refund;
+
+// Marks a response as refunded, through the Refund extension, or optionally a header
+struct RefundMarker {
+    header: Option<HeaderName>,
+}
+
+impl RefundMarker {
+    // The header is removed, so that it isn't sent to the client
+    fn take<B>(&self, response: &mut ServiceResponse<B>) -> bool {
+        let mut refunded = response.response().extensions().contains::<Refund>();
+        if let Some(header) = &self.header {
+            if let Some(value) = response.headers_mut().remove(header).next() {
+                refunded |= value.as_bytes().eq_ignore_ascii_case(b"true");
+            }
+        }
+        refunded
+    }
+}
 
 /// Rate limit middleware.
 pub struct RateLimiter<BA, BO, F> {
     backend: BA,
     input_fn: Rc<F>,
     fail_open: bool,
+    backend_error_status: Option<Rc<BackendErrorStatus>>,
+    control: Option<RateLimiterControl>,
     allowed_mutation: Option<Rc<AllowedTransformation<BO>>>,
     denied_response: Rc<DeniedResponse<BO>>,
     rollback_condition: Option<Rc<RollbackCondition>>,
+    refund_marker: Option<Rc<RefundMarker>>,
+    on_request: Option<Rc<RequestHook<BO>>>,
+    on_denied: Option<Rc<DeniedHook<BO>>>,
+    deny_events: Option<Rc<DenyEvents<BO>>>,
+    bans: Option<Rc<Bans>>,
+    banned_response: Rc<BannedResponse>,
+    refund_handle: Option<Rc<MakeRefundHandle>>,
+    backend_timeout: Option<(Duration, TimeoutPolicy)>,
+    throttle: Option<Rc<Throttle<BO>>>,
+    challenge: Option<Rc<Challenge<BO>>>,
+    recorder: Option<Rc<Recorder>>,
 }
 
 impl<BA, BI, BO, F, O> Clone for RateLimiter<BA, BO, F>
@@ -39,9 +200,22 @@ where
             backend: self.backend.clone(),
             input_fn: self.input_fn.clone(),
             fail_open: self.fail_open,
+            backend_error_status: self.backend_error_status.clone(),
+            control: self.control.clone(),
             allowed_mutation: self.allowed_mutation.clone(),
             denied_response: self.denied_response.clone(),
             rollback_condition: self.rollback_condition.clone(),
+            refund_marker: self.refund_marker.clone(),
+            on_request: self.on_request.clone(),
+            on_denied: self.on_denied.clone(),
+            deny_events: self.deny_events.clone(),
+            bans: self.bans.clone(),
+            banned_response: self.banned_response.clone(),
+            refund_handle: self.refund_handle.clone(),
+            backend_timeout: self.backend_timeout,
+            throttle: self.throttle.clone(),
+            challenge: self.challenge.clone(),
+            recorder: self.recorder.clone(),
         }
     }
 }
@@ -62,12 +236,75 @@ where
     }
 }
 
+// The input function type is only a placeholder, so that the presets can be called as
+// `RateLimiter::per_ip_per_minute`
+impl<BA> RateLimiter<BA, SimpleOutput, ()>
+where
+    BA: Backend<SimpleInput, Output = SimpleOutput> + 'static,
+{
+    /// Allow `max_requests` per second for each client, keyed on the connection's peer IP, with
+    /// the [add_headers](RateLimiterBuilder::add_headers) headers.
+    ///
+    /// Behind a reverse proxy, use [RateLimiter::builder] with a
+    /// [SimpleInputFunctionBuilder] keyed on the client IP the proxy forwards instead.
+    ///
+    /// # Example
+    /// ```
+    /// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+    /// # use actix_extensible_rate_limit::RateLimiter;
+    /// # use actix_web::App;
+    /// let backend = InMemoryBackend::builder().build();
+    /// let app = App::new().wrap(RateLimiter::per_ip_per_minute(backend, 100));
+    /// ```
+    pub fn per_ip_per_second(
+        backend: BA,
+        max_requests: u64,
+    ) -> RateLimiter<BA, SimpleOutput, impl Fn(&ServiceRequest) -> SimpleInputFuture + 'static>
+    {
+        Self::per_ip(
+            backend,
+            SimpleInputFunctionBuilder::per_second(max_requests),
+        )
+    }
+
+    /// Allow `max_requests` per minute for each client, see [RateLimiter::per_ip_per_second].
+    pub fn per_ip_per_minute(
+        backend: BA,
+        max_requests: u64,
+    ) -> RateLimiter<BA, SimpleOutput, impl Fn(&ServiceRequest) -> SimpleInputFuture + 'static>
+    {
+        Self::per_ip(
+            backend,
+            SimpleInputFunctionBuilder::per_minute(max_requests),
+        )
+    }
+
+    /// Allow `max_requests` per hour for each client, see [RateLimiter::per_ip_per_second].
+    pub fn per_ip_per_hour(
+        backend: BA,
+        max_requests: u64,
+    ) -> RateLimiter<BA, SimpleOutput, impl Fn(&ServiceRequest) -> SimpleInputFuture + 'static>
+    {
+        Self::per_ip(backend, SimpleInputFunctionBuilder::per_hour(max_requests))
+    }
+
+    fn per_ip(
+        backend: BA,
+        input: SimpleInputFunctionBuilder,
+    ) -> RateLimiter<BA, SimpleOutput, impl Fn(&ServiceRequest) -> SimpleInputFuture + 'static>
+    {
+        let input = input.peer_ip_key().build();
+        RateLimiter::builder(backend, input).add_headers().build()
+    }
+}
+
 impl<S, B, BA, BI, BO, BE, F, O> Transform<S, ServiceRequest> for RateLimiter<BA, BO, F>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
     S::Future: 'static,
     B: 'static,
     BA: Backend<BI, Output = BO, Error = BE> + 'static,
+    BA::RollbackToken: 'static,
     BI: 'static,
     BO: 'static,
     BE: Into<actix_web::Error> + std::fmt::Display + 'static,
@@ -86,9 +323,22 @@ where
             backend: self.backend.clone(),
             input_fn: Rc::clone(&self.input_fn),
             fail_open: self.fail_open,
+            backend_error_status: self.backend_error_status.clone(),
+            control: self.control.clone(),
             allowed_transformation: self.allowed_mutation.clone(),
             denied_response: self.denied_response.clone(),
             rollback_condition: self.rollback_condition.clone(),
+            refund_marker: self.refund_marker.clone(),
+            on_request: self.on_request.clone(),
+            on_denied: self.on_denied.clone(),
+            deny_events: self.deny_events.clone(),
+            bans: self.bans.clone(),
+            banned_response: self.banned_response.clone(),
+            refund_handle: self.refund_handle.clone(),
+            backend_timeout: self.backend_timeout,
+            throttle: self.throttle.clone(),
+            challenge: self.challenge.clone(),
+            recorder: self.recorder.clone(),
         })
     }
 }
@@ -98,9 +348,22 @@ pub struct RateLimiterMiddleware<S, BE, BO, F> {
     backend: BE,
     input_fn: Rc<F>,
     fail_open: bool,
+    backend_error_status: Option<Rc<BackendErrorStatus>>,
+    control: Option<RateLimiterControl>,
     allowed_transformation: Option<Rc<AllowedTransformation<BO>>>,
     denied_response: Rc<DeniedResponse<BO>>,
     rollback_condition: Option<Rc<RollbackCondition>>,
+    refund_marker: Option<Rc<RefundMarker>>,
+    on_request: Option<Rc<RequestHook<BO>>>,
+    on_denied: Option<Rc<DeniedHook<BO>>>,
+    deny_events: Option<Rc<DenyEvents<BO>>>,
+    bans: Option<Rc<Bans>>,
+    banned_response: Rc<BannedResponse>,
+    refund_handle: Option<Rc<MakeRefundHandle>>,
+    backend_timeout: Option<(Duration, TimeoutPolicy)>,
+    throttle: Option<Rc<Throttle<BO>>>,
+    challenge: Option<Rc<Challenge<BO>>>,
+    recorder: Option<Rc<Recorder>>,
 }
 
 impl<S, B, BA, BI, BO, BE, F, O> Service<ServiceRequest> for RateLimiterMiddleware<S, BA, BO, F>
@@ -109,6 +372,7 @@ where
     S::Future: 'static,
     B: 'static,
     BA: Backend<BI, Output = BO, Error = BE> + 'static,
+    BA::RollbackToken: 'static,
     BI: 'static,
     BO: 'static,
     BE: Into<actix_web::Error> + std::fmt::Display + 'static,
@@ -126,60 +390,294 @@ where
         let backend = self.backend.clone();
         let input_fn = self.input_fn.clone();
         let fail_open = self.fail_open;
+        let backend_error_status = self.backend_error_status.clone();
+        let control = self.control.clone();
         let allowed_transformation = self.allowed_transformation.clone();
         let denied_response = self.denied_response.clone();
         let rollback_condition = self.rollback_condition.clone();
+        let refund_marker = self.refund_marker.clone();
+        let on_request = self.on_request.clone();
+        let on_denied = self.on_denied.clone();
+        let deny_events = self.deny_events.clone();
+        let bans = self.bans.clone();
+        let banned_response = self.banned_response.clone();
+        let refund_handle = self.refund_handle.clone();
+        let backend_timeout = self.backend_timeout;
+        let throttle = self.throttle.clone();
+        let challenge = self.challenge.clone();
+        let recorder = self.recorder.clone();
 
         Box::pin(async move {
+            let enforcing = || control.as_ref().is_none_or(|c| c.is_enforcing());
+            if challenge.as_ref().is_some_and(|c| (c.verify)(&req)) {
+                metrics::record_request(metrics::OUTCOME_EXEMPT);
+                let service_response = service.call(req).await?;
+                return Ok(service_response.map_into_left_body());
+            }
+
             let input = match (input_fn)(&req).await {
                 Ok(input) => input,
+                Err(e) if e.as_error::<Exempt>().is_some() => {
+                    metrics::record_request(metrics::OUTCOME_EXEMPT);
+                    let service_response = service.call(req).await?;
+                    return Ok(service_response.map_into_left_body());
+                }
                 Err(e) => {
                     log::error!("Rate limiter input function failed: {e}");
+                    metrics::record_request(metrics::OUTCOME_INPUT_ERROR);
                     return Ok(req.into_response(e.error_response()).map_into_right_body());
                 }
             };
 
-            let (output, rollback) = match backend.request(input).await {
+            let ban_key = bans.as_ref().and_then(|bans| (bans.input_key)(&input));
+            if let (Some(bans), Some(key)) = (bans, ban_key) {
+                match (bans.is_banned)(key).await {
+                    Ok(true) => {
+                        if enforcing() {
+                            metrics::record_request(metrics::OUTCOME_BANNED);
+                            if let Some(hook) = &on_request {
+                                hook(&req, &Decision::Banned);
+                            }
+                            let response = (banned_response)();
+                            return Ok(req.into_response(response).map_into_right_body());
+                        }
+                        log::info!("Rate limit key is banned, allowing the request anyway because enforcement is disabled");
+                    }
+                    Ok(false) => {}
+                    Err(e) if fail_open => {
+                        log::warn!(
+                            "Rate limiter ban check failed: {e}, ignoring bans for the request"
+                        );
+                    }
+                    Err(e) => {
+                        log::error!("Rate limiter ban check failed: {e}");
+                        metrics::record_request(metrics::OUTCOME_BACKEND_ERROR);
+                        return Ok(req.into_response(e.error_response()).map_into_right_body());
+                    }
+                }
+            }
+
+            // The input is consumed by the backend, so the key must be taken beforehand
+            let deny_key = deny_events
+                .as_ref()
+                .and_then(|events| (events.input_key)(&input));
+            let challenge_key = challenge
+                .as_ref()
+                .and_then(|challenge| (challenge.input_key)(&input));
+            let throttle_key = throttle
+                .as_ref()
+                .and_then(|throttle| (throttle.input_key)(&input));
+            let record = recorder
+                .as_ref()
+                .and_then(|recorder| (recorder.input_record)(&input));
+            let call_backend = |input: BI| async {
+                let started = Instant::now();
+                let request = backend.request(input);
+                let result = match backend_timeout {
+                    Some((timeout, _)) => actix_web::rt::time::timeout(timeout, request).await.ok(),
+                    None => Some(request.await),
+                };
+                metrics::record_backend_duration(started.elapsed());
+                result
+            };
+            let mut slot = None;
+            let deadline = throttle
+                .as_ref()
+                .map(|throttle| Instant::now() + throttle.max_wait);
+            if let (Some(throttle), Some(key), Some(deadline)) =
+                (&throttle, &throttle_key, deadline)
+            {
+                // Queue behind the requests already waiting for the key, so that they are let
+                // through first
+                if enforcing() && throttle.is_queued(key) {
+                    slot = throttle.enqueue(key.clone());
+                    if let Some(slot) = &slot {
+                        let wait = deadline.saturating_duration_since(Instant::now());
+                        let _ = actix_web::rt::time::timeout(wait, slot.turn()).await;
+                    }
+                }
+            }
+            let mut result = call_backend(input).await;
+            // Throttled requests wait for the limit to reset, and are then counted again
+            if let (Some(throttle), Some(key), Some(deadline)) = (&throttle, throttle_key, deadline)
+            {
+                while let Some(Ok((false, output, _))) = &result {
+                    if !enforcing() {
+                        break;
+                    }
+                    if slot.is_none() {
+                        slot = throttle.enqueue(key.clone());
+                    }
+                    let Some(slot) = &slot else {
+                        break;
+                    };
+                    let now = Instant::now();
+                    if slot.is_first() {
+                        let wait = (throttle.wait_for)(output);
+                        if now + wait > deadline {
+                            break;
+                        }
+                        actix_web::rt::time::sleep(wait).await;
+                    } else {
+                        let wait = deadline.saturating_duration_since(now);
+                        if actix_web::rt::time::timeout(wait, slot.turn())
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    let Ok(input) = (input_fn)(&req).await else {
+                        break;
+                    };
+                    result = call_backend(input).await;
+                }
+            }
+            // Let the next request in the queue through
+            drop(slot);
+            let (output, rollback) = match result {
                 // Able to successfully query rate limiter backend
-                Ok((allow, output, rollback)) => {
+                Some(Ok((allow, output, rollback))) => {
+                    if let (Some(recorder), Some((key, cost))) = (&recorder, record) {
+                        recorder.sink.record(&TrafficRecord {
+                            ts: SystemTime::now(),
+                            key,
+                            cost,
+                            allowed: allow,
+                        });
+                    }
                     if !allow {
-                        let response: HttpResponse = (denied_response)(&output);
-                        return Ok(req.into_response(response).map_into_right_body());
+                        if enforcing() {
+                            metrics::record_request(metrics::OUTCOME_DENIED);
+                            if let Some(hook) = on_request {
+                                hook(&req, &Decision::Denied(&output));
+                            }
+                            if let Some(hook) = on_denied {
+                                hook(&req, &output);
+                            }
+                            if let (Some(events), Some(key)) = (deny_events, deny_key) {
+                                (events.publish)(key, req.path(), &output);
+                            }
+                            let response: HttpResponse = match (&challenge, &challenge_key) {
+                                (Some(challenge), Some(key)) if challenge.deny(key, &output) => {
+                                    (challenge.response)(&output)
+                                }
+                                _ => (denied_response)(&output),
+                            };
+                            return Ok(req.into_response(response).map_into_right_body());
+                        }
+                        log::info!("Rate limit exceeded, allowing the request anyway because enforcement is disabled");
+                        metrics::record_request(metrics::OUTCOME_SHADOW_DENIED);
+                        if let Some(hook) = on_request {
+                            hook(&req, &Decision::ShadowDenied(&output));
+                        }
+                    } else {
+                        metrics::record_request(metrics::OUTCOME_ALLOWED);
+                        if let Some(hook) = on_request {
+                            hook(&req, &Decision::Allowed(&output));
+                        }
                     }
                     (Some(output), Some(rollback))
                 }
                 // Unable to query rate limiter backend
-                Err(e) => {
+                Some(Err(e)) => {
+                    let e: actix_web::Error = e.into();
+                    let backend_error = e.as_error::<BackendError>();
+                    if let Some(backend_error) = backend_error {
+                        metrics::record_backend_error(backend_error.kind());
+                    }
+                    if let Some(hook) = on_request {
+                        hook(&req, &Decision::BackendFailed { allowed: fail_open });
+                    }
                     if fail_open {
                         log::warn!("Rate limiter failed: {}, allowing the request anyway", e);
+                        metrics::record_request(metrics::OUTCOME_BACKEND_ERROR_ALLOWED);
                         (None, None)
                     } else {
                         log::error!("Rate limiter failed: {}", e);
+                        metrics::record_request(metrics::OUTCOME_BACKEND_ERROR);
+                        let mut response = e.error_response();
+                        if let (Some(status), Some(backend_error)) =
+                            (&backend_error_status, backend_error)
+                        {
+                            *response.status_mut() = status(backend_error);
+                        }
+                        return Ok(req.into_response(response).map_into_right_body());
+                    }
+                }
+                // The backend timed out
+                None => {
+                    let allowed =
+                        backend_timeout.map(|(_, policy)| policy) == Some(TimeoutPolicy::Allow);
+                    if let Some(hook) = on_request {
+                        hook(&req, &Decision::TimedOut { allowed });
+                    }
+                    if allowed {
+                        log::warn!("Rate limiter timed out, allowing the request anyway");
+                        metrics::record_request(metrics::OUTCOME_TIMEOUT_ALLOWED);
+                        (None, None)
+                    } else {
+                        log::error!("Rate limiter timed out");
+                        metrics::record_request(metrics::OUTCOME_TIMEOUT);
                         return Ok(req
-                            .into_response(e.into().error_response())
+                            .into_response(BackendTimeout.error_response())
                             .map_into_right_body());
                     }
                 }
             };
 
+            // The token is moved into the handle, so that the rollback below doesn't refund
+            // anything the handler has already refunded
+            let (rollback, handle) = match (refund_handle, rollback) {
+                (Some(make_handle), Some(token)) => (None, make_handle(Box::new(token))),
+                (_, rollback) => (rollback, None),
+            };
+            if let Some(handle) = &handle {
+                req.extensions_mut().insert(handle.clone());
+            }
+            let charged = handle.as_ref().map(RateLimitHandle::charged);
+
             let mut service_response = service.call(req).await?;
 
             let mut rolled_back = false;
-            if let Some(token) = rollback {
-                if let Some(rollback_condition) = rollback_condition {
-                    let status = service_response.status();
-                    if rollback_condition(status) {
-                        if let Err(e) = backend.rollback(token).await {
-                            log::error!("Unable to rollback rate-limit count for response: {:?}, error: {e}", status);
-                        } else {
-                            rolled_back = true;
-                        };
+            let mut rollback_cost = 0;
+            let status = service_response.status();
+            let refunded = refund_marker.is_some_and(|marker| marker.take(&mut service_response));
+            if refunded || rollback_condition.is_some_and(|condition| condition(status)) {
+                let result = match (rollback, &handle) {
+                    (Some(token), _) => {
+                        rollback_cost = backend.rollback_cost(&token).unwrap_or(1);
+                        Some(backend.rollback(token).await.map_err(Into::into))
                     }
+                    (None, Some(handle)) => Some(handle.refund(handle.charged()).await),
+                    (None, None) => None,
+                };
+                if let Some(result) = result {
+                    if let Err(e) = result {
+                        log::error!(
+                            "Unable to rollback rate-limit count for response: {:?}, error: {e}",
+                            status
+                        );
+                    } else {
+                        rolled_back = true;
+                    };
+                    metrics::record_rollback(rolled_back);
                 }
             }
 
             if let Some(transformation) = allowed_transformation {
-                (transformation)(service_response.headers_mut(), output.as_ref(), rolled_back);
+                // Including anything the handler refunded through the RateLimitHandle
+                let refunded = match (charged, &handle) {
+                    (Some(charged), Some(handle)) => charged.saturating_sub(handle.charged()),
+                    _ if rolled_back => rollback_cost,
+                    _ => 0,
+                };
+                (transformation)(
+                    service_response.headers_mut(),
+                    output.as_ref(),
+                    rolled_back,
+                    refunded,
+                );
             }
 
             Ok(service_response.map_into_left_body())
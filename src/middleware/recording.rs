@@ -0,0 +1,157 @@
+use std::fmt::Write as _;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A rate limit decision recorded by the [RateLimiter](crate::RateLimiter), see
+/// [RateLimiterBuilder::record_traffic](crate::RateLimiterBuilder::record_traffic).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrafficRecord {
+    /// When the backend made its decision.
+    pub ts: SystemTime,
+    /// The rate limit key derived from the request.
+    pub key: String,
+    /// The cost of the request.
+    pub cost: u64,
+    /// Whether the backend allowed the request; in shadow mode the request is let through
+    /// anyway.
+    pub allowed: bool,
+}
+
+impl TrafficRecord {
+    /// Formats the record as a line of JSON (without the newline), e.g.
+    /// `{"ts_ms":1700000000123,"key":"10.0.0.1","cost":1,"allowed":true}`.
+    pub fn to_json(&self) -> String {
+        let ts_ms = self
+            .ts
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut json = String::with_capacity(self.key.len() + 64);
+        let _ = write!(json, "{{\"ts_ms\":{},\"key\":\"", ts_ms);
+        escape_json(&self.key, &mut json);
+        let _ = write!(
+            json,
+            "\",\"cost\":{},\"allowed\":{}}}",
+            self.cost, self.allowed
+        );
+        json
+    }
+}
+
+fn escape_json(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+/// Receives each [TrafficRecord], e.g. to write it to a file for tuning limits offline.
+///
+/// Implemented for closures, and by [JsonlSink].
+pub trait TrafficSink {
+    /// Called on the request's thread, so should not block for long.
+    fn record(&self, record: &TrafficRecord);
+}
+
+impl<F: Fn(&TrafficRecord)> TrafficSink for F {
+    fn record(&self, record: &TrafficRecord) {
+        self(record)
+    }
+}
+
+/// A [TrafficSink] that writes each record as a line of JSON (see [TrafficRecord::to_json]) to a
+/// buffered writer, such as a file.
+///
+/// Clones share the same writer, so a single sink can be given to the middleware on every
+/// worker. The buffer is flushed when the last clone is dropped, or by [JsonlSink::flush].
+///
+/// # Example
+/// ```no_run
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+/// # use actix_extensible_rate_limit::{JsonlSink, RateLimiter};
+/// # use std::time::Duration;
+/// let sink = JsonlSink::new(std::fs::File::create("traffic.jsonl").unwrap());
+/// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 5)
+///     .real_ip_key()
+///     .build();
+/// let middleware = RateLimiter::builder(InMemoryBackend::builder().build(), input)
+///     .record_traffic(sink.clone())
+///     .build();
+/// ```
+pub struct JsonlSink<W: Write> {
+    writer: Arc<Mutex<BufWriter<W>>>,
+}
+
+impl<W: Write> Clone for JsonlSink<W> {
+    fn clone(&self) -> Self {
+        Self {
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+impl<W: Write> JsonlSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(BufWriter::new(writer))),
+        }
+    }
+
+    /// Write any buffered records.
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+impl<W: Write> TrafficSink for JsonlSink<W> {
+    fn record(&self, record: &TrafficRecord) {
+        let mut line = record.to_json();
+        line.push('\n');
+        if let Err(e) = self.writer.lock().unwrap().write_all(line.as_bytes()) {
+            log::warn!("Failed to record rate limit traffic: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_jsonl_sink() {
+        let sink = JsonlSink::new(Vec::new());
+        sink.record(&TrafficRecord {
+            ts: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            key: "10.0.0.1".to_owned(),
+            cost: 1,
+            allowed: true,
+        });
+        sink.record(&TrafficRecord {
+            ts: UNIX_EPOCH,
+            key: "quote\"\nline".to_owned(),
+            cost: 3,
+            allowed: false,
+        });
+        sink.flush().unwrap();
+        let written = String::from_utf8(sink.writer.lock().unwrap().get_ref().clone()).unwrap();
+        assert_eq!(
+            written,
+            concat!(
+                "{\"ts_ms\":1700000000123,\"key\":\"10.0.0.1\",\"cost\":1,\"allowed\":true}\n",
+                "{\"ts_ms\":0,\"key\":\"quote\\\"\\nline\",\"cost\":3,\"allowed\":false}\n",
+            )
+        );
+    }
+}
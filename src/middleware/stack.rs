@@ -0,0 +1,190 @@
+use crate::backend::{Backend, Health, SimpleInput, SimpleOutput};
+use crate::middleware::builder::RateLimiterBuilder;
+use crate::middleware::{Exempt, RateLimiter};
+use actix_web::dev::ServiceRequest;
+use futures::future::{join_all, LocalBoxFuture};
+use std::future::Future;
+use std::rc::Rc;
+
+type Layer =
+    dyn Fn(&ServiceRequest) -> LocalBoxFuture<'static, Result<SimpleInput, actix_web::Error>>;
+pub(super) type StackInputFuture =
+    LocalBoxFuture<'static, Result<Vec<SimpleInput>, actix_web::Error>>;
+
+/// Builds a single [RateLimiter] that checks several limits for each request, e.g. a global, a
+/// per-IP and a per-user limit.
+///
+/// Each layer is an input function, as given to [RateLimiter::builder]. All of the layers are
+/// charged together with [Backend::request_many], and the request is only allowed if every layer
+/// allows it. If any layer denies it, the charges made to the layers that allowed it are rolled
+/// back, so that a request denied by one limit doesn't use up the others.
+///
+/// The [SimpleOutput] given to the [RateLimiterBuilder] (e.g. for
+/// [add_headers](RateLimiterBuilder::add_headers)) is that of the allowing layer with the fewest
+/// remaining requests, or of the denying layer that resets last.
+///
+/// The layers share the backend, so each must use distinct keys, e.g. by giving each a
+/// [key_prefix](crate::backend::SimpleInputFunctionBuilder::key_prefix).
+///
+/// A layer may exempt a request from its own limit by returning [Exempt]; the request is only
+/// exempt from the whole stack if every layer exempts it.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+/// # use actix_extensible_rate_limit::RateLimiterStack;
+/// # use std::time::Duration;
+/// # async {
+/// let backend = InMemoryBackend::builder().build();
+/// let limiter = RateLimiterStack::new(backend)
+///     .layer(
+///         SimpleInputFunctionBuilder::new(Duration::from_secs(1), 1000)
+///             .custom_key("global")
+///             .build(),
+///     )
+///     .layer(
+///         SimpleInputFunctionBuilder::new(Duration::from_secs(60), 100)
+///             .real_ip_key()
+///             .build(),
+///     )
+///     .builder()
+///     .add_headers()
+///     .build();
+/// # };
+/// ```
+pub struct RateLimiterStack<B> {
+    backend: B,
+    layers: Vec<Rc<Layer>>,
+}
+
+impl<B> RateLimiterStack<B>
+where
+    B: Backend<SimpleInput, Output = SimpleOutput> + 'static,
+{
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            layers: Vec::new(),
+        }
+    }
+
+    /// Add a limit to the stack.
+    pub fn layer<F, O>(mut self, input_fn: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> O + 'static,
+        O: Future<Output = Result<SimpleInput, actix_web::Error>> + 'static,
+    {
+        self.layers
+            .push(Rc::new(move |req| Box::pin(input_fn(req))));
+        self
+    }
+
+    /// Finish adding layers, returning a [RateLimiterBuilder] to configure the middleware.
+    ///
+    /// # Panics
+    ///
+    /// If no layers have been added.
+    pub fn builder(
+        self,
+    ) -> RateLimiterBuilder<
+        StackBackend<B>,
+        SimpleOutput,
+        impl Fn(&ServiceRequest) -> StackInputFuture,
+    > {
+        assert!(
+            !self.layers.is_empty(),
+            "A stack must have at least one layer"
+        );
+        let layers = self.layers;
+        let input_fn = move |req: &ServiceRequest| {
+            let pending = layers.iter().map(|layer| layer(req)).collect::<Vec<_>>();
+            let inputs: StackInputFuture = Box::pin(async move {
+                let mut inputs = Vec::with_capacity(pending.len());
+                for result in join_all(pending).await {
+                    match result {
+                        Ok(input) => inputs.push(input),
+                        Err(e) if e.as_error::<Exempt>().is_some() => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                if inputs.is_empty() {
+                    return Err(Exempt.into());
+                }
+                Ok(inputs)
+            });
+            inputs
+        };
+        RateLimiter::builder(StackBackend(self.backend), input_fn)
+    }
+}
+
+/// The [Backend] used by a [RateLimiterStack], charging each of its layers against the wrapped
+/// backend.
+#[derive(Clone)]
+pub struct StackBackend<B>(B);
+
+impl<B> StackBackend<B> {
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.0
+    }
+}
+
+impl<B> Backend<Vec<SimpleInput>> for StackBackend<B>
+where
+    B: Backend<SimpleInput, Output = SimpleOutput>,
+{
+    type Output = SimpleOutput;
+    /// The tokens for the layers that are still charged.
+    type RollbackToken = Vec<B::RollbackToken>;
+    type Error = B::Error;
+
+    /// # Panics
+    ///
+    /// If `inputs` is empty, which the [RateLimiterStack] never passes.
+    async fn request(
+        &self,
+        inputs: Vec<SimpleInput>,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        let results = self.0.request_many(inputs).await?;
+        let allow = results.iter().all(|(allow, _, _)| *allow);
+        let output = if allow {
+            results.iter().min_by_key(|(_, output, _)| output.remaining)
+        } else {
+            results
+                .iter()
+                .filter(|(allow, _, _)| !allow)
+                .max_by_key(|(_, output, _)| output.reset)
+        }
+        .map(|(_, output, _)| output.clone())
+        .expect("A stack must have at least one layer");
+        let mut tokens = Vec::with_capacity(results.len());
+        for (layer_allowed, _, token) in results {
+            if allow || !layer_allowed {
+                tokens.push(token);
+            } else {
+                self.0.rollback(token).await?;
+            }
+        }
+        Ok((allow, output, tokens))
+    }
+
+    async fn rollback(&self, tokens: Self::RollbackToken) -> Result<(), Self::Error> {
+        for token in tokens {
+            self.0.rollback(token).await?;
+        }
+        Ok(())
+    }
+
+    /// The largest cost charged to any layer, if known for every layer.
+    fn rollback_cost(&self, tokens: &Self::RollbackToken) -> Option<u64> {
+        tokens.iter().try_fold(0, |max, token| {
+            self.0.rollback_cost(token).map(|cost| max.max(cost))
+        })
+    }
+
+    async fn health(&self) -> Result<Health, Self::Error> {
+        self.0.health().await
+    }
+}
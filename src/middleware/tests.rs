@@ -1,9 +1,13 @@
+use crate::backend::{BanStore, KeyedInput};
+use crate::middleware::builder::BuildError;
+use crate::middleware::events::DenyEvent;
 use crate::middleware::*;
 use actix_web::http::header::{HeaderName, HeaderValue};
 use actix_web::http::StatusCode;
 use actix_web::test::{read_body, TestRequest};
 use actix_web::{get, test, App, HttpResponse, Responder, ResponseError};
-use async_trait::async_trait;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use thiserror::Error;
@@ -32,7 +36,12 @@ struct MockBackendInput<T> {
     backend_error: Option<MockError>,
 }
 
-#[async_trait(?Send)]
+impl<T> KeyedInput for MockBackendInput<T> {
+    fn key(&self) -> &str {
+        "mock"
+    }
+}
+
 impl<T: 'static> Backend<MockBackendInput<T>> for MockBackend {
     type Output = T;
     type RollbackToken = ();
@@ -169,7 +178,7 @@ async fn test_fail_open() {
         Ok(MockBackendInput {
             max: u64::MAX,
             output: (),
-            backend_error: Some(MockError::default().into()),
+            backend_error: Some(MockError::default()),
         })
     })
     .build();
@@ -182,7 +191,7 @@ async fn test_fail_open() {
         Ok(MockBackendInput {
             max: u64::MAX,
             output: (),
-            backend_error: Some(MockError::default().into()),
+            backend_error: Some(MockError::default()),
         })
     })
     .request_allowed_transformation(Some(
@@ -233,3 +242,719 @@ async fn test_rollback() {
     assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
     assert_eq!(backend.0.counter.load(Ordering::Relaxed), 1);
 }
+
+#[actix_web::test]
+async fn test_rollback_on_refund() {
+    #[get("/cached")]
+    async fn cached() -> impl Responder {
+        let mut response = HttpResponse::Ok().finish();
+        response.extensions_mut().insert(Refund);
+        response
+    }
+
+    #[get("/noop")]
+    async fn noop() -> impl Responder {
+        HttpResponse::Ok()
+            .insert_header(("x-ratelimit-refund", "true"))
+            .finish()
+    }
+
+    let backend = MockBackend::default();
+    let limiter = RateLimiter::builder(backend.clone(), |_req| async {
+        Ok(MockBackendInput {
+            max: u64::MAX,
+            output: (),
+            backend_error: None,
+        })
+    })
+    .rollback_on_refund(Some(HeaderName::from_static("x-ratelimit-refund")))
+    .build();
+    let app = test::init_service(
+        App::new()
+            .service(route_200)
+            .service(cached)
+            .service(noop)
+            .wrap(limiter),
+    )
+    .await;
+
+    let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(backend.0.counter.load(Ordering::Relaxed), 1);
+
+    let response = test::call_service(&app, TestRequest::get().uri("/cached").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(backend.0.counter.load(Ordering::Relaxed), 1);
+
+    // The header is stripped from the response
+    let response = test::call_service(&app, TestRequest::get().uri("/noop").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key("x-ratelimit-refund"));
+    assert_eq!(backend.0.counter.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn test_control() {
+    let backend = MockBackend::default();
+    let control = RateLimiterControl::new();
+    let limiter = RateLimiter::builder(backend.clone(), |_req| async {
+        Ok(MockBackendInput {
+            max: 1,
+            output: (),
+            backend_error: None,
+        })
+    })
+    .control(control.clone())
+    .build();
+    let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+    let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // With enforcement disabled the request should be allowed, but still counted
+    control.disable();
+    let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(backend.0.counter.load(Ordering::Relaxed), 3);
+
+    control.enable();
+    let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn test_exempt() {
+    let backend = MockBackend::default();
+    let limiter = RateLimiter::builder(backend.clone(), |req: &ServiceRequest| {
+        let exempt = req.path() == "/200";
+        async move {
+            if exempt {
+                return Err(Exempt.into());
+            }
+            Ok(MockBackendInput {
+                max: 0,
+                output: (),
+                backend_error: None,
+            })
+        }
+    })
+    .build();
+    let app = test::init_service(
+        App::new()
+            .service(route_200)
+            .service(route_500)
+            .wrap(limiter),
+    )
+    .await;
+    let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(backend.0.counter.load(Ordering::Relaxed), 0);
+    let response = test::call_service(&app, TestRequest::get().uri("/500").to_request()).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn test_hooks() {
+    let backend = MockBackend::default();
+    let decisions = Rc::new(RefCell::new(Vec::new()));
+    let denied = Rc::new(RefCell::new(Vec::new()));
+    let limiter = RateLimiter::builder(backend.clone(), |req: &ServiceRequest| {
+        let backend_error = (req.path() == "/500").then(MockError::default);
+        async move {
+            Ok(MockBackendInput {
+                max: 1,
+                output: 7,
+                backend_error,
+            })
+        }
+    })
+    .on_request({
+        let decisions = decisions.clone();
+        move |req, decision| {
+            let decision = match decision {
+                Decision::Allowed(_) => "allowed",
+                Decision::Denied(_) => "denied",
+                Decision::ShadowDenied(_) => "shadow",
+                Decision::BackendFailed { .. } => "failed",
+                Decision::TimedOut { .. } => "timed out",
+                Decision::Banned => "banned",
+            };
+            decisions
+                .borrow_mut()
+                .push(format!("{} {decision}", req.path()));
+        }
+    })
+    .on_denied({
+        let denied = denied.clone();
+        move |_req, output| denied.borrow_mut().push(*output)
+    })
+    .build();
+    let app = test::init_service(
+        App::new()
+            .service(route_200)
+            .service(route_500)
+            .wrap(limiter),
+    )
+    .await;
+    for uri in ["/200", "/200", "/500"] {
+        test::call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+    }
+    assert_eq!(
+        *decisions.borrow(),
+        ["/200 allowed", "/200 denied", "/500 failed"]
+    );
+    assert_eq!(*denied.borrow(), [7]);
+}
+
+#[actix_web::test]
+async fn test_deny_events() {
+    let (sender, mut receiver) = DenyEvent::channel(2);
+    let limiter = RateLimiter::builder(MockBackend::default(), |_req| async {
+        Ok(MockBackendInput {
+            max: 1,
+            output: 7,
+            backend_error: None,
+        })
+    })
+    .deny_events(sender)
+    .build();
+    let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+    for _ in 0..5 {
+        test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    }
+    // The channel is bounded, so only the latest 2 of the 4 events are kept
+    assert!(matches!(
+        receiver.try_recv(),
+        Err(tokio::sync::broadcast::error::TryRecvError::Lagged(2))
+    ));
+    let event = receiver.try_recv().unwrap();
+    assert_eq!(event.key, "mock");
+    assert_eq!(event.path, "/200");
+    assert_eq!(event.output, 7);
+    assert!(receiver.try_recv().is_ok());
+    assert!(receiver.try_recv().is_err());
+}
+
+#[actix_web::test]
+async fn test_record_traffic() {
+    let records = Rc::new(RefCell::new(Vec::new()));
+    let sink = {
+        let records = records.clone();
+        move |record: &TrafficRecord| records.borrow_mut().push(record.clone())
+    };
+    let control = RateLimiterControl::new();
+    let limiter = RateLimiter::builder(MockBackend::default(), |_req| async {
+        Ok(MockBackendInput {
+            max: 1,
+            output: (),
+            backend_error: None,
+        })
+    })
+    .control(control.clone())
+    .record_traffic(sink)
+    .build();
+    let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+    test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    // Shadow mode records the backend's decision
+    control.disable();
+    test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    let records = records.borrow();
+    let decisions: Vec<_> = records
+        .iter()
+        .map(|r| (r.key.as_str(), r.cost, r.allowed))
+        .collect();
+    assert_eq!(
+        decisions,
+        [("mock", 1, true), ("mock", 1, false), ("mock", 1, false)]
+    );
+}
+
+#[derive(Clone)]
+struct MockBanStore {
+    banned: &'static str,
+    // The keys passed to ban() and unban()
+    changes: Rc<RefCell<Vec<String>>>,
+}
+
+impl BanStore for MockBanStore {
+    type Error = MockError;
+
+    async fn ban(&self, key: &str, _: std::time::Duration) -> Result<(), Self::Error> {
+        self.changes.borrow_mut().push(format!("ban:{key}"));
+        Ok(())
+    }
+
+    async fn unban(&self, key: &str) -> Result<(), Self::Error> {
+        self.changes.borrow_mut().push(format!("unban:{key}"));
+        Ok(())
+    }
+
+    async fn is_banned(&self, key: &str) -> Result<bool, Self::Error> {
+        Ok(key == self.banned)
+    }
+}
+
+#[actix_web::test]
+async fn test_ban_store() {
+    let backend = MockBackend::default();
+    let changes = Rc::new(RefCell::new(Vec::new()));
+    let limiter = |banned| {
+        RateLimiter::builder(backend.clone(), |_req| async {
+            Ok(MockBackendInput {
+                max: 10,
+                output: (),
+                backend_error: None,
+            })
+        })
+        .ban_store(MockBanStore {
+            banned,
+            changes: changes.clone(),
+        })
+        .build()
+    };
+    let app = test::init_service(App::new().service(route_200).wrap(limiter("mock"))).await;
+    let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    // The backend should not have been consulted
+    assert_eq!(backend.0.counter.load(Ordering::Relaxed), 0);
+
+    let app = test::init_service(App::new().service(route_200).wrap(limiter("other"))).await;
+    let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(backend.0.counter.load(Ordering::Relaxed), 1);
+    // The middleware only checks bans, it never changes them
+    assert!(changes.borrow().is_empty());
+}
+
+#[actix_web::test]
+async fn test_try_build() {
+    let input = |_: &ServiceRequest| async {
+        Ok(MockBackendInput {
+            max: 1,
+            output: (),
+            backend_error: None,
+        })
+    };
+    let builder = || RateLimiter::builder(MockBackend::default(), input);
+    assert!(builder().try_build().is_ok());
+    assert_eq!(
+        builder().throttle_max_total_queued(10).try_build().err(),
+        Some(BuildError::MaxTotalQueuedWithoutThrottle)
+    );
+    assert_eq!(
+        builder()
+            .banned_response(|| HttpResponse::NotFound().finish())
+            .try_build()
+            .err(),
+        Some(BuildError::BannedResponseWithoutBanStore)
+    );
+    assert_eq!(
+        builder()
+            .backend_timeout(Duration::ZERO, TimeoutPolicy::Allow)
+            .try_build()
+            .err(),
+        Some(BuildError::ZeroBackendTimeout)
+    );
+}
+
+#[cfg(feature = "dashmap")]
+#[actix_web::test]
+async fn test_per_ip_preset() {
+    use crate::backend::memory::InMemoryBackend;
+
+    let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+    let limiter = RateLimiter::per_ip_per_minute(backend, 1);
+    let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+    let request = |ip: &str| {
+        TestRequest::get()
+            .uri("/200")
+            .peer_addr(format!("{}:8080", ip).parse().unwrap())
+            .to_request()
+    };
+    let response = test::call_service(&app, request("10.0.0.1")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("x-ratelimit-remaining").unwrap(),
+        "0"
+    );
+    let response = test::call_service(&app, request("10.0.0.1")).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "60");
+    let response = test::call_service(&app, request("10.0.0.2")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[cfg(feature = "dashmap")]
+#[actix_web::test]
+async fn test_refund_handle() {
+    use crate::backend::memory::InMemoryBackend;
+    use crate::backend::{InspectableBackend, SimpleInput};
+    use crate::RateLimitHandle;
+    use std::time::Duration;
+
+    #[get("/cached")]
+    async fn cached(rate_limit: RateLimitHandle) -> actix_web::Result<HttpResponse> {
+        assert_eq!(rate_limit.charged(), 5);
+        rate_limit.refund(3).await?;
+        assert_eq!(rate_limit.charged(), 2);
+        Ok(HttpResponse::Ok().finish())
+    }
+
+    #[get("/failed")]
+    async fn failed(rate_limit: RateLimitHandle) -> actix_web::Result<HttpResponse> {
+        rate_limit.refund(1).await?;
+        Ok(HttpResponse::InternalServerError().finish())
+    }
+
+    let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+    let limiter = RateLimiter::builder(backend.clone(), |req| {
+        let key = req.path().to_owned();
+        async move {
+            Ok(SimpleInput {
+                interval: Duration::from_secs(60),
+                max_requests: 100,
+                key,
+                cost: 5,
+            })
+        }
+    })
+    .refund_handle()
+    .rollback_server_errors()
+    .add_headers()
+    .build();
+    let app = test::init_service(App::new().service(cached).service(failed).wrap(limiter)).await;
+
+    let res = test::call_service(&app, TestRequest::get().uri("/cached").to_request()).await;
+    assert!(res.status().is_success());
+    assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "98");
+    let status = backend.key_status("/cached").await.unwrap().unwrap();
+    assert_eq!(status.count, 2);
+
+    // The rollback only refunds what the handler didn't
+    let res = test::call_service(&app, TestRequest::get().uri("/failed").to_request()).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "100");
+    let status = backend.key_status("/failed").await.unwrap().unwrap();
+    assert_eq!(status.count, 0);
+}
+
+#[cfg(feature = "dashmap")]
+#[actix_web::test]
+async fn test_rollback_headers() {
+    use crate::backend::memory::InMemoryBackend;
+    use crate::backend::SimpleInput;
+    use std::time::Duration;
+
+    let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+    let limiter = RateLimiter::builder(backend, |_req| async {
+        Ok(SimpleInput {
+            interval: Duration::from_secs(60),
+            max_requests: 10,
+            key: "KEY1".to_owned(),
+            cost: 5,
+        })
+    })
+    .rollback_server_errors()
+    .add_headers()
+    .build();
+    let app = test::init_service(
+        App::new()
+            .service(route_200)
+            .service(route_500)
+            .wrap(limiter),
+    )
+    .await;
+
+    let res = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "5");
+    // The whole cost is available again after the rollback
+    let res = test::call_service(&app, TestRequest::get().uri("/500").to_request()).await;
+    assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "5");
+    let res = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+}
+
+#[actix_web::test]
+async fn test_backend_timeout() {
+    #[derive(Clone)]
+    struct SlowBackend;
+
+    impl Backend<()> for SlowBackend {
+        type Output = ();
+        type RollbackToken = ();
+        type Error = MockError;
+
+        async fn request(&self, _: ()) -> Result<(bool, (), ()), MockError> {
+            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            Ok((false, (), ()))
+        }
+
+        async fn rollback(&self, _: ()) -> Result<(), MockError> {
+            Ok(())
+        }
+    }
+
+    tokio::time::pause();
+    for (policy, expected) in [
+        (TimeoutPolicy::Allow, StatusCode::OK),
+        (TimeoutPolicy::Deny, StatusCode::SERVICE_UNAVAILABLE),
+    ] {
+        let limiter = RateLimiter::builder(SlowBackend, |_req| async { Ok(()) })
+            .backend_timeout(std::time::Duration::from_millis(100), policy)
+            .build();
+        let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+        let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+        assert_eq!(response.status(), expected);
+    }
+}
+
+#[actix_web::test]
+async fn test_backend_error_status() {
+    use crate::backend::BackendError;
+
+    #[derive(Clone)]
+    struct FailingBackend;
+
+    impl Backend<()> for FailingBackend {
+        type Output = ();
+        type RollbackToken = ();
+        type Error = BackendError;
+
+        async fn request(&self, _: ()) -> Result<(bool, (), ()), BackendError> {
+            Err(BackendError::Timeout)
+        }
+
+        async fn rollback(&self, _: ()) -> Result<(), BackendError> {
+            Ok(())
+        }
+    }
+
+    let limiter = RateLimiter::builder(FailingBackend, |_req| async { Ok(()) }).build();
+    let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+    let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let limiter = RateLimiter::builder(FailingBackend, |_req| async { Ok(()) })
+        .backend_error_status(|e| match e {
+            BackendError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })
+        .build();
+    let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+    let response = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+}
+
+#[cfg(feature = "dashmap")]
+#[actix_web::test]
+async fn test_stack() {
+    use crate::backend::memory::InMemoryBackend;
+    use crate::backend::{InspectableBackend, SimpleInput};
+    use crate::RateLimiterStack;
+    use std::time::Duration;
+
+    fn layer(
+        key: &'static str,
+        max_requests: u64,
+    ) -> impl Fn(&ServiceRequest) -> Ready<Result<SimpleInput, actix_web::Error>> {
+        move |req| {
+            if key == "user" && req.headers().get("x-exempt").is_some() {
+                return futures::future::err(Exempt.into());
+            }
+            ok(SimpleInput {
+                interval: Duration::from_secs(60),
+                max_requests,
+                key: key.to_owned(),
+                cost: 1,
+            })
+        }
+    }
+
+    let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+    let limiter = RateLimiterStack::new(backend.clone())
+        .layer(layer("global", 3))
+        .layer(layer("user", 1))
+        .builder()
+        .add_headers()
+        .build();
+    let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+
+    // The headers come from the most restrictive layer
+    let res = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+    assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+
+    // Denied by the user layer, so the global charge is rolled back
+    let res = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+    assert_eq!(
+        backend.key_status("global").await.unwrap().unwrap().count,
+        1
+    );
+    assert_eq!(backend.key_status("user").await.unwrap().unwrap().count, 2);
+
+    // Exempt from the user layer only
+    let req = TestRequest::get()
+        .uri("/200")
+        .insert_header(("x-exempt", "1"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+    assert_eq!(
+        backend.key_status("global").await.unwrap().unwrap().count,
+        2
+    );
+}
+
+#[cfg(feature = "dashmap")]
+#[actix_web::test]
+async fn test_throttle() {
+    use crate::backend::memory::InMemoryBackend;
+    use crate::backend::SimpleInputFunctionBuilder;
+    use std::time::Duration;
+
+    for (max_wait, max_queued, max_total_queued, expected) in [
+        (Duration::from_secs(5), 1, 1, StatusCode::OK),
+        (
+            Duration::from_millis(10),
+            1,
+            1,
+            StatusCode::TOO_MANY_REQUESTS,
+        ),
+        (Duration::from_secs(5), 0, 1, StatusCode::TOO_MANY_REQUESTS),
+        (Duration::from_secs(5), 1, 0, StatusCode::TOO_MANY_REQUESTS),
+    ] {
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let input = SimpleInputFunctionBuilder::new(Duration::from_millis(100), 1)
+            .custom_key("throttled")
+            .build();
+        let limiter = RateLimiter::builder(backend, input)
+            .throttle(max_wait, max_queued)
+            .throttle_max_total_queued(max_total_queued)
+            .build();
+        let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+        let res = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let started = std::time::Instant::now();
+        let res = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+        assert_eq!(res.status(), expected);
+        if expected == StatusCode::OK {
+            assert!(started.elapsed() >= Duration::from_millis(50));
+        }
+    }
+
+    // Requests that can't be throttled get the custom denied response
+    let input = SimpleInputFunctionBuilder::new(Duration::from_millis(100), 1)
+        .custom_key("throttled")
+        .build();
+    let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+    let limiter = RateLimiter::builder(backend, input)
+        .throttle(Duration::from_millis(10), 1)
+        .request_denied_response(|_| HttpResponse::ServiceUnavailable().finish())
+        .build();
+    let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+    let res = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[cfg(feature = "dashmap")]
+#[actix_web::test]
+async fn test_login_protection() {
+    use crate::backend::memory::InMemoryBackend;
+    use crate::LoginProtection;
+    use actix_web::web;
+    use std::time::Duration;
+
+    let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+    let limiter = LoginProtection::new(backend, |req| {
+        let username = req.headers().get("x-username")?.to_str().ok()?;
+        Some(username.to_owned())
+    })
+    .per_username(Duration::from_secs(60), 2)
+    .builder()
+    .build();
+    let app = test::init_service(
+        App::new()
+            .route(
+                "/login",
+                web::post().to(|req: actix_web::HttpRequest| async move {
+                    match req.headers().get("x-password") {
+                        Some(password) if password == "right" => HttpResponse::Ok().finish(),
+                        _ => HttpResponse::Unauthorized().finish(),
+                    }
+                }),
+            )
+            .wrap(limiter),
+    )
+    .await;
+    let login = |password: &str| {
+        TestRequest::post()
+            .uri("/login")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .insert_header(("x-username", "alice"))
+            .insert_header(("x-password", password))
+            .to_request()
+    };
+
+    // Successful attempts aren't counted
+    for _ in 0..3 {
+        let res = test::call_service(&app, login("right")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    for _ in 0..2 {
+        let res = test::call_service(&app, login("wrong")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+    // Locked out, even with the right password
+    let res = test::call_service(&app, login("right")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[cfg(feature = "dashmap")]
+#[actix_web::test]
+async fn test_challenge() {
+    use crate::backend::memory::InMemoryBackend;
+    use crate::backend::SimpleInputFunctionBuilder;
+    use std::time::Duration;
+
+    let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+    let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 1)
+        .custom_key("challenged")
+        .build();
+    let limiter = RateLimiter::builder(backend, input)
+        .challenge(
+            1,
+            |req| req.headers().get("x-challenge-token").is_some(),
+            |_| HttpResponse::Forbidden().finish(),
+        )
+        .build();
+    let app = test::init_service(App::new().service(route_200).wrap(limiter)).await;
+    let mut statuses = Vec::new();
+    for _ in 0..3 {
+        let res = test::call_service(&app, TestRequest::get().uri("/200").to_request()).await;
+        statuses.push(res.status());
+    }
+    assert_eq!(
+        statuses,
+        [
+            StatusCode::OK,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::FORBIDDEN
+        ]
+    );
+
+    // A solved challenge bypasses the limit
+    let req = TestRequest::get()
+        .uri("/200")
+        .insert_header(("x-challenge-token", "solved"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
@@ -0,0 +1,200 @@
+use crate::middleware::InputKey;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+type WaitFor<BO> = dyn Fn(&BO) -> Duration;
+
+/// Delays requests over the limit instead of denying them, see
+/// [RateLimiterBuilder::throttle](crate::RateLimiterBuilder::throttle).
+///
+/// The requests waiting for each key are queued, and let through in the order they arrived.
+pub(super) struct Throttle<BO> {
+    pub(super) max_wait: Duration,
+    max_queued: usize,
+    pub(super) max_total_queued: Option<usize>,
+    pub(super) input_key: Box<InputKey>,
+    pub(super) wait_for: Box<WaitFor<BO>>,
+    queues: RefCell<HashMap<String, KeyQueue>>,
+    total_queued: Cell<usize>,
+    next_ticket: Cell<u64>,
+}
+
+struct KeyQueue {
+    tickets: VecDeque<u64>,
+    // Notified when the front of the queue changes
+    notify: Rc<Notify>,
+}
+
+impl<BO> Throttle<BO> {
+    pub(super) fn new(
+        max_wait: Duration,
+        max_queued: usize,
+        input_key: Box<InputKey>,
+        wait_for: Box<WaitFor<BO>>,
+    ) -> Self {
+        Self {
+            max_wait,
+            max_queued,
+            max_total_queued: None,
+            input_key,
+            wait_for,
+            queues: RefCell::new(HashMap::new()),
+            total_queued: Cell::new(0),
+            next_ticket: Cell::new(0),
+        }
+    }
+
+    /// Whether any requests are waiting for `key`.
+    pub(super) fn is_queued(&self, key: &str) -> bool {
+        self.queues.borrow().contains_key(key)
+    }
+
+    /// Take a place at the back of the queue for `key`, if neither it nor the worker's queues as
+    /// a whole are full.
+    pub(super) fn enqueue(self: &Rc<Self>, key: String) -> Option<QueueSlot<BO>> {
+        let total_queued = self.total_queued.get();
+        if self.max_total_queued.is_some_and(|max| total_queued >= max) {
+            return None;
+        }
+        let mut queues = self.queues.borrow_mut();
+        let queue = match queues.get_mut(&key) {
+            Some(queue) if queue.tickets.len() >= self.max_queued => return None,
+            Some(queue) => queue,
+            None if self.max_queued == 0 => return None,
+            None => queues.entry(key.clone()).or_insert_with(|| KeyQueue {
+                tickets: VecDeque::new(),
+                notify: Rc::new(Notify::new()),
+            }),
+        };
+        let ticket = self.next_ticket.get();
+        self.next_ticket.set(ticket.wrapping_add(1));
+        queue.tickets.push_back(ticket);
+        self.total_queued.set(total_queued + 1);
+        Some(QueueSlot {
+            throttle: self.clone(),
+            notify: queue.notify.clone(),
+            key,
+            ticket,
+        })
+    }
+}
+
+/// A place in a [Throttle] queue, given up when dropped.
+pub(super) struct QueueSlot<BO> {
+    throttle: Rc<Throttle<BO>>,
+    notify: Rc<Notify>,
+    key: String,
+    ticket: u64,
+}
+
+impl<BO> QueueSlot<BO> {
+    /// Whether this is the first request in its queue.
+    pub(super) fn is_first(&self) -> bool {
+        self.throttle
+            .queues
+            .borrow()
+            .get(&self.key)
+            .and_then(|queue| queue.tickets.front())
+            == Some(&self.ticket)
+    }
+
+    /// Wait until this is the first request in its queue.
+    pub(super) async fn turn(&self) {
+        loop {
+            // Created before checking, so that a notification in between isn't missed
+            let notified = self.notify.notified();
+            if self.is_first() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl<BO> Drop for QueueSlot<BO> {
+    fn drop(&mut self) {
+        let mut queues = self.throttle.queues.borrow_mut();
+        let Some(queue) = queues.get_mut(&self.key) else {
+            return;
+        };
+        let Some(position) = queue.tickets.iter().position(|t| *t == self.ticket) else {
+            return;
+        };
+        queue.tickets.remove(position);
+        self.throttle
+            .total_queued
+            .set(self.throttle.total_queued.get() - 1);
+        if queue.tickets.is_empty() {
+            queues.remove(&self.key);
+        } else if position == 0 {
+            queue.notify.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(max_queued: usize, max_total_queued: Option<usize>) -> Rc<Throttle<()>> {
+        let mut throttle = Throttle::new(
+            Duration::from_secs(1),
+            max_queued,
+            Box::new(|_| None),
+            Box::new(|_| Duration::ZERO),
+        );
+        throttle.max_total_queued = max_total_queued;
+        Rc::new(throttle)
+    }
+
+    #[test]
+    fn test_queue_slots() {
+        let throttle = throttle(2, None);
+        let first = throttle.enqueue("key".to_owned()).unwrap();
+        let second = throttle.enqueue("key".to_owned()).unwrap();
+        assert!(throttle.enqueue("key".to_owned()).is_none());
+        assert!(throttle.enqueue("other".to_owned()).is_some());
+        drop(first);
+        assert!(throttle.enqueue("key".to_owned()).is_some());
+        drop(second);
+        assert!(!throttle.is_queued("key"));
+        assert_eq!(throttle.total_queued.get(), 0);
+    }
+
+    #[test]
+    fn test_max_total_queued() {
+        let throttle = throttle(2, Some(2));
+        let _first = throttle.enqueue("a".to_owned()).unwrap();
+        let _second = throttle.enqueue("b".to_owned()).unwrap();
+        assert!(throttle.enqueue("c".to_owned()).is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_fifo() {
+        let throttle = throttle(3, None);
+        let first = throttle.enqueue("key".to_owned()).unwrap();
+        let second = throttle.enqueue("key".to_owned()).unwrap();
+        let third = throttle.enqueue("key".to_owned()).unwrap();
+        assert!(first.is_first());
+        assert!(!second.is_first());
+
+        let order = RefCell::new(Vec::new());
+        let wait = |slot: QueueSlot<()>, name| {
+            let order = &order;
+            async move {
+                slot.turn().await;
+                order.borrow_mut().push(name);
+            }
+        };
+        let waiting = futures::future::join(wait(third, "third"), wait(second, "second"));
+        let release = async {
+            tokio::task::yield_now().await;
+            drop(first);
+        };
+        futures::future::join(waiting, release).await;
+        assert_eq!(*order.borrow(), ["second", "third"]);
+    }
+}
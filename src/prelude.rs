@@ -0,0 +1,20 @@
+//! Re-exports the types needed to set up a [RateLimiter], and the common traits.
+//!
+//! # Example
+//! ```
+//! use actix_extensible_rate_limit::prelude::*;
+//! use std::time::Duration;
+//!
+//! let backend = InMemoryBackend::builder().build();
+//! let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 5)
+//!     .real_ip_key()
+//!     .build();
+//! let middleware = RateLimiter::builder(backend, input).add_headers().build();
+//! ```
+#[cfg(feature = "dashmap")]
+pub use crate::backend::memory::InMemoryBackend;
+pub use crate::backend::{
+    Backend, InspectableBackend, KeyedInput, SimpleBackend, SimpleInput,
+    SimpleInputFunctionBuilder, SimpleOutput,
+};
+pub use crate::{Exempt, HeaderCompatibleOutput, RateLimiter, RateLimiterBuilder};
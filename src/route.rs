@@ -0,0 +1,89 @@
+//! Runtime support for the [rate_limit](crate::rate_limit) attribute macro.
+use crate::backend::{ip_key, ArcBackend, Backend, InputError, IpPrefix, SimpleInput};
+use crate::middleware::builder::denied_response_with_headers;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use std::time::Duration;
+
+/// The `key` argument of the macro.
+pub enum RouteKey {
+    Global,
+    PeerIp,
+    RealIp,
+    Path,
+}
+
+/// Counts a request against a route's limit, returning the response to give instead of calling
+/// the handler if it is denied (or if the limit could not be checked).
+pub async fn check_route(
+    req: &HttpRequest,
+    route: &str,
+    interval: Duration,
+    max_requests: u64,
+    key: RouteKey,
+) -> Option<HttpResponse> {
+    let Some(backend) = req.app_data::<web::Data<ArcBackend>>() else {
+        log::error!("Rate limited route `{route}` requires a web::Data<ArcBackend> in app data");
+        return Some(HttpResponse::InternalServerError().finish());
+    };
+    let key = match route_key(req, route, key) {
+        Ok(key) => key,
+        Err(e) => {
+            log::error!("Rate limited route `{route}` failed to build its key: {e}");
+            return Some(e.error_response());
+        }
+    };
+    let input = SimpleInput {
+        interval,
+        max_requests,
+        key,
+        cost: 1,
+    };
+    match backend.request(input).await {
+        Ok((true, _, _)) => None,
+        Ok((false, output, _)) => Some(denied_response_with_headers(&output)),
+        Err(e) => {
+            log::error!("Rate limiter failed: {e}");
+            Some(e.error_response())
+        }
+    }
+}
+
+fn route_key(req: &HttpRequest, route: &str, key: RouteKey) -> Result<String, InputError> {
+    let info = req.connection_info();
+    let ip = match key {
+        RouteKey::Global => return Ok(route.to_owned()),
+        RouteKey::Path => return Ok(format!("{route}:{}", req.path())),
+        RouteKey::PeerIp => info.peer_addr(),
+        RouteKey::RealIp => info.realip_remote_addr(),
+    };
+    let ip = ip.ok_or_else(|| InputError::MissingComponent("client address".to_owned()))?;
+    Ok(format!("{route}:{}", ip_key(ip, &IpPrefix::default())?))
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn test_check_route() {
+        let backend = ArcBackend::new(InMemoryBackend::builder().build());
+        let req = TestRequest::get()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .app_data(web::Data::new(backend))
+            .to_http_request();
+        let check = || check_route(&req, "route", Duration::from_secs(60), 1, RouteKey::PeerIp);
+        assert!(check().await.is_none());
+        let response = check().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get("x-ratelimit-limit").unwrap(), "1");
+
+        let req = TestRequest::get().to_http_request();
+        let response = check_route(&req, "route", Duration::from_secs(60), 1, RouteKey::Global)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,159 @@
+//! Ownership of the background tasks spawned by this crate.
+use actix_web::rt::task::JoinHandle;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// A handle that owns background tasks (e.g. garbage collectors) spawned by this crate.
+///
+/// Components that spawn background work can be given a [Supervisor] through their builders,
+/// after which [Supervisor::shutdown()] can be used to stop and drain all of those tasks, for
+/// example at the end of a test, or when the application receives a termination signal.
+///
+/// The handle is cheap to clone, all clones refer to the same set of tasks.
+///
+/// # Example
+/// ```no_run
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::Supervisor;
+/// # async {
+/// let supervisor = Supervisor::new();
+/// let backend = InMemoryBackend::builder()
+///     .with_supervisor(supervisor.clone())
+///     .build();
+/// // ...
+/// supervisor.shutdown().await;
+/// # };
+/// ```
+#[derive(Clone)]
+pub struct Supervisor {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    shutdown: watch::Sender<bool>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        let (shutdown, _) = watch::channel(false);
+        Self {
+            inner: Arc::new(Inner {
+                shutdown,
+                tasks: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Returns true once [Supervisor::shutdown()] has been called.
+    pub fn is_shutdown(&self) -> bool {
+        *self.inner.shutdown.borrow()
+    }
+
+    /// Signals all supervised tasks to stop, and waits for them to finish.
+    ///
+    /// Tasks are expected to observe their [ShutdownSignal] and return promptly. Any tasks that
+    /// are spawned after shutdown has been requested will not be started.
+    pub async fn shutdown(&self) {
+        self.inner.shutdown.send_replace(true);
+        loop {
+            let tasks = std::mem::take(&mut *self.inner.tasks.lock().unwrap());
+            if tasks.is_empty() {
+                break;
+            }
+            for task in tasks {
+                if let Err(e) = task.await {
+                    if e.is_panic() {
+                        log::error!("Supervised task panicked: {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Spawn a task onto the current Actix runtime, under the control of this supervisor.
+    ///
+    /// The closure is given a [ShutdownSignal] that will complete when the task should stop.
+    pub(crate) fn spawn<F, Fut>(&self, task: F)
+    where
+        F: FnOnce(ShutdownSignal) -> Fut,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.track(|signal| actix_web::rt::spawn(task(signal)));
+    }
+
+    /// Spawn a [Send] task onto the current tokio runtime, which need not be an Actix runtime,
+    /// under the control of this supervisor.
+    #[cfg(feature = "dashmap")]
+    pub(crate) fn spawn_send<F, Fut>(&self, task: F)
+    where
+        F: FnOnce(ShutdownSignal) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.track(|signal| tokio::spawn(task(signal)));
+    }
+
+    fn track(&self, spawn: impl FnOnce(ShutdownSignal) -> JoinHandle<()>) {
+        let mut tasks = self.inner.tasks.lock().unwrap();
+        if self.is_shutdown() {
+            log::warn!("Supervisor has been shut down, the task will not be started");
+            return;
+        }
+        tasks.retain(|t| !t.is_finished());
+        let signal = ShutdownSignal(self.inner.shutdown.subscribe());
+        tasks.push(spawn(signal));
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Given to each supervised task so that it can observe when it has been asked to stop.
+pub(crate) struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    /// Completes once the [Supervisor] has been shut down.
+    pub(crate) async fn recv(&mut self) {
+        // An error means every handle to the supervisor has been dropped, including those held by
+        // the components that spawned the task, so there is nothing left for it to do.
+        let _ = self.0.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[actix_web::test]
+    async fn test_shutdown_drains_tasks() {
+        let supervisor = Supervisor::new();
+        let finished = Arc::new(AtomicBool::new(false));
+        let flag = finished.clone();
+        supervisor.spawn(|mut signal| async move {
+            signal.recv().await;
+            flag.store(true, Ordering::SeqCst);
+        });
+        assert!(!finished.load(Ordering::SeqCst));
+        supervisor.shutdown().await;
+        assert!(finished.load(Ordering::SeqCst));
+        assert!(supervisor.is_shutdown());
+    }
+
+    #[actix_web::test]
+    async fn test_spawn_after_shutdown() {
+        let supervisor = Supervisor::new();
+        supervisor.shutdown().await;
+        let started = Arc::new(AtomicBool::new(false));
+        let flag = started.clone();
+        supervisor.spawn(|_| async move {
+            flag.store(true, Ordering::SeqCst);
+        });
+        actix_web::rt::task::yield_now().await;
+        assert!(!started.load(Ordering::SeqCst));
+    }
+}
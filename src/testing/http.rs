@@ -0,0 +1,198 @@
+use crate::middleware::builder::{X_RATELIMIT_LIMIT, X_RATELIMIT_REMAINING, X_RATELIMIT_RESET};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{HeaderMap, HeaderName, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use actix_web::HttpResponse;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// A response whose rate limit headers can be checked, see [assert_rate_limit_headers].
+pub trait ResponseHeaders {
+    fn status(&self) -> StatusCode;
+
+    fn headers(&self) -> &HeaderMap;
+}
+
+impl<B> ResponseHeaders for ServiceResponse<B> {
+    fn status(&self) -> StatusCode {
+        ServiceResponse::status(self)
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        ServiceResponse::headers(self)
+    }
+}
+
+impl<B> ResponseHeaders for HttpResponse<B> {
+    fn status(&self) -> StatusCode {
+        HttpResponse::status(self)
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        HttpResponse::headers(self)
+    }
+}
+
+fn header_u64(response: &impl ResponseHeaders, name: &HeaderName) -> u64 {
+    let value = response
+        .headers()
+        .get(name)
+        .unwrap_or_else(|| panic!("missing {} header", name));
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| panic!("invalid {} header: {:?}", name, value))
+}
+
+/// Asserts that a response has the headers added by
+/// [RateLimiterBuilder::add_headers](crate::RateLimiterBuilder::add_headers), with the given
+/// limit and remaining requests, and a reset no later than `reset_within`.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::backend::SimpleInputFunctionBuilder;
+/// # use actix_extensible_rate_limit::testing::{assert_rate_limit_headers, TestRequestExt};
+/// # use actix_extensible_rate_limit::RateLimiter;
+/// # use actix_web::{test, web, App, HttpResponse};
+/// # use std::time::Duration;
+/// # actix_web::rt::System::new().block_on(async {
+/// # let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 5).peer_ip_key().build();
+/// # let limiter = RateLimiter::builder(InMemoryBackend::builder().build(), input).add_headers().build();
+/// # let app = test::init_service(App::new().wrap(limiter).route("/", web::get().to(HttpResponse::Ok))).await;
+/// let request = test::TestRequest::get().peer_ip("10.0.0.1").to_request();
+/// let response = test::call_service(&app, request).await;
+/// assert_rate_limit_headers(&response, 5, 4, Duration::from_secs(60));
+/// # });
+/// ```
+#[track_caller]
+pub fn assert_rate_limit_headers(
+    response: &impl ResponseHeaders,
+    limit: u64,
+    remaining: u64,
+    reset_within: Duration,
+) {
+    assert_eq!(
+        header_u64(response, &X_RATELIMIT_LIMIT),
+        limit,
+        "wrong {} header",
+        *X_RATELIMIT_LIMIT
+    );
+    assert_eq!(
+        header_u64(response, &X_RATELIMIT_REMAINING),
+        remaining,
+        "wrong {} header",
+        *X_RATELIMIT_REMAINING
+    );
+    let reset = header_u64(response, &X_RATELIMIT_RESET);
+    // The header is rounded up to whole seconds
+    assert!(
+        reset <= reset_within.as_secs_f64().ceil() as u64,
+        "{} header of {} seconds is after {:?}",
+        *X_RATELIMIT_RESET,
+        reset,
+        reset_within
+    );
+}
+
+/// Asserts that a response is a 429 Too Many Requests, with a `Retry-After` header no later than
+/// `retry_within`.
+#[track_caller]
+pub fn assert_rate_limited(response: &impl ResponseHeaders, retry_within: Duration) {
+    assert_eq!(
+        response.status(),
+        StatusCode::TOO_MANY_REQUESTS,
+        "request was not rate limited"
+    );
+    let retry_after = header_u64(response, &RETRY_AFTER);
+    assert!(
+        retry_after <= retry_within.as_secs_f64().ceil() as u64,
+        "{} header of {} seconds is after {:?}",
+        RETRY_AFTER,
+        retry_after,
+        retry_within
+    );
+}
+
+/// Sets the client IP of a [TestRequest], as used by the IP keys of the
+/// [SimpleInputFunctionBuilder](crate::backend::SimpleInputFunctionBuilder).
+pub trait TestRequestExt {
+    /// Sets the peer address, as used by
+    /// [peer_ip_key](crate::backend::SimpleInputFunctionBuilder::peer_ip_key).
+    ///
+    /// # Panics
+    ///
+    /// If `ip` is not an IP address.
+    fn peer_ip(self, ip: &str) -> Self;
+
+    /// Sets the `X-Forwarded-For` header, as used by
+    /// [real_ip_key](crate::backend::SimpleInputFunctionBuilder::real_ip_key).
+    ///
+    /// # Panics
+    ///
+    /// If `ip` is not an IP address.
+    fn real_ip(self, ip: &str) -> Self;
+}
+
+fn parse_ip(ip: &str) -> IpAddr {
+    ip.parse()
+        .unwrap_or_else(|_| panic!("invalid IP address: {}", ip))
+}
+
+impl TestRequestExt for TestRequest {
+    fn peer_ip(self, ip: &str) -> Self {
+        self.peer_addr(SocketAddr::new(parse_ip(ip), 12345))
+    }
+
+    fn real_ip(self, ip: &str) -> Self {
+        self.insert_header(("x-forwarded-for", parse_ip(ip).to_string()))
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use crate::backend::SimpleInputFunctionBuilder;
+    use crate::RateLimiter;
+    use actix_web::{test, web, App};
+
+    #[actix_web::test]
+    async fn test_assertions() {
+        let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 1)
+            .real_ip_key()
+            .build();
+        let limiter = RateLimiter::builder(InMemoryBackend::builder().build(), input)
+            .add_headers()
+            .build();
+        let app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let request = || TestRequest::get().peer_ip("10.0.0.1").real_ip("192.0.2.1");
+        let response = test::call_service(&app, request().to_request()).await;
+        assert_rate_limit_headers(&response, 1, 0, Duration::from_secs(60));
+        let response = test::call_service(&app, request().to_request()).await;
+        assert_rate_limited(&response, Duration::from_secs(60));
+        // A different real IP behind the same peer
+        let request = TestRequest::get().peer_ip("10.0.0.1").real_ip("192.0.2.2");
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    #[should_panic(expected = "wrong x-ratelimit-remaining header")]
+    async fn test_assertion_fails() {
+        let response = HttpResponse::Ok()
+            .insert_header((X_RATELIMIT_LIMIT.clone(), 5))
+            .insert_header((X_RATELIMIT_REMAINING.clone(), 3))
+            .insert_header((X_RATELIMIT_RESET.clone(), 10))
+            .finish();
+        assert_rate_limit_headers(&response, 5, 4, Duration::from_secs(60));
+    }
+}
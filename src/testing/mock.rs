@@ -0,0 +1,316 @@
+use crate::backend::{Backend, SimpleBackend, SimpleInput, SimpleOutput, SimpleRollbackToken};
+use actix_web::rt::time::Instant;
+use actix_web::{HttpResponse, ResponseError};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+
+/// How a [MockBackend] responds to the requests for a key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    deny_after: Option<u64>,
+    error_on_calls: Vec<u64>,
+}
+
+impl Script {
+    /// Allow every request, regardless of the input's limit.
+    pub fn allow() -> Self {
+        Self::default()
+    }
+
+    /// Deny every request.
+    pub fn deny() -> Self {
+        Self::deny_after(0)
+    }
+
+    /// Allow the first `allowed` requests (counting their cost), then deny the rest.
+    pub fn deny_after(allowed: u64) -> Self {
+        Self {
+            deny_after: Some(allowed),
+            error_on_calls: Vec::new(),
+        }
+    }
+
+    /// Also fail the `call`th request for the key (counting from 1) with a [MockError].
+    pub fn error_on_call(mut self, call: u64) -> Self {
+        self.error_on_calls.push(call);
+        self
+    }
+}
+
+/// The error returned by a [MockBackend] when scripted with [Script::error_on_call].
+///
+/// Responds with a 500 Internal Server Error.
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[error("Mock backend error on call {call} for key {key}")]
+pub struct MockError {
+    /// The key of the request that failed.
+    pub key: String,
+    /// Which request for the key failed, counting from 1.
+    pub call: u64,
+}
+
+impl ResponseError for MockError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+/// A [Backend] whose responses are scripted per key, and that records every input and rollback,
+/// for testing how an application handles denied requests and backend errors.
+///
+/// Keys without a script follow the default script, which allows every request unless replaced
+/// with [MockBackend::default_script].
+///
+/// The [SimpleOutput] reports the scripted number of allowed requests as the limit (or the
+/// input's limit if unscripted), and a reset time of one interval after the request.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::testing::{MockBackend, Script};
+/// # use actix_extensible_rate_limit::backend::{SimpleInputFunctionBuilder};
+/// # use actix_extensible_rate_limit::RateLimiter;
+/// # use std::time::Duration;
+/// let backend = MockBackend::new()
+///     .script("/login", Script::deny_after(2))
+///     .script("/search", Script::allow().error_on_call(3));
+/// let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 100)
+///     .path_key()
+///     .build();
+/// let limiter = RateLimiter::builder(backend.clone(), input).build();
+/// // ... after calling the service:
+/// let keys = backend.inputs().into_iter().map(|input| input.key);
+/// ```
+#[derive(Clone, Default)]
+pub struct MockBackend {
+    default: Script,
+    scripts: HashMap<String, Script>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    inputs: Vec<SimpleInput>,
+    rollbacks: Vec<SimpleRollbackToken>,
+    keys: HashMap<String, KeyState>,
+}
+
+#[derive(Default)]
+struct KeyState {
+    calls: u64,
+    count: u64,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Respond to requests for `key` as scripted.
+    pub fn script(mut self, key: impl Into<String>, script: Script) -> Self {
+        self.scripts.insert(key.into(), script);
+        self
+    }
+
+    /// Respond to requests for keys without a script as scripted.
+    pub fn default_script(mut self, script: Script) -> Self {
+        self.default = script;
+        self
+    }
+
+    /// Every input received, in order, including those that failed.
+    pub fn inputs(&self) -> Vec<SimpleInput> {
+        self.state.lock().unwrap().inputs.clone()
+    }
+
+    /// Every rollback token received, in order.
+    pub fn rollbacks(&self) -> Vec<SimpleRollbackToken> {
+        self.state.lock().unwrap().rollbacks.clone()
+    }
+
+    /// The number of requests received for `key`.
+    pub fn calls(&self, key: &str) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.keys.get(key).map_or(0, |key| key.calls)
+    }
+
+    /// Forget every input, rollback, and count, keeping the scripts.
+    pub fn reset(&self) {
+        *self.state.lock().unwrap() = State::default();
+    }
+}
+
+impl Backend<SimpleInput> for MockBackend {
+    type Output = SimpleOutput;
+    type RollbackToken = SimpleRollbackToken;
+    type Error = MockError;
+
+    async fn request(
+        &self,
+        input: SimpleInput,
+    ) -> Result<(bool, Self::Output, Self::RollbackToken), Self::Error> {
+        let script = self.scripts.get(&input.key).unwrap_or(&self.default);
+        let mut state = self.state.lock().unwrap();
+        state.inputs.push(input.clone());
+        let key = state.keys.entry(input.key.clone()).or_default();
+        key.calls += 1;
+        if script.error_on_calls.contains(&key.calls) {
+            return Err(MockError {
+                key: input.key,
+                call: key.calls,
+            });
+        }
+        let limit = script.deny_after.unwrap_or(input.max_requests);
+        let allow = script.deny_after.is_none() || key.count.saturating_add(input.cost) <= limit;
+        if allow {
+            key.count = key.count.saturating_add(input.cost);
+        }
+        let output = SimpleOutput {
+            limit,
+            remaining: limit.saturating_sub(key.count),
+            reset: Instant::now() + input.interval,
+        };
+        let token = SimpleRollbackToken {
+            key: input.key,
+            cost: if allow { input.cost } else { 0 },
+        };
+        Ok((allow, output, token))
+    }
+
+    async fn rollback(&self, token: Self::RollbackToken) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(key) = state.keys.get_mut(&token.key) {
+            key.count = key.count.saturating_sub(token.cost);
+        }
+        state.rollbacks.push(token);
+        Ok(())
+    }
+
+    fn rollback_cost(&self, token: &Self::RollbackToken) -> Option<u64> {
+        Some(token.cost)
+    }
+}
+
+impl SimpleBackend for MockBackend {
+    async fn remove_key(&self, key: &str) -> Result<(), Self::Error> {
+        self.state.lock().unwrap().keys.remove(key);
+        Ok(())
+    }
+
+    async fn peek(&self, input: &SimpleInput) -> Result<(bool, SimpleOutput), Self::Error> {
+        let script = self.scripts.get(&input.key).unwrap_or(&self.default);
+        let state = self.state.lock().unwrap();
+        let count = state.keys.get(&input.key).map_or(0, |key| key.count);
+        let limit = script.deny_after.unwrap_or(input.max_requests);
+        let allow = script.deny_after.is_none() || count.saturating_add(input.cost) <= limit;
+        let output = SimpleOutput {
+            limit,
+            remaining: limit.saturating_sub(count),
+            reset: Instant::now() + input.interval,
+        };
+        Ok((allow, output))
+    }
+
+    async fn set_key(&self, key: &str, count: u64, ttl: Duration) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if ttl.is_zero() {
+            state.keys.remove(key);
+        } else {
+            state.keys.entry(key.to_owned()).or_default().count = count;
+        }
+        Ok(())
+    }
+
+    async fn remove_keys(&self, prefix: &str) -> Result<u64, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.keys.len();
+        state.keys.retain(|key, _| !key.starts_with(prefix));
+        Ok((before - state.keys.len()) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SimpleInputFunctionBuilder;
+    use crate::RateLimiter;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{get, App, HttpResponse, Responder};
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[get("/{name}")]
+    async fn route() -> impl Responder {
+        HttpResponse::Ok().body("Hello world!")
+    }
+
+    fn input(key: &str) -> SimpleInput {
+        SimpleInput {
+            interval: MINUTE,
+            max_requests: 1,
+            key: key.to_owned(),
+            cost: 1,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_scripts() {
+        let backend = MockBackend::new()
+            .script("deny", Script::deny())
+            .script("error", Script::deny_after(2).error_on_call(2));
+
+        // Unscripted keys are always allowed
+        for _ in 0..3 {
+            assert!(backend.request(input("other")).await.unwrap().0);
+        }
+        assert!(!backend.request(input("deny")).await.unwrap().0);
+
+        let (allow, output, token) = backend.request(input("error")).await.unwrap();
+        assert!(allow);
+        assert_eq!(output.limit, 2);
+        assert_eq!(output.remaining, 1);
+        assert_eq!(
+            backend.request(input("error")).await.unwrap_err(),
+            MockError {
+                key: "error".to_owned(),
+                call: 2
+            }
+        );
+        backend.rollback(token.clone()).await.unwrap();
+        assert!(backend.request(input("error")).await.unwrap().0);
+        assert!(backend.request(input("error")).await.unwrap().0);
+        assert!(!backend.request(input("error")).await.unwrap().0);
+
+        assert_eq!(backend.calls("error"), 5);
+        assert_eq!(backend.inputs().len(), 9);
+        assert_eq!(backend.rollbacks(), vec![token]);
+        backend.reset();
+        assert_eq!(backend.calls("error"), 0);
+        assert!(backend.request(input("error")).await.unwrap().0);
+    }
+
+    #[actix_web::test]
+    async fn test_middleware() {
+        let backend = MockBackend::new()
+            .script("/limited", Script::deny())
+            .script("/broken", Script::allow().error_on_call(1));
+        let input_fn = SimpleInputFunctionBuilder::new(MINUTE, 100)
+            .path_key()
+            .build();
+        let limiter = RateLimiter::builder(backend.clone(), input_fn).build();
+        let app = test::init_service(App::new().service(route).wrap(limiter)).await;
+
+        for (uri, status) in [
+            ("/open", StatusCode::OK),
+            ("/limited", StatusCode::TOO_MANY_REQUESTS),
+            ("/broken", StatusCode::INTERNAL_SERVER_ERROR),
+        ] {
+            let response = test::call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(response.status(), status);
+        }
+        let keys: Vec<_> = backend.inputs().into_iter().map(|i| i.key).collect();
+        assert_eq!(keys, ["/open", "/limited", "/broken"]);
+    }
+}
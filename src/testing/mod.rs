@@ -0,0 +1,218 @@
+//! Utilities for testing rate limited applications, and custom backends.
+//!
+//! [MockBackend] responds to requests as scripted per key, and records every input, for testing
+//! how an application handles denied requests and backend errors.
+//!
+//! [assert_rate_limit_headers] and [assert_rate_limited] check the responses of a rate limited
+//! service, and [TestRequestExt] sets the client IP of a test request.
+//!
+//! The remaining functions are a conformance test suite for [Backend] implementations, checking
+//! that a custom backend matches the semantics of the provided ones. Each function panics if the
+//! backend does not conform. The [backend_test_suite] macro generates an `#[actix_web::test]`
+//! for each of them; or they can be called from your own tests, e.g. with a backend that needs
+//! setting up first.
+//!
+//! Keys are suffixed with a random value, so the tests can be run against a shared data store.
+//! [test_expiry] waits for the reset time reported by the backend, which takes no time at all if
+//! the backend reads the time from tokio, and tokio's time is paused with
+//! [tokio::time::pause](https://docs.rs/tokio/latest/tokio/time/fn.pause.html).
+//!
+//! # Example
+//! ```
+//! mod conformance {
+//!     use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+//!     use actix_extensible_rate_limit::backend_test_suite;
+//!
+//!     backend_test_suite!(simple: InMemoryBackend::builder().build());
+//! }
+//! ```
+use crate::backend::{Backend, SimpleBackend, SimpleInput, SimpleOutput};
+use actix_web::rt::time::Instant;
+use std::collections::hash_map::RandomState;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+mod http;
+mod mock;
+
+pub use http::{assert_rate_limit_headers, assert_rate_limited, ResponseHeaders, TestRequestExt};
+pub use mock::{MockBackend, MockError, Script};
+
+/// Generates an `#[actix_web::test]` for each function in [testing](crate::testing), given an
+/// expression that creates the backend (which may `.await`).
+///
+/// Prefix the expression with `simple:` to include the tests that require a [SimpleBackend].
+#[macro_export]
+macro_rules! backend_test_suite {
+    (simple: $backend:expr) => {
+        $crate::backend_test_suite!($backend);
+
+        #[::actix_web::test]
+        async fn conformance_remove_key() {
+            $crate::testing::test_remove_key(&$backend).await;
+        }
+    };
+    ($backend:expr) => {
+        #[::actix_web::test]
+        async fn conformance_allow_deny() {
+            $crate::testing::test_allow_deny(&$backend).await;
+        }
+
+        #[::actix_web::test]
+        async fn conformance_independent_keys() {
+            $crate::testing::test_independent_keys(&$backend).await;
+        }
+
+        #[::actix_web::test]
+        async fn conformance_cost() {
+            $crate::testing::test_cost(&$backend).await;
+        }
+
+        #[::actix_web::test]
+        async fn conformance_rollback() {
+            $crate::testing::test_rollback(&$backend).await;
+        }
+
+        #[::actix_web::test]
+        async fn conformance_expiry() {
+            $crate::testing::test_expiry(&$backend).await;
+        }
+
+        #[::actix_web::test]
+        async fn conformance_concurrency() {
+            $crate::testing::test_concurrency(&$backend).await;
+        }
+    };
+}
+
+/// The tests in this module are for backends with these bounds.
+pub trait TestableBackend: Backend<SimpleInput, Output = SimpleOutput, Error: Debug> {}
+
+impl<B> TestableBackend for B where B: Backend<SimpleInput, Output = SimpleOutput, Error: Debug> {}
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+fn unique_key(name: &str) -> String {
+    let suffix = RandomState::new().build_hasher().finish();
+    format!("conformance-{}-{:016x}", name, suffix)
+}
+
+fn input(key: &str, interval: Duration, max_requests: u64, cost: u64) -> SimpleInput {
+    SimpleInput {
+        interval,
+        max_requests,
+        key: key.to_owned(),
+        cost,
+    }
+}
+
+/// Requests are allowed up to the limit, and then denied, with the limit and the remaining
+/// requests reported in the output.
+pub async fn test_allow_deny<B: TestableBackend>(backend: &B) {
+    let key = unique_key("allow-deny");
+    let start = Instant::now();
+    for remaining in (0..3).rev() {
+        let (allow, output, _) = backend.request(input(&key, MINUTE, 3, 1)).await.unwrap();
+        assert!(allow, "request within the limit was denied");
+        assert_eq!(output.limit, 3, "wrong limit");
+        assert_eq!(output.remaining, remaining, "wrong remaining requests");
+        assert!(output.reset > start, "reset is in the past");
+        assert!(
+            output.reset <= Instant::now() + MINUTE,
+            "reset is after the interval"
+        );
+    }
+    let (allow, output, _) = backend.request(input(&key, MINUTE, 3, 1)).await.unwrap();
+    assert!(!allow, "request over the limit was allowed");
+    assert_eq!(output.remaining, 0, "denied request has remaining requests");
+}
+
+/// Each key is counted separately.
+pub async fn test_independent_keys<B: TestableBackend>(backend: &B) {
+    let key1 = unique_key("independent-1");
+    let key2 = unique_key("independent-2");
+    assert!(backend.request(input(&key1, MINUTE, 1, 1)).await.unwrap().0);
+    assert!(!backend.request(input(&key1, MINUTE, 1, 1)).await.unwrap().0);
+    let (allow, _, _) = backend.request(input(&key2, MINUTE, 1, 1)).await.unwrap();
+    assert!(allow, "a key's limit affected another key");
+}
+
+/// A request counts its cost towards the limit, and a request costing more than remains is
+/// denied.
+pub async fn test_cost<B: TestableBackend>(backend: &B) {
+    let key = unique_key("cost");
+    let (allow, output, _) = backend.request(input(&key, MINUTE, 5, 3)).await.unwrap();
+    assert!(allow, "request within the limit was denied");
+    assert_eq!(output.remaining, 2, "cost was not counted");
+    let (allow, _, _) = backend.request(input(&key, MINUTE, 5, 3)).await.unwrap();
+    assert!(!allow, "request costing more than remains was allowed");
+}
+
+/// Rolling back an allowed request refunds its cost.
+pub async fn test_rollback<B: TestableBackend>(backend: &B) {
+    let key = unique_key("rollback");
+    let (_, _, token) = backend.request(input(&key, MINUTE, 5, 2)).await.unwrap();
+    backend.request(input(&key, MINUTE, 5, 1)).await.unwrap();
+    backend.rollback(token).await.unwrap();
+    let (allow, output, _) = backend.request(input(&key, MINUTE, 5, 1)).await.unwrap();
+    assert!(allow, "request within the limit was denied");
+    assert_eq!(output.remaining, 3, "rollback did not refund the cost");
+}
+
+/// The limit resets by the time reported in the output.
+pub async fn test_expiry<B: TestableBackend>(backend: &B) {
+    let key = unique_key("expiry");
+    let interval = Duration::from_secs(1);
+    assert!(
+        backend
+            .request(input(&key, interval, 1, 1))
+            .await
+            .unwrap()
+            .0
+    );
+    let (allow, output, _) = backend.request(input(&key, interval, 1, 1)).await.unwrap();
+    assert!(!allow, "request over the limit was allowed");
+    // Allow for backends that store the time with a precision of a millisecond
+    actix_web::rt::time::sleep_until(output.reset + Duration::from_millis(1)).await;
+    let (allow, output, _) = backend.request(input(&key, interval, 1, 1)).await.unwrap();
+    assert!(allow, "limit did not reset by the reported time");
+    assert_eq!(output.remaining, 0, "wrong remaining requests");
+}
+
+/// Concurrent requests for the same key allow exactly the limit.
+pub async fn test_concurrency<B: TestableBackend>(backend: &B) {
+    let key = unique_key("concurrency");
+    let requests = (0..50).map(|_| backend.request(input(&key, MINUTE, 10, 1)));
+    let allowed = futures::future::join_all(requests)
+        .await
+        .into_iter()
+        .filter(|result| result.as_ref().unwrap().0)
+        .count();
+    assert_eq!(allowed, 10, "concurrent requests exceeded the limit");
+}
+
+/// Removing a key resets its limit.
+pub async fn test_remove_key<B: TestableBackend + SimpleBackend>(backend: &B) {
+    let key = unique_key("remove-key");
+    assert!(backend.request(input(&key, MINUTE, 1, 1)).await.unwrap().0);
+    assert!(!backend.request(input(&key, MINUTE, 1, 1)).await.unwrap().0);
+    backend.remove_key(&key).await.unwrap();
+    let (allow, _, _) = backend.request(input(&key, MINUTE, 1, 1)).await.unwrap();
+    assert!(allow, "removing the key did not reset its limit");
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    mod memory {
+        use crate::backend::memory::InMemoryBackend;
+
+        backend_test_suite!(simple: InMemoryBackend::builder().build());
+    }
+
+    mod atomic {
+        use crate::backend::atomic::AtomicInMemoryBackend;
+
+        backend_test_suite!(AtomicInMemoryBackend::builder().build());
+    }
+}
@@ -0,0 +1,143 @@
+//! Rate limiting the messages received on long-lived connections, such as WebSockets, which the
+//! middleware only sees once, when they are opened.
+use crate::backend::{Backend, SimpleInput};
+use crate::Limiter;
+
+/// What to do with a message checked by a [MessageLimiter].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageVerdict {
+    /// The message is within the limit.
+    Allow,
+    /// The message is over the limit, and should be ignored.
+    Drop,
+    /// The connection has gone over the limit too many times, and should be closed.
+    Close,
+}
+
+/// Limits the messages received on a single connection, optionally closing it after repeated
+/// violations.
+///
+/// Create one for each connection, with a key identifying it (or its client, to share the limit
+/// between all of that client's connections), then check each message before handling it.
+///
+/// # Example
+/// ```
+/// # use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
+/// # use actix_extensible_rate_limit::ws::{MessageLimiter, MessageVerdict};
+/// # use actix_extensible_rate_limit::Limiter;
+/// # use std::time::Duration;
+/// # async {
+/// let limiter = Limiter::new(InMemoryBackend::builder().build(), Duration::from_secs(1), 10)
+///     .key_prefix("ws:");
+/// let mut messages = MessageLimiter::new(limiter, "connection-id").close_after(5);
+/// // For each message received
+/// match messages.check().await.unwrap() {
+///     MessageVerdict::Allow => { /* handle the message */ }
+///     MessageVerdict::Drop => { /* ignore it */ }
+///     MessageVerdict::Close => { /* close the connection */ }
+/// }
+/// # };
+/// ```
+pub struct MessageLimiter<B> {
+    limiter: Limiter<B>,
+    key: String,
+    close_after: Option<u32>,
+    violations: u32,
+}
+
+impl<B> MessageLimiter<B>
+where
+    B: Backend<SimpleInput>,
+{
+    pub fn new(limiter: Limiter<B>, key: &str) -> Self {
+        Self {
+            limiter,
+            key: key.to_owned(),
+            close_after: None,
+            violations: 0,
+        }
+    }
+
+    /// Return [MessageVerdict::Close] once this many messages have been over the limit, over
+    /// the lifetime of the connection.
+    ///
+    /// By default the connection is never closed, and messages over the limit are dropped.
+    pub fn close_after(mut self, violations: u32) -> Self {
+        self.close_after = Some(violations);
+        self
+    }
+
+    /// The number of messages that have been over the limit so far.
+    pub fn violations(&self) -> u32 {
+        self.violations
+    }
+
+    /// Count a message against the limit.
+    pub async fn check(&mut self) -> Result<MessageVerdict, B::Error> {
+        self.consume(1).await
+    }
+
+    /// Count a message with the given cost against the limit, e.g. its size in kilobytes.
+    pub async fn consume(&mut self, cost: u64) -> Result<MessageVerdict, B::Error> {
+        let (allowed, _, _) = self.limiter.consume(&self.key, cost).await?;
+        if allowed {
+            return Ok(MessageVerdict::Allow);
+        }
+        self.violations = self.violations.saturating_add(1);
+        match self.close_after {
+            Some(close_after) if self.violations >= close_after => Ok(MessageVerdict::Close),
+            _ => Ok(MessageVerdict::Drop),
+        }
+    }
+
+    /// Count a message against the limit, closing `session` with a
+    /// [Policy](actix_ws::CloseCode::Policy) close code if it has gone over the limit too many
+    /// times.
+    ///
+    /// Returns whether the message should be handled.
+    #[cfg(feature = "actix-ws")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "actix-ws")))]
+    pub async fn check_session(&mut self, session: &actix_ws::Session) -> Result<bool, B::Error> {
+        match self.check().await? {
+            MessageVerdict::Allow => Ok(true),
+            MessageVerdict::Drop => Ok(false),
+            MessageVerdict::Close => {
+                let reason = actix_ws::CloseReason {
+                    code: actix_ws::CloseCode::Policy,
+                    description: Some("Rate limit exceeded".to_owned()),
+                };
+                // The session may already be closed, in which case there's nothing to do
+                let _ = session.clone().close(Some(reason)).await;
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "dashmap"))]
+mod tests {
+    use super::*;
+    use crate::backend::memory::InMemoryBackend;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_message_limiter() {
+        let backend = InMemoryBackend::builder().with_gc_interval(None).build();
+        let limiter = Limiter::new(backend, Duration::from_secs(60), 2);
+        let mut messages = MessageLimiter::new(limiter, "connection").close_after(2);
+        let mut verdicts = Vec::new();
+        for _ in 0..4 {
+            verdicts.push(messages.check().await.unwrap());
+        }
+        assert_eq!(
+            verdicts,
+            [
+                MessageVerdict::Allow,
+                MessageVerdict::Allow,
+                MessageVerdict::Drop,
+                MessageVerdict::Close
+            ]
+        );
+        assert_eq!(messages.violations(), 2);
+    }
+}